@@ -0,0 +1,50 @@
+#![no_main]
+
+/*
+    Feeds arbitrary bytes to the ELF parser and to the line table parser the loader runs on what the ELF
+    parser hands it. Both only depend on std and thiserror, so they are built straight from crimson's
+    sources instead of through a library crate.
+
+        cargo +nightly fuzz run elf_parser
+
+    Any panic is a bug, malformed input has to end in an error.
+*/
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/emulator/elf.rs"]
+mod elf;
+
+#[allow(dead_code)]
+#[path = "../../src/emulator/dwarf.rs"]
+mod dwarf;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(elf) = elf::Elf::parse(data) else {
+        return;
+    };
+
+    for header in &elf.program_headers {
+        let _ = elf.segment_data(header);
+    }
+    for header in &elf.section_headers {
+        let _ = elf.section_data(header);
+        let _ = elf.section_name(header);
+    }
+    let _ = elf.symbols();
+
+    let section = |name| elf.section_named(name).and_then(|header| elf.section_data(header).ok());
+    if let Some(debug_line) = section(".debug_line") {
+        let strings = dwarf::Strings {
+            line_str: section(".debug_line_str").unwrap_or_default(),
+            str: section(".debug_str").unwrap_or_default(),
+        };
+
+        if let Ok(lines) = dwarf::LineTable::parse(debug_line, strings) {
+            for header in &elf.section_headers {
+                let _ = lines.locate(header.sh_addr);
+            }
+        }
+    }
+});
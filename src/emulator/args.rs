@@ -0,0 +1,480 @@
+use std::{fs, io, path::PathBuf};
+use super::{cmplog::CmpHook, decoder, hooks::InstHookAt, devices::{uart::{UartInput, UartOutput}, virtio_blk::{Disk, DiskMode}, virtio_console::Port}, memory::{AccessKind, MisalignedAccess, PermGranularity, DEFAULT_MEMORY_LIMIT}, smp::{DEFAULT_QUANTUM, MAX_HARTS}, time_travel::DEFAULT_SNAPSHOT_INTERVAL, trace::{TraceErr, TraceFilter, TraceSink}};
+
+pub const USAGE: &str = "\
+usage: crimson <file> [options]
+
+options:
+    --max-insts <n>             stop after n instructions
+    --memory <n>                most guest memory that may be mapped, in bytes (default 1GB)
+    --perm-granularity <g>      how finely memory permissions are kept, g is one of
+                                    byte (default), page (like a real MMU, faster)
+    --misaligned <m>            what loads and stores that aren't naturally aligned do, m is one of
+                                    allow (default), fault (address misaligned exception)
+    --watch <start>:<end>[,<kinds>]
+                                log every access to start <= addr < end to stderr, kinds is any of
+                                    r (read), w (write), x (execute), default rw
+    --watch-stop                stop the run at the first watched access instead of logging all of them
+    --stub <addr>[=<n>]         return from the function at addr as soon as it is called, with n in a0 if
+                                given, can be given more than once
+    --skip <addr>               step over the instruction at addr without executing it, e.g. a check
+    --stop-at <addr>            stop the run when the instruction at addr is about to execute
+    --set-reg <addr>:<reg>=<n>  set reg (a0, x10, ...) to n every time the instruction at addr is about
+                                to execute, e.g. to force the result of a check
+                                (the addresses of these four can also be symbol names)
+    --stop-on <mnemonic>        stop the run before any instruction with this mnemonic, whatever its
+                                operands, e.g. ecall, fence.i, sc.w
+    --trace <sink>              trace every executed instruction, sink is one of
+                                    stderr, text:<path>, bin:<path>
+    --trace-pc <start>:<end>    only trace instructions with start <= pc < end
+    --trace-icount <start>:<end>
+                                only trace instructions start <= icount < end
+    --input <path>              the guest reads the file at path as its stdin instead of the host's
+    --crash-dir <dir>           keep one reproducer and report per crash bucket in dir, a crash whose
+                                bucket is already there is only counted, without --input the guest's
+                                stdin is read up front so it can be saved
+    --minimize                  shrink the input of every new crash bucket before it is saved (needs
+                                --crash-dir)
+    --cmplog <path>             log the operands of every branch, SLT* and call to a compare function to
+                                path, functions named memcmp, strcmp or strncmp are hooked by name
+    --cmp-hook <fn>=<addr>      with --cmplog, also hook the function at addr (or symbol) as fn, one of
+                                memcmp, strcmp, strncmp, can be given more than once
+    --cmplog-mutations <dir>    write every input that replaces one side of a logged comparison in
+                                --input with the other side to dir
+    --jit                       translate basic blocks to x86-64 code instead of interpreting them (needs
+                                a build with the jit feature)
+    --jit-check                 run the program under the JIT and the interpreter side by side and stop at
+                                the first state they disagree on, give input with --input
+    --record <path>             log every nondeterministic input (stdin, time, randomness) to path
+    --replay <path>             feed the inputs logged by --record back instead of asking the host
+    --gdb <port>                wait for gdb on 127.0.0.1:port instead of running, with reverse execution
+    --snapshot-interval <n>     instructions between the snapshots reverse execution goes back to
+    --bare                      run as bare-metal machine code: start in M mode, traps go to the guest's
+                                handlers, a CLINT timer is attached at 0x2000000 and a PLIC at 0xc000000
+    --kernel                    file is an S mode payload with a RISC-V boot image header, entered the way
+                                firmware hands off to a kernel: a generated device tree in a1 and the
+                                emulator as SBI firmware (implies --bare)
+    --initrd <path>             initramfs for --kernel, loaded below the device tree
+    --append <cmdline>          kernel command line for --kernel
+    --harts <n>                 number of harts sharing memory and devices (needs --bare or --kernel),
+                                they take turns running --quantum instructions each
+    --quantum <n>               instructions a hart runs before the next one gets its turn (default 1000)
+    --wall-clock                CLINT time follows the host clock instead of the instruction count, such
+                                runs can't be recorded or replayed
+    --uart-out <out>            attach a 16550A UART at 0x10000000, out is stdout or a file path
+    --uart-in <in>              console input for the UART, in is stdin, a named pipe or a file of bytes
+                                to feed it
+    --disk <path>               attach a virtio block device at 0x10001000 backed by the image at path
+    --disk-mode <mode>          how the image is written, mode is one of
+                                    cow (default, writes stay in memory), ro, rw
+    --virtio-console <out>[,<in>]
+                                attach a virtio console at 0x10002000, port 0 (hvc0) writes to out and
+                                reads from in, like --uart-out and --uart-in
+    --virtio-port <name>=<out>[,<in>]
+                                add a named port to the virtio console, can be given more than once
+    --virtio-rng                attach a virtio entropy device at 0x10003000 fed from the host
+    --rng-seed <n>              feed the virtio entropy device from a generator seeded with n instead,
+                                runs with the same seed see the same bytes
+
+numbers are decimal, or hex with a 0x prefix";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ArgsErr {
+    #[error("Missing file")]
+    MissingFile,
+
+    #[error("Unknown option: {0}")]
+    UnknownOption(String),
+
+    #[error("Missing value for: {0}")]
+    MissingValue(String),
+
+    #[error("Invalid value for {0}: {1}")]
+    InvalidValue(String, String),
+
+    #[error("--record and --replay can't be used together")]
+    RecordAndReplay,
+
+    #[error("{0} needs --bare or --kernel")]
+    NeedsBareMetal(&'static str),
+
+    #[error("--wall-clock runs can't be recorded or replayed")]
+    WallClockNondeterministic,
+
+    #[error("--minimize needs --crash-dir")]
+    MinimizeWithoutCrashDir,
+
+    #[error("--cmplog-mutations needs --cmplog and --input")]
+    MutationsWithoutCmpLog,
+
+    #[error("{0} needs a build with the jit feature")]
+    NoJit(&'static str),
+
+    #[error("No symbol named {0}")]
+    UnknownSymbol(String),
+
+    #[error("No instruction named {0}")]
+    UnknownMnemonic(String),
+
+    #[error("Unable to open {0}: {1}")]
+    UnableToOpen(String, io::Error),
+
+    #[error("Trace error: {0}")]
+    Trace(#[from] TraceErr),
+}
+
+pub struct Options {
+    pub file: PathBuf,
+    pub max_insts: Option<u64>,
+    pub memory_limit: usize,
+    pub perm_granularity: PermGranularity,
+    pub misaligned: MisalignedAccess,
+    pub watches: Vec<(AccessKind, u64, u64)>,
+    pub watch_stop: bool,
+    //addresses or symbol names, resolved once the program is loaded
+    //function and what it returns, if anything
+    pub stubs: Vec<(String, Option<u64>)>,
+    pub skips: Vec<String>,
+    pub stop_at: Vec<String>,
+    pub set_regs: Vec<(String, usize, u64)>,
+    pub stop_on: Vec<InstHookAt>,
+    pub trace_sink: Option<TraceSink>,
+    pub trace_filter: TraceFilter,
+    pub input: Option<Vec<u8>>,
+    pub crash_dir: Option<PathBuf>,
+    pub minimize: bool,
+    pub cmplog: Option<PathBuf>,
+    pub cmp_hooks: Vec<(CmpHook, String)>,
+    pub cmplog_mutations: Option<PathBuf>,
+    //always false without the jit feature, parse() refuses them
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    pub jit: bool,
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    pub jit_check: bool,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub gdb_port: Option<u16>,
+    pub snapshot_interval: u64,
+    pub uart_output: Option<UartOutput>,
+    pub uart_input: Option<UartInput>,
+    pub bare_metal: bool,
+    pub wall_clock: bool,
+    pub kernel: bool,
+    pub initrd: Option<PathBuf>,
+    pub cmdline: String,
+    pub harts: usize,
+    pub quantum: u64,
+    pub disk: Option<Disk>,
+    //port 0 first, empty without a virtio console
+    pub console_ports: Vec<Port>,
+    pub virtio_rng: bool,
+    pub rng_seed: Option<u64>,
+}
+
+pub fn parse_num(val: &str) -> Option<u64> {
+    match val.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => val.parse().ok(),
+    }
+}
+
+fn parse_output(val: &str) -> Result<UartOutput, ArgsErr> {
+    match val {
+        "stdout" => Ok(UartOutput::Stdout),
+        path => UartOutput::file(path).map_err(|err| ArgsErr::UnableToOpen(val.to_string(), err)),
+    }
+}
+
+fn parse_input(val: &str) -> Result<UartInput, ArgsErr> {
+    match val {
+        "stdin" => Ok(UartInput::stdin()),
+        path => UartInput::open(path).map_err(|err| ArgsErr::UnableToOpen(val.to_string(), err)),
+    }
+}
+
+//<out>[,<in>]
+fn parse_port(name: Option<String>, val: &str) -> Result<Port, ArgsErr> {
+    let (output, input) = match val.split_once(',') {
+        Some((output, input)) => (output, Some(input)),
+        None => (val, None),
+    };
+
+    Ok(Port::new(name, parse_output(output)?, input.map(parse_input).transpose()?.unwrap_or(UartInput::None)))
+}
+
+fn parse_range(val: &str) -> Option<(u64, u64)> {
+    let (start, end) = val.split_once(':')?;
+
+    Some((parse_num(start)?, parse_num(end)?))
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, ArgsErr> {
+        let mut file = None;
+        let mut max_insts = None;
+        let mut memory_limit = DEFAULT_MEMORY_LIMIT;
+        let mut perm_granularity = PermGranularity::Byte;
+        let mut misaligned = MisalignedAccess::Allow;
+        let mut watches = Vec::new();
+        let mut watch_stop = false;
+        let mut stubs = Vec::new();
+        let mut skips = Vec::new();
+        let mut stop_at = Vec::new();
+        let mut set_regs = Vec::new();
+        let mut stop_on = Vec::new();
+        let mut trace_sink = None;
+        let mut trace_filter = TraceFilter::default();
+        let mut input = None;
+        let mut crash_dir = None;
+        let mut minimize = false;
+        let mut cmplog = None;
+        let mut cmp_hooks = Vec::new();
+        let mut cmplog_mutations = None;
+        let mut jit = false;
+        let mut jit_check = false;
+        let mut record = None;
+        let mut replay = None;
+        let mut gdb_port = None;
+        let mut snapshot_interval = DEFAULT_SNAPSHOT_INTERVAL;
+        let mut uart_output = None;
+        let mut uart_input = None;
+        let mut bare_metal = false;
+        let mut wall_clock = false;
+        let mut kernel = false;
+        let mut initrd = None;
+        let mut cmdline = String::new();
+        let mut harts = 1;
+        let mut quantum = DEFAULT_QUANTUM;
+        let mut disk_path = None;
+        let mut disk_mode = DiskMode::CopyOnWrite;
+        let mut console = None;
+        let mut named_ports = Vec::new();
+        let mut virtio_rng = false;
+        let mut rng_seed = None;
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                file = Some(PathBuf::from(arg));
+                continue;
+            }
+
+            //flags without a value
+            match arg.as_str() {
+                "--bare" => {
+                    bare_metal = true;
+                    continue;
+                }
+                "--wall-clock" => {
+                    wall_clock = true;
+                    continue;
+                }
+                "--kernel" => {
+                    kernel = true;
+                    continue;
+                }
+                "--virtio-rng" => {
+                    virtio_rng = true;
+                    continue;
+                }
+                "--watch-stop" => {
+                    watch_stop = true;
+                    continue;
+                }
+                "--minimize" => {
+                    minimize = true;
+                    continue;
+                }
+                "--jit" => {
+                    jit = true;
+                    continue;
+                }
+                "--jit-check" => {
+                    jit_check = true;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let val = args.next().ok_or_else(|| ArgsErr::MissingValue(arg.clone()))?;
+            let invalid = || ArgsErr::InvalidValue(arg.clone(), val.clone());
+
+            match arg.as_str() {
+                "--max-insts" => max_insts = Some(parse_num(&val).ok_or_else(invalid)?),
+                "--memory" => memory_limit = parse_num(&val).filter(|n| *n > 0).ok_or_else(invalid)? as usize,
+                "--perm-granularity" => {
+                    perm_granularity = match val.as_str() {
+                        "byte" => PermGranularity::Byte,
+                        "page" => PermGranularity::Page,
+                        _ => return Err(invalid()),
+                    };
+                }
+                "--misaligned" => {
+                    misaligned = match val.as_str() {
+                        "allow" => MisalignedAccess::Allow,
+                        "fault" => MisalignedAccess::Fault,
+                        _ => return Err(invalid()),
+                    };
+                }
+                "--watch" => {
+                    let (range, kinds) = val.split_once(',').unwrap_or((&val, "rw"));
+                    let (start, end) = parse_range(range).filter(|(start, end)| start < end).ok_or_else(invalid)?;
+
+                    for kind in kinds.chars() {
+                        let kind = match kind {
+                            'r' => AccessKind::Read,
+                            'w' => AccessKind::Write,
+                            'x' => AccessKind::Execute,
+                            _ => return Err(invalid()),
+                        };
+                        watches.push((kind, start, end));
+                    }
+                }
+                "--stub" => {
+                    let (addr, ret) = match val.split_once('=') {
+                        Some((addr, ret)) => (addr.to_string(), Some(parse_num(ret).ok_or_else(invalid)?)),
+                        None => (val, None),
+                    };
+                    stubs.push((addr, ret));
+                }
+                "--skip" => skips.push(val),
+                "--stop-at" => stop_at.push(val),
+                "--set-reg" => {
+                    let (addr, assign) = val.split_once(':').ok_or_else(invalid)?;
+                    let (reg, value) = assign.split_once('=').ok_or_else(invalid)?;
+
+                    set_regs.push((addr.to_string(), decoder::reg_index(reg).ok_or_else(invalid)?, parse_num(value).ok_or_else(invalid)?));
+                }
+                "--stop-on" => stop_on.push(InstHookAt::kind_named(&val).ok_or(ArgsErr::UnknownMnemonic(val))?),
+                "--trace" => {
+                    trace_sink = Some(match val.split_once(':') {
+                        None if val == "stderr" => TraceSink::Stderr,
+                        Some(("text", path)) => TraceSink::text(path)?,
+                        Some(("bin", path)) => TraceSink::binary(path)?,
+                        _ => return Err(invalid()),
+                    });
+                }
+                "--trace-pc" => trace_filter.pc_range = Some(parse_range(&val).ok_or_else(invalid)?),
+                "--trace-icount" => trace_filter.icount_range = Some(parse_range(&val).ok_or_else(invalid)?),
+                "--input" => input = Some(fs::read(&val).map_err(|err| ArgsErr::UnableToOpen(val.clone(), err))?),
+                "--crash-dir" => crash_dir = Some(PathBuf::from(val)),
+                "--cmplog" => cmplog = Some(PathBuf::from(val)),
+                "--cmp-hook" => {
+                    let (name, addr) = val.split_once('=').ok_or_else(invalid)?;
+                    cmp_hooks.push((CmpHook::from_name(name).ok_or_else(invalid)?, addr.to_string()));
+                }
+                "--cmplog-mutations" => cmplog_mutations = Some(PathBuf::from(val)),
+                "--record" => record = Some(PathBuf::from(val)),
+                "--replay" => replay = Some(PathBuf::from(val)),
+                "--gdb" => gdb_port = Some(val.parse().map_err(|_| invalid())?),
+                "--snapshot-interval" => snapshot_interval = parse_num(&val).filter(|n| *n > 0).ok_or_else(invalid)?,
+                "--uart-out" => uart_output = Some(parse_output(&val)?),
+                "--uart-in" => uart_input = Some(parse_input(&val)?),
+                "--initrd" => initrd = Some(PathBuf::from(val)),
+                "--append" => cmdline = val,
+                "--harts" => {
+                    harts = parse_num(&val).filter(|n| (1..=MAX_HARTS as u64).contains(n)).ok_or_else(invalid)? as usize;
+                }
+                "--quantum" => quantum = parse_num(&val).filter(|n| *n > 0).ok_or_else(invalid)?,
+                "--disk" => disk_path = Some(val),
+                "--disk-mode" => {
+                    disk_mode = match val.as_str() {
+                        "cow" => DiskMode::CopyOnWrite,
+                        "ro" => DiskMode::ReadOnly,
+                        "rw" => DiskMode::ReadWrite,
+                        _ => return Err(invalid()),
+                    };
+                }
+                "--virtio-console" => console = Some(parse_port(None, &val)?),
+                "--virtio-port" => {
+                    let (name, port) = val.split_once('=').filter(|(name, _)| !name.is_empty()).ok_or_else(invalid)?;
+                    named_ports.push(parse_port(Some(name.to_string()), port)?);
+                }
+                "--rng-seed" => {
+                    rng_seed = Some(parse_num(&val).ok_or_else(invalid)?);
+                    virtio_rng = true;
+                }
+                _ => return Err(ArgsErr::UnknownOption(arg)),
+            }
+        }
+
+        if record.is_some() && replay.is_some() {
+            return Err(ArgsErr::RecordAndReplay);
+        }
+
+        if minimize && crash_dir.is_none() {
+            return Err(ArgsErr::MinimizeWithoutCrashDir);
+        }
+
+        if cmplog_mutations.is_some() && (cmplog.is_none() || input.is_none()) {
+            return Err(ArgsErr::MutationsWithoutCmpLog);
+        }
+
+        if cfg!(not(feature = "jit")) && (jit || jit_check) {
+            return Err(ArgsErr::NoJit(if jit { "--jit" } else { "--jit-check" }));
+        }
+
+        //the CLINT reads the host clock directly, time never goes through the log
+        if wall_clock && (record.is_some() || replay.is_some()) {
+            return Err(ArgsErr::WallClockNondeterministic);
+        }
+
+        if harts > 1 && !bare_metal && !kernel {
+            return Err(ArgsErr::NeedsBareMetal("--harts"));
+        }
+
+        //opened once the mode is known, it can come after the path
+        let disk = match disk_path {
+            Some(path) => Some(Disk::open(&path, disk_mode).map_err(|err| ArgsErr::UnableToOpen(path, err))?),
+            None => None,
+        };
+
+        //named ports need a console to hang off, its port 0 then goes nowhere
+        let mut console_ports = Vec::new();
+        if console.is_some() || !named_ports.is_empty() {
+            console_ports.push(console.unwrap_or_else(|| Port::new(None, UartOutput::None, UartInput::None)));
+            console_ports.extend(named_ports);
+        }
+
+        Ok(Options {
+            file: file.ok_or(ArgsErr::MissingFile)?,
+            max_insts,
+            memory_limit,
+            perm_granularity,
+            misaligned,
+            watches,
+            watch_stop,
+            stubs,
+            skips,
+            stop_at,
+            set_regs,
+            stop_on,
+            trace_sink,
+            trace_filter,
+            input,
+            crash_dir,
+            minimize,
+            cmplog,
+            cmp_hooks,
+            cmplog_mutations,
+            jit,
+            jit_check,
+            record,
+            replay,
+            gdb_port,
+            snapshot_interval,
+            uart_output,
+            uart_input,
+            bare_metal,
+            wall_clock,
+            kernel,
+            initrd,
+            cmdline,
+            harts,
+            quantum,
+            disk,
+            console_ports,
+            virtio_rng,
+            rng_seed,
+        })
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, sync::Arc};
+use super::{decoder::{self, Inst}, memory::{Mmu, PAGE_SIZE}};
+
+//long straight-line runs are split, so a single block never holds more than this
+const MAX_BLOCK_INSTS: usize = 64;
+
+pub struct Block {
+    pub start: u64,
+    //address right after the last instruction of the block
+    pub end: u64,
+    //with their size, 2 for RVC instructions
+    pub insts: Vec<(Inst, u64)>,
+}
+
+/*
+    Decoded basic blocks keyed by the physical address they start at. A block ends after the first
+    instruction that can change control flow or the cache itself, at the end of a page, whose successor
+    may be mapped anywhere, and right before anything that would trap while fetching (not executable,
+    misaligned, undefined) or that straddles the end of the page, so the single step path is left to
+    raise that trap or translate both halves.
+
+    Blocks are only ever built from memory that was executable at the time, and Mmu reports every write
+    to executable memory, so invalidating those ranges (and flushing on FENCE.I) keeps the cache coherent.
+*/
+#[derive(Clone, Default)]
+pub struct BlockCache {
+    blocks: HashMap<u64, Arc<Block>>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_build(&mut self, pc: u64, mmu: &Mmu) -> Arc<Block> {
+        if let Some(block) = self.blocks.get(&pc) {
+            return block.clone();
+        }
+
+        let block = Arc::new(build_block(pc, mmu));
+
+        //empty blocks are not cached, the fetch they stopped at will trap anyway
+        if !block.insts.is_empty() {
+            self.blocks.insert(pc, block.clone());
+        }
+
+        block
+    }
+
+    pub fn invalidate(&mut self, ranges: Vec<(usize, usize)>) {
+        for (start, end) in ranges {
+            self.blocks.retain(|_, block| block.end <= start as u64 || block.start >= end as u64);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+fn ends_block(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Jal { .. } | Inst::Jalr { .. } |
+        Inst::Beq { .. } | Inst::Bne { .. } | Inst::Blt { .. } | Inst::Bge { .. } | Inst::Bltu { .. } | Inst::Bgeu { .. } |
+        Inst::Ecall | Inst::Ebreak | Inst::FenceI |
+        //privilege and interrupt enables can change, pending interrupts are checked between blocks
+        Inst::Mret | Inst::Sret | Inst::Wfi | Inst::SfenceVma { .. } |
+        Inst::Csrrw { .. } | Inst::Csrrs { .. } | Inst::Csrrc { .. } | Inst::Csrrwi { .. } | Inst::Csrrsi { .. } | Inst::Csrrci { .. }
+    )
+}
+
+fn build_block(start: u64, mmu: &Mmu) -> Block {
+    let mut insts = Vec::new();
+    let mut pc = start;
+
+    while insts.len() < MAX_BLOCK_INSTS {
+        let Some(rinst) = fetch(pc, mmu) else {
+            break;
+        };
+        let inst = decoder::decode(rinst);
+
+        if let Inst::Undefined = inst {
+            break;
+        }
+
+        let size = decoder::inst_size(rinst);
+        insts.push((inst, size));
+        pc += size;
+
+        if ends_block(&inst) || pc.is_multiple_of(PAGE_SIZE as u64) {
+            break;
+        }
+    }
+
+    Block {
+        start,
+        end: pc,
+        insts,
+    }
+}
+
+fn fetch(pc: u64, mmu: &Mmu) -> Option<u32> {
+    let low = mmu.fetch_u16(pc).ok()? as u32;
+
+    if decoder::inst_size(low) == 2 {
+        return Some(low);
+    }
+
+    let high = pc + 2;
+    if high.is_multiple_of(PAGE_SIZE as u64) {
+        return None;
+    }
+
+    Some(low | (mmu.fetch_u16(high).ok()? as u32) << 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{exceptions::Exceptions, memory, testing, EmulatorErr};
+
+    const ADDI_A0_1: u32 = 0x0015_0513;
+    const ADDI_A0_100: u32 = 0x0645_0513;
+    const C_NOP: u32 = 0x0001;
+    //li a7, 93; ecall
+    const EXIT: [u32; 2] = [0x05d0_0893, 0x0000_0073];
+
+    fn mmu_with(code: &[u32]) -> Mmu {
+        let mut mmu = Mmu::new();
+        let bytes: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+
+        mmu.map_region(0x1000, bytes.len(), memory::PERM_R | memory::PERM_X, "text").unwrap();
+        mmu.dram_write(0x1000, &bytes).unwrap();
+        mmu
+    }
+
+    #[test]
+    fn blocks_end_after_every_terminator() {
+        const TERMINATORS: [(&str, u32); 21] = [
+            ("jal", 0x0080_006f), ("jalr", 0x0000_8067),
+            ("beq", 0x00b5_0463), ("bne", 0x00b5_1463), ("blt", 0x00b5_4463), ("bge", 0x00b5_5463), ("bltu", 0x00b5_6463), ("bgeu", 0x00b5_7463),
+            ("ecall", 0x0000_0073), ("ebreak", 0x0010_0073), ("fence.i", 0x0000_100f),
+            ("mret", 0x3020_0073), ("sret", 0x1020_0073), ("wfi", 0x1050_0073), ("sfence.vma", 0x1200_0073),
+            ("csrrw", 0x3405_9573), ("csrrs", 0x3405_a573), ("csrrc", 0x3405_b573),
+            ("csrrwi", 0x3400_d573), ("csrrsi", 0x3400_e573), ("csrrci", 0x3400_f573),
+        ];
+
+        for (name, terminator) in TERMINATORS {
+            let block = build_block(0x1000, &mmu_with(&[ADDI_A0_1, terminator, ADDI_A0_1]));
+            assert_eq!((block.insts.len(), block.end), (2, 0x1008), "{}", name);
+        }
+
+        //fence, loads and stores run on
+        let block = build_block(0x1000, &mmu_with(&[ADDI_A0_1, 0x0ff0_000f, 0x0005_a503, 0x00a5_a023, ADDI_A0_1]));
+        assert_eq!((block.insts.len(), block.end), (5, 0x1014));
+    }
+
+    #[test]
+    fn blocks_stop_before_what_would_trap_and_split_long_runs() {
+        //an undefined instruction is left for the single step path
+        let block = build_block(0x1000, &mmu_with(&[ADDI_A0_1, 0, ADDI_A0_1]));
+        assert_eq!((block.insts.len(), block.end), (1, 0x1004));
+
+        //so is running off the end of executable memory
+        let block = build_block(0x1000, &mmu_with(&[ADDI_A0_1; 2]));
+        assert_eq!((block.insts.len(), block.end), (2, 0x1008));
+
+        let mmu = mmu_with(&[ADDI_A0_1; MAX_BLOCK_INSTS + 1]);
+        let mut cache = BlockCache::new();
+        assert_eq!(cache.get_or_build(0x1000, &mmu).insts.len(), MAX_BLOCK_INSTS);
+        assert_eq!(cache.get_or_build(0x1000 + 4 * MAX_BLOCK_INSTS as u64, &mmu).insts.len(), 1);
+
+        //empty blocks aren't kept
+        assert!(cache.get_or_build(0x1000 + 4 * (MAX_BLOCK_INSTS as u64 + 1), &mmu).insts.is_empty());
+        assert_eq!(cache.blocks.len(), 2);
+
+        //or off the end of a page, the next one may be mapped anywhere
+        let block = build_block(0x1ff8, &mmu_with(&[ADDI_A0_1; PAGE_SIZE / 4 + 2]));
+        assert_eq!((block.insts.len(), block.end), (2, 0x2000));
+
+        //which a 32 bit instruction 2 bytes before it would need as well
+        let mut code = [ADDI_A0_1; PAGE_SIZE / 4 + 1];
+        code[PAGE_SIZE / 4 - 2] = C_NOP << 16 | C_NOP;
+        code[PAGE_SIZE / 4 - 1] = ADDI_A0_1 << 16 | C_NOP;
+        code[PAGE_SIZE / 4] = ADDI_A0_1 >> 16;
+        let block = build_block(0x1ff8, &mmu_with(&code));
+        assert_eq!((block.insts.len(), block.end), (3, 0x1ffe));
+    }
+
+    #[test]
+    fn blocks_hold_instructions_of_both_sizes() {
+        //c.nop, c.nop, addi, c.jr ra
+        let block = build_block(0x1000, &mmu_with(&[C_NOP << 16 | C_NOP, ADDI_A0_1, 0x8082, ADDI_A0_1]));
+        let sizes: Vec<u64> = block.insts.iter().map(|(_, size)| *size).collect();
+
+        assert_eq!(sizes, [2, 2, 4, 2]);
+        assert_eq!(block.end, 0x100a);
+        assert!(matches!(block.insts[3].0, Inst::Jalr { rd: 0, rs1: 1, imm: 0 }));
+    }
+
+    #[test]
+    fn invalidating_drops_only_overlapping_blocks() {
+        let mmu = mmu_with(&[ADDI_A0_1, 0x0000_8067, ADDI_A0_1, 0x0000_8067]);
+        let mut cache = BlockCache::new();
+        cache.get_or_build(0x1000, &mmu);
+        cache.get_or_build(0x1008, &mmu);
+
+        cache.invalidate(vec![(0x1007, 0x1008)]);
+        assert!(!cache.blocks.contains_key(&0x1000) && cache.blocks.contains_key(&0x1008));
+
+        cache.invalidate(vec![(0x100f, 0x1010)]);
+        assert!(cache.blocks.is_empty());
+    }
+
+    #[test]
+    fn stores_into_cached_code_are_seen() {
+        let mut emu = testing::emulator(&[
+            //call f, patch its first instruction from the word after it, call it again
+            0x01c0_00ef, 0x0000_1337, 0x0243_2283, 0x0053_2e23, 0x00c0_00ef, EXIT[0], EXIT[1],
+            //f: a0 += 1
+            ADDI_A0_1, 0x0000_8067,
+            ADDI_A0_100,
+        ]);
+        emu.mmu.mprotect(testing::CODE_BASE as usize, 1, memory::PERM_R | memory::PERM_W | memory::PERM_X).unwrap();
+
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(101))));
+
+        //a block that patches the very next instruction of itself stops there
+        let mut emu = testing::emulator(&[
+            0x0000_1337, 0x0183_2283, 0x0053_2823, EXIT[0], 0x0010_0513, EXIT[1],
+            //li a0, 7
+            0x0070_0513,
+        ]);
+        emu.mmu.mprotect(testing::CODE_BASE as usize, 1, memory::PERM_R | memory::PERM_W | memory::PERM_X).unwrap();
+
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(7))));
+    }
+
+    #[test]
+    fn fence_i_flushes_the_cache() {
+        //call f, fence.i, call f again
+        let mut emu = testing::emulator(&[0x0140_00ef, 0x0000_100f, 0x00c0_00ef, EXIT[0], EXIT[1], ADDI_A0_1, 0x0000_8067]);
+
+        emu.run(Some(3)).unwrap();
+        assert!(emu.block_cache.blocks.contains_key(&(testing::CODE_BASE + 0x14)));
+
+        //a write the cache never hears about, only fence.i can make it visible
+        emu.mmu.dram_write(testing::CODE_BASE as usize + 0x14, &ADDI_A0_100.to_le_bytes()).unwrap();
+        emu.mmu.take_exec_writes();
+
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(101))));
+    }
+
+    #[test]
+    fn mprotect_changes_reach_cached_code() {
+        const F: u64 = 0x3000;
+        let rx = memory::PERM_R | memory::PERM_X;
+
+        //call f at 0x3000 twice
+        let program = [0x0000_3337, 0x0003_00e7, 0x0003_00e7, EXIT[0], EXIT[1]];
+        let emulator = || {
+            let mut emu = testing::emulator(&program);
+            emu.mmu.map_region(F as usize, memory::PAGE_SIZE, rx, "f").unwrap();
+            emu.mmu.dram_write(F as usize, &[ADDI_A0_1.to_le_bytes(), 0x0000_8067u32.to_le_bytes()].concat()).unwrap();
+            emu.run(Some(4)).unwrap();
+            assert!(emu.block_cache.blocks.contains_key(&F));
+            emu
+        };
+
+        //dropping execute makes the cached block unreachable
+        let mut emu = emulator();
+        emu.mmu.mprotect(F as usize, 1, memory::PERM_R).unwrap();
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrTrap(Exceptions::ExceptionAccessFault(0x3000)))));
+
+        //code rewritten while it wasn't executable is picked up once it is again
+        let mut emu = emulator();
+        emu.mmu.mprotect(F as usize, 1, memory::PERM_R | memory::PERM_W).unwrap();
+        emu.mmu.dram_write(F as usize, &ADDI_A0_100.to_le_bytes()).unwrap();
+        emu.mmu.mprotect(F as usize, 1, rx).unwrap();
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(101))));
+    }
+}
@@ -0,0 +1,323 @@
+use std::{fs, io, path::Path};
+use super::{cpu, devices::{clint::TIMEBASE_FREQ, plic::PLIC_SOURCES}, fdt::FdtBuilder, memory::{self, MmmuErr}, Emulator};
+
+//RAM as the device tree describes it, where QEMU's virt machine has it and clear of every device
+const RAM_BASE: u64 = 0x8000_0000;
+const RAM_SIZE: u64 = 128 * 1024 * 1024;
+
+//where an Image without a text_offset goes, RV64 kernels want 2MB alignment
+const KERNEL_OFFSET: u64 = 0x20_0000;
+
+//room at the top of RAM for the device tree, the initrd goes right below it
+const DTB_MAX: u64 = 0x1_0000;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+
+//Documentation/arch/riscv/boot-image-header.rst
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+
+//what the 16550A's baud rate divisor is relative to, Linux needs it to set a baud rate
+const UART_CLOCK: u32 = 3_686_400;
+
+//interrupt numbers on the cpu interrupt controller
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BootErr {
+    #[error("Unable to read {0}: {1}")]
+    UnableToRead(String, io::Error),
+
+    #[error("Not a RISC-V kernel Image (no boot image header)")]
+    NotAnImage,
+
+    #[error("{0} doesn't fit in RAM")]
+    DoesNotFit(&'static str),
+
+    #[error("Kernel boot needs the machine devices (CLINT, PLIC)")]
+    NotBareMetal,
+
+    #[error("Memory error: {0}")]
+    Memory(#[from] MmmuErr),
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, BootErr> {
+    fs::read(path).map_err(|err| BootErr::UnableToRead(path.display().to_string(), err))
+}
+
+fn align_down(value: u64, align: u64) -> u64 {
+    value & !(align - 1)
+}
+
+impl Emulator {
+    /*
+        Loads an image with the RISC-V boot image header the way a boot loader running on top of M mode
+        firmware would: the image at its text_offset into RAM, the initrd and the device tree at the top of
+        RAM, then enters it in S mode with a0 = hartid and a1 = the device tree. The emulator's SBI stands in
+        for the firmware, which is enough for a stock rv64imac Linux Image.
+    */
+    pub fn boot_kernel(&mut self, image: &Path, initrd: Option<&Path>, cmdline: &str) -> Result<(), BootErr> {
+        if !self.bare_metal {
+            return Err(BootErr::NotBareMetal);
+        }
+
+        let kernel = read(image)?;
+        if kernel.len() < IMAGE_HEADER_SIZE || &kernel[56..60] != IMAGE_MAGIC2 {
+            return Err(BootErr::NotAnImage);
+        }
+
+        let text_offset = u64::from_le_bytes(kernel[8..16].try_into().unwrap());
+        let image_size = u64::from_le_bytes(kernel[16..24].try_into().unwrap()).max(kernel.len() as u64);
+
+        //both come from the header, a corrupt one mustn't wrap around into fitting
+        let kernel_start = RAM_BASE.checked_add(if text_offset == 0 { KERNEL_OFFSET } else { text_offset }).ok_or(BootErr::DoesNotFit("kernel"))?;
+        let kernel_end = kernel_start.checked_add(image_size).ok_or(BootErr::DoesNotFit("kernel"))?;
+
+        let dtb_start = RAM_BASE + RAM_SIZE - DTB_MAX;
+
+        let initrd = match initrd {
+            Some(path) => {
+                let data = read(path)?;
+                let start = align_down(dtb_start.checked_sub(data.len() as u64).ok_or(BootErr::DoesNotFit("initrd"))?, PAGE_SIZE);
+
+                Some((start, data))
+            }
+            None => None,
+        };
+
+        if kernel_end > initrd.as_ref().map_or(dtb_start, |(start, _)| *start) {
+            return Err(BootErr::DoesNotFit(if initrd.is_some() { "kernel + initrd" } else { "kernel" }));
+        }
+
+        let initrd_range = initrd.as_ref().map(|(start, data)| (*start, start + data.len() as u64));
+        let dtb = self.device_tree(cmdline, initrd_range);
+        if dtb.len() as u64 > DTB_MAX {
+            return Err(BootErr::DoesNotFit("device tree"));
+        }
+
+        //all of RAM is the supervisor's, protection is up to its own page tables
+        self.mmu.map_region(RAM_BASE as usize, RAM_SIZE as usize, memory::PERM_R | memory::PERM_W | memory::PERM_X, "ram")?;
+
+        self.mmu.dram_write(kernel_start as usize, &kernel)?;
+        if let Some((start, data)) = &initrd {
+            self.mmu.dram_write(*start as usize, data)?;
+        }
+        self.mmu.dram_write(dtb_start as usize, &dtb)?;
+
+        let mut regs = [0; cpu::MAX_REGS];
+        regs[REG_A0] = self.cpu.csr.hartid();
+        regs[REG_A1] = dtb_start;
+
+        self.cpu.set_regs(regs);
+        self.cpu.set_pc(kernel_start);
+        self.cpu.csr.enter_supervisor();
+        self.sbi = true;
+
+        //the other harts wait for the kernel to start them through the SBI
+        for hart in 0..self.hart_count() {
+            self.hart_mut(hart).stopped = hart != self.hart;
+        }
+
+        Ok(())
+    }
+
+    //the machine as it is put together right now, every device on the bus included
+    pub fn device_tree(&self, cmdline: &str, initrd: Option<(u64, u64)>) -> Vec<u8> {
+        let harts: Vec<u32> = (0..self.hart_count() as u32).collect();
+        let mut fdt = FdtBuilder::new();
+
+        let intc: Vec<u32> = harts.iter().map(|_| fdt.alloc_phandle()).collect();
+        let plic = fdt.alloc_phandle();
+
+        fdt.begin_node("");
+        fdt.prop_u32("#address-cells", 2);
+        fdt.prop_u32("#size-cells", 2);
+        fdt.prop_str("compatible", "riscv-virtio");
+        fdt.prop_str("model", "crimson,virt");
+
+        fdt.begin_node("chosen");
+        if !cmdline.is_empty() {
+            fdt.prop_str("bootargs", cmdline);
+        }
+        if let Some((base, ..)) = self.bus.devices().find(|(.., name)| *name == "uart") {
+            fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", base));
+        }
+        if let Some((start, end)) = initrd {
+            fdt.prop_u64("linux,initrd-start", start);
+            fdt.prop_u64("linux,initrd-end", end);
+        }
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{:x}", RAM_BASE));
+        fdt.prop_str("device_type", "memory");
+        fdt.prop_reg(&[(RAM_BASE, RAM_SIZE)]);
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.prop_u32("#address-cells", 1);
+        fdt.prop_u32("#size-cells", 0);
+        fdt.prop_u32("timebase-frequency", TIMEBASE_FREQ as u32);
+
+        for (hart, phandle) in harts.iter().zip(&intc) {
+            fdt.begin_node(&format!("cpu@{:x}", hart));
+            fdt.prop_str("device_type", "cpu");
+            fdt.prop_u32("reg", *hart);
+            fdt.prop_str("status", "okay");
+            fdt.prop_str("compatible", "riscv");
+            fdt.prop_str("riscv,isa", "rv64imac_zicsr_zifencei");
+            fdt.prop_str("riscv,isa-base", "rv64i");
+            fdt.prop_strs("riscv,isa-extensions", &["i", "m", "a", "c", "zicsr", "zifencei"]);
+            fdt.prop_str("mmu-type", "riscv,sv39");
+
+            fdt.begin_node("interrupt-controller");
+            fdt.prop_u32("#interrupt-cells", 1);
+            fdt.prop_empty("interrupt-controller");
+            fdt.prop_str("compatible", "riscv,cpu-intc");
+            fdt.prop_u32("phandle", *phandle);
+            fdt.end_node();
+
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.prop_u32("#address-cells", 2);
+        fdt.prop_u32("#size-cells", 2);
+        fdt.prop_str("compatible", "simple-bus");
+        fdt.prop_empty("ranges");
+
+        //every hart's pair of interrupts on its cpu interrupt controller
+        let per_hart = |m: u32, s: u32| -> Vec<u32> { intc.iter().flat_map(|phandle| [*phandle, m, *phandle, s]).collect() };
+
+        for (base, size, irq, name) in self.bus.devices() {
+            match name {
+                "clint" => {
+                    fdt.begin_node(&format!("clint@{:x}", base));
+                    fdt.prop_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+                    fdt.prop_cells("interrupts-extended", &per_hart(IRQ_M_SOFT, IRQ_M_TIMER));
+                }
+                "plic" => {
+                    fdt.begin_node(&format!("plic@{:x}", base));
+                    fdt.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                    fdt.prop_u32("#address-cells", 0);
+                    fdt.prop_u32("#interrupt-cells", 1);
+                    fdt.prop_empty("interrupt-controller");
+                    fdt.prop_u32("riscv,ndev", PLIC_SOURCES - 1);
+                    fdt.prop_cells("interrupts-extended", &per_hart(IRQ_M_EXT, IRQ_S_EXT));
+                    fdt.prop_u32("phandle", plic);
+                }
+                "uart" => {
+                    fdt.begin_node(&format!("serial@{:x}", base));
+                    fdt.prop_str("compatible", "ns16550a");
+                    fdt.prop_u32("clock-frequency", UART_CLOCK);
+                }
+                name if name.starts_with("virtio") => {
+                    fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+                    fdt.prop_str("compatible", "virtio,mmio");
+                }
+                //a device the device tree has no binding for
+                _ => continue,
+            }
+
+            fdt.prop_reg(&[(base, size)]);
+            if let Some(irq) = irq {
+                fdt.prop_u32("interrupt-parent", plic);
+                fdt.prop_u32("interrupts", irq);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+
+        fdt.finish(self.cpu.csr.hartid() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{csr::Privilege, devices::clint::Clock, EmulatorErr};
+
+    //reads sstatus, which only works from S mode up, then asks the SBI to shut down with a failure
+    const PAYLOAD: [u32; 7] = [0x1000_22f3, 0x5352_58b7, 0x3548_889b, 0x0000_0813, 0x0000_0513, 0x0010_0593, 0x0000_0073];
+
+    //a boot image header that jumps over itself to the payload
+    fn image(name: &str, text_offset: u64, image_size: u64) -> std::path::PathBuf {
+        let mut image = vec![0; IMAGE_HEADER_SIZE];
+        image[0..4].copy_from_slice(&0x0400_006fu32.to_le_bytes());
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[48..56].copy_from_slice(b"RISCV\0\0\0");
+        image[56..60].copy_from_slice(IMAGE_MAGIC2);
+        image.extend(PAYLOAD.iter().flat_map(|inst| inst.to_le_bytes()));
+
+        let path = std::env::temp_dir().join(format!("crimson-boot-{}-{}", name, std::process::id()));
+        fs::write(&path, image).unwrap();
+
+        path
+    }
+
+    fn machine(harts: usize) -> Emulator {
+        let mut emu = Emulator::new();
+        emu.enable_bare_metal(Clock::Instructions, harts).unwrap();
+
+        emu
+    }
+
+    #[test]
+    fn payload_is_entered_in_supervisor_mode() {
+        let path = image("handoff", 0, 0);
+        let initrd = std::env::temp_dir().join(format!("crimson-boot-initrd-{}", std::process::id()));
+        fs::write(&initrd, [0x5a; 100]).unwrap();
+
+        let mut emu = machine(2);
+        let booted = emu.boot_kernel(&path, Some(&initrd), "console=hvc0");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&initrd);
+        booted.unwrap();
+
+        let entry = RAM_BASE + KERNEL_OFFSET;
+        let dtb = RAM_BASE + RAM_SIZE - DTB_MAX;
+        assert_eq!(emu.cpu.get_pc(), entry);
+        assert_eq!((emu.cpu.get_reg(REG_A0).unwrap(), emu.cpu.get_reg(REG_A1).unwrap()), (0, dtb));
+        assert_eq!(emu.cpu.csr.privilege, Privilege::Supervisor);
+        assert!(!emu.hart(0).stopped && emu.hart(1).stopped);
+
+        assert_eq!(emu.mmu.dram_read(dtb as usize, 4).unwrap()[..], 0xd00d_feedu32.to_be_bytes());
+        assert_eq!(emu.mmu.dram_read((dtb - PAGE_SIZE) as usize, 100).unwrap()[..], [0x5a; 100]);
+
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(1))));
+    }
+
+    #[test]
+    fn images_are_checked_before_loading() {
+        let path = image("checks", 0, 0);
+
+        assert!(matches!(Emulator::new().boot_kernel(&path, None, ""), Err(BootErr::NotBareMetal)));
+        assert!(matches!(machine(1).boot_kernel(&path, Some(Path::new("/nonexistent")), ""), Err(BootErr::UnableToRead(..))));
+        let _ = fs::remove_file(&path);
+
+        let path = image("too-big", RAM_SIZE - DTB_MAX, 0);
+        assert!(matches!(machine(1).boot_kernel(&path, None, ""), Err(BootErr::DoesNotFit("kernel"))));
+        let _ = fs::remove_file(&path);
+
+        //sizes and offsets that would overflow the address space
+        for (name, text_offset, image_size) in [("huge-offset", u64::MAX, 0), ("huge-size", 0, u64::MAX)] {
+            let path = image(name, text_offset, image_size);
+            assert!(matches!(machine(1).boot_kernel(&path, None, ""), Err(BootErr::DoesNotFit("kernel"))));
+            let _ = fs::remove_file(&path);
+        }
+
+        let path = std::env::temp_dir().join(format!("crimson-boot-elf-{}", std::process::id()));
+        fs::write(&path, b"\x7fELF").unwrap();
+        assert!(matches!(machine(1).boot_kernel(&path, None, ""), Err(BootErr::NotAnImage)));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,402 @@
+use super::{memory::Mmu, replay::{Nondet, NondetSource}};
+
+#[derive(thiserror::Error, Debug)]
+pub enum BusErr {
+    #[error("{name} at {base:#x} overlaps {other} at {other_base:#x}")]
+    Overlap { name: String, base: u64, other: String, other_base: u64 },
+
+    #[error("{0} has no registers")]
+    EmptyDevice(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DeviceErr {
+    #[error("No register at offset {0:#x}")]
+    InvalidOffset(u64),
+
+    #[error("Unsupported access size {0}")]
+    InvalidSize(usize),
+}
+
+//what a device gets to see while the guest runs
+pub struct TickCtx<'a> {
+    //instructions retired since the last tick
+    pub elapsed: u64,
+    pub icount: u64,
+    //for DMA
+    pub ram: &'a mut Mmu,
+    //host input has to be polled through here so it can be recorded and replayed
+    pub nondet: &'a mut Nondet,
+    //identifies this device in the nondet log
+    pub source: NondetSource,
+}
+
+/*
+    Something that answers loads and stores to a range of physical addresses instead of RAM. Offsets are
+    relative to where the device is attached, sizes are 1, 2, 4 or 8 bytes and values are little endian
+    like RAM. Reads take &mut self since reading a register can have side effects (popping a FIFO).
+
+    An access a device refuses ends up as an access fault for the guest, the same as touching memory it
+    has no permission for.
+*/
+pub trait Device: Send {
+    fn name(&self) -> &str;
+
+    //bytes of address space the device decodes
+    fn size(&self) -> u64;
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, DeviceErr>;
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), DeviceErr>;
+
+    fn tick(&mut self, _ctx: &mut TickCtx) {}
+
+    //level of the device's interrupt line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    //mip bits the device drives straight into a hart (timer and software interrupts from the CLINT, external
+    //interrupts from the PLIC)
+    fn mip(&self, _hart: usize) -> u64 {
+        0
+    }
+
+    //level of an interrupt line wired to the bus, only interrupt controllers care
+    fn set_irq(&mut self, _irq: u32, _level: bool) {}
+
+    //devices are part of the emulator state, snapshots copy them
+    fn box_clone(&self) -> Box<dyn Device>;
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+#[derive(Clone)]
+struct Region {
+    base: u64,
+    size: u64,
+    //interrupt controller input the device's line is wired to
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+}
+
+/*
+    Physical address space: device regions are checked first, RAM (the Mmu, with its permissions) is the
+    region everything no device claims falls through to.
+*/
+#[derive(Clone, Default)]
+pub struct Bus {
+    regions: Vec<Region>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus { regions: Vec::new() }
+    }
+
+    pub fn attach(&mut self, base: u64, device: Box<dyn Device>) -> Result<(), BusErr> {
+        self.attach_region(base, None, device)
+    }
+
+    pub fn attach_with_irq(&mut self, base: u64, irq: u32, device: Box<dyn Device>) -> Result<(), BusErr> {
+        self.attach_region(base, Some(irq), device)
+    }
+
+    fn attach_region(&mut self, base: u64, irq: Option<u32>, device: Box<dyn Device>) -> Result<(), BusErr> {
+        let size = device.size();
+
+        if size == 0 {
+            return Err(BusErr::EmptyDevice(device.name().to_string()));
+        }
+
+        let end = base.saturating_add(size);
+        if let Some(other) = self.regions.iter().find(|r| base < r.base.saturating_add(r.size) && r.base < end) {
+            return Err(BusErr::Overlap {
+                name: device.name().to_string(),
+                base,
+                other: other.device.name().to_string(),
+                other_base: other.base,
+            });
+        }
+
+        self.regions.push(Region { base, size, irq, device });
+
+        Ok(())
+    }
+
+    //whether [addr, addr + size) touches a device, an access straddling RAM and a device is still a device access
+    pub fn is_mmio(&self, addr: u64, size: usize) -> bool {
+        let end = addr.saturating_add(size as u64);
+
+        self.regions.iter().any(|r| addr < r.base.saturating_add(r.size) && r.base < end)
+    }
+
+    fn region_mut(&mut self, addr: u64, size: usize) -> Result<&mut Region, DeviceErr> {
+        let region = self.regions.iter_mut().find(|r| r.contains(addr)).ok_or(DeviceErr::InvalidOffset(addr))?;
+
+        //an access has to fit in one device
+        if !region.contains(addr + size as u64 - 1) {
+            return Err(DeviceErr::InvalidOffset(addr - region.base));
+        }
+
+        Ok(region)
+    }
+
+    //register accesses can raise or drop interrupt lines (reading a FIFO empty, completing a claim), the
+    //controllers see the new levels before the next instruction
+    pub fn read(&mut self, addr: u64, size: usize) -> Result<u64, DeviceErr> {
+        let region = self.region_mut(addr, size)?;
+        let result = region.device.read(addr - region.base, size);
+
+        self.route_irqs();
+
+        result
+    }
+
+    pub fn write(&mut self, addr: u64, size: usize, value: u64) -> Result<(), DeviceErr> {
+        let region = self.region_mut(addr, size)?;
+        let result = region.device.write(addr - region.base, size, value);
+
+        self.route_irqs();
+
+        result
+    }
+
+    pub fn tick(&mut self, elapsed: u64, icount: u64, ram: &mut Mmu, nondet: &mut Nondet) {
+        for region in &mut self.regions {
+            region.device.tick(&mut TickCtx {
+                elapsed,
+                icount,
+                ram,
+                nondet,
+                source: NondetSource::Device(region.base),
+            });
+        }
+
+        self.route_irqs();
+    }
+
+    //hands the level of every wired line to the interrupt controllers
+    fn route_irqs(&mut self) {
+        let lines: Vec<(u32, bool)> = self.irq_lines().collect();
+        if lines.is_empty() {
+            return;
+        }
+
+        for region in &mut self.regions {
+            for &(irq, level) in &lines {
+                region.device.set_irq(irq, level);
+            }
+        }
+    }
+
+    pub fn mip(&self, hart: usize) -> u64 {
+        self.regions.iter().fold(0, |mip, r| mip | r.device.mip(hart))
+    }
+
+    //(base, size, irq, name) of every attached device, for describing the machine
+    pub fn devices(&self) -> impl Iterator<Item = (u64, u64, Option<u32>, &str)> + '_ {
+        self.regions.iter().map(|r| (r.base, r.size, r.irq, r.device.name()))
+    }
+
+    //(irq, level) of every wired interrupt line
+    pub fn irq_lines(&self) -> impl Iterator<Item = (u32, bool)> + '_ {
+        self.regions.iter().filter_map(|r| Some((r.irq?, r.device.irq())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{exceptions::Exceptions, testing, EmulatorErr};
+
+    //four 32 bit registers, writing the last one sets the interrupt line
+    #[derive(Clone, Default)]
+    struct Regs {
+        name: &'static str,
+        regs: [u32; 4],
+    }
+
+    impl Device for Regs {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn size(&self) -> u64 {
+            16
+        }
+
+        fn read(&mut self, offset: u64, size: usize) -> Result<u64, DeviceErr> {
+            if size != 4 {
+                return Err(DeviceErr::InvalidSize(size));
+            }
+
+            Ok(self.regs[(offset / 4) as usize] as u64)
+        }
+
+        fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), DeviceErr> {
+            if size != 4 {
+                return Err(DeviceErr::InvalidSize(size));
+            }
+
+            self.regs[(offset / 4) as usize] = value as u32;
+            Ok(())
+        }
+
+        fn irq(&self) -> bool {
+            self.regs[3] != 0
+        }
+
+        fn box_clone(&self) -> Box<dyn Device> {
+            Box::new(self.clone())
+        }
+    }
+
+    //raises MEIP on hart 0 while line 5 is high
+    #[derive(Clone, Default)]
+    struct Controller {
+        lines: Vec<(u32, bool)>,
+    }
+
+    impl Device for Controller {
+        fn name(&self) -> &str {
+            "controller"
+        }
+
+        fn size(&self) -> u64 {
+            4
+        }
+
+        fn read(&mut self, offset: u64, _size: usize) -> Result<u64, DeviceErr> {
+            Err(DeviceErr::InvalidOffset(offset))
+        }
+
+        fn write(&mut self, offset: u64, _size: usize, _value: u64) -> Result<(), DeviceErr> {
+            Err(DeviceErr::InvalidOffset(offset))
+        }
+
+        fn mip(&self, hart: usize) -> u64 {
+            if hart == 0 && self.lines.contains(&(5, true)) { 1 << 11 } else { 0 }
+        }
+
+        fn set_irq(&mut self, irq: u32, level: bool) {
+            self.lines.retain(|&(line, _)| line != irq);
+            self.lines.push((irq, level));
+        }
+
+        fn box_clone(&self) -> Box<dyn Device> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn regs(name: &'static str) -> Box<dyn Device> {
+        Box::new(Regs { name, ..Default::default() })
+    }
+
+    #[test]
+    fn accesses_go_to_the_device_at_their_offset() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, regs("a")).unwrap();
+        bus.attach(0x1010, regs("b")).unwrap();
+
+        bus.write(0x1000, 4, 1).unwrap();
+        bus.write(0x1008, 4, 2).unwrap();
+        bus.write(0x1010, 4, 3).unwrap();
+
+        assert_eq!((bus.read(0x1000, 4).unwrap(), bus.read(0x1008, 4).unwrap()), (1, 2));
+        assert_eq!((bus.read(0x1010, 4).unwrap(), bus.read(0x1018, 4).unwrap()), (3, 0));
+        assert!(bus.is_mmio(0x101c, 4) && bus.is_mmio(0xffe, 4) && !bus.is_mmio(0x1020, 4));
+    }
+
+    #[test]
+    fn attaches_must_not_overlap_or_be_empty() {
+        #[derive(Clone)]
+        struct Empty;
+
+        impl Device for Empty {
+            fn name(&self) -> &str {
+                "empty"
+            }
+
+            fn size(&self) -> u64 {
+                0
+            }
+
+            fn read(&mut self, offset: u64, _size: usize) -> Result<u64, DeviceErr> {
+                Err(DeviceErr::InvalidOffset(offset))
+            }
+
+            fn write(&mut self, offset: u64, _size: usize, _value: u64) -> Result<(), DeviceErr> {
+                Err(DeviceErr::InvalidOffset(offset))
+            }
+
+            fn box_clone(&self) -> Box<dyn Device> {
+                Box::new(self.clone())
+            }
+        }
+
+        let mut bus = Bus::new();
+        bus.attach(0x1000, regs("a")).unwrap();
+
+        let err = bus.attach(0x100c, regs("b")).unwrap_err();
+        assert!(matches!(&err, BusErr::Overlap { base: 0x100c, other_base: 0x1000, .. }));
+        assert_eq!(err.to_string(), "b at 0x100c overlaps a at 0x1000");
+        assert!(matches!(bus.attach(0xff4, regs("b")), Err(BusErr::Overlap { .. })));
+        //the top of the address space doesn't wrap around onto the bottom
+        assert!(matches!(bus.attach(u64::MAX - 7, regs("b")), Ok(())));
+
+        assert!(matches!(bus.attach(0x2000, Box::new(Empty)), Err(BusErr::EmptyDevice(name)) if name == "empty"));
+        assert_eq!(bus.devices().count(), 2);
+    }
+
+    #[test]
+    fn accesses_no_device_takes_fail() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, regs("a")).unwrap();
+
+        assert!(matches!(bus.read(0x2000, 4), Err(DeviceErr::InvalidOffset(0x2000))));
+        assert!(matches!(bus.write(0xffc, 4, 0), Err(DeviceErr::InvalidOffset(0xffc))));
+        //straddling the end of a device
+        assert!(matches!(bus.read(0x100e, 4), Err(DeviceErr::InvalidOffset(0xe))));
+        assert!(matches!(bus.read(0x1000, 2), Err(DeviceErr::InvalidSize(2))));
+
+        //the guest sees a refused access as an access fault: lui a0, 0x10000; lh a1, 0(a0)
+        let mut emu = testing::emulator(&[0x1000_0537, 0x0005_1583]);
+        emu.bus.attach(0x1000_0000, regs("a")).unwrap();
+        assert!(matches!(emu.run(Some(2)), Err(EmulatorErr::ErrTrap(Exceptions::ExceptionLoadAccessFault(0x1000_0000)))));
+    }
+
+    #[test]
+    fn interrupt_lines_reach_the_controller_and_mip() {
+        let mut bus = Bus::new();
+        bus.attach(0x0, Box::new(Controller::default())).unwrap();
+        bus.attach_with_irq(0x1000, 5, regs("a")).unwrap();
+        bus.attach_with_irq(0x2000, 6, regs("b")).unwrap();
+
+        assert_eq!(bus.irq_lines().collect::<Vec<_>>(), [(5, false), (6, false)]);
+        assert_eq!(bus.mip(0), 0);
+
+        //a register write routes the new level straight away
+        bus.write(0x100c, 4, 1).unwrap();
+        assert_eq!(bus.irq_lines().collect::<Vec<_>>(), [(5, true), (6, false)]);
+        assert_eq!((bus.mip(0), bus.mip(1)), (1 << 11, 0));
+
+        bus.write(0x200c, 4, 1).unwrap();
+        bus.write(0x100c, 4, 0).unwrap();
+        assert_eq!(bus.mip(0), 0);
+
+        bus.write(0x100c, 4, 1).unwrap();
+        bus.tick(1, 1, &mut Mmu::new(), &mut Nondet::default());
+        assert_eq!(bus.mip(0), 1 << 11);
+    }
+}
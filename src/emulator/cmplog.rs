@@ -0,0 +1,377 @@
+use std::{collections::{HashMap, HashSet}, fmt, fs, io, path::Path};
+use super::{args::ArgsErr, cpu::Cpu, memory::Mmu, Emulator};
+
+//upper bound of logged comparisons per run, loops comparing against a counter would flood the log otherwise
+const MAX_ENTRIES: usize = 4096;
+
+//longest string we read from the guest for a strcmp style hook
+const MAX_STR_LEN: usize = 256;
+
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+const REG_A2: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CmpOperands {
+    Int { a: u64, b: u64 },
+    Bytes { a: Vec<u8>, b: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CmpEntry {
+    pub pc: u64,
+    pub operands: CmpOperands,
+}
+
+//one line of a --cmplog file: pc, then the operands, integers in hex and buffers as hex bytes
+impl fmt::Display for CmpEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.operands {
+            CmpOperands::Int { a, b } => write!(f, "{:#x} int {:#x} {:#x}", self.pc, a, b),
+            CmpOperands::Bytes { a, b } => write!(f, "{:#x} bytes {} {}", self.pc, hex(a), hex(b)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+//libc style comparison functions we know how to read arguments of, keyed by their entry point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpHook {
+    Memcmp,
+    Strcmp,
+    Strncmp,
+}
+
+impl CmpHook {
+    pub const ALL: [CmpHook; 3] = [CmpHook::Memcmp, CmpHook::Strcmp, CmpHook::Strncmp];
+
+    //also the symbol name a function is hooked by without being named on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            CmpHook::Memcmp => "memcmp",
+            CmpHook::Strcmp => "strcmp",
+            CmpHook::Strncmp => "strncmp",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|hook| hook.name() == name)
+    }
+}
+
+/*
+    Compare-operand log (CmpLog / RedQueen "input-to-state" correspondence).
+
+    While enabled, every BEQ/BNE/BLT/BGE/BLTU/BGEU and SLT/SLTU/SLTI/SLTIU logs the pair of values it
+    compared, and calls to hooked memcmp/strcmp like functions log the compared buffers. The fuzzer then
+    looks for one side of a comparison in its input and replaces it with the other side, which gets it past
+    magic numbers and checksums it would practically never guess.
+*/
+#[derive(Clone, Default)]
+pub struct CmpLog {
+    entries: Vec<CmpEntry>,
+    seen: HashSet<CmpEntry>,
+    hooks: HashMap<u64, CmpHook>,
+}
+
+impl CmpLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_hook(&mut self, addr: u64, hook: CmpHook) {
+        self.hooks.insert(addr, hook);
+    }
+
+    pub fn entries(&self) -> &[CmpEntry] {
+        &self.entries
+    }
+
+    //one entry per line
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.entries().iter().map(|entry| format!("{}\n", entry)).collect::<String>())
+    }
+
+    //each of `mutations` of input in a file of its own, named by its index
+    pub fn save_mutations<P: AsRef<Path>>(&self, input: &[u8], dir: P) -> io::Result<usize> {
+        let mutations = self.mutations(input);
+        fs::create_dir_all(&dir)?;
+
+        for (i, mutation) in mutations.iter().enumerate() {
+            fs::write(dir.as_ref().join(format!("{:06}", i)), mutation)?;
+        }
+
+        Ok(mutations.len())
+    }
+
+    fn push(&mut self, entry: CmpEntry) {
+        if self.entries.len() >= MAX_ENTRIES || self.seen.contains(&entry) {
+            return;
+        }
+
+        self.seen.insert(entry.clone());
+        self.entries.push(entry);
+    }
+
+    pub fn log_int(&mut self, pc: u64, a: u64, b: u64) {
+        //equal operands give us nothing to substitute
+        if a == b {
+            return;
+        }
+
+        self.push(CmpEntry { pc, operands: CmpOperands::Int { a, b } });
+    }
+
+    pub fn log_bytes(&mut self, pc: u64, a: Vec<u8>, b: Vec<u8>) {
+        if a == b || a.is_empty() || b.is_empty() {
+            return;
+        }
+
+        self.push(CmpEntry { pc, operands: CmpOperands::Bytes { a, b } });
+    }
+
+    //called before the instruction at pc executes, logs the arguments if pc is a hooked function
+    pub fn on_pc(&mut self, pc: u64, cpu: &Cpu, mmu: &Mmu) {
+        let Some(hook) = self.hooks.get(&pc).copied() else {
+            return;
+        };
+
+        let regs = cpu.get_regs();
+        let (s1, s2, n) = (regs[REG_A0] as usize, regs[REG_A1] as usize, regs[REG_A2] as usize);
+
+        let (a, b) = match hook {
+            CmpHook::Memcmp => (read_guest_bytes(mmu, s1, n, false), read_guest_bytes(mmu, s2, n, false)),
+            CmpHook::Strcmp => (read_guest_bytes(mmu, s1, MAX_STR_LEN, true), read_guest_bytes(mmu, s2, MAX_STR_LEN, true)),
+            CmpHook::Strncmp => (read_guest_bytes(mmu, s1, n, true), read_guest_bytes(mmu, s2, n, true)),
+        };
+
+        self.log_bytes(pc, a, b);
+    }
+
+    //every input we get by replacing one side of a logged comparison with the other
+    pub fn mutations(&self, input: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut seen = HashSet::new();
+
+        for entry in &self.entries {
+            let pairs = match &entry.operands {
+                CmpOperands::Int { a, b } => int_patterns(*a, *b),
+                CmpOperands::Bytes { a, b } => vec![(a.clone(), b.clone())],
+            };
+
+            for (a, b) in pairs {
+                for (from, to) in [(&a, &b), (&b, &a)] {
+                    for candidate in substitute(input, from, to) {
+                        if seen.insert(candidate.clone()) {
+                            out.push(candidate);
+                        }
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+//reads at most `max` bytes, stops early at the first unmapped byte or, for strings, at the terminator
+fn read_guest_bytes(mmu: &Mmu, addr: usize, max: usize, stop_at_nul: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for i in 0..max.min(MAX_STR_LEN) {
+        let Some(vaddr) = addr.checked_add(i) else {
+            break;
+        };
+        let Ok(byte) = mmu.dram_read(vaddr, 1) else {
+            break;
+        };
+
+        if stop_at_nul && byte[0] == 0 {
+            break;
+        }
+        out.push(byte[0]);
+    }
+
+    out
+}
+
+/*
+    Registers hold XLEN wide values, but the input most likely stored the operand in fewer bytes and in
+    either byte order. We try every width both operands survive a round trip through (zero or sign
+    extended), in little and big endian.
+*/
+fn int_patterns(a: u64, b: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut out = Vec::new();
+
+    for width in [1usize, 2, 4, 8] {
+        if !fits_in(a, width) || !fits_in(b, width) {
+            continue;
+        }
+
+        let a_le = a.to_le_bytes()[..width].to_vec();
+        let b_le = b.to_le_bytes()[..width].to_vec();
+        let a_be: Vec<u8> = a_le.iter().rev().copied().collect();
+        let b_be: Vec<u8> = b_le.iter().rev().copied().collect();
+
+        out.push((a_le, b_le));
+        if width > 1 {
+            out.push((a_be, b_be));
+        }
+    }
+
+    out
+}
+
+fn fits_in(val: u64, width: usize) -> bool {
+    if width >= 8 {
+        return true;
+    }
+
+    let bits = width * 8;
+    let zero_ext = val >> bits == 0;
+    let sign_ext = ((val << (64 - bits)) as i64 >> (64 - bits)) as u64 == val;
+
+    zero_ext || sign_ext
+}
+
+fn substitute(input: &[u8], from: &[u8], to: &[u8]) -> Vec<Vec<u8>> {
+    let mut out = Vec::new();
+
+    if from.is_empty() || from.len() > input.len() {
+        return out;
+    }
+
+    for offset in 0..=input.len() - from.len() {
+        if &input[offset..offset + from.len()] != from {
+            continue;
+        }
+
+        //operands of unequal length (strcmp) only replace the overlapping prefix, the input keeps its size
+        let len = to.len().min(input.len() - offset);
+        let mut candidate = input.to_vec();
+        candidate[offset..offset + len].copy_from_slice(&to[..len]);
+        out.push(candidate);
+    }
+
+    out
+}
+
+impl Emulator {
+    /*
+        --cmplog: starts logging compare operands, with every function named like one of the hooks hooked
+        as that hook, and the functions of `hooks` (addresses or symbols) hooked on top of those.
+    */
+    pub(super) fn add_cli_cmplog(&mut self, hooks: &[(CmpHook, String)]) -> Result<(), ArgsErr> {
+        let mut resolved = Vec::new();
+
+        for hook in CmpHook::ALL {
+            if let Some(addr) = self.symbols().addr_of(hook.name()) {
+                resolved.push((addr, hook));
+            }
+        }
+        for (hook, addr) in hooks {
+            resolved.push((self.resolve(addr).ok_or_else(|| ArgsErr::UnknownSymbol(addr.clone()))?, *hook));
+        }
+
+        let cmplog = self.enable_cmplog();
+        for (addr, hook) in resolved {
+            cmplog.add_hook(addr, hook);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{testing, EmulatorErr};
+
+    /*
+        a0 = 0x1234, a1 = 0x5678, beq a0, a1; sltiu t0, a0, 100; then memcmp(DATA_BASE, DATA_BASE + 16, 4)
+        through a call to the function at +0x34, and exit
+    */
+    const COMPARES: [u32; 14] = [
+        0x0000_1537, 0x2345_0513, 0x0000_55b7, 0x6785_8593, 0x00b5_0c63, 0x0645_3293, 0x0000_2537, 0x0105_0593,
+        0x0040_0613, 0x0100_00ef, 0x0000_0513, 0x05d0_0893, 0x0000_0073, 0x0000_8067,
+    ];
+    const MEMCMP: u64 = testing::CODE_BASE + 0x34;
+
+    #[test]
+    fn logs_branch_slt_and_hooked_call_operands() {
+        let mut emu = testing::emulator(&COMPARES);
+        emu.mmu.dram_write(testing::DATA_BASE as usize, b"abcd").unwrap();
+        emu.mmu.dram_write(testing::DATA_BASE as usize + 16, b"abXd").unwrap();
+        emu.enable_cmplog().add_hook(MEMCMP, CmpHook::Memcmp);
+
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(0))));
+
+        let cmplog = emu.disable_cmplog().unwrap();
+        let operands: Vec<_> = cmplog.entries().iter().map(|entry| (entry.pc - testing::CODE_BASE, entry.operands.clone())).collect();
+
+        assert_eq!(operands, [
+            (0x10, CmpOperands::Int { a: 0x1234, b: 0x5678 }),
+            (0x14, CmpOperands::Int { a: 0x1234, b: 100 }),
+            (0x34, CmpOperands::Bytes { a: b"abcd".to_vec(), b: b"abXd".to_vec() }),
+        ]);
+        assert_eq!(cmplog.entries()[0].to_string(), "0x1010 int 0x1234 0x5678");
+        assert_eq!(cmplog.entries()[2].to_string(), "0x1034 bytes 61626364 61625864");
+    }
+
+    #[test]
+    fn hooks_are_named_like_the_functions() {
+        for hook in CmpHook::ALL {
+            assert_eq!(CmpHook::from_name(hook.name()), Some(hook));
+        }
+        assert_eq!(CmpHook::from_name("bcmp"), None);
+    }
+
+    #[test]
+    fn equal_and_repeated_comparisons_are_logged_once_at_most() {
+        let mut cmplog = CmpLog::new();
+
+        cmplog.log_int(0x1000, 5, 5);
+        cmplog.log_int(0x1000, 5, 6);
+        cmplog.log_int(0x1000, 5, 6);
+        cmplog.log_bytes(0x1000, b"a".to_vec(), Vec::new());
+
+        assert_eq!(cmplog.entries().len(), 1);
+    }
+
+    #[test]
+    fn operands_are_tried_at_every_width_they_fit() {
+        assert!(fits_in(0x7f, 1) && fits_in(u64::MAX, 1) && fits_in(0xff, 1));
+        assert!(!fits_in(0x100, 1) && fits_in(0x100, 2));
+        assert!(fits_in(0xffff_ffff_ffff_ff80, 1) && !fits_in(0xffff_ffff_ffff_7f00, 1));
+
+        let patterns = int_patterns(0x1234, 0xabcd);
+        assert_eq!(patterns, [
+            (vec![0x34, 0x12], vec![0xcd, 0xab]),
+            (vec![0x12, 0x34], vec![0xab, 0xcd]),
+            (vec![0x34, 0x12, 0, 0], vec![0xcd, 0xab, 0, 0]),
+            (vec![0, 0, 0x12, 0x34], vec![0, 0, 0xab, 0xcd]),
+            (vec![0x34, 0x12, 0, 0, 0, 0, 0, 0], vec![0xcd, 0xab, 0, 0, 0, 0, 0, 0]),
+            (vec![0, 0, 0, 0, 0, 0, 0x12, 0x34], vec![0, 0, 0, 0, 0, 0, 0xab, 0xcd]),
+        ]);
+    }
+
+    #[test]
+    fn mutations_replace_either_side_in_place() {
+        assert_eq!(substitute(b"xxABxxAB", b"AB", b"CD"), [b"xxCDxxAB".to_vec(), b"xxABxxCD".to_vec()]);
+        //the input keeps its size
+        assert_eq!(substitute(b"xxAB", b"AB", b"CDEF"), [b"xxCD".to_vec()]);
+        assert!(substitute(b"x", b"AB", b"CD").is_empty());
+
+        let mut cmplog = CmpLog::new();
+        cmplog.log_bytes(0x1000, b"FUZZ".to_vec(), b"ABCD".to_vec());
+        cmplog.log_int(0x1004, 0x1337, 0x4142);
+
+        let mutations = cmplog.mutations(b"ABCDxx");
+        assert!(mutations.contains(&b"FUZZxx".to_vec()));
+        //0x4142 is "AB" as a big endian u16
+        assert!(mutations.contains(&b"\x13\x37CDxx".to_vec()));
+    }
+}
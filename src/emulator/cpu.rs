@@ -1,8 +1,12 @@
-use super::{decoder::Inst, Emulator};
+use super::{csr::{self, Counters, Csrs, Privilege}, decoder::{self, Inst}, exceptions::Exceptions, memory::{self, AccessKind}, paging::{Phys, Tlb}, trace::MemAccessKind, Emulator, EmulatorErr};
 
 pub const MAX_REGS: usize = 32;
 pub const RAW_INST_SIZE:u64 = 4;
 
+pub const REG_RA: usize = 1;
+pub const REG_T0: usize = 5;
+pub const REG_A0: usize = 10;
+
 #[derive(thiserror::Error, Debug)]
 pub enum CpuErr {
     #[error("Invalid register: {0}")]
@@ -10,12 +14,55 @@ pub enum CpuErr {
 
     #[error("Invalid instruction: {0}")]
     InvalidInstruction(u32),
+
+    #[error("Unimplemented instruction: {0:?}")]
+    UnimplementedInstruction(Inst),
+}
+
+/*
+    The width of the integer registers. An RV32 hart keeps its registers sign extended from 32 bits, the
+    way RV64 keeps the results of the *W instructions: most instructions then compute the right low 32 bits
+    with their RV64 implementation, and signed and unsigned compares still order the values correctly.
+    Addresses (the pc, loads, stores, jump targets) wrap around at 32 bits.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Xlen {
+    Rv32,
+    #[default]
+    Rv64,
+}
+
+impl Xlen {
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
 }
 
 #[derive(Clone)]
 pub struct Cpu {
     r: [u64; MAX_REGS],
     pc: u64,
+    xlen: Xlen,
+
+    //return addresses of the calls currently in flight, built from JAL/JALR link register hints
+    call_stack: Vec<u64>,
+
+    pub csr: Csrs,
+
+    //stalled in WFI
+    pub waiting: bool,
+    //not started yet or stopped through the SBI, the scheduler passes it over
+    pub stopped: bool,
+    //physical address and size the last LR reserved, a store there from another hart makes the SC fail
+    pub reservation: Option<(u64, usize)>,
+    pub tlb: Tlb,
 }
 
 impl Cpu {
@@ -23,9 +70,30 @@ impl Cpu {
         Cpu {
             r: [0; MAX_REGS],
             pc: 0,
+            xlen: Xlen::Rv64,
+            call_stack: Vec::new(),
+            csr: Csrs::user(),
+            waiting: false,
+            stopped: false,
+            reservation: None,
+            tlb: Tlb::new(),
         }
     }
 
+    pub fn get_regs(&self) -> &[u64; MAX_REGS] {
+        &self.r
+    }
+
+    pub fn set_regs(&mut self, regs: [u64; MAX_REGS]) {
+        self.r = regs.map(|value| self.sign_extend(value));
+        //since X0 is hardwired to zero
+        self.r[0] = 0;
+    }
+
+    pub fn call_stack(&self) -> &[u64] {
+        &self.call_stack
+    }
+
     pub fn get_reg(&self, reg_index: usize) -> Result<u64, CpuErr> {
         if reg_index >= MAX_REGS {
             return Err(CpuErr::InvalidRegister(reg_index));
@@ -43,7 +111,7 @@ impl Cpu {
             return Err(CpuErr::InvalidRegister(reg_index));
         }
         
-        self.r[reg_index] = value;
+        self.r[reg_index] = self.sign_extend(value);
             
   
         Ok(())
@@ -54,14 +122,39 @@ impl Cpu {
     }
 
     pub fn set_pc(&mut self, val: u64) {
-        self.pc = val;
+        self.pc = self.zero_extend(val);
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    //the registers keep whatever they hold, set it before anything is written to them
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+    }
+
+    //a result as the register holds it, the low XLEN bits sign extended
+    pub fn sign_extend(&self, value: u64) -> u64 {
+        match self.xlen {
+            Xlen::Rv32 => value as i32 as i64 as u64,
+            Xlen::Rv64 => value,
+        }
+    }
+
+    //a register value as an unsigned XLEN bit number, which is what an address is
+    pub fn zero_extend(&self, value: u64) -> u64 {
+        match self.xlen {
+            Xlen::Rv32 => value as u32 as u64,
+            Xlen::Rv64 => value,
+        }
     }
 
 }
 
 macro_rules! inc_pc {
-    ($cpu: expr) => {
-        $cpu.set_pc($cpu.get_pc() + RAW_INST_SIZE);
+    ($cpu: expr, $size: expr) => {
+        $cpu.set_pc($cpu.get_pc() + $size);
     
     };
 }
@@ -73,8 +166,258 @@ macro_rules! dec_pc {
     };
 }
 
-fn handle_undefined() {
-    todo!("handle undefined");
+//logs the operands of a compare/branch when compare-operand logging is enabled
+macro_rules! cmplog {
+    ($emu: expr, $a: expr, $b: expr) => {
+        if let Some(cmplog) = &mut $emu.cmplog {
+            cmplog.log_int($emu.cpu.get_pc(), $a as u64, $b as u64);
+        }
+    };
+}
+
+fn handle_undefined(inst: Inst) -> Result<(), EmulatorErr> {
+    Err(CpuErr::UnimplementedInstruction(inst).into())
+}
+
+/*
+    2.6. Load and Store Instructions
+        Loads and stores are the only instructions that access memory. An access to bytes the program has no
+        permission for raises an access fault with the faulting address, the memory itself is little endian.
+        Misaligned accesses work or raise an address misaligned exception, depending on how the Mmu is set up.
+        Addresses claimed by a device on the bus go to the device instead of RAM. With paging on, devices and
+        RAM see the physical address while hooks and traces keep seeing the one the program used.
+*/
+fn load(emu: &mut Emulator, vaddr: u64, size: usize) -> Result<u64, EmulatorErr> {
+    let value = match emu.translate_access(vaddr, size, AccessKind::Read) {
+        Ok(Phys::At(paddr)) if emu.bus.is_mmio(paddr, size) => {
+            emu.bus.read(paddr, size).map_err(|_| Exceptions::ExceptionLoadAccessFault(vaddr as usize))
+        }
+        Ok(Phys::At(paddr)) => {
+            let value = match size {
+                1 => emu.mmu.read_u8(paddr).map(u64::from),
+                2 => emu.mmu.read_u16(paddr).map(u64::from),
+                4 => emu.mmu.read_u32(paddr).map(u64::from),
+                _ => emu.mmu.read_u64(paddr),
+            };
+
+            value.map_err(|fault| fault.at(vaddr).into())
+        }
+        Ok(Phys::Split(low, len, high)) => emu.read_split(vaddr, size, (low, len, high)),
+        Err(exception) => Err(exception),
+    }.map_err(EmulatorErr::from);
+
+    let value = if emu.mmu.has_hooks() { emu.hook_load(vaddr, size, value)? } else { value? };
+
+    if let Some(tracer) = &emu.tracer {
+        super::lock_tracer(tracer).log_mem(MemAccessKind::Read, vaddr, size, value);
+    }
+
+    Ok(value)
+}
+
+fn store(emu: &mut Emulator, vaddr: u64, size: usize, value: u64) -> Result<(), EmulatorErr> {
+    let value = if size < 8 { value & ((1 << (size * 8)) - 1) } else { value };
+
+    let value = if emu.mmu.has_hooks() {
+        match emu.hook_store(vaddr, size, value)? {
+            Some(value) => value,
+            None => return Ok(()),
+        }
+    } else {
+        value
+    };
+
+    match emu.translate_access(vaddr, size, AccessKind::Write)? {
+        Phys::At(paddr) if emu.bus.is_mmio(paddr, size) => {
+            emu.bus.write(paddr, size, value).map_err(|_| Exceptions::ExceptionStoreAccessFault(vaddr as usize))?;
+            emu.break_reservations(paddr, size);
+        }
+        Phys::At(paddr) => {
+            let written = match size {
+                1 => emu.mmu.write_u8(paddr, value as u8),
+                2 => emu.mmu.write_u16(paddr, value as u16),
+                4 => emu.mmu.write_u32(paddr, value as u32),
+                _ => emu.mmu.write_u64(paddr, value),
+            };
+
+            written.map_err(|fault| Exceptions::from(fault.at(vaddr)))?;
+            emu.break_reservations(paddr, size);
+        }
+        Phys::Split(low, len, high) => {
+            emu.write_split(vaddr, size, (low, len, high), value)?;
+            emu.break_reservations(low, len);
+            emu.break_reservations(high, size - len);
+        }
+    }
+
+    if let Some(tracer) = &emu.tracer {
+        super::lock_tracer(tracer).log_mem(MemAccessKind::Write, vaddr, size, value);
+    }
+
+    Ok(())
+}
+
+//rs1 plus the sign extended offset, the address space wraps around at XLEN bits
+fn effective_addr(emu: &Emulator, rs1: u32, imm: i32) -> Result<u64, EmulatorErr> {
+    let base = emu.cpu.get_reg(rs1 as usize)?;
+
+    Ok(emu.cpu.zero_extend(base.wrapping_add_signed(imm as i64)))
+}
+
+/*
+    8. "A" Extension for Atomic Instructions
+        Harts never run at the same time, every instruction is atomic by itself and memory is sequentially
+        consistent. LR/SC and AMOs have to be naturally aligned, anything else is an access fault.
+*/
+fn atomic_addr(emu: &Emulator, rs1: u32, size: usize, fault: fn(usize) -> Exceptions) -> Result<u64, EmulatorErr> {
+    let vaddr = effective_addr(emu, rs1, 0)?;
+
+    if vaddr % size as u64 != 0 {
+        return Err(fault(vaddr as usize).into());
+    }
+
+    Ok(vaddr)
+}
+
+//32 bit values are sign extended like LW does, for the register and for the AMO's arithmetic
+fn sign_extend(value: u64, size: usize) -> u64 {
+    if size == 4 { value as i32 as i64 as u64 } else { value }
+}
+
+fn exec_lr(emu: &mut Emulator, rd: u32, rs1: u32, size: usize) -> Result<(), EmulatorErr> {
+    let vaddr = atomic_addr(emu, rs1, size, Exceptions::ExceptionLoadAccessFault)?;
+    let value = load(emu, vaddr, size)?;
+
+    //the load translated it already, this is a TLB hit
+    emu.cpu.reservation = Some((emu.translate(vaddr, AccessKind::Read)?, size));
+    emu.cpu.set_reg(rd as usize, sign_extend(value, size))?;
+
+    Ok(())
+}
+
+//rd is 0 if the store happened, 1 if the reservation was lost; either way the reservation is gone
+fn exec_sc(emu: &mut Emulator, rd: u32, rs1: u32, rs2: u32, size: usize) -> Result<(), EmulatorErr> {
+    let vaddr = atomic_addr(emu, rs1, size, Exceptions::ExceptionStoreAccessFault)?;
+    let paddr = emu.translate(vaddr, AccessKind::Write)?;
+    let reserved = emu.cpu.reservation.take() == Some((paddr, size));
+
+    if reserved {
+        let value = emu.cpu.get_reg(rs2 as usize)?;
+        store(emu, vaddr, size, value)?;
+    }
+
+    emu.cpu.set_reg(rd as usize, !reserved as u64)?;
+
+    Ok(())
+}
+
+//rd gets the old value, memory gets op(old, rs2)
+fn exec_amo(emu: &mut Emulator, rd: u32, rs1: u32, rs2: u32, size: usize, op: fn(u64, u64) -> u64) -> Result<(), EmulatorErr> {
+    let vaddr = atomic_addr(emu, rs1, size, Exceptions::ExceptionStoreAccessFault)?;
+    let src = sign_extend(emu.cpu.get_reg(rs2 as usize)?, size);
+
+    //an AMO that can't write faults as a store, before anything is read
+    let paddr = emu.translate(vaddr, AccessKind::Write)?;
+    if !emu.bus.is_mmio(paddr, size) && !emu.mmu.perm_check(paddr as usize, size, memory::PERM_W) {
+        return Err(Exceptions::ExceptionStoreAccessFault(vaddr as usize).into());
+    }
+
+    let old = sign_extend(load(emu, vaddr, size)?, size);
+    store(emu, vaddr, size, op(old, src))?;
+
+    emu.cpu.set_reg(rd as usize, old)?;
+
+    Ok(())
+}
+
+enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+
+fn illegal_instruction(emu: &Emulator) -> EmulatorErr {
+    Exceptions::ExceptionIllegalInstruction(emu.fetch_rinst().unwrap_or(0)).into()
+}
+
+fn exec_csr(emu: &mut Emulator, rd: u32, csr: u32, src: u64, op: CsrOp, write: bool) -> Result<(), EmulatorErr> {
+    let counters = Counters {
+        cycle: emu.icount,
+        instret: emu.icount,
+        time: if csr == csr::CSR_TIME || csr == csr::CSR_TIMEH { emu.mtime() } else { None },
+    };
+
+    let rv32 = emu.cpu.xlen == Xlen::Rv32;
+    let old = if rv32 { emu.cpu.csr.read_rv32(csr, &counters) } else { emu.cpu.csr.read(csr, &counters) };
+    let Some(old) = old else {
+        return Err(illegal_instruction(emu));
+    };
+
+    if write {
+        let new = match op {
+            CsrOp::Write => src,
+            CsrOp::Set => old | src,
+            CsrOp::Clear => old & !src,
+        };
+
+        let written = if rv32 { emu.cpu.csr.write_rv32(csr, new) } else { emu.cpu.csr.write(csr, new) };
+        if !written {
+            return Err(illegal_instruction(emu));
+        }
+
+        //without ASIDs nothing cached belongs to the new address space
+        if csr == csr::CSR_SATP {
+            emu.cpu.tlb.flush();
+        }
+    }
+
+    emu.cpu.set_reg(rd as usize, old)?;
+
+    Ok(())
+}
+
+//shift amounts held in a register only use their low log2(XLEN) bits
+fn shamt_mask(emu: &Emulator) -> u64 {
+    emu.cpu.xlen.bits() as u64 - 1
+}
+
+fn is_link_reg(reg: u32) -> bool {
+    reg as usize == REG_RA || reg as usize == REG_T0
+}
+
+/*
+    2.5.1. Unconditional Jumps
+        Return-address prediction stacks are a common feature of high-performance instruction-fetch units,
+        but require accurate detection of instructions used for procedure calls and returns to be effective.
+        For RISC-V, hints as to the instructions' usage are encoded implicitly via the register numbers used.
+
+        Table 3. Return-address stack prediction hints encoded in the register operands of a JALR instruction
+
+            +---------+---------+---------+---------------+
+            | rd      | rs1     | rs1=rd  | RAS action    |
+            +---------+---------+---------+---------------+
+            | !link   | !link   | -       | None          |
+            | !link   | link    | -       | Pop           |
+            | link    | !link   | -       | Push          |
+            | link    | link    | 0       | Pop, then push|
+            | link    | link    | 1       | Push          |
+            +---------+---------+---------+---------------+
+
+        JAL only ever pushes, when rd is a link register.
+*/
+fn update_call_stack(cpu: &mut Cpu, rd: u32, rs1: Option<u32>, return_addr: u64) {
+    let push = is_link_reg(rd);
+    let pop = match rs1 {
+        Some(rs1) => is_link_reg(rs1) && rd != rs1,
+        None => false,
+    };
+
+    if pop {
+        cpu.call_stack.pop();
+    }
+    if push {
+        cpu.call_stack.push(return_addr);
+    }
 }
 
 
@@ -102,8 +445,13 @@ macro_rules! get_circular_mem_vaddr {
         The standard calling convention uses register x2 as the stack pointer.
 */
 
-pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
-    let inc_pc = true;
+//`size` is 2 for an instruction expanded from an RVC one, the pc then moves on by 2
+pub fn exec(emu: &mut Emulator, inst: Inst, size: u64) -> Result<(), EmulatorErr> {
+    let mut inc_pc = true;
+
+    if emu.cpu.xlen == Xlen::Rv32 && (decoder::is_rv64_only(&inst) || size != RAW_INST_SIZE) {
+        return Err(illegal_instruction(emu));
+    }
 
     match inst {
                 
@@ -143,6 +491,8 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
             let rs1_val = emu.cpu.get_reg(rs1 as usize)? as i64;
             let imm = imm as i64;
 
+            cmplog!(emu, rs1_val, imm);
+
             let value = rs1_val < imm ;
             
             emu.cpu.set_reg(rd as usize, value as u64)?;     
@@ -158,6 +508,8 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
                                  
             let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
             let imm = imm as i64 as u64;
+
+            cmplog!(emu, rs1_val, imm);
                                         
             let value = if rs1_val < imm { 1 } else { 0 };
                                                  
@@ -224,7 +576,8 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
          
         Inst::Srli { rd, rs1, shamt } => {
              
-            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            //zeros have to come in above bit XLEN-1, not copies of the sign extension
+            let rs1_val = emu.cpu.zero_extend(emu.cpu.get_reg(rs1 as usize)?);
              
             let value  = rs1_val >> shamt; 
              
@@ -342,6 +695,8 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
             let rs1_val = emu.cpu.get_reg(rs1 as usize)? as i64;
             let rs2_val = emu.cpu.get_reg(rs2 as usize)? as i64;
 
+            cmplog!(emu, rs1_val, rs2_val);
+
             let value = rs1_val < rs2_val;
 
             emu.cpu.set_reg(rd as usize, value as u64)?;
@@ -351,6 +706,8 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
             let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
             let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
 
+            cmplog!(emu, rs1_val, rs2_val);
+
             let value = rs1_val < rs2_val;
 
             emu.cpu.set_reg(rd as usize, value as u64)?;
@@ -359,24 +716,24 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
             /*
                 SLL, SRL, and SRA perform logical left, logical right, and arithmetic right shifts on the value in
                 register rs1 by the shift amount held in register rs2.
-                In RV64I, only the low 6 bits of rs2 are considered for the shift amount.
+                In RV64I, only the low 6 bits of rs2 are considered for the shift amount, in RV32I the low 5 bits.
             */
 
         Inst::Sll { rd, rs1, rs2 } => {
             let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
             let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
 
-            let value = rs1_val << (rs2_val & 0b1111_11);
+            let value = rs1_val << (rs2_val & shamt_mask(emu));
 
             emu.cpu.set_reg(rd as usize, value)?;
 
         }
 
         Inst::Srl { rd, rs1, rs2 } => {
-            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            let rs1_val = emu.cpu.zero_extend(emu.cpu.get_reg(rs1 as usize)?);
             let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
 
-            let value = rs1_val >> (rs2_val & 0b1111_11);
+            let value = rs1_val >> (rs2_val & shamt_mask(emu));
 
             emu.cpu.set_reg(rd as usize, value)?;
 
@@ -386,7 +743,7 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
             let rs1_val = emu.cpu.get_reg(rs1 as usize)? as i64;
             let rs2_val = emu.cpu.get_reg(rs2 as usize)? as i64;
 
-            let value = rs1_val >> (rs2_val & 0b1111_11);
+            let value = rs1_val >> (rs2_val as u64 & shamt_mask(emu));
 
             emu.cpu.set_reg(rd as usize, value as u64)?;
 
@@ -430,12 +787,523 @@ pub fn exec(emu: &mut Emulator, inst: Inst) -> Result<(), CpuErr> {
 
 
 
-        _=> handle_undefined()
+        Inst::Xor { rd, rs1, rs2 } => {
+            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
+
+            emu.cpu.set_reg(rd as usize, rs1_val ^ rs2_val)?;
+        }
+
+        Inst::Or { rd, rs1, rs2 } => {
+            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
+
+            emu.cpu.set_reg(rd as usize, rs1_val | rs2_val)?;
+        }
+
+        Inst::And { rd, rs1, rs2 } => {
+            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
+
+            emu.cpu.set_reg(rd as usize, rs1_val & rs2_val)?;
+        }
+
+        /*
+            13. "M" Extension for Integer Multiplication and Division
+                MUL puts the low XLEN bits of the product in rd, MULH, MULHU and MULHSU the upper XLEN bits of
+                the signed, unsigned and signed x unsigned product. Nothing traps: dividing by zero gives a
+                quotient of all ones and the dividend as remainder, the one signed overflow (the most negative
+                number divided by -1) gives the dividend and a remainder of zero.
+                The registers of an RV32 hart hold their signed value sign extended, the unsigned one is the
+                register zero extended, so the same arithmetic works for both widths.
+        */
+
+        Inst::Mul { rd, rs1, rs2 } |
+        Inst::Mulh { rd, rs1, rs2 } |
+        Inst::Mulhsu { rd, rs1, rs2 } |
+        Inst::Mulhu { rd, rs1, rs2 } |
+        Inst::Div { rd, rs1, rs2 } |
+        Inst::Divu { rd, rs1, rs2 } |
+        Inst::Rem { rd, rs1, rs2 } |
+        Inst::Remu { rd, rs1, rs2 } => {
+            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
+
+            let (signed1, signed2) = (rs1_val as i64, rs2_val as i64);
+            let (unsigned1, unsigned2) = (emu.cpu.zero_extend(rs1_val), emu.cpu.zero_extend(rs2_val));
+            let bits = emu.cpu.xlen.bits();
+
+            let value = match inst {
+                Inst::Mul { .. } => rs1_val.wrapping_mul(rs2_val),
+                Inst::Mulh { .. } => ((signed1 as i128 * signed2 as i128) >> bits) as u64,
+                Inst::Mulhsu { .. } => ((signed1 as i128 * unsigned2 as i128) >> bits) as u64,
+                Inst::Mulhu { .. } => ((unsigned1 as u128 * unsigned2 as u128) >> bits) as u64,
+                Inst::Div { .. } => if signed2 == 0 { u64::MAX } else { signed1.wrapping_div(signed2) as u64 },
+                Inst::Divu { .. } => unsigned1.checked_div(unsigned2).unwrap_or(u64::MAX),
+                Inst::Rem { .. } => if signed2 == 0 { rs1_val } else { signed1.wrapping_rem(signed2) as u64 },
+                _ => unsigned1.checked_rem(unsigned2).unwrap_or(rs1_val),
+            };
+
+            emu.cpu.set_reg(rd as usize, value)?;
+        }
+
+        //the W forms work on the low 32 bits and sign extend their 32 bit result
+        Inst::Mulw { rd, rs1, rs2 } |
+        Inst::Divw { rd, rs1, rs2 } |
+        Inst::Divuw { rd, rs1, rs2 } |
+        Inst::Remw { rd, rs1, rs2 } |
+        Inst::Remuw { rd, rs1, rs2 } => {
+            let rs1_val = emu.cpu.get_reg(rs1 as usize)? as i32;
+            let rs2_val = emu.cpu.get_reg(rs2 as usize)? as i32;
+
+            let (unsigned1, unsigned2) = (rs1_val as u32, rs2_val as u32);
+
+            let value = match inst {
+                Inst::Mulw { .. } => rs1_val.wrapping_mul(rs2_val),
+                Inst::Divw { .. } => if rs2_val == 0 { -1 } else { rs1_val.wrapping_div(rs2_val) },
+                Inst::Divuw { .. } => unsigned1.checked_div(unsigned2).unwrap_or(u32::MAX) as i32,
+                Inst::Remw { .. } => if rs2_val == 0 { rs1_val } else { rs1_val.wrapping_rem(rs2_val) },
+                _ => unsigned1.checked_rem(unsigned2).unwrap_or(unsigned1) as i32,
+            };
+
+            emu.cpu.set_reg(rd as usize, value as i64 as u64)?;
+        }
+
+        /*
+            2.5.1. Unconditional Jumps
+        */
+
+        Inst::Jal { rd, imm } => {
+            /*
+                The jump and link (JAL) instruction uses the J-type format, where the J-immediate encodes a signed
+                offset in multiples of 2 bytes. The offset is sign-extended and added to the address of the jump
+                instruction to form the jump target address. JAL stores the address of the instruction following the
+                jump ('pc'+4) into register rd.
+            */
+
+            //pc+2 after c.j
+            let pc = emu.cpu.get_pc();
+            let return_addr = pc.wrapping_add(size);
+
+            emu.cpu.set_reg(rd as usize, return_addr)?;
+            update_call_stack(&mut emu.cpu, rd, None, return_addr);
+
+            emu.cpu.set_pc(pc.wrapping_add_signed(imm as i64));
+            inc_pc = false;
+        }
+
+        Inst::Jalr { rd, rs1, imm } => {
+            /*
+                The indirect jump instruction JALR (jump and link register) uses the I-type encoding. The target
+                address is obtained by adding the sign-extended 12-bit I-immediate to the register rs1, then setting
+                the least-significant bit of the result to zero. The address of the instruction following the jump
+                (pc+4) is written to register rd.
+            */
+
+            //pc+2 after c.jalr
+            let pc = emu.cpu.get_pc();
+            let return_addr = pc.wrapping_add(size);
+
+            //rs1 has to be read before rd is written, they may be the same register
+            let target = emu.cpu.get_reg(rs1 as usize)?.wrapping_add_signed(imm as i64) & !1;
+
+            emu.cpu.set_reg(rd as usize, return_addr)?;
+            update_call_stack(&mut emu.cpu, rd, Some(rs1), return_addr);
+
+            emu.cpu.set_pc(target);
+            inc_pc = false;
+        }
+
+        /*
+            2.5.2. Conditional Branches
+                All branch instructions use the B-type instruction format. The 12-bit B-immediate encodes signed
+                offsets in multiples of 2 bytes. The offset is sign-extended and added to the address of the branch
+                instruction to give the target address.
+
+                BEQ and BNE take the branch if registers rs1 and rs2 are equal or unequal respectively. BLT and BLTU
+                take the branch if rs1 is less than rs2, using signed and unsigned comparison respectively. BGE and
+                BGEU take the branch if rs1 is greater than or equal to rs2, using signed and unsigned comparison
+                respectively.
+        */
+
+        Inst::Beq { rs1, rs2, imm } |
+        Inst::Bne { rs1, rs2, imm } |
+        Inst::Blt { rs1, rs2, imm } |
+        Inst::Bge { rs1, rs2, imm } |
+        Inst::Bltu { rs1, rs2, imm } |
+        Inst::Bgeu { rs1, rs2, imm } => {
+            let rs1_val = emu.cpu.get_reg(rs1 as usize)?;
+            let rs2_val = emu.cpu.get_reg(rs2 as usize)?;
+
+            cmplog!(emu, rs1_val, rs2_val);
+
+            let taken = match inst {
+                Inst::Beq { .. } => rs1_val == rs2_val,
+                Inst::Bne { .. } => rs1_val != rs2_val,
+                Inst::Blt { .. } => (rs1_val as i64) < (rs2_val as i64),
+                Inst::Bge { .. } => (rs1_val as i64) >= (rs2_val as i64),
+                Inst::Bltu { .. } => rs1_val < rs2_val,
+                _ => rs1_val >= rs2_val,
+            };
+
+            if taken {
+                emu.cpu.set_pc(emu.cpu.get_pc().wrapping_add_signed(imm as i64));
+                inc_pc = false;
+            }
+        }
+
+        /*
+            2.6. Load and Store Instructions &
+            4.3. Load and Store Instructions
+                The effective address is obtained by adding register rs1 to the sign-extended 12-bit offset.
+                LW loads a 32-bit value from memory and sign-extends this to 64 bits before storing it in register rd.
+                LWU zero-extends the 32-bit value from memory. LH and LHU, LB and LBU are defined analogously for
+                16-bit and 8-bit values. LD loads a 64-bit value from memory into register rd.
+                SD, SW, SH, and SB instructions store 64-bit, 32-bit, 16-bit, and 8-bit values from the low bits of
+                register rs2 to memory respectively.
+        */
+
+        Inst::Lb { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 1)? as i8 as i64;
+
+            emu.cpu.set_reg(rd as usize, value as u64)?;
+        }
+
+        Inst::Lh { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 2)? as i16 as i64;
+
+            emu.cpu.set_reg(rd as usize, value as u64)?;
+        }
+
+        Inst::Lw { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 4)? as i32 as i64;
+
+            emu.cpu.set_reg(rd as usize, value as u64)?;
+        }
+
+        Inst::Ld { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 8)?;
+
+            emu.cpu.set_reg(rd as usize, value)?;
+        }
+
+        Inst::Lbu { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 1)?;
+
+            emu.cpu.set_reg(rd as usize, value)?;
+        }
+
+        Inst::Lhu { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 2)?;
+
+            emu.cpu.set_reg(rd as usize, value)?;
+        }
+
+        Inst::Lwu { rd, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = load(emu, vaddr, 4)?;
+
+            emu.cpu.set_reg(rd as usize, value)?;
+        }
+
+        Inst::Sb { rs2, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = emu.cpu.get_reg(rs2 as usize)?;
+
+            store(emu, vaddr, 1, value)?;
+        }
+
+        Inst::Sh { rs2, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = emu.cpu.get_reg(rs2 as usize)?;
+
+            store(emu, vaddr, 2, value)?;
+        }
+
+        Inst::Sw { rs2, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = emu.cpu.get_reg(rs2 as usize)?;
+
+            store(emu, vaddr, 4, value)?;
+        }
+
+        Inst::Sd { rs2, rs1, imm } => {
+            let vaddr = effective_addr(emu, rs1, imm)?;
+            let value = emu.cpu.get_reg(rs2 as usize)?;
+
+            store(emu, vaddr, 8, value)?;
+        }
+
+        /*
+            8.2. Load-Reserved/Store-Conditional Instructions
+            8.4. Atomic Memory Operations
+        */
+
+        Inst::LrW { rd, rs1 } => exec_lr(emu, rd, rs1, 4)?,
+        Inst::LrD { rd, rs1 } => exec_lr(emu, rd, rs1, 8)?,
+        Inst::ScW { rd, rs1, rs2 } => exec_sc(emu, rd, rs1, rs2, 4)?,
+        Inst::ScD { rd, rs1, rs2 } => exec_sc(emu, rd, rs1, rs2, 8)?,
+
+        Inst::AmoswapW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, |_, src| src)?,
+        Inst::AmoaddW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, u64::wrapping_add)?,
+        Inst::AmoxorW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, |old, src| old ^ src)?,
+        Inst::AmoandW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, |old, src| old & src)?,
+        Inst::AmoorW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, |old, src| old | src)?,
+        Inst::AmominW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, |old, src| (old as i64).min(src as i64) as u64)?,
+        Inst::AmomaxW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, |old, src| (old as i64).max(src as i64) as u64)?,
+        Inst::AmominuW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, u64::min)?,
+        Inst::AmomaxuW { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 4, u64::max)?,
+
+        Inst::AmoswapD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, |_, src| src)?,
+        Inst::AmoaddD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, u64::wrapping_add)?,
+        Inst::AmoxorD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, |old, src| old ^ src)?,
+        Inst::AmoandD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, |old, src| old & src)?,
+        Inst::AmoorD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, |old, src| old | src)?,
+        Inst::AmominD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, |old, src| (old as i64).min(src as i64) as u64)?,
+        Inst::AmomaxD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, |old, src| (old as i64).max(src as i64) as u64)?,
+        Inst::AmominuD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, u64::min)?,
+        Inst::AmomaxuD { rd, rs1, rs2 } => exec_amo(emu, rd, rs1, rs2, 8, u64::max)?,
+
+        /*
+            2.7. Memory Ordering Instructions
+                Harts take turns and each performs its accesses in program order, so FENCE, FENCE.TSO and
+                PAUSE have nothing to order.
+        */
+
+        Inst::Fence { .. } | Inst::FenceTso | Inst::Pause => {}
+
+        /*
+            Zifencei
+                FENCE.I synchronizes the instruction and data streams, stores made before it must be visible
+                to instruction fetches after it, so nothing decoded before it can be reused.
+        */
+
+        Inst::FenceI => {
+            emu.block_cache.flush();
+
+            #[cfg(feature = "jit")]
+            if let Some(jit) = &mut emu.jit {
+                jit.flush();
+            }
+        }
+
+        /*
+            2.8. Environment Call and Breakpoints
+        */
+
+        Inst::Ecall => {
+            return Err(Exceptions::ExceptionEnvironmentCall(emu.cpu.get_pc() as usize).into());
+        }
+
+        Inst::Ebreak => {
+            return Err(Exceptions::ExceptionBreakpoint(emu.cpu.get_pc() as usize).into());
+        }
+
+        /*
+            7.1. CSR Instructions
+                CSRRS/CSRRC with rs1=x0 (or a zero immediate) do not write the CSR at all, so they can read
+                read-only CSRs; every other form writes, even if the value doesn't change.
+        */
+
+        Inst::Csrrw { rd, rs1, csr } => exec_csr(emu, rd, csr, emu.cpu.get_reg(rs1 as usize)?, CsrOp::Write, true)?,
+        Inst::Csrrs { rd, rs1, csr } => exec_csr(emu, rd, csr, emu.cpu.get_reg(rs1 as usize)?, CsrOp::Set, rs1 != 0)?,
+        Inst::Csrrc { rd, rs1, csr } => exec_csr(emu, rd, csr, emu.cpu.get_reg(rs1 as usize)?, CsrOp::Clear, rs1 != 0)?,
+        Inst::Csrrwi { rd, uimm, csr } => exec_csr(emu, rd, csr, uimm as u64, CsrOp::Write, true)?,
+        Inst::Csrrsi { rd, uimm, csr } => exec_csr(emu, rd, csr, uimm as u64, CsrOp::Set, uimm != 0)?,
+        Inst::Csrrci { rd, uimm, csr } => exec_csr(emu, rd, csr, uimm as u64, CsrOp::Clear, uimm != 0)?,
+
+        /*
+            3.3.2. Trap-Return Instructions
+                MRET is only legal in M mode, SRET in S mode unless mstatus.TSR is set, and in M mode.
+        */
+
+        Inst::Mret => {
+            if emu.cpu.csr.privilege != Privilege::Machine {
+                return Err(illegal_instruction(emu));
+            }
+
+            let pc = emu.cpu.csr.mret();
+            emu.cpu.set_pc(pc);
+            inc_pc = false;
+        }
+
+        Inst::Sret => {
+            let privilege = emu.cpu.csr.privilege;
+            if privilege < Privilege::Supervisor || (privilege == Privilege::Supervisor && emu.cpu.csr.mstatus() & csr::MSTATUS_TSR != 0) {
+                return Err(illegal_instruction(emu));
+            }
+
+            let pc = emu.cpu.csr.sret();
+            emu.cpu.set_pc(pc);
+            inc_pc = false;
+        }
+
+        /*
+            3.3.3. Wait for Interrupt
+                The hart may stall until an interrupt might need servicing, it resumes at the next
+                instruction, or in the handler if the interrupt is enabled and taken.
+        */
+
+        Inst::Wfi => {
+            let privilege = emu.cpu.csr.privilege;
+            if privilege == Privilege::User || (privilege == Privilege::Supervisor && emu.cpu.csr.mstatus() & csr::MSTATUS_TW != 0) {
+                return Err(illegal_instruction(emu));
+            }
+
+            emu.wait_for_interrupt();
+        }
+
+        //flushes every translation, whatever address or ASID it names
+        Inst::SfenceVma { .. } => {
+            let privilege = emu.cpu.csr.privilege;
+            if privilege == Privilege::User || (privilege == Privilege::Supervisor && emu.cpu.csr.mstatus() & csr::MSTATUS_TVM != 0) {
+                return Err(illegal_instruction(emu));
+            }
+
+            emu.cpu.tlb.flush();
+        }
+
+        _=> handle_undefined(inst)?
     }
 
     if inc_pc {
-        inc_pc!(emu.cpu);
+        inc_pc!(emu.cpu, size);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing;
+
+    //ld, addiw, slli by 32, lwu, amoadd.d, mulw
+    const RV64_ONLY: [u32; 6] = [0x0007_b503, 0x0015_051b, 0x0205_1513, 0x0007_e503, 0x00b6_352f, 0x02b5_0dbb];
+    //lw, slli by 31, amoadd.w, add
+    const RV32: [u32; 4] = [0x0007_a503, 0x01f5_1513, 0x00b6_252f, 0x00b5_0633];
+
+    fn rv32_emulator(code: &[u32]) -> Emulator {
+        let mut emu = testing::emulator(code);
+        emu.cpu.set_xlen(Xlen::Rv32);
+        //the page addresses wrap around to
+        emu.mmu.map_region(0, 0x1000, memory::PERM_R | memory::PERM_W, "low").unwrap();
+
+        emu
+    }
+
+    #[test]
+    fn rv32_registers_are_sign_extended_and_addresses_zero_extended() {
+        let mut cpu = Cpu::new();
+        cpu.set_reg(5, 0x8000_0000).unwrap();
+        assert_eq!(cpu.get_reg(5).unwrap(), 0x8000_0000);
+
+        cpu.set_xlen(Xlen::Rv32);
+        assert_eq!((cpu.xlen().bits(), cpu.xlen().bytes()), (32, 4));
+
+        cpu.set_reg(5, 0x8000_0000).unwrap();
+        assert_eq!(cpu.get_reg(5).unwrap(), 0xffff_ffff_8000_0000);
+        cpu.set_reg(5, 0x1_0000_0001).unwrap();
+        assert_eq!(cpu.get_reg(5).unwrap(), 1);
+
+        cpu.set_regs([u32::MAX as u64; MAX_REGS]);
+        assert_eq!((cpu.get_reg(0).unwrap(), cpu.get_reg(1).unwrap()), (0, u64::MAX));
+        assert_eq!(cpu.zero_extend(u64::MAX), u32::MAX as u64);
+
+        cpu.set_pc(0x1_0000_1000);
+        assert_eq!(cpu.get_pc(), 0x1000);
+    }
+
+    #[test]
+    fn rv64_only_instructions_are_illegal_on_rv32() {
+        for raw in RV64_ONLY {
+            assert!(decoder::is_rv64_only(&decoder::decode(raw)), "{:#x}", raw);
+
+            let mut emu = rv32_emulator(&[raw]);
+            assert!(matches!(emu.run(Some(1)), Err(EmulatorErr::ErrTrap(Exceptions::ExceptionIllegalInstruction(inst))) if inst == raw), "{:#x}", raw);
+        }
+
+        for raw in RV32 {
+            assert!(!decoder::is_rv64_only(&decoder::decode(raw)), "{:#x}", raw);
+        }
+    }
+
+    #[test]
+    fn rv32_results_and_addresses_wrap_at_32_bits() {
+        let mut emu = rv32_emulator(&[
+            //s1 = 0x80000000 - 1, a1 = -1 >> 28, a3 = 1 << (33 & 31)
+            0x8000_04b7, 0xfff4_8493, 0xfff0_0613, 0x01c6_5593, 0x0210_0293, 0x0010_0313, 0x0053_16b3,
+            //a5 = -16, a4 = [a5 + 16], [a5 + 20] = s1, both wrap around to the bottom page
+            0xff00_0793, 0x0107_a703, 0x0097_aa23,
+            0x0000_0513, 0x05d0_0893, 0x0000_0073,
+        ]);
+        emu.mmu.write_u32(0, 0x8000_0001).unwrap();
+
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(0))));
+
+        let reg = |reg| emu.cpu.get_reg(reg).unwrap();
+        assert_eq!(reg(9), 0x7fff_ffff);
+        assert_eq!(reg(11), 0xf);
+        assert_eq!(reg(13), 2);
+        assert_eq!(reg(14), 0xffff_ffff_8000_0001);
+        assert_eq!(emu.mmu.read_u32(4).unwrap(), 0x7fff_ffff);
+    }
+
+    //-7 and 2 through every M instruction, then the two edge cases: by zero and the most negative / -1
+    const MULDIV: [u32; 23] = [
+        0xff90_0513, 0x0020_0593, 0x02b5_0633, 0x02b5_16b3, 0x02b5_3733, 0x02b5_27b3, 0x02b5_4833, 0x02b5_64b3,
+        0x02b5_5933, 0x02b5_79b3, 0x0205_4a33, 0x0205_6ab3, 0x0010_0293, 0x03f2_9293, 0xfff0_0313, 0x0262_cb33,
+        0x0262_ebb3, 0x02b5_4c3b, 0x02b5_7cbb, 0x02b5_5d3b, 0x02b5_0dbb, 0x05d0_0893, 0x0000_0073,
+    ];
+
+    #[test]
+    fn multiplies_and_divides_never_trap() {
+        let mut emu = testing::emulator(&MULDIV);
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(_))));
+
+        let reg = |reg| emu.cpu.get_reg(reg).unwrap();
+        //mul, mulh, mulhu, mulhsu
+        assert_eq!([reg(12), reg(13), reg(14), reg(15)], [-14i64 as u64, u64::MAX, 1, u64::MAX]);
+        //div, rem, divu, remu
+        assert_eq!([reg(16), reg(9), reg(18), reg(19)], [-3i64 as u64, u64::MAX, 0x7fff_ffff_ffff_fffc, 1]);
+        //by zero, then the overflow
+        assert_eq!([reg(20), reg(21), reg(22), reg(23)], [u64::MAX, -7i64 as u64, 1 << 63, 0]);
+        //divw, remuw, divuw, mulw
+        assert_eq!([reg(24), reg(25), reg(26), reg(27)], [-3i64 as u64, 1, 0x7fff_fffc, -14i64 as u64]);
+    }
+
+    #[test]
+    fn rv32_multiplies_and_divides_at_32_bits() {
+        //up to remu, then the exit
+        let mut emu = rv32_emulator(&[&MULDIV[..10], &MULDIV[21..]].concat());
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(_))));
+
+        let reg = |reg| emu.cpu.get_reg(reg).unwrap();
+        assert_eq!([reg(12), reg(13), reg(14), reg(15)], [-14i64 as u64, u64::MAX, 1, u64::MAX]);
+        assert_eq!([reg(16), reg(9), reg(18), reg(19)], [-3i64 as u64, u64::MAX, 0x7fff_fffc, 1]);
+    }
+
+    //c.li a0, 5; auipc a1, 0; c.addi a1, 12; c.jalr a1; c.j exit; c.nop; f: c.addi a0, 1; c.jr ra; exit,
+    //whose ecall ends 2 bytes into the last word
+    const COMPRESSED: [u32; 7] = [0x0597_4515, 0x05b1_0000, 0xa021_9582, 0x0505_0001, 0x0893_8082, 0x0073_05d0, 0];
+
+    #[test]
+    fn compressed_instructions_advance_and_link_by_2() {
+        let mut emu = testing::emulator(&COMPRESSED);
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrExited(6))));
+
+        let reg = |reg| emu.cpu.get_reg(reg).unwrap();
+        //auipc ran 2 bytes in, c.jalr linked past itself
+        assert_eq!((reg(11), reg(REG_RA)), (testing::CODE_BASE + 0xe, testing::CODE_BASE + 0xa));
+
+        //RV32 harts don't have them
+        let mut emu = rv32_emulator(&COMPRESSED);
+        assert!(matches!(emu.run(Some(1)), Err(EmulatorErr::ErrTrap(Exceptions::ExceptionIllegalInstruction(0x4515)))));
+    }
+}
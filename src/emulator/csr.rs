@@ -0,0 +1,603 @@
+/*
+    Control and status registers (Zicsr) and the privileged state they hold: the current privilege mode,
+    trap setup/handling registers for M and S mode, interrupt enables/pendings and the counters. satp
+    accepts Bare and Sv39 without ASIDs, the translation itself is in paging.rs.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 3 {
+            3 => Privilege::Machine,
+            1 => Privilege::Supervisor,
+            _ => Privilege::User,
+        }
+    }
+}
+
+//supervisor
+pub const CSR_SSTATUS: u32 = 0x100;
+pub const CSR_SIE: u32 = 0x104;
+pub const CSR_STVEC: u32 = 0x105;
+pub const CSR_SCOUNTEREN: u32 = 0x106;
+pub const CSR_SENVCFG: u32 = 0x10a;
+pub const CSR_SSCRATCH: u32 = 0x140;
+pub const CSR_SEPC: u32 = 0x141;
+pub const CSR_SCAUSE: u32 = 0x142;
+pub const CSR_STVAL: u32 = 0x143;
+pub const CSR_SIP: u32 = 0x144;
+pub const CSR_SATP: u32 = 0x180;
+
+//machine
+pub const CSR_MSTATUS: u32 = 0x300;
+pub const CSR_MISA: u32 = 0x301;
+pub const CSR_MEDELEG: u32 = 0x302;
+pub const CSR_MIDELEG: u32 = 0x303;
+pub const CSR_MIE: u32 = 0x304;
+pub const CSR_MTVEC: u32 = 0x305;
+pub const CSR_MCOUNTEREN: u32 = 0x306;
+pub const CSR_MENVCFG: u32 = 0x30a;
+pub const CSR_MCOUNTINHIBIT: u32 = 0x320;
+pub const CSR_MSCRATCH: u32 = 0x340;
+pub const CSR_MEPC: u32 = 0x341;
+pub const CSR_MCAUSE: u32 = 0x342;
+pub const CSR_MTVAL: u32 = 0x343;
+pub const CSR_MIP: u32 = 0x344;
+pub const CSR_MSTATUSH: u32 = 0x310;
+pub const CSR_MCYCLE: u32 = 0xb00;
+pub const CSR_MINSTRET: u32 = 0xb02;
+pub const CSR_MVENDORID: u32 = 0xf11;
+pub const CSR_MARCHID: u32 = 0xf12;
+pub const CSR_MIMPID: u32 = 0xf13;
+pub const CSR_MHARTID: u32 = 0xf14;
+
+//unprivileged counters
+pub const CSR_CYCLE: u32 = 0xc00;
+pub const CSR_TIME: u32 = 0xc01;
+pub const CSR_INSTRET: u32 = 0xc02;
+pub const CSR_TIMEH: u32 = 0xc81;
+
+//RV32 only, the high halves of the machine and unprivileged counters, 0x80 above their low halves
+const CSR_MCOUNTERH: (u32, u32) = (0xb80, 0xb9f);
+const CSR_COUNTERH: (u32, u32) = (0xc80, 0xc9f);
+const COUNTERH_OFFSET: u32 = 0x80;
+
+//PMP and the hpm counters/events exist so firmware can probe them, they read as zero and ignore writes
+const CSR_PMPCFG: (u32, u32) = (0x3a0, 0x3af);
+const CSR_PMPADDR: (u32, u32) = (0x3b0, 0x3ef);
+const CSR_MHPMEVENT: (u32, u32) = (0x323, 0x33f);
+const CSR_MHPMCOUNTER: (u32, u32) = (0xb03, 0xb1f);
+const CSR_HPMCOUNTER: (u32, u32) = (0xc03, 0xc1f);
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP: u64 = 3 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+//XLEN is fixed at 64 for U and S mode
+const MSTATUS_UXL_SXL: u64 = (2 << 32) | (2 << 34);
+
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_PPN: u64 = (1 << 44) - 1;
+
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP | MSTATUS_MPP |
+    MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | (3 << 32);
+
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+const MIE_WRITABLE: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+//bits of mip software can write, the rest follow the devices
+const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const S_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+//every exception but an ecall from M mode can be delegated
+const MEDELEG_WRITABLE: u64 = 0xffff & !(1 << 11);
+
+//misaligned fetch, breakpoint, ecall from U, instruction/load/store page faults
+const FIRMWARE_MEDELEG: u64 = (1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15);
+
+pub const CAUSE_INTERRUPT: u64 = 1 << 63;
+
+//3.1.9: interrupts are taken in this order when several are pending
+const INTERRUPT_PRIORITY: [u64; 6] = [11, 3, 7, 9, 1, 5];
+
+//MXL = 64, A (bit 0), C, I, M, S, U
+const MISA: u64 = (2 << 62) | 1 | (1 << (b'C' - b'A')) | (1 << (b'I' - b'A')) | (1 << (b'M' - b'A')) | (1 << (b'S' - b'A')) | (1 << (b'U' - b'A'));
+//MXL = 32, the same extensions but C, RV32 harts don't decode RVC instructions
+const MISA_32: u64 = (1 << 30) | (MISA & 0x3ff_ffff & !(1 << (b'C' - b'A')));
+
+//where an RV32 mcause/scause has the interrupt bit
+const CAUSE_INTERRUPT_32: u64 = 1 << 31;
+
+const COUNTEREN_CY_TM_IR: u64 = 0b111;
+
+//mtvec/stvec MODE field, only direct and vectored are defined
+const TVEC_VECTORED: u64 = 1;
+
+#[derive(Clone)]
+pub struct Csrs {
+    pub privilege: Privilege,
+    hartid: u64,
+
+    mstatus: u64,
+    medeleg: u64,
+    mideleg: u64,
+    mie: u64,
+    //software written pending bits
+    mip: u64,
+    //pending bits driven by devices (CLINT, PLIC), refreshed between instructions
+    mip_hw: u64,
+    mtvec: u64,
+    mcounteren: u64,
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+
+    stvec: u64,
+    scounteren: u64,
+    sscratch: u64,
+    sepc: u64,
+    scause: u64,
+    stval: u64,
+    satp: u64,
+}
+
+//counter values a CSR read may need, the registers themselves don't keep them
+pub struct Counters {
+    pub cycle: u64,
+    pub instret: u64,
+    pub time: Option<u64>,
+}
+
+impl Csrs {
+    //reset state for a hart that starts in M mode
+    pub fn new(hartid: u64) -> Self {
+        Csrs {
+            privilege: Privilege::Machine,
+            hartid,
+            mstatus: MSTATUS_UXL_SXL,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mip_hw: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+        }
+    }
+
+    //a user mode process as the execution environment presents it, with the counters readable
+    pub fn user() -> Self {
+        Csrs {
+            privilege: Privilege::User,
+            mcounteren: COUNTEREN_CY_TM_IR,
+            scounteren: COUNTEREN_CY_TM_IR,
+            ..Csrs::new(0)
+        }
+    }
+
+    pub fn hartid(&self) -> u64 {
+        self.hartid
+    }
+
+    pub fn mie(&self) -> u64 {
+        self.mie
+    }
+
+    pub fn mip(&self) -> u64 {
+        self.mip | self.mip_hw
+    }
+
+    //software pending bits set from outside the hart (an IPI through the SBI)
+    pub fn set_mip(&mut self, bits: u64) {
+        self.mip |= bits & MIP_WRITABLE;
+    }
+
+    /*
+        What M mode firmware leaves behind when it jumps to a supervisor: the hart in S mode, the
+        supervisor interrupts and the exceptions a kernel handles itself delegated (the set OpenSBI
+        delegates), the counters readable.
+    */
+    pub fn enter_supervisor(&mut self) {
+        self.privilege = Privilege::Supervisor;
+        self.mideleg = S_INTERRUPTS;
+        self.medeleg = FIRMWARE_MEDELEG;
+        self.mcounteren = COUNTEREN_CY_TM_IR;
+    }
+
+    pub fn set_mip_hw(&mut self, bits: u64) {
+        self.mip_hw = bits;
+    }
+
+    pub fn mstatus(&self) -> u64 {
+        self.mstatus
+    }
+
+    pub fn satp(&self) -> u64 {
+        self.satp
+    }
+
+    //3.1.6.3: loads and stores from M mode with MPRV set are translated and checked as if made from MPP
+    pub fn data_privilege(&self) -> Privilege {
+        if self.privilege == Privilege::Machine && self.mstatus & MSTATUS_MPRV != 0 {
+            Privilege::from_bits(self.mstatus >> MSTATUS_MPP_SHIFT)
+        } else {
+            self.privilege
+        }
+    }
+
+    //the handler a trap with this cause would go to, 0 when nothing was installed there
+    pub fn trap_vector(&self, cause: u64) -> u64 {
+        if self.delegated(cause) { self.stvec } else { self.mtvec }
+    }
+
+    fn delegated(&self, cause: u64) -> bool {
+        let deleg = if cause & CAUSE_INTERRUPT != 0 { self.mideleg } else { self.medeleg };
+
+        self.privilege <= Privilege::Supervisor && (deleg >> (cause & 63)) & 1 != 0
+    }
+
+    /*
+        3.1.6.1 / 4.1.1: a CSR's address says who may access it, bits 9:8 hold the lowest privilege that
+        can and bits 11:10 == 0b11 make it read-only. The unprivileged counters additionally have to be
+        enabled through mcounteren/scounteren for lower modes.
+    */
+    fn accessible(&self, csr: u32, write: bool) -> bool {
+        if write && (csr >> 10) & 3 == 3 {
+            return false;
+        }
+        if ((csr >> 8) & 3) as u64 > self.privilege as u64 {
+            return false;
+        }
+
+        if (CSR_CYCLE..=CSR_HPMCOUNTER.1).contains(&csr) {
+            let bit = 1 << (csr - CSR_CYCLE);
+
+            if self.privilege < Privilege::Machine && self.mcounteren & bit == 0 {
+                return false;
+            }
+            if self.privilege == Privilege::User && self.scounteren & bit == 0 {
+                return false;
+            }
+        }
+
+        //trapping satp accesses lets a hypervisor-like M mode take over translation
+        !(csr == CSR_SATP && self.privilege == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0)
+    }
+
+    //None means the access raises an illegal instruction exception
+    pub fn read(&self, csr: u32, counters: &Counters) -> Option<u64> {
+        if !self.accessible(csr, false) {
+            return None;
+        }
+
+        let value = match csr {
+            CSR_SSTATUS => self.mstatus & SSTATUS_MASK,
+            CSR_SIE => self.mie & self.mideleg & S_INTERRUPTS,
+            CSR_STVEC => self.stvec,
+            CSR_SCOUNTEREN => self.scounteren,
+            CSR_SENVCFG => 0,
+            CSR_SSCRATCH => self.sscratch,
+            CSR_SEPC => self.sepc,
+            CSR_SCAUSE => self.scause,
+            CSR_STVAL => self.stval,
+            CSR_SIP => self.mip() & self.mideleg & S_INTERRUPTS,
+            CSR_SATP => self.satp,
+
+            CSR_MSTATUS => self.mstatus,
+            CSR_MISA => MISA,
+            CSR_MEDELEG => self.medeleg,
+            CSR_MIDELEG => self.mideleg,
+            CSR_MIE => self.mie,
+            CSR_MTVEC => self.mtvec,
+            CSR_MCOUNTEREN => self.mcounteren,
+            CSR_MENVCFG | CSR_MCOUNTINHIBIT => 0,
+            CSR_MSCRATCH => self.mscratch,
+            CSR_MEPC => self.mepc,
+            CSR_MCAUSE => self.mcause,
+            CSR_MTVAL => self.mtval,
+            CSR_MIP => self.mip(),
+            CSR_MVENDORID | CSR_MARCHID | CSR_MIMPID => 0,
+            CSR_MHARTID => self.hartid,
+
+            CSR_CYCLE | CSR_MCYCLE => counters.cycle,
+            CSR_INSTRET | CSR_MINSTRET => counters.instret,
+            //without a timer there is nothing to read, firmware usually traps and emulates it then
+            CSR_TIME => counters.time?,
+
+            _ if in_range(csr, CSR_PMPCFG) || in_range(csr, CSR_PMPADDR) || in_range(csr, CSR_MHPMEVENT) ||
+                in_range(csr, CSR_MHPMCOUNTER) || in_range(csr, CSR_HPMCOUNTER) => 0,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /*
+        What an RV32 hart reads: misa says MXL = 32 and lacks C, the counters' upper halves have CSRs of their own and
+        the interrupt bit of a cause is bit 31. Everything else is the RV64 value, cut to 32 bits when it
+        reaches the register. mstatush holds nothing we implement.
+    */
+    pub fn read_rv32(&self, csr: u32, counters: &Counters) -> Option<u64> {
+        let value = match csr {
+            CSR_MISA => self.read(csr, counters).map(|_| MISA_32)?,
+            CSR_MSTATUSH => self.read(CSR_MSTATUS, counters).map(|_| 0)?,
+            CSR_MCAUSE | CSR_SCAUSE => {
+                let cause = self.read(csr, counters)?;
+                if cause & CAUSE_INTERRUPT != 0 { (cause & !CAUSE_INTERRUPT) | CAUSE_INTERRUPT_32 } else { cause }
+            }
+            _ if in_range(csr, CSR_MCOUNTERH) || in_range(csr, CSR_COUNTERH) => self.read(csr - COUNTERH_OFFSET, counters)? >> 32,
+            _ => self.read(csr, counters)?,
+        };
+
+        Some(value)
+    }
+
+    //the writes read_rv32 has to match
+    pub fn write_rv32(&mut self, csr: u32, value: u64) -> bool {
+        match csr {
+            CSR_MSTATUSH => self.accessible(CSR_MSTATUS, true),
+            //without C instructions are 4 byte aligned, so is every return address
+            CSR_MEPC | CSR_SEPC => self.write(csr, value & !3),
+            CSR_MCAUSE | CSR_SCAUSE => {
+                let value = value as u32 as u64;
+                let value = if value & CAUSE_INTERRUPT_32 != 0 { (value & !CAUSE_INTERRUPT_32) | CAUSE_INTERRUPT } else { value };
+
+                self.write(csr, value)
+            }
+            //the upper halves ignore writes like the counters themselves
+            _ if in_range(csr, CSR_MCOUNTERH) || in_range(csr, CSR_COUNTERH) => self.accessible(csr, true),
+            _ => self.write(csr, value),
+        }
+    }
+
+    //false means the access raises an illegal instruction exception
+    pub fn write(&mut self, csr: u32, value: u64) -> bool {
+        if !self.accessible(csr, true) {
+            return false;
+        }
+
+        match csr {
+            CSR_SSTATUS => self.mstatus = (self.mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK & MSTATUS_WRITABLE) | MSTATUS_UXL_SXL,
+            CSR_SIE => {
+                let mask = self.mideleg & S_INTERRUPTS;
+                self.mie = (self.mie & !mask) | (value & mask);
+            }
+            CSR_STVEC => self.stvec = value & !2,
+            CSR_SCOUNTEREN => self.scounteren = value & 0xffff_ffff,
+            CSR_SSCRATCH => self.sscratch = value,
+            CSR_SEPC => self.sepc = value & !1,
+            CSR_SCAUSE => self.scause = value,
+            CSR_STVAL => self.stval = value,
+            CSR_SIP => {
+                let mask = self.mideleg & MIP_SSIP;
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            //Sv48 and up are WARL and leave satp alone, ASIDLEN is 0 so the ASID field stays 0
+            CSR_SATP => {
+                let mode = value >> SATP_MODE_SHIFT;
+                if mode == SATP_MODE_BARE || mode == SATP_MODE_SV39 {
+                    self.satp = value & ((0xf << SATP_MODE_SHIFT) | SATP_PPN);
+                }
+            }
+
+            CSR_MSTATUS => {
+                let mut value = value & MSTATUS_WRITABLE;
+
+                //MPP is WARL, 2 is reserved
+                if (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 2 {
+                    value = (value & !MSTATUS_MPP) | (self.mstatus & MSTATUS_MPP);
+                }
+                self.mstatus = value | MSTATUS_UXL_SXL;
+            }
+            CSR_MEDELEG => self.medeleg = value & MEDELEG_WRITABLE,
+            CSR_MIDELEG => self.mideleg = value & S_INTERRUPTS,
+            CSR_MIE => self.mie = value & MIE_WRITABLE,
+            CSR_MTVEC => self.mtvec = value & !2,
+            CSR_MCOUNTEREN => self.mcounteren = value & 0xffff_ffff,
+            CSR_MSCRATCH => self.mscratch = value,
+            CSR_MEPC => self.mepc = value & !1,
+            CSR_MCAUSE => self.mcause = value,
+            CSR_MTVAL => self.mtval = value,
+            CSR_MIP => self.mip = (self.mip & !MIP_WRITABLE) | (value & MIP_WRITABLE),
+
+            //misa is WARL and can't be changed, the counters don't take writes either
+            CSR_MISA | CSR_MENVCFG | CSR_SENVCFG | CSR_MCOUNTINHIBIT | CSR_MCYCLE | CSR_MINSTRET => {}
+            _ if in_range(csr, CSR_PMPCFG) || in_range(csr, CSR_PMPADDR) || in_range(csr, CSR_MHPMEVENT) ||
+                in_range(csr, CSR_MHPMCOUNTER) => {}
+            _ => return false,
+        }
+
+        true
+    }
+
+    /*
+        3.1.6.1 / 3.1.9: an interrupt is taken by M mode if it is pending, enabled in mie, not delegated and
+        either the hart runs below M mode or mstatus.MIE is set; delegated ones the same way for S mode.
+        Returns the cause of the highest priority interrupt to take.
+    */
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.mip() & self.mie;
+        if pending == 0 {
+            return None;
+        }
+
+        let m_enabled = self.privilege < Privilege::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < Privilege::Supervisor || (self.privilege == Privilege::Supervisor && self.mstatus & MSTATUS_SIE != 0);
+
+        let m_pending = if m_enabled { pending & !self.mideleg } else { 0 };
+        let s_pending = if s_enabled { pending & self.mideleg } else { 0 };
+
+        INTERRUPT_PRIORITY.iter()
+            .find(|code| (m_pending | s_pending) >> *code & 1 != 0)
+            .map(|code| CAUSE_INTERRUPT | code)
+    }
+
+    //enters the trap handler for `cause`, returns the pc to continue at
+    pub fn trap(&mut self, pc: u64, cause: u64, tval: u64) -> u64 {
+        let interrupt = cause & CAUSE_INTERRUPT != 0;
+        let from = self.privilege as u64;
+
+        let tvec = if self.delegated(cause) {
+            self.sepc = pc;
+            self.scause = cause;
+            self.stval = tval;
+
+            let sie = (self.mstatus & MSTATUS_SIE) << 4;
+            self.mstatus = (self.mstatus & !(MSTATUS_SPIE | MSTATUS_SIE | MSTATUS_SPP)) | sie | (from << 8);
+            self.privilege = Privilege::Supervisor;
+
+            self.stvec
+        } else {
+            self.mepc = pc;
+            self.mcause = cause;
+            self.mtval = tval;
+
+            let mie = (self.mstatus & MSTATUS_MIE) << 4;
+            self.mstatus = (self.mstatus & !(MSTATUS_MPIE | MSTATUS_MIE | MSTATUS_MPP)) | mie | (from << MSTATUS_MPP_SHIFT);
+            self.privilege = Privilege::Machine;
+
+            self.mtvec
+        };
+
+        let base = tvec & !3;
+        if interrupt && tvec & 3 == TVEC_VECTORED {
+            base + 4 * (cause & 63)
+        } else {
+            base
+        }
+    }
+
+    //3.3.2: returns the pc to continue at
+    pub fn mret(&mut self) -> u64 {
+        let mpp = Privilege::from_bits(self.mstatus >> MSTATUS_MPP_SHIFT);
+        let mpie = (self.mstatus & MSTATUS_MPIE) >> 4;
+
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | mpie | MSTATUS_MPIE;
+        if mpp != Privilege::Machine {
+            self.mstatus &= !MSTATUS_MPRV;
+        }
+        self.privilege = mpp;
+
+        self.mepc
+    }
+
+    pub fn sret(&mut self) -> u64 {
+        let spp = if self.mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+        let spie = (self.mstatus & MSTATUS_SPIE) >> 4;
+
+        self.mstatus = (self.mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | spie | MSTATUS_SPIE;
+        self.privilege = spp;
+
+        self.sepc
+    }
+}
+
+fn in_range(csr: u32, (start, end): (u32, u32)) -> bool {
+    (start..=end).contains(&csr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSR_CYCLEH: u32 = CSR_CYCLE + COUNTERH_OFFSET;
+    const CSR_MINSTRETH: u32 = CSR_MINSTRET + COUNTERH_OFFSET;
+
+    const COUNTERS: Counters = Counters { cycle: 0x1_2345_6789, instret: 0x2_0000_0000, time: Some(0x5_0000_0001) };
+
+    #[test]
+    fn rv32_reads_split_and_narrow_the_rv64_values() {
+        let csrs = Csrs::new(0);
+
+        assert_eq!(csrs.read_rv32(CSR_MISA, &COUNTERS), Some(1 << 30 | (MISA & 0x3ff_fffb)));
+        assert_eq!(csrs.read_rv32(CSR_MSTATUSH, &COUNTERS), Some(0));
+
+        //the low halves read the whole counter, the register keeps the low 32 bits of it
+        assert_eq!(csrs.read_rv32(CSR_CYCLE, &COUNTERS), Some(0x1_2345_6789));
+        assert_eq!(csrs.read_rv32(CSR_CYCLEH, &COUNTERS), Some(1));
+        assert_eq!(csrs.read_rv32(CSR_TIMEH, &COUNTERS), Some(5));
+        assert_eq!(csrs.read_rv32(CSR_MINSTRETH, &COUNTERS), Some(2));
+
+        //the high halves don't exist on RV64
+        assert_eq!(csrs.read(CSR_CYCLEH, &COUNTERS), None);
+        assert_eq!(csrs.read(CSR_MSTATUSH, &COUNTERS), None);
+    }
+
+    #[test]
+    fn rv32_causes_keep_the_interrupt_bit_in_bit_31() {
+        let mut csrs = Csrs::new(0);
+
+        assert!(csrs.write_rv32(CSR_MCAUSE, 0x8000_0007));
+        assert_eq!(csrs.read(CSR_MCAUSE, &COUNTERS), Some(CAUSE_INTERRUPT | 7));
+        assert_eq!(csrs.read_rv32(CSR_MCAUSE, &COUNTERS), Some(0x8000_0007));
+
+        assert!(csrs.write_rv32(CSR_SCAUSE, 0xffff_ffff_0000_000d));
+        assert_eq!(csrs.read_rv32(CSR_SCAUSE, &COUNTERS), Some(0xd));
+
+        //the upper halves take writes and ignore them like the counters do
+        assert!(csrs.write_rv32(CSR_MSTATUSH, 0xffff_ffff));
+        assert!(csrs.write_rv32(CSR_MINSTRETH, 1));
+        assert_eq!(csrs.read_rv32(CSR_MINSTRETH, &COUNTERS), Some(2));
+    }
+
+    #[test]
+    fn epcs_are_as_aligned_as_instructions() {
+        let mut csrs = Csrs::new(0);
+
+        //RVC instructions are 2 byte aligned
+        assert!(csrs.write(CSR_MEPC, 0x1003) && csrs.write(CSR_SEPC, 0x2007));
+        assert_eq!((csrs.read(CSR_MEPC, &COUNTERS), csrs.read(CSR_SEPC, &COUNTERS)), (Some(0x1002), Some(0x2006)));
+
+        //RV32 harts have none
+        assert!(csrs.write_rv32(CSR_MEPC, 0x1003) && csrs.write_rv32(CSR_SEPC, 0x2007));
+        assert_eq!((csrs.read_rv32(CSR_MEPC, &COUNTERS), csrs.read_rv32(CSR_SEPC, &COUNTERS)), (Some(0x1000), Some(0x2004)));
+    }
+
+    #[test]
+    fn rv32_machine_csrs_stay_out_of_user_reach() {
+        let mut csrs = Csrs::user();
+
+        assert_eq!(csrs.read_rv32(CSR_MSTATUSH, &COUNTERS), None);
+        assert_eq!(csrs.read_rv32(CSR_MINSTRETH, &COUNTERS), None);
+        assert!(!csrs.write_rv32(CSR_MCAUSE, 0));
+
+        //the unprivileged counters are readable, and read only
+        assert_eq!(csrs.read_rv32(CSR_CYCLEH, &COUNTERS), Some(1));
+        assert!(!csrs.write_rv32(CSR_CYCLEH, 0));
+    }
+}
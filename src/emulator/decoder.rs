@@ -30,6 +30,45 @@ pub enum Inst {
     Srlw {rd: u32, rs1: u32, rs2: u32},
     Sraw {rd: u32, rs1: u32, rs2: u32},
 
+    //M extension
+    Mul {rd: u32, rs1: u32, rs2: u32},
+    Mulh {rd: u32, rs1: u32, rs2: u32},
+    Mulhsu {rd: u32, rs1: u32, rs2: u32},
+    Mulhu {rd: u32, rs1: u32, rs2: u32},
+    Div {rd: u32, rs1: u32, rs2: u32},
+    Divu {rd: u32, rs1: u32, rs2: u32},
+    Rem {rd: u32, rs1: u32, rs2: u32},
+    Remu {rd: u32, rs1: u32, rs2: u32},
+    Mulw {rd: u32, rs1: u32, rs2: u32},
+    Divw {rd: u32, rs1: u32, rs2: u32},
+    Divuw {rd: u32, rs1: u32, rs2: u32},
+    Remw {rd: u32, rs1: u32, rs2: u32},
+    Remuw {rd: u32, rs1: u32, rs2: u32},
+
+    //A extension, the aq/rl ordering bits are dropped since every access is sequentially consistent
+    LrW {rd: u32, rs1: u32},
+    ScW {rd: u32, rs1: u32, rs2: u32},
+    AmoswapW {rd: u32, rs1: u32, rs2: u32},
+    AmoaddW {rd: u32, rs1: u32, rs2: u32},
+    AmoxorW {rd: u32, rs1: u32, rs2: u32},
+    AmoandW {rd: u32, rs1: u32, rs2: u32},
+    AmoorW {rd: u32, rs1: u32, rs2: u32},
+    AmominW {rd: u32, rs1: u32, rs2: u32},
+    AmomaxW {rd: u32, rs1: u32, rs2: u32},
+    AmominuW {rd: u32, rs1: u32, rs2: u32},
+    AmomaxuW {rd: u32, rs1: u32, rs2: u32},
+    LrD {rd: u32, rs1: u32},
+    ScD {rd: u32, rs1: u32, rs2: u32},
+    AmoswapD {rd: u32, rs1: u32, rs2: u32},
+    AmoaddD {rd: u32, rs1: u32, rs2: u32},
+    AmoxorD {rd: u32, rs1: u32, rs2: u32},
+    AmoandD {rd: u32, rs1: u32, rs2: u32},
+    AmoorD {rd: u32, rs1: u32, rs2: u32},
+    AmominD {rd: u32, rs1: u32, rs2: u32},
+    AmomaxD {rd: u32, rs1: u32, rs2: u32},
+    AmominuD {rd: u32, rs1: u32, rs2: u32},
+    AmomaxuD {rd: u32, rs1: u32, rs2: u32},
+
     //Itype
     Jalr {rd: u32, rs1: u32, imm: i32},
     Lb {rd: u32, rs1: u32, imm: i32},
@@ -49,8 +88,19 @@ pub enum Inst {
     Fence {rd: u32, rs1: u32, imm_raw: u32},
     FenceTso,
     Pause,
+    FenceI,
     Ecall,
     Ebreak,
+    Mret,
+    Sret,
+    Wfi,
+    SfenceVma {rs1: u32, rs2: u32},
+    Csrrw {rd: u32, rs1: u32, csr: u32},
+    Csrrs {rd: u32, rs1: u32, csr: u32},
+    Csrrc {rd: u32, rs1: u32, csr: u32},
+    Csrrwi {rd: u32, uimm: u32, csr: u32},
+    Csrrsi {rd: u32, uimm: u32, csr: u32},
+    Csrrci {rd: u32, uimm: u32, csr: u32},
     Lwu {rd: u32, rs1: u32, imm: i32},
     Ld {rd: u32, rs1: u32, imm: i32},
     Addiw {rd: u32, rs1: u32, imm: i32},
@@ -100,6 +150,10 @@ pub fn decode(inst: u32) -> Inst {
         return Inst::Undefined;
     }
 
+    if inst_size(inst) == 2 {
+        return decode_compressed(inst & 0xffff);
+    }
+
     let opcode = inst & 0b1111_111;
 
     if let Some(inst_type) = fetch_inst_type(inst) {
@@ -114,6 +168,29 @@ pub fn decode(inst: u32) -> Inst {
                 let funct7 = (inst >> 25) & 0b1111_111;
 
                 match opcode {
+                    //M extension, funct7 = 1 next to the base ops
+                    0b0110011 if funct7 == 0b0000001 => {
+                        match funct3 {
+                            0b000 => return Inst::Mul { rd, rs1, rs2 },
+                            0b001 => return Inst::Mulh { rd, rs1, rs2 },
+                            0b010 => return Inst::Mulhsu { rd, rs1, rs2 },
+                            0b011 => return Inst::Mulhu { rd, rs1, rs2 },
+                            0b100 => return Inst::Div { rd, rs1, rs2 },
+                            0b101 => return Inst::Divu { rd, rs1, rs2 },
+                            0b110 => return Inst::Rem { rd, rs1, rs2 },
+                            _ => return Inst::Remu { rd, rs1, rs2 },
+                        }
+                    }
+                    0b0111011 if funct7 == 0b0000001 => {
+                        match funct3 {
+                            0b000 => return Inst::Mulw { rd, rs1, rs2 },
+                            0b100 => return Inst::Divw { rd, rs1, rs2 },
+                            0b101 => return Inst::Divuw { rd, rs1, rs2 },
+                            0b110 => return Inst::Remw { rd, rs1, rs2 },
+                            0b111 => return Inst::Remuw { rd, rs1, rs2 },
+                            _=> return Inst::Undefined,
+                        }
+                    }
                     0b0110011 => {
                         match funct3 {
                             0b000 => {
@@ -158,7 +235,35 @@ pub fn decode(inst: u32) -> Inst {
                             }
                             _=> return Inst::Undefined,
                         }
-                    }                       
+                    }
+                    0b0101111 => {
+                        //funct5 picks the operation, LR has to have rs2 = 0
+                        match (funct3, funct7 >> 2) {
+                            (0b010, 0b00010) if rs2 == 0 => return Inst::LrW { rd, rs1 },
+                            (0b010, 0b00011) => return Inst::ScW { rd, rs1, rs2 },
+                            (0b010, 0b00001) => return Inst::AmoswapW { rd, rs1, rs2 },
+                            (0b010, 0b00000) => return Inst::AmoaddW { rd, rs1, rs2 },
+                            (0b010, 0b00100) => return Inst::AmoxorW { rd, rs1, rs2 },
+                            (0b010, 0b01100) => return Inst::AmoandW { rd, rs1, rs2 },
+                            (0b010, 0b01000) => return Inst::AmoorW { rd, rs1, rs2 },
+                            (0b010, 0b10000) => return Inst::AmominW { rd, rs1, rs2 },
+                            (0b010, 0b10100) => return Inst::AmomaxW { rd, rs1, rs2 },
+                            (0b010, 0b11000) => return Inst::AmominuW { rd, rs1, rs2 },
+                            (0b010, 0b11100) => return Inst::AmomaxuW { rd, rs1, rs2 },
+                            (0b011, 0b00010) if rs2 == 0 => return Inst::LrD { rd, rs1 },
+                            (0b011, 0b00011) => return Inst::ScD { rd, rs1, rs2 },
+                            (0b011, 0b00001) => return Inst::AmoswapD { rd, rs1, rs2 },
+                            (0b011, 0b00000) => return Inst::AmoaddD { rd, rs1, rs2 },
+                            (0b011, 0b00100) => return Inst::AmoxorD { rd, rs1, rs2 },
+                            (0b011, 0b01100) => return Inst::AmoandD { rd, rs1, rs2 },
+                            (0b011, 0b01000) => return Inst::AmoorD { rd, rs1, rs2 },
+                            (0b011, 0b10000) => return Inst::AmominD { rd, rs1, rs2 },
+                            (0b011, 0b10100) => return Inst::AmomaxD { rd, rs1, rs2 },
+                            (0b011, 0b11000) => return Inst::AmominuD { rd, rs1, rs2 },
+                            (0b011, 0b11100) => return Inst::AmomaxuD { rd, rs1, rs2 },
+                            _=> return Inst::Undefined,
+                        }
+                    }
                     _=> return Inst::Undefined
                 }   
             }
@@ -203,10 +308,11 @@ pub fn decode(inst: u32) -> Inst {
                             0b110 => return Inst::Ori  { rd: rd, rs1: rs1, imm: imm },
                             0b111 => return Inst::Andi { rd: rd, rs1: rs1, imm: imm },
                             0b001 => return Inst::Slli { rd: rd, rs1: rs1, shamt: shamt_6 },
+                            //bit 25 is shamt[5] on RV64, only funct6 tells the two apart
                             0b101 => {
-                                match funct7 {
-                                    0b0000000 => return Inst::Srli { rd: rd, rs1: rs1, shamt: shamt_6 },
-                                    0b0100000 => return Inst::Srai { rd: rd, rs1: rs1, shamt: shamt_6 },
+                                match funct7 >> 1 {
+                                    0b000000 => return Inst::Srli { rd: rd, rs1: rs1, shamt: shamt_6 },
+                                    0b010000 => return Inst::Srai { rd: rd, rs1: rs1, shamt: shamt_6 },
                                     _=> return Inst::Undefined
                                 }
                             }
@@ -219,31 +325,46 @@ pub fn decode(inst: u32) -> Inst {
                                     match imm_raw {
                                         0b100000110011 => return Inst::FenceTso,
                                         0b000000010000 => return Inst::Pause,
-                                        _=> {},
+                                        _=> return Inst::Fence { rd: rd, rs1: rs1, imm_raw: imm_raw },
                                     }
                                 }     
                                (_, _, 0) => return Inst::Fence { rd: rd, rs1: rs1, imm_raw: imm_raw },                                
+                               //Zifencei, the imm/rs1/rd fields are reserved for future extensions
+                               (_, _, 1) => return Inst::FenceI,
                                _=> return Inst::Undefined,
                            }
                         
                         
                     }
                     0b1110011 => {
-                        if rd == 0 && funct3 == 0 && rs1 == 0 {
-                            match imm {
-                                0 => return Inst::Ecall,
-                                1 => return Inst::Ebreak,
-                                _=> return Inst::Undefined,
+                        match funct3 {
+                            0b000 if rd == 0 && funct7 == 0b0001001 => return Inst::SfenceVma { rs1: rs1, rs2: (inst >> 20) & 0b11111 },
+                            0b000 if rd == 0 && rs1 == 0 => {
+                                match imm_raw {
+                                    0x000 => return Inst::Ecall,
+                                    0x001 => return Inst::Ebreak,
+                                    0x302 => return Inst::Mret,
+                                    0x102 => return Inst::Sret,
+                                    0x105 => return Inst::Wfi,
+                                    _=> return Inst::Undefined,
+                                }
                             }
+                            //Zicsr, the immediate forms put a 5 bit zero extended immediate in the rs1 field
+                            0b001 => return Inst::Csrrw { rd: rd, rs1: rs1, csr: imm_raw },
+                            0b010 => return Inst::Csrrs { rd: rd, rs1: rs1, csr: imm_raw },
+                            0b011 => return Inst::Csrrc { rd: rd, rs1: rs1, csr: imm_raw },
+                            0b101 => return Inst::Csrrwi { rd: rd, uimm: rs1, csr: imm_raw },
+                            0b110 => return Inst::Csrrsi { rd: rd, uimm: rs1, csr: imm_raw },
+                            0b111 => return Inst::Csrrci { rd: rd, uimm: rs1, csr: imm_raw },
+                            _=> return Inst::Undefined,
                         }
-                        return Inst::Undefined;
                     }
                     0b0011011 => {
                         match funct3 {
                             0b000 => return Inst::Addiw { rd: rd, rs1: rs1, imm: imm },
                             0b001 => return Inst::Slliw { rd: rd, rs1: rs1, shamt: shamt_5 },
                             0b101 => {
-                                match funct7 >> 5 {
+                                match funct7 {
                                     0b0000000 => return Inst::Srliw { rd: rd, rs1: rs1, shamt: shamt_5 },
                                     0b0100000 => return Inst::Sraiw { rd: rd, rs1: rs1, shamt: shamt_5 },
                                     _=> return Inst::Undefined,
@@ -295,7 +416,7 @@ pub fn decode(inst: u32) -> Inst {
                 let imm105 = (imm2 & 0b1111_11) << 5;
                 let imm12 = (imm2 >> 6) << 12;
                 let imm = imm41 | imm105 | imm11 | imm12;
-                let imm = ((imm as i32) << 20) >> 20;  
+                let imm = ((imm as i32) << 19) >> 19;  
 
                 match opcode {
                     0b1100011 => {
@@ -315,10 +436,9 @@ pub fn decode(inst: u32) -> Inst {
             InstType::U => {
                 let opcode = opcode;
                 let rd = (inst >> 7) & 0b1111_1;
-                let imm = inst >> 12;
 
-                //sign extend imm
-                let imm = ((inst as i32) << 12) >> 12;
+                //sign extend imm[31:12]
+                let imm = (inst as i32) >> 12;
 
                 match opcode {
                     0b0110111 => return Inst::Lui { rd: rd, imm: imm },
@@ -329,7 +449,6 @@ pub fn decode(inst: u32) -> Inst {
             InstType::J => {
                 let opcode = opcode;
                 let rd = (inst >> 7 ) & 0b1111_1;
-                let imm = inst >> 12;
 
                 //merging and sign extending imm
                 let imm1912 = ((inst >> 12) & 0b1111_1111) << 12;
@@ -337,7 +456,7 @@ pub fn decode(inst: u32) -> Inst {
                 let imm101 = ((inst >> 21) & 0b1111_1111_11) << 1;
                 let imm20 = (inst >> 31) << 20;
                 let imm = imm101 | imm11 | imm1912 | imm20;
-                let imm = ((imm as i32) << 12) >> 12;
+                let imm = ((imm as i32) << 11) >> 11;
 
                 match opcode {
                     0b1101111 => return Inst::Jal { rd: rd, imm: imm },
@@ -350,8 +469,278 @@ pub fn decode(inst: u32) -> Inst {
     return Inst::Undefined
 }
 
-pub fn disassemble(inst: Inst) {
-    
+//how long the instruction starting with these bits is, RVC ones don't have 0b11 in their low two bits
+pub fn inst_size(inst: u32) -> u64 {
+    if inst & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+//the 3 bit register fields of RVC instructions only name x8..x15
+fn creg(field: u32) -> u32 {
+    8 + (field & 0b111)
+}
+
+/*
+    16. "C" Extension for Compressed Instructions
+        Every RVC instruction expands to a base instruction and decodes to that, so it executes like one
+        with a pc that only advances by 2. These are the RV64C encodings; the FP loads and stores need
+        F/D, reserved encodings and the all-zero instruction are illegal, HINTs run as their expansion.
+*/
+fn decode_compressed(inst: u32) -> Inst {
+    let funct3 = (inst >> 13) & 0b111;
+    let rd = (inst >> 7) & 0b1111_1;
+    let rs2 = (inst >> 2) & 0b1111_1;
+    //rs1'/rd' in bits 9:7, rs2'/rd' in bits 4:2
+    let rs1_c = creg(inst >> 7);
+    let rs2_c = creg(inst >> 2);
+
+    //imm[5] in bit 12, imm[4:0] in bits 6:2, sign extended for all but the shifts
+    let shamt = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f);
+    let imm = ((shamt as i32) << 26) >> 26;
+
+    //offsets of the word and doubleword loads and stores, off rs1' or sp
+    let offset_w = ((inst >> 7) & 0x38) | ((inst >> 4) & 0x4) | ((inst << 1) & 0x40);
+    let offset_d = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+    let offset_lwsp = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1c) | ((inst << 4) & 0xc0);
+    let offset_ldsp = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18) | ((inst << 4) & 0x1c0);
+    let offset_swsp = ((inst >> 7) & 0x3c) | ((inst >> 1) & 0xc0);
+    let offset_sdsp = ((inst >> 7) & 0x38) | ((inst >> 1) & 0x1c0);
+
+    match (inst & 0b11, funct3) {
+        //C.ADDI4SPN, nzuimm[5:4|9:6|2|3]
+        (0b00, 0b000) => {
+            let imm = ((inst >> 7) & 0x30) | ((inst >> 1) & 0x3c0) | ((inst >> 4) & 0x4) | ((inst >> 2) & 0x8);
+            match imm {
+                0 => Inst::Undefined,
+                _ => Inst::Addi { rd: rs2_c, rs1: 2, imm: imm as i32 },
+            }
+        }
+        (0b00, 0b010) => Inst::Lw { rd: rs2_c, rs1: rs1_c, imm: offset_w as i32 },
+        (0b00, 0b011) => Inst::Ld { rd: rs2_c, rs1: rs1_c, imm: offset_d as i32 },
+        (0b00, 0b110) => Inst::Sw { rs2: rs2_c, rs1: rs1_c, imm: offset_w as i32 },
+        (0b00, 0b111) => Inst::Sd { rs2: rs2_c, rs1: rs1_c, imm: offset_d as i32 },
+
+        //C.NOP and C.ADDI
+        (0b01, 0b000) => Inst::Addi { rd: rd, rs1: rd, imm: imm },
+        (0b01, 0b001) if rd != 0 => Inst::Addiw { rd: rd, rs1: rd, imm: imm },
+        //C.LI
+        (0b01, 0b010) => Inst::Addi { rd: rd, rs1: 0, imm: imm },
+        //C.ADDI16SP, nzimm[9] in bit 12 and nzimm[4|6|8:7|5] in bits 6:2
+        (0b01, 0b011) if rd == 2 => {
+            let imm = ((inst >> 3) & 0x200) | ((inst >> 2) & 0x10) | ((inst << 1) & 0x40) | ((inst << 4) & 0x180) | ((inst << 3) & 0x20);
+            match imm {
+                0 => Inst::Undefined,
+                _ => Inst::Addi { rd: 2, rs1: 2, imm: ((imm as i32) << 22) >> 22 },
+            }
+        }
+        //C.LUI, nzimm[17:12]
+        (0b01, 0b011) if imm != 0 => Inst::Lui { rd: rd, imm: imm },
+        (0b01, 0b100) => {
+            match ((inst >> 10) & 0b11, (inst >> 12) & 1, (inst >> 5) & 0b11) {
+                (0b00, _, _) => Inst::Srli { rd: rs1_c, rs1: rs1_c, shamt: shamt },
+                (0b01, _, _) => Inst::Srai { rd: rs1_c, rs1: rs1_c, shamt: shamt },
+                (0b10, _, _) => Inst::Andi { rd: rs1_c, rs1: rs1_c, imm: imm },
+                (_, 0, 0b00) => Inst::Sub { rd: rs1_c, rs1: rs1_c, rs2: rs2_c },
+                (_, 0, 0b01) => Inst::Xor { rd: rs1_c, rs1: rs1_c, rs2: rs2_c },
+                (_, 0, 0b10) => Inst::Or { rd: rs1_c, rs1: rs1_c, rs2: rs2_c },
+                (_, 0, _) => Inst::And { rd: rs1_c, rs1: rs1_c, rs2: rs2_c },
+                (_, _, 0b00) => Inst::Subw { rd: rs1_c, rs1: rs1_c, rs2: rs2_c },
+                (_, _, 0b01) => Inst::Addw { rd: rs1_c, rs1: rs1_c, rs2: rs2_c },
+                _ => Inst::Undefined,
+            }
+        }
+        //C.J, offset[11|4|9:8|10|6|7|3:1|5] in bits 12:2
+        (0b01, 0b101) => {
+            let imm = ((inst >> 1) & 0x800) | ((inst >> 7) & 0x10) | ((inst >> 1) & 0x300) | ((inst << 2) & 0x400) |
+                ((inst >> 1) & 0x40) | ((inst << 1) & 0x80) | ((inst >> 2) & 0xe) | ((inst << 3) & 0x20);
+            Inst::Jal { rd: 0, imm: ((imm as i32) << 20) >> 20 }
+        }
+        //C.BEQZ and C.BNEZ, offset[8|4:3] in bits 12:10 and offset[7:6|2:1|5] in bits 6:2
+        (0b01, 0b110 | 0b111) => {
+            let imm = ((inst >> 4) & 0x100) | ((inst >> 7) & 0x18) | ((inst << 1) & 0xc0) | ((inst >> 2) & 0x6) | ((inst << 3) & 0x20);
+            let imm = ((imm as i32) << 23) >> 23;
+            match funct3 {
+                0b110 => Inst::Beq { rs1: rs1_c, rs2: 0, imm: imm },
+                _ => Inst::Bne { rs1: rs1_c, rs2: 0, imm: imm },
+            }
+        }
+
+        (0b10, 0b000) => Inst::Slli { rd: rd, rs1: rd, shamt: shamt },
+        (0b10, 0b010) if rd != 0 => Inst::Lw { rd: rd, rs1: 2, imm: offset_lwsp as i32 },
+        (0b10, 0b011) if rd != 0 => Inst::Ld { rd: rd, rs1: 2, imm: offset_ldsp as i32 },
+        //C.JR, C.MV, C.EBREAK, C.JALR and C.ADD
+        (0b10, 0b100) => {
+            match ((inst >> 12) & 1, rd, rs2) {
+                (0, 0, 0) => Inst::Undefined,
+                (0, _, 0) => Inst::Jalr { rd: 0, rs1: rd, imm: 0 },
+                (0, _, _) => Inst::Add { rd: rd, rs1: 0, rs2: rs2 },
+                (_, 0, 0) => Inst::Ebreak,
+                (_, _, 0) => Inst::Jalr { rd: 1, rs1: rd, imm: 0 },
+                _ => Inst::Add { rd: rd, rs1: rd, rs2: rs2 },
+            }
+        }
+        (0b10, 0b110) => Inst::Sw { rs2: rs2, rs1: 2, imm: offset_swsp as i32 },
+        (0b10, 0b111) => Inst::Sd { rs2: rs2, rs1: 2, imm: offset_sdsp as i32 },
+
+        _ => Inst::Undefined,
+    }
+}
+
+/*
+    Encodings RV32 doesn't have: the *W ops, 64 bit loads and stores, the .D atomics, and immediate
+    shifts by 32 or more (shamt[5] set is reserved in RV32I). An RV32 hart raises an illegal instruction
+    exception for them.
+*/
+pub fn is_rv64_only(inst: &Inst) -> bool {
+    match *inst {
+        Inst::Slli { shamt, .. } | Inst::Srli { shamt, .. } | Inst::Srai { shamt, .. } => shamt >= 32,
+
+        Inst::Addiw { .. } | Inst::Slliw { .. } | Inst::Srliw { .. } | Inst::Sraiw { .. } |
+        Inst::Addw { .. } | Inst::Subw { .. } | Inst::Sllw { .. } | Inst::Srlw { .. } | Inst::Sraw { .. } |
+        Inst::Mulw { .. } | Inst::Divw { .. } | Inst::Divuw { .. } | Inst::Remw { .. } | Inst::Remuw { .. } |
+        Inst::Ld { .. } | Inst::Lwu { .. } | Inst::Sd { .. } |
+        Inst::LrD { .. } | Inst::ScD { .. } | Inst::AmoswapD { .. } | Inst::AmoaddD { .. } | Inst::AmoxorD { .. } |
+        Inst::AmoandD { .. } | Inst::AmoorD { .. } | Inst::AmominD { .. } | Inst::AmomaxD { .. } |
+        Inst::AmominuD { .. } | Inst::AmomaxuD { .. } => true,
+
+        _ => false,
+    }
+}
+
+const ABI_REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+pub fn reg_name(reg: u32) -> &'static str {
+    ABI_REG_NAMES.get(reg as usize).copied().unwrap_or("?")
+}
+
+//an ABI name or x0..x31
+pub fn reg_index(name: &str) -> Option<usize> {
+    if let Some(reg) = ABI_REG_NAMES.iter().position(|abi| *abi == name) {
+        return Some(reg);
+    }
+
+    name.strip_prefix('x').and_then(|num| num.parse().ok()).filter(|reg| *reg < ABI_REG_NAMES.len())
+}
+
+pub fn disassemble(inst: Inst) -> String {
+    let r = reg_name;
+
+    //branch and jump offsets are printed relative to the instruction, like objdump does without symbols
+    match inst {
+        Inst::Add { rd, rs1, rs2 } => format!("add {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Sub { rd, rs1, rs2 } => format!("sub {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Sll { rd, rs1, rs2 } => format!("sll {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Slt { rd, rs1, rs2 } => format!("slt {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Sltu { rd, rs1, rs2 } => format!("sltu {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Xor { rd, rs1, rs2 } => format!("xor {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Srl { rd, rs1, rs2 } => format!("srl {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Sra { rd, rs1, rs2 } => format!("sra {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Or { rd, rs1, rs2 } => format!("or {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::And { rd, rs1, rs2 } => format!("and {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Addw { rd, rs1, rs2 } => format!("addw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Subw { rd, rs1, rs2 } => format!("subw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Sllw { rd, rs1, rs2 } => format!("sllw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Srlw { rd, rs1, rs2 } => format!("srlw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Sraw { rd, rs1, rs2 } => format!("sraw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+
+        Inst::Mul { rd, rs1, rs2 } => format!("mul {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Mulh { rd, rs1, rs2 } => format!("mulh {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Mulhsu { rd, rs1, rs2 } => format!("mulhsu {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Mulhu { rd, rs1, rs2 } => format!("mulhu {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Div { rd, rs1, rs2 } => format!("div {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Divu { rd, rs1, rs2 } => format!("divu {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Rem { rd, rs1, rs2 } => format!("rem {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Remu { rd, rs1, rs2 } => format!("remu {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Mulw { rd, rs1, rs2 } => format!("mulw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Divw { rd, rs1, rs2 } => format!("divw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Divuw { rd, rs1, rs2 } => format!("divuw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Remw { rd, rs1, rs2 } => format!("remw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Inst::Remuw { rd, rs1, rs2 } => format!("remuw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+
+        Inst::LrW { rd, rs1 } => format!("lr.w {}, ({})", r(rd), r(rs1)),
+        Inst::ScW { rd, rs1, rs2 } => format!("sc.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoswapW { rd, rs1, rs2 } => format!("amoswap.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoaddW { rd, rs1, rs2 } => format!("amoadd.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoxorW { rd, rs1, rs2 } => format!("amoxor.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoandW { rd, rs1, rs2 } => format!("amoand.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoorW { rd, rs1, rs2 } => format!("amoor.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmominW { rd, rs1, rs2 } => format!("amomin.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmomaxW { rd, rs1, rs2 } => format!("amomax.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmominuW { rd, rs1, rs2 } => format!("amominu.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmomaxuW { rd, rs1, rs2 } => format!("amomaxu.w {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::LrD { rd, rs1 } => format!("lr.d {}, ({})", r(rd), r(rs1)),
+        Inst::ScD { rd, rs1, rs2 } => format!("sc.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoswapD { rd, rs1, rs2 } => format!("amoswap.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoaddD { rd, rs1, rs2 } => format!("amoadd.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoxorD { rd, rs1, rs2 } => format!("amoxor.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoandD { rd, rs1, rs2 } => format!("amoand.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmoorD { rd, rs1, rs2 } => format!("amoor.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmominD { rd, rs1, rs2 } => format!("amomin.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmomaxD { rd, rs1, rs2 } => format!("amomax.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmominuD { rd, rs1, rs2 } => format!("amominu.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+        Inst::AmomaxuD { rd, rs1, rs2 } => format!("amomaxu.d {}, {}, ({})", r(rd), r(rs2), r(rs1)),
+
+        Inst::Jalr { rd, rs1, imm } => format!("jalr {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Lb { rd, rs1, imm } => format!("lb {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Lh { rd, rs1, imm } => format!("lh {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Lw { rd, rs1, imm } => format!("lw {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Lbu { rd, rs1, imm } => format!("lbu {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Lhu { rd, rs1, imm } => format!("lhu {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Lwu { rd, rs1, imm } => format!("lwu {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Ld { rd, rs1, imm } => format!("ld {}, {}({})", r(rd), imm, r(rs1)),
+        Inst::Addi { rd, rs1, imm } => format!("addi {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Slti { rd, rs1, imm } => format!("slti {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Sltiu { rd, rs1, imm } => format!("sltiu {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Xori { rd, rs1, imm } => format!("xori {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Ori { rd, rs1, imm } => format!("ori {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Andi { rd, rs1, imm } => format!("andi {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Slli { rd, rs1, shamt } => format!("slli {}, {}, {}", r(rd), r(rs1), shamt),
+        Inst::Srli { rd, rs1, shamt } => format!("srli {}, {}, {}", r(rd), r(rs1), shamt),
+        Inst::Srai { rd, rs1, shamt } => format!("srai {}, {}, {}", r(rd), r(rs1), shamt),
+        Inst::Addiw { rd, rs1, imm } => format!("addiw {}, {}, {}", r(rd), r(rs1), imm),
+        Inst::Slliw { rd, rs1, shamt } => format!("slliw {}, {}, {}", r(rd), r(rs1), shamt),
+        Inst::Srliw { rd, rs1, shamt } => format!("srliw {}, {}, {}", r(rd), r(rs1), shamt),
+        Inst::Sraiw { rd, rs1, shamt } => format!("sraiw {}, {}, {}", r(rd), r(rs1), shamt),
+        Inst::Fence { imm_raw, .. } => format!("fence {:#x}", imm_raw),
+        Inst::FenceTso => "fence.tso".to_string(),
+        Inst::Pause => "pause".to_string(),
+        Inst::FenceI => "fence.i".to_string(),
+        Inst::Ecall => "ecall".to_string(),
+        Inst::Ebreak => "ebreak".to_string(),
+        Inst::Mret => "mret".to_string(),
+        Inst::Sret => "sret".to_string(),
+        Inst::Wfi => "wfi".to_string(),
+        Inst::SfenceVma { rs1, rs2 } => format!("sfence.vma {}, {}", r(rs1), r(rs2)),
+        Inst::Csrrw { rd, rs1, csr } => format!("csrrw {}, {:#x}, {}", r(rd), csr, r(rs1)),
+        Inst::Csrrs { rd, rs1, csr } => format!("csrrs {}, {:#x}, {}", r(rd), csr, r(rs1)),
+        Inst::Csrrc { rd, rs1, csr } => format!("csrrc {}, {:#x}, {}", r(rd), csr, r(rs1)),
+        Inst::Csrrwi { rd, uimm, csr } => format!("csrrwi {}, {:#x}, {}", r(rd), csr, uimm),
+        Inst::Csrrsi { rd, uimm, csr } => format!("csrrsi {}, {:#x}, {}", r(rd), csr, uimm),
+        Inst::Csrrci { rd, uimm, csr } => format!("csrrci {}, {:#x}, {}", r(rd), csr, uimm),
+
+        Inst::Sb { rs2, rs1, imm } => format!("sb {}, {}({})", r(rs2), imm, r(rs1)),
+        Inst::Sh { rs2, rs1, imm } => format!("sh {}, {}({})", r(rs2), imm, r(rs1)),
+        Inst::Sw { rs2, rs1, imm } => format!("sw {}, {}({})", r(rs2), imm, r(rs1)),
+        Inst::Sd { rs2, rs1, imm } => format!("sd {}, {}({})", r(rs2), imm, r(rs1)),
+
+        Inst::Beq { rs1, rs2, imm } => format!("beq {}, {}, pc{:+}", r(rs1), r(rs2), imm),
+        Inst::Bne { rs1, rs2, imm } => format!("bne {}, {}, pc{:+}", r(rs1), r(rs2), imm),
+        Inst::Blt { rs1, rs2, imm } => format!("blt {}, {}, pc{:+}", r(rs1), r(rs2), imm),
+        Inst::Bge { rs1, rs2, imm } => format!("bge {}, {}, pc{:+}", r(rs1), r(rs2), imm),
+        Inst::Bltu { rs1, rs2, imm } => format!("bltu {}, {}, pc{:+}", r(rs1), r(rs2), imm),
+        Inst::Bgeu { rs1, rs2, imm } => format!("bgeu {}, {}, pc{:+}", r(rs1), r(rs2), imm),
+
+        Inst::Lui { rd, imm } => format!("lui {}, {:#x}", r(rd), imm as u32 & 0xf_ffff),
+        Inst::Auipc { rd, imm } => format!("auipc {}, {:#x}", r(rd), imm as u32 & 0xf_ffff),
+
+        Inst::Jal { rd, imm } => format!("jal {}, pc{:+}", r(rd), imm),
+
+        Inst::Undefined => "unimp".to_string(),
+    }
 }
 
 const INSTRUCTION_TYPE_LOOKUP_TABLE: [Option<InstType>; SIZE_INSTRUCTION_TYPE_LOOKUP_TABLE] = [
@@ -402,7 +791,7 @@ const INSTRUCTION_TYPE_LOOKUP_TABLE: [Option<InstType>; SIZE_INSTRUCTION_TYPE_LO
     /*   101100 */ None,
     /*   101101 */ None,
     /*   101110 */ None,
-    /*   101111 */ None,
+    /*   101111 */ Some(InstType::R),
     /*   110000 */ None,
     /*   110001 */ None,
     /*   110010 */ None,
@@ -483,4 +872,78 @@ const INSTRUCTION_TYPE_LOOKUP_TABLE: [Option<InstType>; SIZE_INSTRUCTION_TYPE_LO
     /*  1111101 */ None,
     /*  1111110 */ None,
     /*  1111111 */ None,
-];
\ No newline at end of file
+];
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branch_offsets_are_sign_extended_from_bit_12() {
+        //beq a0, a1, -8 / bne a0, a1, -4096 / bgeu a0, a1, 4094
+        assert!(matches!(decode(0xfeb5_0ce3), Inst::Beq { rs1: 10, rs2: 11, imm: -8 }));
+        assert!(matches!(decode(0x80b5_1063), Inst::Bne { rs1: 10, rs2: 11, imm: -4096 }));
+        assert!(matches!(decode(0x7eb5_7fe3), Inst::Bgeu { rs1: 10, rs2: 11, imm: 4094 }));
+    }
+
+    #[test]
+    fn jump_offsets_are_sign_extended_from_bit_20() {
+        //jal ra, -2048 / jal zero, -1048576
+        assert!(matches!(decode(0x801f_f0ef), Inst::Jal { rd: 1, imm: -2048 }));
+        assert!(matches!(decode(0x8000_006f), Inst::Jal { rd: 0, imm: -1048576 }));
+    }
+
+    #[test]
+    fn upper_immediates_are_sign_extended_from_bit_31() {
+        //lui a0, 0xfffff / auipc a0, 0x80000 / lui a0, 0x7ffff
+        assert!(matches!(decode(0xffff_f537), Inst::Lui { rd: 10, imm: -1 }));
+        assert!(matches!(decode(0x8000_0517), Inst::Auipc { rd: 10, imm: -0x80000 }));
+        assert!(matches!(decode(0x7fff_f537), Inst::Lui { rd: 10, imm: 0x7ffff }));
+    }
+
+    #[test]
+    fn immediate_shifts_take_shamt_5_out_of_funct7() {
+        //srli a0, a0, 32 / srai a0, a0, 63 / srai a0, a0, 1
+        assert!(matches!(decode(0x0205_5513), Inst::Srli { rd: 10, rs1: 10, shamt: 32 }));
+        assert!(matches!(decode(0x43f5_5513), Inst::Srai { rd: 10, rs1: 10, shamt: 63 }));
+        assert!(matches!(decode(0x4015_5513), Inst::Srai { rd: 10, rs1: 10, shamt: 1 }));
+
+        //sraiw a0, a0, 31 / srliw a0, a0, 31, the W forms have no shamt[5]
+        assert!(matches!(decode(0x41f5_551b), Inst::Sraiw { rd: 10, rs1: 10, shamt: 31 }));
+        assert!(matches!(decode(0x01f5_551b), Inst::Srliw { rd: 10, rs1: 10, shamt: 31 }));
+        assert!(matches!(decode(0x0215_551b), Inst::Undefined));
+    }
+
+    #[test]
+    fn compressed_instructions_decode_to_their_expansion() {
+        //every RV64C form next to the instruction it stands for, with its largest or most negative immediate
+        const EXPANSIONS: [(u32, u32); 37] = [
+            (0x1fe8, 0x3fc1_0513), (0x0040, 0x0041_0413), (0x5de8, 0x07c5_a503), (0x7de8, 0x0f85_b503),
+            (0xdde8, 0x06a5_ae23), (0xfde8, 0x0ea5_bc23), (0x0001, 0x0000_0013), (0x1501, 0xfe05_0513),
+            (0x257d, 0x01f5_051b), (0x557d, 0xfff0_0513), (0x7101, 0xe001_0113), (0x617d, 0x1f01_0113),
+            (0x7505, 0xfffe_1537), (0x657d, 0x0001_f537), (0x917d, 0x03f5_5513), (0x8505, 0x4015_5513),
+            (0x9901, 0xfe05_7513), (0x8d0d, 0x40b5_0533), (0x8d2d, 0x00b5_4533), (0x8d4d, 0x00b5_6533),
+            (0x8d6d, 0x00b5_7533), (0x9d0d, 0x40b5_053b), (0x9d2d, 0x00b5_053b), (0xb001, 0x801f_f06f),
+            (0xaffd, 0x7fe0_006f), (0xd101, 0xf005_00e3), (0xed7d, 0x0e05_1f63), (0x157e, 0x03f5_1513),
+            (0x557e, 0x0fc1_2503), (0x757e, 0x1f81_3503), (0x8082, 0x0000_8067), (0x852e, 0x00b0_0533),
+            (0x9002, 0x0010_0073), (0x9502, 0x0005_00e7), (0x952e, 0x00b5_0533), (0xdfaa, 0x0ea1_2e23),
+            (0xffaa, 0x1ea1_3c23),
+        ];
+
+        for (compressed, expanded) in EXPANSIONS {
+            assert_eq!(inst_size(compressed), 2);
+            assert_eq!(format!("{:?}", decode(compressed)), format!("{:?}", decode(expanded)), "{:#06x}", compressed);
+        }
+
+        //the upper half belongs to the next instruction
+        assert!(matches!(decode(0xffff_0001), Inst::Addi { rd: 0, rs1: 0, imm: 0 }));
+    }
+
+    #[test]
+    fn reserved_compressed_encodings_are_illegal() {
+        //all zeros, c.addi4spn/c.addi16sp/c.lui with a zero immediate, c.jr zero, c.fld, c.addiw zero,
+        //c.lwsp zero, c.fsdsp and the reserved c.subw/c.addw neighbour
+        for raw in [0x0000, 0x0004, 0x6101, 0x6501, 0x8002, 0x2000, 0x2001, 0x4002, 0xa002, 0x9d4d] {
+            assert!(matches!(decode(raw), Inst::Undefined), "{:#06x}", raw);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::time::Instant;
+use crate::emulator::{bus::{Device, DeviceErr, TickCtx}, csr::{MIP_MSIP, MIP_MTIP}};
+
+//qemu virt layout, the SiFive CLINT register map
+pub const CLINT_BASE: u64 = 0x0200_0000;
+const CLINT_SIZE: u64 = 0x1_0000;
+
+const REG_MSIP: u64 = 0x0;
+pub const REG_MTIMECMP: u64 = 0x4000;
+pub const REG_MTIME: u64 = 0xbff8;
+
+//rate mtime counts at, what the device tree advertises as timebase-frequency
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
+//for deterministic time the hart is taken to run one instruction per cycle at 100MHz
+const CYCLES_PER_TICK: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub enum Clock {
+    //mtime follows the instructions executed (and the time spent waiting in WFI), runs are reproducible
+    Instructions,
+    //mtime follows the host's clock, a run depends on how fast the host is and can't be replayed exactly
+    WallClock(Instant),
+}
+
+/*
+    Core-local interruptor: per hart a software interrupt bit (msip) and a timer compare register
+    (mtimecmp), and one mtime shared by all harts. The timer interrupt is pending for as long as
+    mtime >= mtimecmp, both drive the hart's mip directly instead of going through the PLIC.
+*/
+#[derive(Clone)]
+pub struct Clint {
+    clock: Clock,
+    //cycles that have passed under Clock::Instructions
+    cycles: u64,
+    //what the guest wrote to mtime, relative to the clock
+    offset: u64,
+
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+}
+
+impl Clint {
+    pub fn new(harts: usize, clock: Clock) -> Self {
+        Clint {
+            clock,
+            cycles: 0,
+            offset: 0,
+            msip: vec![false; harts],
+            //no timer interrupt until software programs one
+            mtimecmp: vec![u64::MAX; harts],
+        }
+    }
+
+    fn clock_ticks(&self) -> u64 {
+        match &self.clock {
+            Clock::Instructions => self.cycles / CYCLES_PER_TICK,
+            Clock::WallClock(start) => (start.elapsed().as_nanos() * TIMEBASE_FREQ as u128 / 1_000_000_000) as u64,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.clock_ticks().wrapping_add(self.offset)
+    }
+}
+
+//registers are 32 or 64 bits, the 64 bit ones can also be accessed a half at a time
+fn read_part(reg: u64, offset: u64, size: usize) -> Result<u64, DeviceErr> {
+    match (size, offset % 8) {
+        (8, 0) => Ok(reg),
+        (4, 0) => Ok(reg & 0xffff_ffff),
+        (4, 4) => Ok(reg >> 32),
+        _ => Err(DeviceErr::InvalidSize(size)),
+    }
+}
+
+fn write_part(reg: u64, offset: u64, size: usize, value: u64) -> Result<u64, DeviceErr> {
+    match (size, offset % 8) {
+        (8, 0) => Ok(value),
+        (4, 0) => Ok((reg & !0xffff_ffff) | (value & 0xffff_ffff)),
+        (4, 4) => Ok((reg & 0xffff_ffff) | (value << 32)),
+        _ => Err(DeviceErr::InvalidSize(size)),
+    }
+}
+
+impl Device for Clint {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn size(&self) -> u64 {
+        CLINT_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, DeviceErr> {
+        let harts = self.msip.len() as u64;
+
+        match offset {
+            REG_MSIP.. if offset < REG_MSIP + 4 * harts => {
+                if size != 4 {
+                    return Err(DeviceErr::InvalidSize(size));
+                }
+
+                Ok(self.msip[(offset / 4) as usize] as u64)
+            }
+            REG_MTIMECMP.. if offset < REG_MTIMECMP + 8 * harts => {
+                read_part(self.mtimecmp[((offset - REG_MTIMECMP) / 8) as usize], offset, size)
+            }
+            REG_MTIME..=0xbfff => read_part(self.mtime(), offset, size),
+            _ => Err(DeviceErr::InvalidOffset(offset)),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), DeviceErr> {
+        let harts = self.msip.len() as u64;
+
+        match offset {
+            REG_MSIP.. if offset < REG_MSIP + 4 * harts => {
+                if size != 4 {
+                    return Err(DeviceErr::InvalidSize(size));
+                }
+
+                self.msip[(offset / 4) as usize] = value & 1 != 0;
+            }
+            REG_MTIMECMP.. if offset < REG_MTIMECMP + 8 * harts => {
+                let hart = ((offset - REG_MTIMECMP) / 8) as usize;
+                self.mtimecmp[hart] = write_part(self.mtimecmp[hart], offset, size, value)?;
+            }
+            REG_MTIME..=0xbfff => {
+                let mtime = write_part(self.mtime(), offset, size, value)?;
+                self.offset = mtime.wrapping_sub(self.clock_ticks());
+            }
+            _ => return Err(DeviceErr::InvalidOffset(offset)),
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self, ctx: &mut TickCtx) {
+        self.cycles = self.cycles.wrapping_add(ctx.elapsed);
+    }
+
+    fn mip(&self, hart: usize) -> u64 {
+        let mut mip = 0;
+
+        if self.msip.get(hart).copied().unwrap_or(false) {
+            mip |= MIP_MSIP;
+        }
+        if self.mtimecmp.get(hart).is_some_and(|cmp| self.mtime() >= *cmp) {
+            mip |= MIP_MTIP;
+        }
+
+        mip
+    }
+
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{memory::Mmu, replay::{Nondet, NondetSource}};
+
+    fn tick(clint: &mut Clint, elapsed: u64) {
+        clint.tick(&mut TickCtx {
+            elapsed,
+            icount: 0,
+            ram: &mut Mmu::new(),
+            nondet: &mut Nondet::default(),
+            source: NondetSource::Device(CLINT_BASE),
+        });
+    }
+
+    #[test]
+    fn mtime_counts_instructions_and_takes_writes() {
+        let mut clint = Clint::new(1, Clock::Instructions);
+
+        tick(&mut clint, 3 * CYCLES_PER_TICK + 1);
+        assert_eq!(clint.read(REG_MTIME, 8).unwrap(), 3);
+
+        //writing mtime moves it, it keeps counting from there
+        clint.write(REG_MTIME + 4, 4, 1).unwrap();
+        assert_eq!(clint.read(REG_MTIME, 8).unwrap(), 1 << 32 | 3);
+        tick(&mut clint, CYCLES_PER_TICK);
+        assert_eq!((clint.read(REG_MTIME, 4).unwrap(), clint.read(REG_MTIME + 4, 4).unwrap()), (4, 1));
+
+        assert!(matches!(clint.read(REG_MTIME, 2), Err(DeviceErr::InvalidSize(2))));
+        assert!(matches!(clint.read(REG_MTIME + 2, 4), Err(DeviceErr::InvalidSize(4))));
+    }
+
+    #[test]
+    fn mtip_is_pending_while_mtime_is_past_mtimecmp() {
+        let mut clint = Clint::new(2, Clock::Instructions);
+        let mtimecmp1 = REG_MTIMECMP + 8;
+
+        //nothing is programmed at reset
+        assert_eq!(clint.read(mtimecmp1, 8).unwrap(), u64::MAX);
+        assert_eq!(clint.mip(0) | clint.mip(1), 0);
+
+        clint.write(mtimecmp1, 4, 5).unwrap();
+        clint.write(mtimecmp1 + 4, 4, 0).unwrap();
+        tick(&mut clint, 4 * CYCLES_PER_TICK);
+        assert_eq!(clint.mip(1), 0);
+
+        tick(&mut clint, CYCLES_PER_TICK);
+        assert_eq!((clint.mip(0), clint.mip(1)), (0, MIP_MTIP));
+
+        //moving mtimecmp ahead is how software clears it
+        clint.write(mtimecmp1, 8, 6).unwrap();
+        assert_eq!(clint.mip(1), 0);
+    }
+
+    #[test]
+    fn msip_raises_and_clears_the_software_interrupt() {
+        let mut clint = Clint::new(2, Clock::Instructions);
+
+        clint.write(REG_MSIP + 4, 4, 0xffff_ffff).unwrap();
+        assert_eq!(clint.read(REG_MSIP + 4, 4).unwrap(), 1);
+        assert_eq!((clint.mip(0), clint.mip(1)), (0, MIP_MSIP));
+
+        clint.write(REG_MSIP + 4, 4, 0).unwrap();
+        assert_eq!(clint.mip(1), 0);
+
+        //past the last hart
+        assert!(matches!(clint.write(REG_MSIP + 8, 4, 1), Err(DeviceErr::InvalidOffset(8))));
+        assert!(matches!(clint.write(REG_MSIP, 8, 1), Err(DeviceErr::InvalidSize(8))));
+    }
+}
@@ -0,0 +1,7 @@
+pub mod uart;
+pub mod clint;
+pub mod plic;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_rng;
@@ -0,0 +1,359 @@
+use crate::emulator::{bus::{Device, DeviceErr}, csr::{MIP_MEIP, MIP_SEIP}};
+
+//qemu virt layout, the SiFive PLIC register map
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+const PLIC_SIZE: u64 = 0x400_0000;
+
+//interrupt ids 1..PLIC_SOURCES, 0 means no interrupt
+pub const PLIC_SOURCES: u32 = 96;
+
+//priorities are 3 bits, 0 never interrupts
+const PRIORITY_MASK: u32 = 0x7;
+
+const REG_PRIORITY: u64 = 0x0;
+const REG_PENDING: u64 = 0x1000;
+const REG_ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const REG_CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+//offsets within a context's block
+const CONTEXT_THRESHOLD: u64 = 0x0;
+const CONTEXT_CLAIM: u64 = 0x4;
+
+const WORDS: usize = PLIC_SOURCES.div_ceil(32) as usize;
+
+/*
+    Platform-level interrupt controller. Every hart has two contexts, 2 * hart for M mode and 2 * hart + 1
+    for S mode, each with its own enables and threshold; a context interrupts its hart (MEIP or SEIP) while
+    an enabled pending source has a priority above its threshold.
+
+    The gateways are level triggered: a source whose line is high becomes pending, claiming it clears
+    pending and holds it off until the claim is completed, after which a line that is still high makes it
+    pending again.
+*/
+#[derive(Clone)]
+pub struct Plic {
+    priority: Vec<u32>,
+    pending: [u32; WORDS],
+    //claimed and not completed yet
+    in_service: [u32; WORDS],
+    //line levels as last routed by the bus
+    level: [u32; WORDS],
+
+    enable: Vec<[u32; WORDS]>,
+    threshold: Vec<u32>,
+}
+
+fn bit(id: u32) -> (usize, u32) {
+    ((id / 32) as usize, 1 << (id % 32))
+}
+
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        let contexts = 2 * harts;
+
+        Plic {
+            priority: vec![0; PLIC_SOURCES as usize],
+            pending: [0; WORDS],
+            in_service: [0; WORDS],
+            level: [0; WORDS],
+            enable: vec![[0; WORDS]; contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+
+    //what a gateway forwards: a high line that isn't already being serviced
+    fn update_pending(&mut self) {
+        for word in 0..WORDS {
+            self.pending[word] |= self.level[word] & !self.in_service[word];
+        }
+    }
+
+    //highest priority enabled pending source above the threshold, the lowest id wins a tie
+    fn best(&self, context: usize) -> u32 {
+        let mut best = 0;
+        let mut best_priority = self.threshold[context];
+
+        for id in 1..PLIC_SOURCES {
+            let (word, mask) = bit(id);
+
+            if self.pending[word] & self.enable[context][word] & mask != 0 && self.priority[id as usize] > best_priority {
+                best = id;
+                best_priority = self.priority[id as usize];
+            }
+        }
+
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let id = self.best(context);
+
+        if id != 0 {
+            let (word, mask) = bit(id);
+            self.pending[word] &= !mask;
+            self.in_service[word] |= mask;
+        }
+
+        id
+    }
+
+    fn complete(&mut self, context: usize, id: u32) {
+        //completing an id the context doesn't have enabled is silently ignored
+        if id == 0 || id >= PLIC_SOURCES {
+            return;
+        }
+
+        let (word, mask) = bit(id);
+        if self.enable[context][word] & mask == 0 {
+            return;
+        }
+
+        self.in_service[word] &= !mask;
+        self.update_pending();
+    }
+
+    //which context's block an offset falls in, and where in it
+    fn context(&self, offset: u64) -> Option<(usize, u64)> {
+        let context = ((offset - REG_CONTEXT) / CONTEXT_STRIDE) as usize;
+
+        (context < self.threshold.len()).then_some((context, (offset - REG_CONTEXT) % CONTEXT_STRIDE))
+    }
+}
+
+impl Device for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn size(&self) -> u64 {
+        PLIC_SIZE
+    }
+
+    //every register is 32 bits
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, DeviceErr> {
+        if size != 4 {
+            return Err(DeviceErr::InvalidSize(size));
+        }
+
+        let contexts = self.threshold.len() as u64;
+
+        let value = match offset {
+            REG_PRIORITY.. if offset < REG_PRIORITY + 4 * PLIC_SOURCES as u64 => self.priority[(offset / 4) as usize],
+            REG_PENDING.. if offset < REG_PENDING + 4 * WORDS as u64 => self.pending[((offset - REG_PENDING) / 4) as usize],
+            REG_ENABLE.. if offset < REG_ENABLE + ENABLE_STRIDE * contexts => {
+                let context = ((offset - REG_ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - REG_ENABLE) % ENABLE_STRIDE / 4) as usize;
+
+                self.enable[context].get(word).copied().unwrap_or(0)
+            }
+            REG_CONTEXT.. => match self.context(offset) {
+                Some((context, CONTEXT_THRESHOLD)) => self.threshold[context],
+                Some((context, CONTEXT_CLAIM)) => self.claim(context),
+                _ => return Err(DeviceErr::InvalidOffset(offset)),
+            },
+            _ => return Err(DeviceErr::InvalidOffset(offset)),
+        };
+
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), DeviceErr> {
+        if size != 4 {
+            return Err(DeviceErr::InvalidSize(size));
+        }
+
+        let value = value as u32;
+        let contexts = self.threshold.len() as u64;
+
+        match offset {
+            //source 0 doesn't exist, its priority is hardwired to 0
+            REG_PRIORITY.. if offset < REG_PRIORITY + 4 * PLIC_SOURCES as u64 => {
+                if offset != REG_PRIORITY {
+                    self.priority[(offset / 4) as usize] = value & PRIORITY_MASK;
+                }
+            }
+            //pending bits are read only
+            REG_PENDING.. if offset < REG_PENDING + 4 * WORDS as u64 => {}
+            REG_ENABLE.. if offset < REG_ENABLE + ENABLE_STRIDE * contexts => {
+                let context = ((offset - REG_ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - REG_ENABLE) % ENABLE_STRIDE / 4) as usize;
+
+                if let Some(enable) = self.enable[context].get_mut(word) {
+                    //bit 0 of the first word is source 0
+                    *enable = if word == 0 { value & !1 } else { value };
+                }
+            }
+            REG_CONTEXT.. => match self.context(offset) {
+                Some((context, CONTEXT_THRESHOLD)) => self.threshold[context] = value & PRIORITY_MASK,
+                Some((context, CONTEXT_CLAIM)) => self.complete(context, value),
+                _ => return Err(DeviceErr::InvalidOffset(offset)),
+            },
+            _ => return Err(DeviceErr::InvalidOffset(offset)),
+        }
+
+        Ok(())
+    }
+
+    fn set_irq(&mut self, irq: u32, level: bool) {
+        if irq == 0 || irq >= PLIC_SOURCES {
+            return;
+        }
+
+        let (word, mask) = bit(irq);
+        if level {
+            self.level[word] |= mask;
+        } else {
+            self.level[word] &= !mask;
+        }
+
+        self.update_pending();
+    }
+
+    fn mip(&self, hart: usize) -> u64 {
+        let mut mip = 0;
+
+        if 2 * hart + 1 >= self.threshold.len() {
+            return mip;
+        }
+
+        if self.best(2 * hart) != 0 {
+            mip |= MIP_MEIP;
+        }
+        if self.best(2 * hart + 1) != 0 {
+            mip |= MIP_SEIP;
+        }
+
+        mip
+    }
+
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(plic: &mut Plic, offset: u64, value: u32) {
+        plic.write(offset, 4, value as u64).unwrap();
+    }
+
+    fn claim(plic: &mut Plic, context: u64) -> u32 {
+        plic.read(REG_CONTEXT + context * CONTEXT_STRIDE + CONTEXT_CLAIM, 4).unwrap() as u32
+    }
+
+    fn complete(plic: &mut Plic, context: u64, id: u32) {
+        write(plic, REG_CONTEXT + context * CONTEXT_STRIDE + CONTEXT_CLAIM, id);
+    }
+
+    fn set_threshold(plic: &mut Plic, context: u64, threshold: u32) {
+        write(plic, REG_CONTEXT + context * CONTEXT_STRIDE + CONTEXT_THRESHOLD, threshold);
+    }
+
+    //sources 3 and 40 at priority 1, 10 and 33 at 5, all enabled for hart 0's M mode context
+    fn plic() -> Plic {
+        let mut plic = Plic::new(2);
+
+        for (id, priority) in [(3, 1), (10, 5), (33, 5), (40, 1)] {
+            write(&mut plic, REG_PRIORITY + 4 * id, priority);
+        }
+        write(&mut plic, REG_ENABLE, 1 << 3 | 1 << 10);
+        write(&mut plic, REG_ENABLE + 4, 1 << (33 - 32) | 1 << (40 - 32));
+        plic
+    }
+
+    #[test]
+    fn claims_return_the_highest_priority_pending_source() {
+        let mut plic = plic();
+        assert_eq!((claim(&mut plic, 0), plic.mip(0)), (0, 0));
+
+        for id in [3, 10, 33, 40] {
+            plic.set_irq(id, true);
+        }
+        assert_eq!(plic.read(REG_PENDING, 4).unwrap(), 1 << 3 | 1 << 10);
+        assert_eq!(plic.mip(0), MIP_MEIP);
+
+        //10 and 33 tie, the lower id goes first, then 3 ties with 40
+        assert_eq!([claim(&mut plic, 0), claim(&mut plic, 0), claim(&mut plic, 0), claim(&mut plic, 0)], [10, 33, 3, 40]);
+        assert_eq!((claim(&mut plic, 0), plic.mip(0)), (0, 0));
+        assert_eq!(plic.read(REG_PENDING, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn completing_rearms_a_source_whose_line_is_still_high() {
+        let mut plic = plic();
+
+        plic.set_irq(10, true);
+        assert_eq!(claim(&mut plic, 0), 10);
+
+        //the line staying high doesn't make it pending again while it is being serviced
+        plic.set_irq(10, true);
+        assert_eq!(claim(&mut plic, 0), 0);
+
+        complete(&mut plic, 0, 10);
+        assert_eq!(claim(&mut plic, 0), 10);
+
+        //a line that dropped meanwhile leaves nothing behind
+        plic.set_irq(10, false);
+        complete(&mut plic, 0, 10);
+        assert_eq!((claim(&mut plic, 0), plic.mip(0)), (0, 0));
+
+        //completing an id the context hasn't enabled is ignored
+        plic.set_irq(10, true);
+        assert_eq!(claim(&mut plic, 0), 10);
+        complete(&mut plic, 1, 10);
+        assert_eq!(claim(&mut plic, 0), 0);
+    }
+
+    #[test]
+    fn the_threshold_masks_sources_at_or_below_it() {
+        let mut plic = plic();
+        plic.set_irq(3, true);
+        plic.set_irq(10, true);
+
+        set_threshold(&mut plic, 0, 5);
+        assert_eq!((plic.mip(0), claim(&mut plic, 0)), (0, 0));
+
+        set_threshold(&mut plic, 0, 1);
+        assert_eq!(claim(&mut plic, 0), 10);
+        assert_eq!((plic.mip(0), claim(&mut plic, 0)), (0, 0));
+
+        set_threshold(&mut plic, 0, 0);
+        assert_eq!(claim(&mut plic, 0), 3);
+
+        //priority 0 never interrupts, whatever the threshold
+        write(&mut plic, REG_PRIORITY + 4 * 10, 0);
+        complete(&mut plic, 0, 10);
+        assert_eq!(claim(&mut plic, 0), 0);
+    }
+
+    #[test]
+    fn each_context_has_its_own_enables() {
+        let mut plic = plic();
+        //hart 0 S mode takes 40, hart 1 M mode takes 3
+        write(&mut plic, REG_ENABLE + ENABLE_STRIDE + 4, 1 << (40 - 32));
+        write(&mut plic, REG_ENABLE + 2 * ENABLE_STRIDE, 1 << 3);
+        assert_eq!(plic.read(REG_ENABLE + 2 * ENABLE_STRIDE, 4).unwrap(), 1 << 3);
+
+        plic.set_irq(40, true);
+        assert_eq!((plic.mip(0), plic.mip(1)), (MIP_MEIP | MIP_SEIP, 0));
+
+        plic.set_irq(3, true);
+        assert_eq!(plic.mip(1), MIP_MEIP);
+
+        //hart 1 claiming 3 takes it away from hart 0 as well
+        assert_eq!(claim(&mut plic, 2), 3);
+        assert_eq!(claim(&mut plic, 1), 40);
+        assert_eq!(claim(&mut plic, 0), 0);
+        assert_eq!((plic.mip(0), plic.mip(1)), (0, 0));
+
+        //source 0 can't be enabled, contexts past the last hart don't exist
+        write(&mut plic, REG_ENABLE, !0);
+        assert_eq!(plic.read(REG_ENABLE, 4).unwrap() & 1, 0);
+        assert!(matches!(plic.read(REG_CONTEXT + 4 * CONTEXT_STRIDE, 4), Err(DeviceErr::InvalidOffset(_))));
+        assert_eq!(plic.mip(2), 0);
+    }
+}
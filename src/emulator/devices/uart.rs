@@ -0,0 +1,540 @@
+use std::{collections::VecDeque, fs, io::{self, BufWriter, Read, Write}, os::unix::fs::FileTypeExt, path::{Path, PathBuf}, sync::{mpsc, Arc, Mutex}, thread};
+use crate::emulator::{bus::{Device, DeviceErr, TickCtx}, replay::NondetSource};
+
+//where the qemu virt machine puts its UART, so firmware built for it finds the console
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_IRQ: u32 = 10;
+const UART_SIZE: u64 = 0x100;
+
+const FIFO_SIZE: usize = 16;
+
+//instructions an rx FIFO below its trigger level sits idle before the character timeout interrupt
+const CHAR_TIMEOUT: u64 = 1024;
+
+//register offsets, with DLAB set 0 and 1 are the divisor latch instead
+const REG_RBR_THR: u64 = 0;
+const REG_IER: u64 = 1;
+const REG_IIR_FCR: u64 = 2;
+const REG_LCR: u64 = 3;
+const REG_MCR: u64 = 4;
+const REG_LSR: u64 = 5;
+const REG_MSR: u64 = 6;
+const REG_SCR: u64 = 7;
+
+const IER_RX_AVAILABLE: u8 = 1;
+const IER_THR_EMPTY: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_CHAR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+//in loopback the modem status inputs follow the modem control outputs
+const MSR_LOOPBACK_BITS: u8 = 0xf0;
+
+//shared by snapshots, like the tracer, output that already happened stays written; the virtio console
+//ports use it as well
+#[derive(Clone)]
+pub enum UartOutput {
+    Stdout,
+    Writer(Arc<Mutex<dyn Write + Send>>),
+    None,
+}
+
+impl UartOutput {
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(UartOutput::Writer(Arc::new(Mutex::new(BufWriter::new(fs::File::create(path)?)))))
+    }
+
+    pub fn write(&self, byte: u8) {
+        //the guest can't do anything about a host side write error, the byte is dropped like on a real line
+        let _ = match self {
+            UartOutput::Stdout => {
+                let mut out = io::stdout().lock();
+                out.write_all(&[byte]).and_then(|_| out.flush())
+            }
+            UartOutput::Writer(out) => out.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).write_all(&[byte]),
+            UartOutput::None => Ok(()),
+        };
+    }
+
+    pub fn flush(&self) {
+        if let UartOutput::Writer(out) = self {
+            let _ = out.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).flush();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum UartInput {
+    //read by a background thread as it arrives (stdin, a named pipe), polled through the nondet log so a
+    //run with console input can be replayed
+    Stream(Arc<Mutex<mpsc::Receiver<u8>>>),
+    //fed to the guest as fast as it takes them, deterministic by itself
+    Script(VecDeque<u8>),
+    None,
+}
+
+impl UartInput {
+    //the thread opens the input itself, opening a pipe blocks until the other end shows up
+    fn stream<R, F>(open: F) -> Self
+    where
+        R: Read,
+        F: FnOnce() -> io::Result<R> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let Ok(mut input) = open() else {
+                return;
+            };
+            let mut buf = [0; 256];
+
+            while let Ok(len @ 1..) = input.read(&mut buf) {
+                if buf[..len].iter().any(|byte| tx.send(*byte).is_err()) {
+                    break;
+                }
+            }
+        });
+
+        UartInput::Stream(Arc::new(Mutex::new(rx)))
+    }
+
+    pub fn stdin() -> Self {
+        Self::stream(|| Ok(io::stdin()))
+    }
+
+    //a named pipe is streamed, anything else is a script read up front
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        if fs::metadata(&path)?.file_type().is_fifo() {
+            let path = PathBuf::from(path.as_ref());
+            return Ok(Self::stream(move || fs::File::open(path)));
+        }
+
+        Ok(UartInput::Script(fs::read(path)?.into()))
+    }
+
+    //up to max bytes that arrived since the last poll
+    pub fn poll(&mut self, ctx: &mut TickCtx, source: NondetSource, max: usize) -> Vec<u8> {
+        let received = match self {
+            UartInput::Stream(rx) => {
+                let rx = rx.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                ctx.nondet.poll(ctx.icount, source, || rx.try_iter().take(max).collect())
+            }
+            UartInput::Script(bytes) => Some(bytes.drain(..max.min(bytes.len())).collect()),
+            UartInput::None => None,
+        };
+
+        received.unwrap_or_default()
+    }
+}
+
+/*
+    NS16550A with its 16 byte FIFOs. Transmission is instant, so THR is always empty and bytes go to the
+    output as soon as they are written; the baud rate divisor and line settings are stored but have no
+    effect. The interrupt line is the highest priority pending source out of IIR.
+*/
+#[derive(Clone)]
+pub struct Uart {
+    output: UartOutput,
+    input: UartInput,
+
+    rx: VecDeque<u8>,
+    rx_idle: u64,
+
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr_errors: u8,
+    scr: u8,
+    divisor: u16,
+
+    //THR empty interrupt, set when THR becomes empty and cleared by reading IIR or writing THR
+    thr_empty_pending: bool,
+}
+
+impl Uart {
+    pub fn new(output: UartOutput, input: UartInput) -> Self {
+        Uart {
+            output,
+            input,
+            rx: VecDeque::new(),
+            rx_idle: 0,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr_errors: 0,
+            scr: 0,
+            divisor: 0,
+            thr_empty_pending: false,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_FIFO_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() { FIFO_SIZE } else { 1 }
+    }
+
+    fn rx_trigger(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < self.rx_capacity() {
+            self.rx.push_back(byte);
+            self.rx_idle = 0;
+        } else {
+            self.lsr_errors |= LSR_OVERRUN;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            self.receive(byte);
+        } else {
+            self.output.write(byte);
+        }
+
+        //the byte left right away
+        self.thr_empty_pending = true;
+    }
+
+    //highest priority pending interrupt, in IIR encoding
+    fn pending(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx.is_empty() {
+            if self.rx.len() >= self.rx_trigger() {
+                return IIR_RX_AVAILABLE;
+            }
+            if self.rx_idle >= CHAR_TIMEOUT {
+                return IIR_CHAR_TIMEOUT;
+            }
+        }
+
+        if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            return IIR_THR_EMPTY;
+        }
+
+        IIR_NO_INTERRUPT
+    }
+
+    fn read_reg(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            REG_RBR_THR if dlab => self.divisor as u8,
+            REG_RBR_THR => {
+                self.rx_idle = 0;
+                self.rx.pop_front().unwrap_or(0)
+            }
+            REG_IER if dlab => (self.divisor >> 8) as u8,
+            REG_IER => self.ier,
+            REG_IIR_FCR => {
+                let iir = self.pending();
+                if iir == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+
+                iir | if self.fifo_enabled() { IIR_FIFO_ENABLED } else { 0 }
+            }
+            REG_LCR => self.lcr,
+            REG_MCR => self.mcr,
+            REG_LSR => {
+                let lsr = LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY | self.lsr_errors | if self.rx.is_empty() { 0 } else { LSR_DATA_READY };
+                self.lsr_errors = 0;
+
+                lsr
+            }
+            REG_MSR if self.mcr & MCR_LOOPBACK != 0 => (self.mcr << 4) & MSR_LOOPBACK_BITS,
+            REG_SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            REG_RBR_THR if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            REG_RBR_THR => self.transmit(value),
+            REG_IER if dlab => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
+            REG_IER => {
+                //enabling the THR empty interrupt while THR is empty raises it right away
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            REG_IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 || (value ^ self.fcr) & FCR_FIFO_ENABLE != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value;
+            }
+            REG_LCR => self.lcr = value,
+            REG_MCR => self.mcr = value,
+            REG_SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn size(&self) -> u64 {
+        UART_SIZE
+    }
+
+    //registers are a byte wide, the way Linux's 8250 driver with reg-shift 0 accesses them
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, DeviceErr> {
+        if size != 1 {
+            return Err(DeviceErr::InvalidSize(size));
+        }
+
+        Ok(self.read_reg(offset) as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), DeviceErr> {
+        if size != 1 {
+            return Err(DeviceErr::InvalidSize(size));
+        }
+
+        self.write_reg(offset, value as u8);
+
+        Ok(())
+    }
+
+    fn tick(&mut self, ctx: &mut TickCtx) {
+        self.rx_idle = self.rx_idle.saturating_add(ctx.elapsed);
+
+        //a full FIFO takes nothing new, output written meanwhile still goes out
+        let room = self.rx_capacity().saturating_sub(self.rx.len());
+        if room != 0 {
+            let source = ctx.source;
+
+            for byte in self.input.poll(ctx, source, room) {
+                self.receive(byte);
+            }
+        }
+
+        self.output.flush();
+    }
+
+    fn irq(&self) -> bool {
+        self.pending() != IIR_NO_INTERRUPT
+    }
+
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{memory::Mmu, replay::Nondet};
+
+    #[derive(Default)]
+    struct Sink {
+        bytes: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    fn uart_with(input: &[u8]) -> (Uart, Arc<Mutex<Sink>>) {
+        let sink = Arc::new(Mutex::new(Sink::default()));
+        (Uart::new(UartOutput::Writer(sink.clone()), UartInput::Script(input.iter().copied().collect())), sink)
+    }
+
+    fn tick(uart: &mut Uart, elapsed: u64) {
+        uart.tick(&mut TickCtx {
+            elapsed,
+            icount: 0,
+            ram: &mut Mmu::new(),
+            nondet: &mut Nondet::default(),
+            source: NondetSource::Device(UART_BASE),
+        });
+    }
+
+    fn read(uart: &mut Uart, reg: u64) -> u8 {
+        uart.read(reg, 1).unwrap() as u8
+    }
+
+    fn write(uart: &mut Uart, reg: u64, value: u8) {
+        uart.write(reg, 1, value as u64).unwrap();
+    }
+
+    #[test]
+    fn reset_state_has_an_empty_transmitter_and_nothing_pending() {
+        let (mut uart, _) = uart_with(b"");
+
+        assert_eq!(read(&mut uart, REG_LSR), LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_NO_INTERRUPT);
+        assert!(!uart.irq());
+
+        write(&mut uart, REG_IIR_FCR, FCR_FIFO_ENABLE);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_FIFO_ENABLED | IIR_NO_INTERRUPT);
+        assert!(matches!(uart.read(REG_LSR, 4), Err(DeviceErr::InvalidSize(4))));
+    }
+
+    #[test]
+    fn rx_fifo_interrupts_at_its_trigger_level_or_after_a_timeout() {
+        let (mut uart, _) = uart_with(b"0123456789abcdefghij");
+        //FIFO on, trigger level 8
+        write(&mut uart, REG_IIR_FCR, 2 << 6 | FCR_FIFO_ENABLE);
+        write(&mut uart, REG_IER, IER_RX_AVAILABLE);
+
+        //only as much as fits is taken, the rest waits instead of overrunning
+        tick(&mut uart, 1);
+        assert_eq!(uart.rx.len(), FIFO_SIZE);
+        assert_eq!(read(&mut uart, REG_LSR), LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY | LSR_DATA_READY);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_FIFO_ENABLED | IIR_RX_AVAILABLE);
+        assert!(uart.irq());
+
+        let received: Vec<u8> = (0..9).map(|_| read(&mut uart, REG_RBR_THR)).collect();
+        assert_eq!(received, b"012345678");
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_FIFO_ENABLED | IIR_NO_INTERRUPT);
+
+        tick(&mut uart, 1);
+        assert_eq!(uart.rx.len(), 11);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_FIFO_ENABLED | IIR_RX_AVAILABLE);
+
+        //bytes sitting below the trigger level long enough time out
+        (0..4).for_each(|_| { read(&mut uart, REG_RBR_THR); });
+        tick(&mut uart, CHAR_TIMEOUT - 1);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_FIFO_ENABLED | IIR_NO_INTERRUPT);
+        tick(&mut uart, 1);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_FIFO_ENABLED | IIR_CHAR_TIMEOUT);
+
+        //without the FIFO a single byte is the whole buffer
+        let (mut plain, _) = uart_with(b"ab");
+        write(&mut plain, REG_IER, IER_RX_AVAILABLE);
+        tick(&mut plain, 1);
+        assert_eq!((plain.rx.len(), read(&mut plain, REG_IIR_FCR)), (1, IIR_RX_AVAILABLE));
+        assert_eq!(read(&mut plain, REG_RBR_THR), b'a');
+        assert_eq!(read(&mut plain, REG_LSR) & LSR_DATA_READY, 0);
+    }
+
+    #[test]
+    fn overruns_are_reported_once() {
+        let (mut uart, sink) = uart_with(b"");
+        write(&mut uart, REG_IIR_FCR, FCR_FIFO_ENABLE);
+        write(&mut uart, REG_MCR, MCR_LOOPBACK);
+
+        for byte in 0..=FIFO_SIZE as u8 {
+            write(&mut uart, REG_RBR_THR, byte);
+        }
+
+        assert!(sink.lock().unwrap().bytes.is_empty());
+        assert_eq!(read(&mut uart, REG_LSR), LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY | LSR_DATA_READY | LSR_OVERRUN);
+        assert_eq!(read(&mut uart, REG_LSR) & LSR_OVERRUN, 0);
+        assert_eq!(read(&mut uart, REG_RBR_THR), 0);
+    }
+
+    #[test]
+    fn thr_empty_interrupts_after_every_byte_until_iir_is_read() {
+        let (mut uart, sink) = uart_with(b"x");
+
+        write(&mut uart, REG_IER, IER_THR_EMPTY);
+        assert!(uart.irq());
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_THR_EMPTY);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_NO_INTERRUPT);
+
+        write(&mut uart, REG_RBR_THR, b'h');
+        write(&mut uart, REG_RBR_THR, b'i');
+        assert_eq!(sink.lock().unwrap().bytes, b"hi");
+        assert!(uart.irq());
+
+        //received data outranks it
+        write(&mut uart, REG_IER, IER_THR_EMPTY | IER_RX_AVAILABLE);
+        tick(&mut uart, 1);
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_RX_AVAILABLE);
+        assert_eq!(read(&mut uart, REG_RBR_THR), b'x');
+        assert_eq!(read(&mut uart, REG_IIR_FCR), IIR_THR_EMPTY);
+        assert!(!uart.irq());
+
+        write(&mut uart, REG_IER, 0);
+        write(&mut uart, REG_RBR_THR, b'!');
+        assert!(!uart.irq());
+    }
+
+    #[test]
+    fn dlab_banks_the_divisor_over_data_and_ier() {
+        let (mut uart, sink) = uart_with(b"z");
+        write(&mut uart, REG_IER, IER_RX_AVAILABLE);
+        tick(&mut uart, 1);
+
+        write(&mut uart, REG_LCR, LCR_DLAB | 0x03);
+        write(&mut uart, REG_RBR_THR, 0x34);
+        write(&mut uart, REG_IER, 0x12);
+        assert_eq!((read(&mut uart, REG_RBR_THR), read(&mut uart, REG_IER)), (0x34, 0x12));
+        assert_eq!(uart.divisor, 0x1234);
+        assert_eq!(read(&mut uart, REG_LCR), LCR_DLAB | 0x03);
+
+        //none of that touched the data path
+        write(&mut uart, REG_LCR, 0x03);
+        assert!(sink.lock().unwrap().bytes.is_empty());
+        assert_eq!((read(&mut uart, REG_IER), read(&mut uart, REG_RBR_THR)), (IER_RX_AVAILABLE, b'z'));
+    }
+
+    #[test]
+    fn output_is_flushed_even_with_a_full_rx_fifo() {
+        let (mut uart, sink) = uart_with(b"ab");
+        tick(&mut uart, 1);
+        assert_eq!(uart.rx.len(), 1);
+
+        write(&mut uart, REG_RBR_THR, b'o');
+        let flushes = sink.lock().unwrap().flushes;
+        tick(&mut uart, 1);
+
+        assert_eq!(sink.lock().unwrap().flushes, flushes + 1);
+        assert_eq!(uart.input.poll(&mut TickCtx {
+            elapsed: 0,
+            icount: 0,
+            ram: &mut Mmu::new(),
+            nondet: &mut Nondet::default(),
+            source: NondetSource::Device(UART_BASE),
+        }, NondetSource::Device(UART_BASE), 2), b"b");
+    }
+}
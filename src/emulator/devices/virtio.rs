@@ -0,0 +1,785 @@
+use crate::emulator::{bus::{Device, DeviceErr, TickCtx}, memory::{MmmuErr, Mmu}};
+
+//qemu virt puts eight virtio-mmio slots here, slot n is wired to PLIC source VIRTIO_IRQ + n
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_STRIDE: u64 = 0x1000;
+pub const VIRTIO_IRQ: u32 = 1;
+const VIRTIO_SIZE: u64 = 0x1000;
+
+pub const DEVICE_ID_BLOCK: u32 = 2;
+pub const DEVICE_ID_CONSOLE: u32 = 3;
+pub const DEVICE_ID_RNG: u32 = 4;
+
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+//what qemu reports, drivers don't care
+const VENDOR_ID: u32 = 0x554d_4551;
+
+//every queue offers this many descriptors at most
+const QUEUE_SIZE_MAX: u16 = 256;
+
+//transport features, the device specific ones are in the low 24 bits
+const F_VERSION_1: u64 = 1 << 32;
+
+const REG_MAGIC: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_NEEDS_RESET: u32 = 0x40;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+const DESC_SIZE: u64 = 16;
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 1 << 1;
+const DESC_F_INDIRECT: u16 = 1 << 2;
+
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VirtioErr {
+    #[error("Descriptor {0} is out of the queue")]
+    InvalidDescriptor(u16),
+
+    #[error("Descriptor chain starting at {0} loops")]
+    DescriptorLoop(u16),
+
+    #[error("Indirect descriptor {0}, the feature wasn't offered")]
+    UnexpectedIndirect(u16),
+
+    #[error("Malformed request in chain {0}")]
+    MalformedRequest(u16),
+
+    #[error("Queue memory isn't RAM: {0}")]
+    Memory(#[from] MmmuErr),
+}
+
+fn read_u16(ram: &Mmu, addr: u64) -> Result<u16, VirtioErr> {
+    Ok(u16::from_le_bytes(ram.dram_read(addr as usize, 2)?[..].try_into().unwrap()))
+}
+
+/*
+    A descriptor chain the driver made available: the buffers the device reads from, then the ones it
+    writes to, each as (guest physical address, length).
+*/
+#[derive(Clone)]
+pub struct DescChain {
+    pub head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+//copies between buf and the part of a buffer list starting offset bytes in
+fn gather(buffers: &[(u64, u32)], mut offset: usize, len: usize, mut copy: impl FnMut(u64, usize, usize) -> Result<(), VirtioErr>) -> Result<usize, VirtioErr> {
+    let mut done = 0;
+
+    for &(addr, size) in buffers {
+        let size = size as usize;
+
+        if offset >= size {
+            offset -= size;
+            continue;
+        }
+
+        let n = (size - offset).min(len - done);
+        copy(addr + offset as u64, done, n)?;
+
+        done += n;
+        offset = 0;
+
+        if done == len {
+            break;
+        }
+    }
+
+    Ok(done)
+}
+
+impl DescChain {
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    //reads the driver's bytes from offset on, returns how many there were
+    pub fn read(&self, ram: &Mmu, offset: usize, buf: &mut [u8]) -> Result<usize, VirtioErr> {
+        gather(&self.readable, offset, buf.len(), |addr, at, n| {
+            buf[at..at + n].copy_from_slice(&ram.dram_read(addr as usize, n)?);
+            Ok(())
+        })
+    }
+
+    //fills the device writable buffers from offset on, returns how much fit
+    pub fn write(&self, ram: &mut Mmu, offset: usize, data: &[u8]) -> Result<usize, VirtioErr> {
+        gather(&self.writable, offset, data.len(), |addr, at, n| {
+            ram.dram_write(addr as usize, &data[at..at + n])?;
+            Ok(())
+        })
+    }
+}
+
+/*
+    Split virtqueue: a descriptor table, the available ring the driver adds chain heads to and the used
+    ring the device returns them on. Addresses are guest physical, which is where RAM is.
+*/
+#[derive(Clone, Default)]
+pub struct Virtqueue {
+    num: u16,
+    ready: bool,
+    desc: u64,
+    avail: u64,
+    used: u64,
+
+    //next available ring entry the device hasn't taken yet
+    last_avail: u16,
+    //used buffers were returned and the driver wants to hear about it
+    signal: bool,
+}
+
+impl Virtqueue {
+    pub fn is_ready(&self) -> bool {
+        self.ready && self.num != 0
+    }
+
+    //next chain the driver made available
+    pub fn pop(&mut self, ram: &Mmu) -> Result<Option<DescChain>, VirtioErr> {
+        if !self.is_ready() {
+            return Ok(None);
+        }
+
+        if read_u16(ram, self.avail + 2)? == self.last_avail {
+            return Ok(None);
+        }
+
+        let head = read_u16(ram, self.avail + 4 + 2 * (self.last_avail % self.num) as u64)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = DescChain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut idx = head;
+
+        //a chain can't be longer than the table, a longer one is a loop
+        for _ in 0..self.num {
+            if idx >= self.num {
+                return Err(VirtioErr::InvalidDescriptor(idx));
+            }
+
+            let desc = ram.dram_read((self.desc + DESC_SIZE * idx as u64) as usize, DESC_SIZE as usize)?;
+            let addr = u64::from_le_bytes(desc[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(desc[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(desc[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(desc[14..16].try_into().unwrap());
+
+            if flags & DESC_F_INDIRECT != 0 {
+                return Err(VirtioErr::UnexpectedIndirect(idx));
+            }
+
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else {
+                chain.readable.push((addr, len));
+            }
+
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+
+            idx = next;
+        }
+
+        Err(VirtioErr::DescriptorLoop(head))
+    }
+
+    //hands a chain back with how many bytes the device wrote into it
+    pub fn push_used(&mut self, ram: &mut Mmu, head: u16, len: u32) -> Result<(), VirtioErr> {
+        let idx = read_u16(ram, self.used + 2)?;
+
+        let mut elem = [0; 8];
+        elem[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..].copy_from_slice(&len.to_le_bytes());
+
+        ram.dram_write((self.used + 4 + 8 * (idx % self.num) as u64) as usize, &elem)?;
+        ram.dram_write((self.used + 2) as usize, &idx.wrapping_add(1).to_le_bytes())?;
+
+        if read_u16(ram, self.avail)? & AVAIL_F_NO_INTERRUPT == 0 {
+            self.signal = true;
+        }
+
+        Ok(())
+    }
+}
+
+/*
+    The device type specific half of a virtio device, the transport takes care of feature negotiation,
+    queue setup and interrupts.
+*/
+pub trait VirtioDevice: Clone + Send + 'static {
+    fn name(&self) -> &str;
+
+    fn device_id(&self) -> u32;
+
+    //device specific feature bits offered to the driver
+    fn features(&self) -> u64;
+
+    fn queues(&self) -> usize;
+
+    //the device specific configuration space
+    fn config(&self) -> Vec<u8>;
+
+    fn config_write(&mut self, _offset: u64, _data: &[u8]) {}
+
+    //the driver notified queue, the device takes what it made available
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr>;
+
+    //called every tick while the driver is running, for devices that have something to say on their own
+    fn poll(&mut self, _queues: &mut [Virtqueue], _ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        Ok(())
+    }
+
+    //the driver reset the device
+    fn reset(&mut self) {}
+}
+
+/*
+    virtio-mmio transport, version 2 (no legacy interface). Notifications are taken on the next tick since
+    that is where the device gets to see RAM; a driver error in a queue puts the device in NEEDS_RESET
+    instead of taking the emulator down.
+*/
+#[derive(Clone)]
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    notified: Vec<bool>,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        let queues = device.queues();
+
+        VirtioMmio {
+            device,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues: vec![Virtqueue::default(); queues],
+            notified: vec![false; queues],
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Virtqueue::default());
+        self.notified.fill(false);
+        self.interrupt_status = 0;
+        self.device.reset();
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | F_VERSION_1
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn fail(&mut self, err: VirtioErr) {
+        eprintln!("{}: {}, the device needs a reset", self.device.name(), err);
+
+        self.status |= STATUS_NEEDS_RESET;
+        self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+    }
+
+    fn run(&mut self, ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        for queue in 0..self.queues.len() {
+            if std::mem::take(&mut self.notified[queue]) {
+                self.device.notify(queue, &mut self.queues, ctx)?;
+            }
+        }
+
+        self.device.poll(&mut self.queues, ctx)
+    }
+}
+
+fn set_low(reg: &mut u64, value: u64) {
+    *reg = (*reg & !0xffff_ffff) | (value & 0xffff_ffff);
+}
+
+fn set_high(reg: &mut u64, value: u64) {
+    *reg = (*reg & 0xffff_ffff) | (value << 32);
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn size(&self) -> u64 {
+        VIRTIO_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, DeviceErr> {
+        //the config space can be read at any width
+        if offset >= REG_CONFIG {
+            let config = self.device.config();
+            let start = (offset - REG_CONFIG) as usize;
+            let bytes = config.get(start..start + size).ok_or(DeviceErr::InvalidOffset(offset))?;
+
+            return Ok(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64));
+        }
+
+        if size != 4 {
+            return Err(DeviceErr::InvalidSize(size));
+        }
+
+        let value = match offset {
+            REG_MAGIC => MAGIC,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => if self.queue().is_some() { QUEUE_SIZE_MAX as u32 } else { 0 },
+            REG_QUEUE_NUM => self.queue().map_or(0, |q| q.num as u32),
+            REG_QUEUE_READY => self.queue().is_some_and(|q| q.ready) as u32,
+            REG_QUEUE_DESC_LOW => self.queue().map_or(0, |q| q.desc as u32),
+            REG_QUEUE_DESC_HIGH => self.queue().map_or(0, |q| (q.desc >> 32) as u32),
+            REG_QUEUE_DRIVER_LOW => self.queue().map_or(0, |q| q.avail as u32),
+            REG_QUEUE_DRIVER_HIGH => self.queue().map_or(0, |q| (q.avail >> 32) as u32),
+            REG_QUEUE_DEVICE_LOW => self.queue().map_or(0, |q| q.used as u32),
+            REG_QUEUE_DEVICE_HIGH => self.queue().map_or(0, |q| (q.used >> 32) as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => self.config_generation,
+            //the write only registers read as 0
+            REG_DEVICE_FEATURES_SEL | REG_DRIVER_FEATURES | REG_DRIVER_FEATURES_SEL | REG_QUEUE_SEL | REG_QUEUE_NOTIFY | REG_INTERRUPT_ACK => 0,
+            _ => return Err(DeviceErr::InvalidOffset(offset)),
+        };
+
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), DeviceErr> {
+        if offset >= REG_CONFIG {
+            let data = value.to_le_bytes();
+            self.device.config_write(offset - REG_CONFIG, &data[..size.min(8)]);
+
+            return Ok(());
+        }
+
+        if size != 4 {
+            return Err(DeviceErr::InvalidSize(size));
+        }
+
+        let value = value & 0xffff_ffff;
+
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value as u32,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value as u32,
+            //only what was offered can be accepted
+            REG_DRIVER_FEATURES => {
+                let offered = self.features();
+
+                match self.driver_features_sel {
+                    0 => set_low(&mut self.driver_features, value & offered),
+                    1 => set_high(&mut self.driver_features, value & (offered >> 32)),
+                    _ => {}
+                }
+            }
+            REG_QUEUE_SEL => self.queue_sel = value as u32,
+            REG_QUEUE_NUM => {
+                if let Some(q) = self.queue() {
+                    q.num = (value as u16).min(QUEUE_SIZE_MAX);
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(q) = self.queue() {
+                    q.ready = value & 1 != 0;
+                }
+            }
+            REG_QUEUE_DESC_LOW => self.queue().into_iter().for_each(|q| set_low(&mut q.desc, value)),
+            REG_QUEUE_DESC_HIGH => self.queue().into_iter().for_each(|q| set_high(&mut q.desc, value)),
+            REG_QUEUE_DRIVER_LOW => self.queue().into_iter().for_each(|q| set_low(&mut q.avail, value)),
+            REG_QUEUE_DRIVER_HIGH => self.queue().into_iter().for_each(|q| set_high(&mut q.avail, value)),
+            REG_QUEUE_DEVICE_LOW => self.queue().into_iter().for_each(|q| set_low(&mut q.used, value)),
+            REG_QUEUE_DEVICE_HIGH => self.queue().into_iter().for_each(|q| set_high(&mut q.used, value)),
+            REG_QUEUE_NOTIFY => {
+                if let Some(notified) = self.notified.get_mut(value as usize) {
+                    *notified = true;
+                }
+            }
+            REG_INTERRUPT_ACK => self.interrupt_status &= !(value as u32),
+            //writing 0 resets the device
+            REG_STATUS if value == 0 => self.reset(),
+            REG_STATUS => self.status = value as u32,
+            _ => return Err(DeviceErr::InvalidOffset(offset)),
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self, ctx: &mut TickCtx) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+
+        if let Err(err) = self.run(ctx) {
+            self.fail(err);
+        }
+
+        for queue in &mut self.queues {
+            if std::mem::take(&mut queue.signal) {
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use crate::emulator::{bus::{Device, TickCtx}, memory::{Mmu, PERM_R, PERM_W}, replay::{Nondet, NondetSource}};
+    use super::*;
+
+    pub const RAM_BASE: u64 = 0x8000_0000;
+    const RAM_SIZE: usize = 0x10_0000;
+
+    const NUM: u16 = 16;
+    //descriptor table, available and used ring of queue n are in the n-th QUEUE_SPACE bytes of RAM
+    const QUEUE_SPACE: u64 = 0x400;
+    const AVAIL: u64 = 0x100;
+    const USED: u64 = 0x200;
+    //buffers are handed out from here on and never freed
+    const BUFFERS: u64 = RAM_BASE + 0x1_0000;
+
+    #[derive(Default, Clone, Copy)]
+    struct Queue {
+        next_desc: u16,
+        avail_idx: u16,
+        used_idx: u16,
+    }
+
+    //a driver for a device behind the mmio transport, everything it does goes through the registers
+    pub struct Driver<D: VirtioDevice> {
+        pub mmio: VirtioMmio<D>,
+        pub ram: Mmu,
+        pub nondet: Nondet,
+        queues: Vec<Queue>,
+        next_buffer: u64,
+        icount: u64,
+    }
+
+    impl<D: VirtioDevice> Driver<D> {
+        //a device with all of its queues set up and DRIVER_OK set
+        pub fn new(device: D) -> Self {
+            let mut ram = Mmu::new();
+            ram.map_region(RAM_BASE as usize, RAM_SIZE, PERM_R | PERM_W, "ram").unwrap();
+
+            let queues = device.queues();
+            let mut driver = Driver {
+                mmio: VirtioMmio::new(device),
+                ram,
+                nondet: Nondet::default(),
+                queues: vec![Queue::default(); queues],
+                next_buffer: BUFFERS,
+                icount: 0,
+            };
+
+            for queue in 0..queues as u64 {
+                let base = RAM_BASE + queue * QUEUE_SPACE;
+
+                driver.reg_write(REG_QUEUE_SEL, queue);
+                driver.reg_write(REG_QUEUE_NUM, NUM as u64);
+                driver.reg_write(REG_QUEUE_DESC_LOW, base);
+                driver.reg_write(REG_QUEUE_DESC_HIGH, base >> 32);
+                driver.reg_write(REG_QUEUE_DRIVER_LOW, base + AVAIL);
+                driver.reg_write(REG_QUEUE_DRIVER_HIGH, (base + AVAIL) >> 32);
+                driver.reg_write(REG_QUEUE_DEVICE_LOW, base + USED);
+                driver.reg_write(REG_QUEUE_DEVICE_HIGH, (base + USED) >> 32);
+                driver.reg_write(REG_QUEUE_READY, 1);
+            }
+            driver.reg_write(REG_STATUS, STATUS_DRIVER_OK as u64);
+
+            driver
+        }
+
+        pub fn reg_read(&mut self, offset: u64) -> u64 {
+            self.mmio.read(offset, 4).unwrap()
+        }
+
+        pub fn reg_write(&mut self, offset: u64, value: u64) {
+            self.mmio.write(offset, 4, value).unwrap();
+        }
+
+        pub fn needs_reset(&mut self) -> bool {
+            self.reg_read(REG_STATUS) as u32 & STATUS_NEEDS_RESET != 0
+        }
+
+        pub fn alloc(&mut self, len: usize) -> u64 {
+            let addr = self.next_buffer;
+            self.next_buffer += (len as u64).next_multiple_of(16);
+
+            addr
+        }
+
+        pub fn read(&self, addr: u64, len: usize) -> Vec<u8> {
+            self.ram.dram_read(addr as usize, len).unwrap().into_owned()
+        }
+
+        //makes a chain of (address, length, device writable) descriptors available, returns its head
+        pub fn add_raw(&mut self, queue: usize, descs: &[(u64, u32, bool)]) -> u16 {
+            let base = RAM_BASE + queue as u64 * QUEUE_SPACE;
+            let q = &mut self.queues[queue];
+            let head = q.next_desc;
+
+            for (i, &(addr, len, writable)) in descs.iter().enumerate() {
+                let idx = (head + i as u16) % NUM;
+                let last = i + 1 == descs.len();
+                let flags = if writable { DESC_F_WRITE } else { 0 } | if last { 0 } else { DESC_F_NEXT };
+
+                let mut desc = [0; DESC_SIZE as usize];
+                desc[0..8].copy_from_slice(&addr.to_le_bytes());
+                desc[8..12].copy_from_slice(&len.to_le_bytes());
+                desc[12..14].copy_from_slice(&flags.to_le_bytes());
+                desc[14..16].copy_from_slice(&((idx + 1) % NUM).to_le_bytes());
+                self.ram.dram_write((base + DESC_SIZE * idx as u64) as usize, &desc).unwrap();
+            }
+
+            q.next_desc = (head + descs.len() as u16) % NUM;
+            self.ram.dram_write((base + AVAIL + 4 + 2 * (q.avail_idx % NUM) as u64) as usize, &head.to_le_bytes()).unwrap();
+            q.avail_idx = q.avail_idx.wrapping_add(1);
+            self.ram.dram_write((base + AVAIL + 2) as usize, &q.avail_idx.to_le_bytes()).unwrap();
+
+            head
+        }
+
+        /*
+            Makes a chain available without notifying: readable buffers with the given contents first, then
+            writable ones of the given sizes, returns the writable buffers' addresses.
+        */
+        pub fn add(&mut self, queue: usize, readable: &[&[u8]], writable: &[usize]) -> Vec<u64> {
+            let mut descs = Vec::new();
+
+            for data in readable {
+                let addr = self.alloc(data.len());
+                self.ram.dram_write(addr as usize, data).unwrap();
+                descs.push((addr, data.len() as u32, false));
+            }
+            for &len in writable {
+                descs.push((self.alloc(len), len as u32, true));
+            }
+
+            self.add_raw(queue, &descs);
+
+            descs.iter().filter(|desc| desc.2).map(|desc| desc.0).collect()
+        }
+
+        pub fn notify(&mut self, queue: usize) {
+            self.reg_write(REG_QUEUE_NOTIFY, queue as u64);
+        }
+
+        pub fn tick(&mut self) {
+            self.icount += 1;
+
+            let mut ctx = TickCtx {
+                elapsed: 1,
+                icount: self.icount,
+                ram: &mut self.ram,
+                nondet: &mut self.nondet,
+                source: NondetSource::Device(VIRTIO_BASE),
+            };
+            self.mmio.tick(&mut ctx);
+        }
+
+        //the (head, length) pairs the device returned since the last call
+        pub fn used(&mut self, queue: usize) -> Vec<(u16, u32)> {
+            let used = RAM_BASE + queue as u64 * QUEUE_SPACE + USED;
+            let q = &mut self.queues[queue];
+            let idx = read_u16(&self.ram, used + 2).unwrap();
+            let mut out = Vec::new();
+
+            while q.used_idx != idx {
+                let elem = self.ram.dram_read((used + 4 + 8 * (q.used_idx % NUM) as u64) as usize, 8).unwrap();
+                out.push((u32::from_le_bytes(elem[..4].try_into().unwrap()) as u16, u32::from_le_bytes(elem[4..].try_into().unwrap())));
+                q.used_idx = q.used_idx.wrapping_add(1);
+            }
+
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{Driver, RAM_BASE};
+    use crate::emulator::bus::Device;
+
+    //takes every chain apart into what it read, answers with it reversed in the writable buffers
+    #[derive(Clone)]
+    struct Echo;
+
+    impl VirtioDevice for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn device_id(&self) -> u32 {
+            0x42
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn queues(&self) -> usize {
+            1
+        }
+
+        fn config(&self) -> Vec<u8> {
+            vec![0x11, 0x22, 0x33, 0x44]
+        }
+
+        fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+            while let Some(chain) = queues[queue].pop(ctx.ram)? {
+                let mut data = vec![0; chain.readable_len()];
+                chain.read(ctx.ram, 0, &mut data)?;
+                data.reverse();
+
+                let written = chain.write(ctx.ram, 0, &data)?;
+                queues[queue].push_used(ctx.ram, chain.head, written as u32)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registers_identify_the_device() {
+        let mut driver = Driver::new(Echo);
+
+        assert_eq!(driver.reg_read(REG_MAGIC) as u32, MAGIC);
+        assert_eq!(driver.reg_read(REG_DEVICE_ID), 0x42);
+        assert_eq!(driver.reg_read(REG_DEVICE_FEATURES), 1 << 3);
+        driver.reg_write(REG_DEVICE_FEATURES_SEL, 1);
+        assert_eq!(driver.reg_read(REG_DEVICE_FEATURES), F_VERSION_1 >> 32);
+
+        //what wasn't offered can't be accepted
+        driver.reg_write(REG_DRIVER_FEATURES_SEL, 0);
+        driver.reg_write(REG_DRIVER_FEATURES, 0xff);
+        assert_eq!(driver.mmio.driver_features, 1 << 3);
+
+        assert_eq!(driver.mmio.read(REG_CONFIG + 1, 2).unwrap(), 0x3322);
+        assert!(driver.mmio.read(REG_CONFIG + 3, 2).is_err());
+        assert!(driver.mmio.read(REG_STATUS, 2).is_err());
+
+        driver.reg_write(REG_QUEUE_SEL, 1);
+        assert_eq!(driver.reg_read(REG_QUEUE_NUM_MAX), 0);
+    }
+
+    #[test]
+    fn chains_are_gathered_across_buffers() {
+        let mut driver = Driver::new(Echo);
+
+        let out = driver.add(0, &[b"abc", b"", b"defg"], &[2, 3, 10]);
+        driver.notify(0);
+        assert!(driver.used(0).is_empty());
+        assert!(!driver.mmio.irq());
+
+        //notifications are taken on the next tick
+        driver.tick();
+        assert_eq!(driver.used(0), [(0, 7)]);
+        assert_eq!([driver.read(out[0], 2), driver.read(out[1], 3), driver.read(out[2], 2)].concat(), b"gfedcba");
+        assert!(driver.mmio.irq());
+
+        driver.reg_write(REG_INTERRUPT_ACK, INTERRUPT_USED_BUFFER as u64);
+        assert!(!driver.mmio.irq());
+    }
+
+    #[test]
+    fn bad_chains_need_a_reset() {
+        let mut driver = Driver::new(Echo);
+
+        //a descriptor pointing at itself, the queue's descriptor table is at the start of RAM
+        let head = driver.add_raw(0, &[(RAM_BASE, 4, false)]);
+        driver.ram.dram_write((RAM_BASE + 12) as usize, &[DESC_F_NEXT as u8, 0, head as u8, 0]).unwrap();
+        driver.notify(0);
+        driver.tick();
+        assert!(driver.needs_reset());
+        assert!(driver.mmio.irq());
+
+        driver.reg_write(REG_STATUS, 0);
+        assert!(!driver.needs_reset());
+
+        let mut driver = Driver::new(Echo);
+        driver.add_raw(0, &[(0x10, 4, false)]);
+        driver.notify(0);
+        driver.tick();
+        assert!(driver.needs_reset());
+    }
+
+    #[test]
+    fn gather_skips_to_the_offset() {
+        let buffers = [(0x100, 4), (0x200, 0), (0x300, 8)];
+        let mut copies = Vec::new();
+
+        let done = gather(&buffers, 6, 20, |addr, at, n| {
+            copies.push((addr, at, n));
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(done, 6);
+        assert_eq!(copies, [(0x302, 0, 6)]);
+        assert_eq!(gather(&buffers, 12, 4, |_, _, _| Ok(())).unwrap(), 0);
+    }
+}
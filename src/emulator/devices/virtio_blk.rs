@@ -0,0 +1,344 @@
+use std::{collections::HashMap, fs, io, os::unix::fs::FileExt, path::Path, sync::Arc};
+use crate::emulator::{bus::TickCtx, memory::Mmu};
+use super::virtio::{DescChain, VirtioDevice, VirtioErr, Virtqueue, DEVICE_ID_BLOCK};
+
+pub const SECTOR_SIZE: usize = 512;
+
+//feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+//request header: type, reserved, sector
+const HEADER_SIZE: usize = 16;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+//the serial number GET_ID answers with, at most 20 bytes
+const DISK_ID: &[u8] = b"crimson";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiskMode {
+    //writes go to the image file, time travel can't take them back
+    ReadWrite,
+    //the driver is told the disk is read only and writes fail
+    ReadOnly,
+    //writes go to an in memory overlay that is part of the emulator state, the image is never modified
+    CopyOnWrite,
+}
+
+/*
+    A disk image on the host. The file is shared by snapshots, the copy-on-write overlay isn't: restoring
+    a snapshot also restores what the disk looked like at that point. Overlay sectors are shared between
+    clones until one of them writes to the sector again.
+*/
+#[derive(Clone)]
+pub struct Disk {
+    file: Arc<fs::File>,
+    sectors: u64,
+    mode: DiskMode,
+    overlay: HashMap<u64, Arc<[u8; SECTOR_SIZE]>>,
+}
+
+impl Disk {
+    pub fn open<P: AsRef<Path>>(path: P, mode: DiskMode) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+
+        //a partial sector at the end of the image isn't addressable
+        let sectors = file.metadata()?.len() / SECTOR_SIZE as u64;
+
+        Ok(Disk { file: Arc::new(file), sectors, mode, overlay: HashMap::new() })
+    }
+
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        sector.checked_add((len / SECTOR_SIZE) as u64).is_some_and(|end| end <= self.sectors)
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + i as u64;
+
+            match self.overlay.get(&sector) {
+                Some(data) => chunk.copy_from_slice(&data[..]),
+                None => self.file.read_exact_at(chunk, sector * SECTOR_SIZE as u64)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.file.write_all_at(data, sector * SECTOR_SIZE as u64),
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::CopyOnWrite => {
+                for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                    self.overlay.insert(sector + i as u64, Arc::new(chunk.try_into().unwrap()));
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/*
+    virtio block device with a single request queue. Every request is a header the driver reads out, the
+    data buffers and a status byte at the very end of the chain the device writes.
+*/
+#[derive(Clone)]
+pub struct VirtioBlk {
+    disk: Disk,
+}
+
+impl VirtioBlk {
+    pub fn new(disk: Disk) -> Self {
+        VirtioBlk { disk }
+    }
+
+    //returns the status and how many data bytes went to the driver
+    fn request(&mut self, chain: &DescChain, ram: &mut Mmu) -> Result<(u8, usize), VirtioErr> {
+        let mut header = [0; HEADER_SIZE];
+        if chain.read(ram, 0, &mut header)? != HEADER_SIZE {
+            return Err(VirtioErr::MalformedRequest(chain.head));
+        }
+
+        let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+
+        //everything writable but the status byte
+        let data_in = chain.writable_len() - 1;
+
+        let status = match kind {
+            T_IN => {
+                if !data_in.is_multiple_of(SECTOR_SIZE) || !self.disk.in_range(sector, data_in) {
+                    return Ok((S_IOERR, 0));
+                }
+
+                //a sector at a time, how much the driver asks for is up to the driver
+                let mut data = [0; SECTOR_SIZE];
+                for i in 0..data_in / SECTOR_SIZE {
+                    if self.disk.read(sector + i as u64, &mut data).is_err() {
+                        return Ok((S_IOERR, 0));
+                    }
+
+                    chain.write(ram, i * SECTOR_SIZE, &data)?;
+                }
+
+                return Ok((S_OK, data_in));
+            }
+            T_OUT => {
+                let data_out = chain.readable_len() - HEADER_SIZE;
+                if !data_out.is_multiple_of(SECTOR_SIZE) || !self.disk.in_range(sector, data_out) {
+                    return Ok((S_IOERR, 0));
+                }
+
+                let mut data = [0; SECTOR_SIZE];
+                for i in 0..data_out / SECTOR_SIZE {
+                    chain.read(ram, HEADER_SIZE + i * SECTOR_SIZE, &mut data)?;
+
+                    if self.disk.write(sector + i as u64, &data).is_err() {
+                        return Ok((S_IOERR, 0));
+                    }
+                }
+
+                S_OK
+            }
+            T_FLUSH => if self.disk.flush().is_ok() { S_OK } else { S_IOERR },
+            T_GET_ID => {
+                let id = &DISK_ID[..DISK_ID.len().min(data_in)];
+                chain.write(ram, 0, id)?;
+
+                return Ok((S_OK, id.len()));
+            }
+            _ => S_UNSUPP,
+        };
+
+        Ok((status, 0))
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        match self.disk.mode {
+            DiskMode::ReadOnly => F_RO | F_FLUSH,
+            _ => F_FLUSH,
+        }
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    //only the capacity in sectors, the other fields belong to features that aren't offered
+    fn config(&self) -> Vec<u8> {
+        self.disk.sectors.to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        let vq = &mut queues[queue];
+
+        while let Some(chain) = vq.pop(ctx.ram)? {
+            //there has to be room for the status byte after the header
+            if chain.readable_len() < HEADER_SIZE || chain.writable_len() == 0 {
+                return Err(VirtioErr::MalformedRequest(chain.head));
+            }
+
+            let (status, written) = self.request(&chain, ctx.ram)?;
+            chain.write(ctx.ram, chain.writable_len() - 1, &[status])?;
+
+            vq.push_used(ctx.ram, chain.head, (written + 1) as u32)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{bus::Device, devices::virtio::testing::{Driver, RAM_BASE}};
+
+    //an image whose sector n is filled with n
+    fn image(name: &str, sectors: u8) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("crimson-blk-{}-{}", name, std::process::id()));
+        let data: Vec<u8> = (0..sectors).flat_map(|n| [n; SECTOR_SIZE]).collect();
+        fs::write(&path, data).unwrap();
+
+        path
+    }
+
+    fn header(kind: u32, sector: u64) -> Vec<u8> {
+        let mut header = vec![0; HEADER_SIZE];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+
+        header
+    }
+
+    //sends one request, returns its status, the length the device reported and the data it wrote
+    fn request(driver: &mut Driver<VirtioBlk>, kind: u32, sector: u64, data: &[&[u8]], writable: &[usize]) -> (u8, u32, Vec<u8>) {
+        let header = header(kind, sector);
+        let readable: Vec<&[u8]> = [&header[..]].into_iter().chain(data.iter().copied()).collect();
+        let out = driver.add(0, &readable, &[writable, &[1]].concat());
+
+        driver.notify(0);
+        driver.tick();
+
+        let [(_, len)] = driver.used(0)[..] else {
+            panic!("the request wasn't completed");
+        };
+        let data = out.iter().zip(writable).flat_map(|(&addr, &len)| driver.read(addr, len)).collect();
+
+        (driver.read(*out.last().unwrap(), 1)[0], len, data)
+    }
+
+    fn driver(path: &Path, mode: DiskMode) -> Driver<VirtioBlk> {
+        Driver::new(VirtioBlk::new(Disk::open(path, mode).unwrap()))
+    }
+
+    #[test]
+    fn reads_go_across_buffers() {
+        let path = image("read", 4);
+        let mut driver = driver(&path, DiskMode::ReadOnly);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(driver.mmio.read(0x100, 8).unwrap(), 4);
+
+        let (status, len, data) = request(&mut driver, T_IN, 1, &[], &[700, SECTOR_SIZE * 2 - 700]);
+        assert_eq!((status, len), (S_OK, 2 * SECTOR_SIZE as u32 + 1));
+        assert_eq!(data, [[1; SECTOR_SIZE], [2; SECTOR_SIZE]].concat());
+
+        assert_eq!(request(&mut driver, T_IN, 3, &[], &[2 * SECTOR_SIZE]).0, S_IOERR);
+        assert_eq!(request(&mut driver, T_IN, 0, &[], &[100]).0, S_IOERR);
+
+        let (status, len, id) = request(&mut driver, T_GET_ID, 0, &[], &[20]);
+        assert_eq!((status, len), (S_OK, DISK_ID.len() as u32 + 1));
+        assert_eq!(&id[..DISK_ID.len()], DISK_ID);
+
+        assert_eq!(request(&mut driver, 99, 0, &[], &[]).0, S_UNSUPP);
+    }
+
+    #[test]
+    fn copy_on_write_leaves_the_image_alone() {
+        let path = image("cow", 4);
+        let mut driver = driver(&path, DiskMode::CopyOnWrite);
+
+        let (status, len, _) = request(&mut driver, T_OUT, 2, &[&[0xaa; 100], &[0xbb; 2 * SECTOR_SIZE - 100]], &[]);
+        assert_eq!((status, len), (S_OK, 1));
+
+        let (_, _, data) = request(&mut driver, T_IN, 1, &[], &[3 * SECTOR_SIZE]);
+        assert_eq!(data, [&[1; SECTOR_SIZE][..], &[0xaa; 100], &[0xbb; 2 * SECTOR_SIZE - 100]].concat());
+
+        assert_eq!(fs::read(&path).unwrap()[2 * SECTOR_SIZE..3 * SECTOR_SIZE], [2; SECTOR_SIZE]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn writes_are_checked_before_anything_is_read() {
+        let path = image("write", 4);
+        let mut read_only = driver(&path, DiskMode::ReadOnly);
+        let mut driver = driver(&path, DiskMode::ReadWrite);
+
+        assert_eq!(request(&mut read_only, T_OUT, 0, &[&[0; SECTOR_SIZE]], &[]).0, S_IOERR);
+        assert_eq!(request(&mut driver, T_OUT, 3, &[&[0; 2 * SECTOR_SIZE]], &[]).0, S_IOERR);
+        assert_eq!(request(&mut driver, T_OUT, 0, &[&[0; 10]], &[]).0, S_IOERR);
+        assert_eq!(request(&mut driver, T_OUT, u64::MAX, &[&[0; SECTOR_SIZE]], &[]).0, S_IOERR);
+
+        //claims 4GB the driver never had, it is refused without being read in
+        let header_addr = driver.alloc(HEADER_SIZE);
+        driver.ram.dram_write(header_addr as usize, &header(T_OUT, 0)).unwrap();
+        let status = driver.alloc(1);
+        let head = driver.add_raw(0, &[(header_addr, HEADER_SIZE as u32, false), (RAM_BASE, !(SECTOR_SIZE as u32 - 1), false), (status, 1, true)]);
+        driver.notify(0);
+        driver.tick();
+        assert_eq!(driver.used(0), [(head, 1)]);
+        assert_eq!(driver.read(status, 1)[0], S_IOERR);
+        assert!(!driver.needs_reset());
+
+        let (status, _, _) = request(&mut driver, T_OUT, 1, &[&[7; SECTOR_SIZE]], &[]);
+        assert_eq!(status, S_OK);
+        assert_eq!(request(&mut driver, T_FLUSH, 0, &[], &[]).0, S_OK);
+        assert_eq!(fs::read(&path).unwrap()[..2 * SECTOR_SIZE], [[0; SECTOR_SIZE], [7; SECTOR_SIZE]].concat());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn short_headers_need_a_reset() {
+        let path = image("short", 1);
+        let mut driver = driver(&path, DiskMode::ReadOnly);
+        let _ = fs::remove_file(&path);
+
+        driver.add(0, &[&[0; 8]], &[1]);
+        driver.notify(0);
+        driver.tick();
+
+        assert!(driver.used(0).is_empty());
+        assert!(driver.needs_reset());
+    }
+}
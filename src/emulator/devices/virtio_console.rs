@@ -0,0 +1,349 @@
+use std::collections::VecDeque;
+use crate::emulator::{bus::TickCtx, replay::NondetSource};
+use super::{uart::{UartInput, UartOutput}, virtio::{VirtioDevice, VirtioErr, Virtqueue, DEVICE_ID_CONSOLE}};
+
+//feature bits
+const F_MULTIPORT: u64 = 1 << 1;
+const F_EMERG_WRITE: u64 = 1 << 2;
+
+//queues 2 and 3 carry control messages, port n > 0 has its rx/tx pair at 2n + 2 and 2n + 3
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+const CONFIG_EMERG_WR: u64 = 8;
+
+//control message: u32 port id, u16 event, u16 value
+const CONTROL_SIZE: usize = 8;
+
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+//input buffered per port while the driver has no rx buffers for it
+const RX_BUFFER: usize = 4096;
+//how much of a tx chain is copied out at once, whole control messages
+const TX_CHUNK: usize = 4096;
+
+#[derive(Clone)]
+pub struct Port {
+    //what the guest finds in /sys/class/virtio-ports/*/name, port 0 is the console and has none
+    name: Option<String>,
+    output: UartOutput,
+    input: UartInput,
+    rx: VecDeque<u8>,
+}
+
+impl Port {
+    pub fn new(name: Option<String>, output: UartOutput, input: UartInput) -> Self {
+        Port { name, output, input, rx: VecDeque::new() }
+    }
+}
+
+fn rx_queue(port: usize) -> usize {
+    if port == 0 { 0 } else { 2 * port + 2 }
+}
+
+fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CONTROL_SIZE);
+    msg.extend_from_slice(&id.to_le_bytes());
+    msg.extend_from_slice(&event.to_le_bytes());
+    msg.extend_from_slice(&value.to_le_bytes());
+
+    msg
+}
+
+/*
+    virtio console with multiport: port 0 is the console (hvc0), the others show up as /dev/vportNpM and
+    are announced to the driver over the control queues once it says it is ready. A driver that doesn't
+    take the multiport feature only ever uses port 0.
+*/
+#[derive(Clone)]
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    //messages for the driver waiting for control rx buffers
+    control_out: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    pub fn new(ports: Vec<Port>) -> Self {
+        VirtioConsole { ports, control_out: VecDeque::new() }
+    }
+
+    //ports are told apart in the nondet log by offsetting the device's own id
+    fn port_source(source: NondetSource, port: usize) -> NondetSource {
+        match source {
+            NondetSource::Device(id) => NondetSource::Device(id + port as u64),
+            source => source,
+        }
+    }
+
+    fn control_message(&mut self, msg: &[u8]) {
+        let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() as u32 {
+                    self.control_out.push_back(control(id, DEVICE_ADD, 0));
+                }
+            }
+            PORT_READY if value == 1 => {
+                let Some(port) = self.ports.get(id as usize) else {
+                    return;
+                };
+
+                if id == 0 {
+                    self.control_out.push_back(control(id, CONSOLE_PORT, 1));
+                }
+                if let Some(name) = &port.name {
+                    let mut msg = control(id, PORT_NAME, 1);
+                    msg.extend_from_slice(name.as_bytes());
+                    self.control_out.push_back(msg);
+                }
+
+                //the host end is always connected
+                self.control_out.push_back(control(id, PORT_OPEN, 1));
+            }
+            //the guest opening and closing ports doesn't change where the host end goes
+            _ => {}
+        }
+    }
+
+    fn transmit(&mut self, queue: usize, vq: &mut Virtqueue, ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        let mut data = [0; TX_CHUNK];
+
+        while let Some(chain) = vq.pop(ctx.ram)? {
+            let mut offset = 0;
+
+            loop {
+                let len = chain.read(ctx.ram, offset, &mut data)?;
+                offset += len;
+
+                if queue == CONTROL_TX {
+                    for msg in data[..len].chunks_exact(CONTROL_SIZE) {
+                        self.control_message(msg);
+                    }
+                } else if let Some(port) = self.ports.get(if queue == 1 { 0 } else { (queue - 3) / 2 }) {
+                    for byte in &data[..len] {
+                        port.output.write(*byte);
+                    }
+                    port.output.flush();
+                }
+
+                if len < TX_CHUNK {
+                    break;
+                }
+            }
+
+            vq.push_used(ctx.ram, chain.head, 0)?;
+        }
+
+        Ok(())
+    }
+
+    //hands buffered input and control messages to the driver, as far as it has buffers for them
+    fn receive(&mut self, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        let source = ctx.source;
+
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            if port.rx.is_empty() {
+                port.rx.extend(port.input.poll(ctx, Self::port_source(source, i), RX_BUFFER));
+            }
+
+            let Some(vq) = queues.get_mut(rx_queue(i)) else {
+                continue;
+            };
+
+            while !port.rx.is_empty() {
+                let Some(chain) = vq.pop(ctx.ram)? else {
+                    break;
+                };
+
+                let data: Vec<u8> = port.rx.iter().copied().take(chain.writable_len()).collect();
+                let written = chain.write(ctx.ram, 0, &data)?;
+                port.rx.drain(..written);
+
+                vq.push_used(ctx.ram, chain.head, written as u32)?;
+            }
+        }
+
+        while !self.control_out.is_empty() {
+            let Some(chain) = queues[CONTROL_RX].pop(ctx.ram)? else {
+                break;
+            };
+
+            let msg = self.control_out.pop_front().unwrap();
+            let written = chain.write(ctx.ram, 0, &msg)?;
+
+            queues[CONTROL_RX].push_used(ctx.ram, chain.head, written as u32)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn name(&self) -> &str {
+        "virtio-console"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        F_MULTIPORT | F_EMERG_WRITE
+    }
+
+    fn queues(&self) -> usize {
+        2 * self.ports.len() + 2
+    }
+
+    //cols, rows (no size reported), max_nr_ports, emerg_wr
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 12];
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+
+        config
+    }
+
+    //an emergency write goes straight to port 0, before any queue is set up
+    fn config_write(&mut self, offset: u64, data: &[u8]) {
+        if offset == CONFIG_EMERG_WR {
+            if let Some(port) = self.ports.first() {
+                port.output.write(data[0]);
+                port.output.flush();
+            }
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        //rx buffers the driver adds are filled on the next poll
+        if queue % 2 == 1 {
+            self.transmit(queue, &mut queues[queue], ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        self.receive(queues, ctx)
+    }
+
+    fn reset(&mut self) {
+        self.control_out.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::{Arc, Mutex}};
+    use super::*;
+    use crate::emulator::{bus::Device, devices::virtio::testing::{Driver, RAM_BASE}};
+
+    struct Console {
+        driver: Driver<VirtioConsole>,
+        outputs: Vec<Arc<Mutex<Vec<u8>>>>,
+        //rx buffers handed to the device by queue and head
+        rx: HashMap<(usize, u16), u64>,
+    }
+
+    //the console and a port named "log", each with its own output and scripted input
+    fn console(inputs: [&[u8]; 2]) -> Console {
+        let outputs: Vec<_> = (0..2).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+        let ports = outputs.iter().zip(inputs).enumerate().map(|(i, (output, input))| {
+            Port::new((i == 1).then(|| "log".to_string()), UartOutput::Writer(output.clone()), UartInput::Script(input.iter().copied().collect()))
+        }).collect();
+
+        Console { driver: Driver::new(VirtioConsole::new(ports)), outputs, rx: HashMap::new() }
+    }
+
+    impl Console {
+        fn output(&self, port: usize) -> Vec<u8> {
+            self.outputs[port].lock().unwrap().clone()
+        }
+
+        fn send(&mut self, queue: usize, data: &[&[u8]]) {
+            self.driver.add(queue, data, &[]);
+            self.driver.notify(queue);
+            self.driver.tick();
+        }
+
+        //hands the device rx buffers of the given sizes, returns what it put in any of its rx buffers
+        fn receive(&mut self, queue: usize, sizes: &[usize]) -> Vec<Vec<u8>> {
+            for &size in sizes {
+                let addr = self.driver.alloc(size);
+                let head = self.driver.add_raw(queue, &[(addr, size as u32, true)]);
+                self.rx.insert((queue, head), addr);
+            }
+            self.driver.tick();
+
+            self.driver.used(queue).iter().map(|&(head, len)| self.driver.read(self.rx[&(queue, head)], len as usize)).collect()
+        }
+    }
+
+    #[test]
+    fn transmitted_chains_reach_the_port_in_order() {
+        let mut console = console([b"", b""]);
+        let data: Vec<u8> = (0..3 * TX_CHUNK as u32 + 5).map(|i| i as u8).collect();
+
+        console.send(1, &[&data[..100], &data[100..TX_CHUNK + 1], &data[TX_CHUNK + 1..]]);
+        assert_eq!(console.driver.used(1), [(0, 0)]);
+        assert_eq!(console.output(0), data);
+
+        console.send(5, &[b"to the log"]);
+        assert_eq!(console.output(1), b"to the log");
+        assert_eq!(console.output(0).len(), data.len());
+
+        //an emergency write needs no queue
+        console.driver.mmio.write(0x100 + CONFIG_EMERG_WR, 1, b'!' as u64).unwrap();
+        assert_eq!(console.output(0).last(), Some(&b'!'));
+    }
+
+    #[test]
+    fn chains_longer_than_ram_fail_the_device() {
+        let mut console = console([b"", b""]);
+
+        console.driver.add_raw(1, &[(RAM_BASE, u32::MAX, false)]);
+        console.driver.notify(1);
+        console.driver.tick();
+
+        assert!(console.driver.used(1).is_empty());
+        assert!(console.driver.needs_reset());
+    }
+
+    #[test]
+    fn input_waits_for_rx_buffers() {
+        let mut console = console([b"hello", b"x"]);
+
+        assert_eq!(console.receive(0, &[3]), [b"hel"]);
+        assert_eq!(console.receive(0, &[8]), [b"lo"]);
+        assert_eq!(console.receive(4, &[8]), [b"x"]);
+    }
+
+    #[test]
+    fn ports_are_announced_over_the_control_queues() {
+        let mut console = console([b"", b""]);
+
+        let ready = control(0, DEVICE_READY, 1);
+        let port_ready = control(1, PORT_READY, 1);
+        //messages split across buffers are put back together
+        console.send(CONTROL_TX, &[&ready[..5], &[&ready[5..], &port_ready[..]].concat()]);
+
+        let messages = console.receive(CONTROL_RX, &[16, 16, 16, 16, 16]);
+        assert_eq!(messages, [
+            control(0, DEVICE_ADD, 0),
+            control(1, DEVICE_ADD, 0),
+            [control(1, PORT_NAME, 1), b"log".to_vec()].concat(),
+            control(1, PORT_OPEN, 1),
+        ]);
+
+        //the last buffer is still there for what comes next
+        console.send(CONTROL_TX, &[&control(0, PORT_READY, 1)]);
+        assert_eq!(console.receive(CONTROL_RX, &[16]), [control(0, CONSOLE_PORT, 1), control(0, PORT_OPEN, 1)]);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fs, io::Read};
+use crate::emulator::bus::TickCtx;
+use super::virtio::{DescChain, VirtioDevice, VirtioErr, Virtqueue, DEVICE_ID_RNG};
+
+//most entropy handed out per request, drivers ask for a few dozen bytes at a time
+const MAX_REQUEST: usize = 4096;
+
+//xoshiro256**, seeded through splitmix64 so any seed (0 included) gives a usable state
+#[derive(Clone)]
+struct Prng {
+    s: [u64; 4],
+}
+
+impl Prng {
+    fn new(seed: u64) -> Self {
+        let mut x = seed;
+        let mut splitmix = || {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+
+        Prng { s: [splitmix(), splitmix(), splitmix(), splitmix()] }
+    }
+
+    fn next(&mut self) -> u64 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+
+        result
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Entropy {
+    //the same bytes on every run with the same seed, no log needed
+    Seeded(Prng),
+    //the host's /dev/urandom, through the nondet log
+    Host,
+}
+
+/*
+    virtio entropy device, one request queue whose buffers get filled with random bytes. Host entropy
+    is polled like console input, a request waits until the log (or the host) has bytes for it.
+*/
+#[derive(Clone)]
+pub struct VirtioRng {
+    entropy: Entropy,
+    //taken off the queue, waiting for host entropy
+    waiting: Option<DescChain>,
+}
+
+impl VirtioRng {
+    pub fn new(seed: Option<u64>) -> Self {
+        VirtioRng {
+            entropy: seed.map_or(Entropy::Host, |seed| Entropy::Seeded(Prng::new(seed))),
+            waiting: None,
+        }
+    }
+
+    fn serve(&mut self, vq: &mut Virtqueue, ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        loop {
+            let chain = match self.waiting.take() {
+                Some(chain) => chain,
+                None => match vq.pop(ctx.ram)? {
+                    Some(chain) => chain,
+                    None => return Ok(()),
+                },
+            };
+
+            let len = chain.writable_len().min(MAX_REQUEST);
+
+            let data = match &mut self.entropy {
+                Entropy::Seeded(prng) => {
+                    let mut data = vec![0; len];
+                    prng.fill(&mut data);
+                    data
+                }
+                Entropy::Host => {
+                    let polled = ctx.nondet.poll(ctx.icount, ctx.source, || {
+                        let mut data = vec![0; len];
+                        let len = fs::File::open("/dev/urandom").and_then(|mut file| file.read(&mut data)).unwrap_or(0);
+                        data.truncate(len);
+                        data
+                    });
+
+                    match polled {
+                        Some(data) => data,
+                        None => {
+                            self.waiting = Some(chain);
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+
+            let written = chain.write(ctx.ram, 0, &data)?;
+            vq.push_used(ctx.ram, chain.head, written as u32)?;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn name(&self) -> &str {
+        "virtio-rng"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        self.serve(&mut queues[queue], ctx)
+    }
+
+    //a request waiting for host entropy is retried
+    fn poll(&mut self, queues: &mut [Virtqueue], ctx: &mut TickCtx) -> Result<(), VirtioErr> {
+        if self.waiting.is_some() {
+            self.serve(&mut queues[0], ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.waiting = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{devices::virtio::testing::Driver, replay::{Nondet, NondetEvent}};
+
+    //hands the device a chain of writable buffers of the given sizes behind a readable one it has to skip,
+    //returns the length the device reported and what ended up in the buffers
+    fn request(driver: &mut Driver<VirtioRng>, sizes: &[usize]) -> Option<(u32, Vec<u8>)> {
+        let buffers = driver.add(0, &[b"ignored"], sizes);
+        driver.notify(0);
+        driver.tick();
+
+        let (_, len) = *driver.used(0).first()?;
+        let data = buffers.iter().zip(sizes).flat_map(|(&addr, &size)| driver.read(addr, size)).collect();
+
+        Some((len, data))
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_bytes() {
+        let bytes = |seed| {
+            let mut driver = Driver::new(VirtioRng::new(Some(seed)));
+            [request(&mut driver, &[5, 16, 11]).unwrap(), request(&mut driver, &[8]).unwrap()]
+        };
+
+        assert_eq!(bytes(7), bytes(7));
+        assert_ne!(bytes(7), bytes(8));
+
+        //straight from the generator, split over the buffers in order
+        let mut expected = vec![0; 40];
+        Prng::new(7).fill(&mut expected);
+        let [(first_len, first), (second_len, second)] = bytes(7);
+        assert_eq!((first_len, second_len), (32, 8));
+        assert_eq!([first, second].concat(), expected);
+    }
+
+    #[test]
+    fn a_request_fills_every_writable_byte_up_to_the_limit() {
+        let mut driver = Driver::new(VirtioRng::new(Some(1)));
+
+        let (len, data) = request(&mut driver, &[3, 1, 4096]).unwrap();
+        assert_eq!(len as usize, MAX_REQUEST);
+        assert_eq!(data.len(), 4100);
+
+        //what is over the limit stays untouched
+        assert_ne!(data[..8], [0; 8]);
+        assert_eq!(data[MAX_REQUEST..], [0; 4]);
+    }
+
+    #[test]
+    fn host_entropy_is_recorded_and_replayed() {
+        let mut recording = Driver::new(VirtioRng::new(None));
+        recording.nondet = Nondet::record();
+        let recorded = request(&mut recording, &[16, 16]).unwrap();
+        assert_eq!(recorded.0, 32);
+
+        let log = recording.nondet.log().to_vec();
+        assert_eq!(log.len(), 1);
+
+        let mut replaying = Driver::new(VirtioRng::new(None));
+        replaying.nondet = Nondet::replay(log.clone());
+        assert_eq!(request(&mut replaying, &[16, 16]).unwrap(), recorded);
+        assert!(replaying.nondet.finish().is_ok());
+
+        //entropy that came later in the recording holds the request until the guest gets there
+        let mut replaying = Driver::new(VirtioRng::new(None));
+        replaying.nondet = Nondet::replay(vec![NondetEvent { icount: 3, ..log[0].clone() }]);
+        assert_eq!(request(&mut replaying, &[16, 16]), None);
+        replaying.tick();
+        assert!(replaying.used(0).is_empty());
+        replaying.tick();
+        assert_eq!(replaying.used(0).len(), 1);
+        assert!(replaying.nondet.finish().is_ok());
+    }
+}
@@ -0,0 +1,604 @@
+use std::fmt;
+
+//standard opcodes of the line number program
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+//extended opcodes
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+//what the entries of a DWARF 5 directory or file name table hold
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DwarfErr {
+    #[error("Truncated debug info")]
+    Truncated,
+
+    #[error("Unsupported DWARF version {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("Unsupported attribute form {0:#x}")]
+    UnsupportedForm(u64),
+
+    #[error("Invalid line program header")]
+    InvalidHeader,
+
+    #[error("Unsupported pointer encoding {0:#x}")]
+    UnsupportedEncoding(u8),
+
+    #[error("Unsupported call frame instruction {0:#x}")]
+    UnsupportedCfi(u8),
+
+    #[error("Invalid call frame information")]
+    InvalidCfi,
+}
+
+//little endian reads that fail instead of running off the end of the section
+pub(super) struct Reader<'a> {
+    data: &'a [u8],
+    //offset of `data` in the section it came from
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub(super) fn at(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    pub(super) fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub(super) fn bytes(&mut self, len: usize) -> Result<&'a [u8], DwarfErr> {
+        if len > self.data.len() {
+            return Err(DwarfErr::Truncated);
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    //1, 2, 4 or 8 bytes
+    pub(super) fn uint(&mut self, len: usize) -> Result<u64, DwarfErr> {
+        Ok(self.bytes(len)?.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64))
+    }
+
+    //sign extended from `len` bytes
+    pub(super) fn sint(&mut self, len: usize) -> Result<i64, DwarfErr> {
+        let shift = 64 - len as u32 * 8;
+
+        Ok(((self.uint(len)? << shift) as i64) >> shift)
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8, DwarfErr> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(super) fn uleb(&mut self) -> Result<u64, DwarfErr> {
+        let mut value = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub(super) fn sleb(&mut self) -> Result<i64, DwarfErr> {
+        let mut value = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    pub(super) fn cstr(&mut self) -> Result<&'a [u8], DwarfErr> {
+        let len = self.data.iter().position(|byte| *byte == 0).ok_or(DwarfErr::Truncated)?;
+        let string = self.bytes(len)?;
+        self.bytes(1)?;
+
+        Ok(string)
+    }
+}
+
+//the NUL terminated string at `offset` in a string section
+fn str_at(section: &[u8], offset: u64) -> Result<String, DwarfErr> {
+    let data = section.get(offset as usize..).ok_or(DwarfErr::Truncated)?;
+
+    Ok(String::from_utf8_lossy(Reader::new(data).cstr()?).into_owned())
+}
+
+//.debug_line_str and .debug_str, where DWARF 5 file names may live
+#[derive(Clone, Copy, Default)]
+pub struct Strings<'a> {
+    pub line_str: &'a [u8],
+    pub str: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LineRange {
+    start: u64,
+    end: u64,
+    file: usize,
+    line: u32,
+    column: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    //0 when the compiler didn't say
+    pub column: u32,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+
+        if self.column != 0 {
+            write!(f, ":{}", self.column)?;
+        }
+
+        Ok(())
+    }
+}
+
+/*
+    The .debug_line tables of every compilation unit, DWARF versions 2 to 5, flattened into address
+    ranges. Each row of a line program covers the addresses up to the next row of its sequence.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    //sorted by start
+    ranges: Vec<LineRange>,
+}
+
+impl LineTable {
+    pub fn parse(debug_line: &[u8], strings: Strings) -> Result<Self, DwarfErr> {
+        let mut table = LineTable::default();
+        let mut reader = Reader::new(debug_line);
+
+        while !reader.is_empty() {
+            table.parse_unit(&mut reader, strings)?;
+        }
+
+        table.ranges.sort_by_key(|range| range.start);
+
+        Ok(table)
+    }
+
+    pub fn locate(&self, addr: u64) -> Option<SourceLocation<'_>> {
+        let idx = self.ranges.partition_point(|range| range.start <= addr).checked_sub(1)?;
+        let range = self.ranges[idx];
+
+        if addr >= range.end {
+            return None;
+        }
+
+        Some(SourceLocation { file: &self.files[range.file], line: range.line, column: range.column })
+    }
+
+    fn parse_unit(&mut self, reader: &mut Reader, strings: Strings) -> Result<(), DwarfErr> {
+        //64 bit DWARF announces itself with an escape in the 32 bit length
+        let (unit_length, offset_size) = match reader.uint(4)? {
+            0xffff_ffff => (reader.uint(8)?, 8),
+            0xffff_fff0.. => return Err(DwarfErr::InvalidHeader),
+            len => (len, 4),
+        };
+        let mut unit = Reader::new(reader.bytes(unit_length as usize)?);
+
+        let version = unit.uint(2)? as u16;
+        if !(2..=5).contains(&version) {
+            return Err(DwarfErr::UnsupportedVersion(version));
+        }
+        if version >= 5 {
+            //address size and segment selector size, addresses come with their own length anyway
+            unit.bytes(2)?;
+        }
+
+        let header_length = unit.uint(offset_size)?;
+        let mut header = Reader::new(unit.bytes(header_length as usize)?);
+
+        let min_inst_length = header.u8()? as u64;
+        if version >= 4 {
+            //max_ops_per_inst, only VLIW targets have more than one
+            header.u8()?;
+        }
+        //default_is_stmt, every row is a good place to report
+        header.u8()?;
+        let line_base = header.u8()? as i8 as i64;
+        let line_range = header.u8()?;
+        let opcode_base = header.u8()?;
+
+        if line_range == 0 || opcode_base == 0 {
+            return Err(DwarfErr::InvalidHeader);
+        }
+        let opcode_lengths = header.bytes(opcode_base as usize - 1)?;
+
+        let files = if version >= 5 {
+            parse_entries_v5(&mut header, offset_size, strings)?
+        } else {
+            parse_entries(&mut header)?
+        };
+
+        let base = self.files.len();
+        let file_count = files.len();
+        self.files.extend(files);
+
+        //the line number program, every row of a sequence is (address, file, line, column)
+        let mut rows: Vec<(u64, usize, u32, u32)> = Vec::new();
+        let mut addr = 0u64;
+        let mut file = 1usize;
+        let mut line = 1u32;
+        let mut column = 0u32;
+
+        let emit = |rows: &mut Vec<_>, addr, file: usize, line, column| {
+            if file < file_count {
+                rows.push((addr, base + file, line, column));
+            }
+        };
+
+        while !unit.is_empty() {
+            let opcode = unit.u8()?;
+
+            if opcode >= opcode_base {
+                let adjusted = (opcode - opcode_base) as u64;
+
+                addr = addr.wrapping_add(adjusted / line_range as u64 * min_inst_length);
+                line = (line as i64 + line_base + (adjusted % line_range as u64) as i64) as u32;
+                emit(&mut rows, addr, file, line, column);
+                continue;
+            }
+
+            match opcode {
+                0 => {
+                    let len = unit.uleb()? as usize;
+                    let mut ext = Reader::new(unit.bytes(len)?);
+
+                    match ext.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            //the end of the sequence is where its last row stops
+                            rows.push((addr, 0, 0, 0));
+
+                            for pair in rows.windows(2) {
+                                let (start, file, line, column) = pair[0];
+
+                                if start < pair[1].0 {
+                                    self.ranges.push(LineRange { start, end: pair[1].0, file, line, column });
+                                }
+                            }
+
+                            rows.clear();
+                            addr = 0;
+                            file = 1;
+                            line = 1;
+                            column = 0;
+                        }
+                        DW_LNE_SET_ADDRESS => addr = ext.uint(len - 1)?,
+                        //define_file and set_discriminator don't change where we are
+                        _ => {}
+                    }
+                }
+                DW_LNS_COPY => emit(&mut rows, addr, file, line, column),
+                DW_LNS_ADVANCE_PC => addr = addr.wrapping_add(unit.uleb()?.wrapping_mul(min_inst_length)),
+                DW_LNS_ADVANCE_LINE => line = (line as i64).wrapping_add(unit.sleb()?) as u32,
+                DW_LNS_SET_FILE => file = unit.uleb()? as usize,
+                DW_LNS_SET_COLUMN => column = unit.uleb()? as u32,
+                DW_LNS_CONST_ADD_PC => addr = addr.wrapping_add((255 - opcode_base) as u64 / line_range as u64 * min_inst_length),
+                DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(unit.uint(2)?),
+                //negate_stmt, basic_block, prologue_end, epilogue_begin, set_isa and whatever a newer version
+                //adds, the header says how many operands to skip
+                _ => {
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        unit.uleb()?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() || name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+//DWARF 2-4: NUL terminated lists, file numbers start at 1 and directory 0 is the compilation directory
+fn parse_entries(header: &mut Reader) -> Result<Vec<String>, DwarfErr> {
+    let mut dirs = vec![String::new()];
+
+    loop {
+        let dir = header.cstr()?;
+        if dir.is_empty() {
+            break;
+        }
+        dirs.push(String::from_utf8_lossy(dir).into_owned());
+    }
+
+    //file 0 doesn't exist, it is never emitted
+    let mut files = vec![String::new()];
+
+    loop {
+        let name = header.cstr()?;
+        if name.is_empty() {
+            break;
+        }
+
+        let dir = header.uleb()? as usize;
+        //modification time and length
+        header.uleb()?;
+        header.uleb()?;
+
+        let dir = dirs.get(dir).map_or("", String::as_str);
+        files.push(join(dir, &String::from_utf8_lossy(name)));
+    }
+
+    Ok(files)
+}
+
+//DWARF 5: the header first describes what each directory and file entry holds, file numbers start at 0
+fn parse_entries_v5(header: &mut Reader, offset_size: usize, strings: Strings) -> Result<Vec<String>, DwarfErr> {
+    let dirs: Vec<String> = parse_table_v5(header, offset_size, strings)?.into_iter().map(|(path, _)| path).collect();
+
+    let files = parse_table_v5(header, offset_size, strings)?.into_iter()
+        .map(|(name, dir)| join(dirs.get(dir as usize).map_or("", String::as_str), &name))
+        .collect();
+
+    Ok(files)
+}
+
+//(path, directory index) of every entry
+fn parse_table_v5(header: &mut Reader, offset_size: usize, strings: Strings) -> Result<Vec<(String, u64)>, DwarfErr> {
+    let format_count = header.u8()?;
+    let mut formats = Vec::new();
+
+    for _ in 0..format_count {
+        formats.push((header.uleb()?, header.uleb()?));
+    }
+
+    let count = header.uleb()?;
+    let mut entries = Vec::new();
+
+    //entries without a format take no bytes, the count alone could make us build billions of them
+    if formats.is_empty() && count != 0 {
+        return Err(DwarfErr::InvalidHeader);
+    }
+
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut dir = 0;
+
+        for &(content, form) in &formats {
+            let (string, value) = match form {
+                DW_FORM_STRING => (Some(String::from_utf8_lossy(header.cstr()?).into_owned()), 0),
+                DW_FORM_LINE_STRP => (Some(str_at(strings.line_str, header.uint(offset_size)?)?), 0),
+                DW_FORM_STRP => (Some(str_at(strings.str, header.uint(offset_size)?)?), 0),
+                DW_FORM_UDATA => (None, header.uleb()?),
+                DW_FORM_DATA1 => (None, header.uint(1)?),
+                DW_FORM_DATA2 => (None, header.uint(2)?),
+                DW_FORM_DATA4 => (None, header.uint(4)?),
+                DW_FORM_DATA8 => (None, header.uint(8)?),
+                DW_FORM_DATA16 => (None, header.bytes(16).map(|_| 0)?),
+                DW_FORM_BLOCK => {
+                    let len = header.uleb()? as usize;
+                    (None, header.bytes(len).map(|_| 0)?)
+                }
+                form => return Err(DwarfErr::UnsupportedForm(form)),
+            };
+
+            match content {
+                DW_LNCT_PATH => path = string.unwrap_or_default(),
+                DW_LNCT_DIRECTORY_INDEX => dir = value,
+                _ => {}
+            }
+        }
+
+        entries.push((path, dir));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SET_PROLOGUE_END: u8 = 10;
+    const SET_ISA: u8 = 12;
+    //line_base -5, line_range 14, opcode_base 13
+    const OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+    //a line program unit: the version, what comes before header_length, the header and the program
+    fn unit(version: u16, pre_header: &[u8], header: &[u8], program: &[u8]) -> Vec<u8> {
+        let mut body = version.to_le_bytes().to_vec();
+        body.extend_from_slice(pre_header);
+        body.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        body.extend_from_slice(header);
+        body.extend_from_slice(program);
+
+        let mut unit = (body.len() as u32).to_le_bytes().to_vec();
+        unit.extend(body);
+        unit
+    }
+
+    fn header(min_inst_length: u8, max_ops: bool, tables: &[u8]) -> Vec<u8> {
+        let mut header = vec![min_inst_length];
+        if max_ops {
+            header.push(1);
+        }
+        header.extend_from_slice(&[1, -5i8 as u8, 14, 13]);
+        header.extend_from_slice(&OPCODE_LENGTHS);
+        header.extend_from_slice(tables);
+        header
+    }
+
+    fn set_address(addr: u64) -> Vec<u8> {
+        let mut op = vec![0, 9, DW_LNE_SET_ADDRESS];
+        op.extend_from_slice(&addr.to_le_bytes());
+        op
+    }
+
+    fn special(addr_advance: u8, line_advance: i8) -> u8 {
+        ((line_advance + 5) as u8) + 14 * addr_advance + 13
+    }
+
+    const END_SEQUENCE: [u8; 3] = [0, 1, DW_LNE_END_SEQUENCE];
+
+    fn v4_unit() -> Vec<u8> {
+        let tables = b"src\0\0a.c\0\x01\0\0/abs/b.h\0\x01\0\0\0";
+
+        let mut program = set_address(0x1000);
+        program.extend_from_slice(&[DW_LNS_SET_COLUMN, 5, DW_LNS_COPY]);
+        program.push(special(4, 1));
+        //opcodes this reader has no use for are skipped by their operand count
+        program.extend_from_slice(&[SET_PROLOGUE_END, SET_ISA, 0x80, 0x01]);
+        program.extend_from_slice(&[DW_LNS_SET_FILE, 2, DW_LNS_ADVANCE_LINE, 8, DW_LNS_ADVANCE_PC, 8, DW_LNS_COPY]);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 4]);
+        program.extend_from_slice(&END_SEQUENCE);
+
+        unit(4, &[], &header(1, true, tables), &program)
+    }
+
+    fn v5_unit() -> Vec<u8> {
+        let mut tables = vec![1];
+        tables.extend_from_slice(&[DW_LNCT_PATH as u8, DW_FORM_LINE_STRP as u8, 1]);
+        tables.extend_from_slice(&4u32.to_le_bytes());
+        tables.extend_from_slice(&[2, DW_LNCT_PATH as u8, DW_FORM_STRING as u8, DW_LNCT_DIRECTORY_INDEX as u8, DW_FORM_UDATA as u8]);
+        tables.extend_from_slice(b"\x01m.c\0\0");
+
+        let mut program = set_address(0x800);
+        program.extend_from_slice(&[DW_LNS_SET_FILE, 0, DW_LNS_COPY, DW_LNS_ADVANCE_PC, 2]);
+        program.extend_from_slice(&END_SEQUENCE);
+
+        //address size 8, no segment selector
+        unit(5, &[8, 0], &header(4, true, &tables), &program)
+    }
+
+    fn locate(table: &LineTable, addr: u64) -> Option<String> {
+        table.locate(addr).map(|location| location.to_string())
+    }
+
+    #[test]
+    fn reader_decodes_dwarf_numbers() {
+        let data = [0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f, 0xfe, 0xff, b'h', b'i', 0];
+        let mut reader = Reader::new(&data);
+
+        assert_eq!(reader.uleb().unwrap(), 624485);
+        assert_eq!(reader.sleb().unwrap(), -1);
+        assert_eq!(reader.sleb().unwrap(), -128);
+        assert_eq!(reader.sint(2).unwrap(), -2);
+        assert_eq!(reader.cstr().unwrap(), b"hi");
+        assert_eq!(reader.pos(), data.len());
+        assert!(reader.is_empty());
+
+        //nothing reads past the end
+        assert!(matches!(reader.u8(), Err(DwarfErr::Truncated)));
+        assert!(matches!(Reader::new(&[0x80]).uleb(), Err(DwarfErr::Truncated)));
+        assert!(matches!(Reader::new(b"no nul").cstr(), Err(DwarfErr::Truncated)));
+        assert!(matches!(Reader::new(&[1, 2, 3]).uint(4), Err(DwarfErr::Truncated)));
+
+        //an overlong number keeps its low 64 bits instead of overflowing
+        let long = [0xff; 11].iter().chain(&[0x01]).copied().collect::<Vec<u8>>();
+        assert_eq!(Reader::new(&long).uleb().unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn line_programs_become_ranges() {
+        let table = LineTable::parse(&v4_unit(), Strings::default()).unwrap();
+
+        assert_eq!(locate(&table, 0xfff), None);
+        assert_eq!(locate(&table, 0x1000).as_deref(), Some("src/a.c:1:5"));
+        assert_eq!(locate(&table, 0x1003).as_deref(), Some("src/a.c:1:5"));
+        assert_eq!(locate(&table, 0x1004).as_deref(), Some("src/a.c:2:5"));
+        assert_eq!(locate(&table, 0x100c).as_deref(), Some("/abs/b.h:10:5"));
+        assert_eq!(locate(&table, 0x1010), None);
+    }
+
+    #[test]
+    fn units_of_every_version_are_merged() {
+        let mut section = v4_unit();
+        section.extend(v5_unit());
+        let strings = Strings { line_str: b"xxx\0/cu\0", str: &[] };
+
+        let table = LineTable::parse(&section, strings).unwrap();
+
+        //the DWARF 5 unit's directory comes from .debug_line_str and its addresses step by 4
+        assert_eq!(locate(&table, 0x800).as_deref(), Some("/cu/m.c:1"));
+        assert_eq!(locate(&table, 0x807).as_deref(), Some("/cu/m.c:1"));
+        assert_eq!(locate(&table, 0x808), None);
+        assert_eq!(locate(&table, 0x1004).as_deref(), Some("src/a.c:2:5"));
+    }
+
+    #[test]
+    fn bad_line_programs_are_errors() {
+        let unsupported = unit(6, &[], &[], &[]);
+        assert!(matches!(LineTable::parse(&unsupported, Strings::default()), Err(DwarfErr::UnsupportedVersion(6))));
+
+        let v4 = v4_unit();
+        assert!(matches!(LineTable::parse(&v4[..v4.len() - 1], Strings::default()), Err(DwarfErr::Truncated)));
+
+        //a directory string offset past the end of .debug_line_str
+        assert!(matches!(LineTable::parse(&v5_unit(), Strings::default()), Err(DwarfErr::Truncated)));
+
+        let no_range = unit(4, &[], &[1, 1, 1, 0, 0, 13], &[]);
+        assert!(matches!(LineTable::parse(&no_range, Strings::default()), Err(DwarfErr::InvalidHeader)));
+    }
+}
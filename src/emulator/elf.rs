@@ -0,0 +1,717 @@
+use thiserror::Error;
+
+/*
+    A bounds-checked ELF parser for ELF32 and ELF64 files. Every field is read one at a time in the byte order the file says it is
+    in, every offset and size taken from the file is checked against the file before it is used, so a
+    truncated or malicious binary ends in an ElfErr and never in a panic or a read past the end. It only
+    depends on std and thiserror, the fuzz target in fuzz/ builds it on its own.
+*/
+
+pub const MAGIC_ELF: [u8; 4] = [0x7F, 0x45, 0x4C, 0x46];        //.ELF
+
+const EI_NIDENT: usize = 16;
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+pub const ELFCLASS32: u8 = 1;
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const ELFDATA2MSB: u8 = 2;
+
+pub const ET_EXEC: u16 = 2;
+pub const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_R: u32 = 0x4;
+pub const PF_W: u32 = 0x2;
+pub const PF_X: u32 = 0x1;
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_DYNSYM: u32 = 11;
+pub const SHN_UNDEF: u16 = 0;
+
+//sizes of the structures of one class, entry sizes that don't match them are rejected
+struct Layout {
+    ehdr: usize,
+    phdr: u16,
+    shdr: u16,
+    sym: u64,
+}
+
+const LAYOUT_32: Layout = Layout { ehdr: 52, phdr: 32, shdr: 40, sym: 16 };
+const LAYOUT_64: Layout = Layout { ehdr: 64, phdr: 56, shdr: 64, sym: 24 };
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum ElfErr {
+    #[error("Not an ELF file")]
+    NotElf,
+
+    #[error("Truncated {0}")]
+    Truncated(&'static str),
+
+    #[error("Unsupported ELF class {0}")]
+    UnsupportedClass(u8),
+
+    #[error("Invalid data encoding {0}")]
+    InvalidEncoding(u8),
+
+    #[error("Invalid {table} entry size {size}")]
+    BadEntrySize { table: &'static str, size: u64 },
+
+    #[error("The {what} at {offset:#x} of {size:#x} bytes is outside the file")]
+    OutOfFile { what: &'static str, offset: u64, size: u64 },
+
+    #[error("Segment at {vaddr:#x} is larger in the file ({filesz:#x}) than in memory ({memsz:#x})")]
+    FileSizeExceedsMemSize { vaddr: u64, filesz: u64, memsz: u64 },
+
+    #[error("Segment at {vaddr:#x} of {memsz:#x} bytes wraps around the address space")]
+    SegmentWraps { vaddr: u64, memsz: u64 },
+
+    #[error("Invalid {what} section index {index}")]
+    BadSectionIndex { what: &'static str, index: u32 },
+
+    #[error("Name offset {0:#x} is outside its string table")]
+    BadName(u32),
+}
+
+/*
+    typedef struct {
+        unsigned char e_ident[EI_NIDENT];
+        uint16_t      e_type;
+        uint16_t      e_machine;
+        uint32_t      e_version;
+        ElfN_Addr     e_entry;
+        ElfN_Off      e_phoff;       //offset to prgram_header
+        ElfN_Off      e_shoff;
+        uint32_t      e_flags;
+        uint16_t      e_ehsize;
+        uint16_t      e_phentsize;   //size in bytes of one entry of program_header_table
+        uint16_t      e_phnum;       //number of entries in the program_header_table.
+        uint16_t      e_shentsize;
+        uint16_t      e_shnum;
+        uint16_t      e_shstrndx;
+    } ElfN_Ehdr;
+*/
+#[derive(Clone, Debug)]
+pub struct ElfHeader {
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+/*
+    Elf32_Phdr has the same fields with p_flags moved after p_memsz, the ElfN_ fields of it and of the
+    section headers and symbols are read zero extended.
+
+    typedef struct {
+        uint32_t   p_type;
+        uint32_t   p_flags;
+        Elf64_Off  p_offset;
+        Elf64_Addr p_vaddr;
+        Elf64_Addr p_paddr;
+        uint64_t   p_filesz;
+        uint64_t   p_memsz;
+        uint64_t   p_align;
+    } Elf64_Phdr;
+*/
+#[derive(Clone, Debug)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+}
+
+/*
+    typedef struct {
+        uint32_t   sh_name;
+        uint32_t   sh_type;
+        uint64_t   sh_flags;
+        Elf64_Addr sh_addr;
+        Elf64_Off  sh_offset;
+        uint64_t   sh_size;
+        uint32_t   sh_link;
+        uint32_t   sh_info;
+        uint64_t   sh_addralign;
+        uint64_t   sh_entsize;
+    } Elf64_Shdr;
+*/
+#[derive(Clone, Debug)]
+pub struct SectionHeader {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_entsize: u64,
+}
+
+/*
+    typedef struct {
+        uint32_t      st_name;
+        unsigned char st_info;
+        unsigned char st_other;
+        uint16_t      st_shndx;
+        Elf64_Addr    st_value;
+        uint64_t      st_size;
+    } Elf64_Sym;
+*/
+#[derive(Clone, Debug)]
+pub struct SymbolEntry<'a> {
+    //st_name already looked up in the symbol table's string table
+    pub name: &'a [u8],
+    pub st_info: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    encoding: Encoding,
+    pub header: ElfHeader,
+    pub program_headers: Vec<ProgramHeader>,
+    pub section_headers: Vec<SectionHeader>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Encoding {
+    big_endian: bool,
+    //ELFCLASS64, ElfN_Addr, ElfN_Off and the like are 64 bits
+    wide: bool,
+}
+
+//reads the fields of one structure in the file's byte order
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+    encoding: Encoding,
+    what: &'static str,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8], encoding: Encoding, what: &'static str) -> Self {
+        Fields { data, pos: 0, encoding, what }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], ElfErr> {
+        let bytes = self.data.get(self.pos..self.pos + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ElfErr::Truncated(self.what))?;
+        self.pos += N;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ElfErr> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ElfErr> {
+        let bytes = self.bytes()?;
+        Ok(if self.encoding.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&mut self) -> Result<u32, ElfErr> {
+        let bytes = self.bytes()?;
+        Ok(if self.encoding.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn u64(&mut self) -> Result<u64, ElfErr> {
+        let bytes = self.bytes()?;
+        Ok(if self.encoding.big_endian { u64::from_be_bytes(bytes) } else { u64::from_le_bytes(bytes) })
+    }
+
+    //a field whose size depends on the class, ElfN_Addr, ElfN_Off and ElfN_Word
+    fn word(&mut self) -> Result<u64, ElfErr> {
+        if self.encoding.wide { self.u64() } else { self.u32().map(u64::from) }
+    }
+}
+
+//the bytes [offset, offset + size) of the file, if all of them are in it
+fn range<'a>(data: &'a [u8], what: &'static str, offset: u64, size: u64) -> Result<&'a [u8], ElfErr> {
+    let end = offset.checked_add(size);
+
+    usize::try_from(offset).ok()
+        .zip(end.and_then(|end| usize::try_from(end).ok()))
+        .and_then(|(start, end)| data.get(start..end))
+        .ok_or(ElfErr::OutOfFile { what, offset, size })
+}
+
+//a table of num entries of entsize bytes, the product can't overflow in u64 with u16 operands
+fn table<'a>(data: &'a [u8], what: &'static str, offset: u64, entsize: u16, num: u16) -> Result<&'a [u8], ElfErr> {
+    range(data, what, offset, entsize as u64 * num as u64)
+}
+
+//the NUL terminated string at offset, a missing terminator ends it at the end of the table
+fn string_at(strings: &[u8], offset: u32) -> Result<&[u8], ElfErr> {
+    strings.get(offset as usize..)
+        .and_then(|rest| rest.split(|byte| *byte == 0).next())
+        .ok_or(ElfErr::BadName(offset))
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfErr> {
+        let ident: [u8; EI_NIDENT] = data.get(..EI_NIDENT)
+            .and_then(|ident| ident.try_into().ok())
+            .ok_or(ElfErr::Truncated("identification"))?;
+
+        if ident[..MAGIC_ELF.len()] != MAGIC_ELF {
+            return Err(ElfErr::NotElf);
+        }
+        let (wide, layout) = match ident[EI_CLASS] {
+            ELFCLASS32 => (false, LAYOUT_32),
+            ELFCLASS64 => (true, LAYOUT_64),
+            class => return Err(ElfErr::UnsupportedClass(class)),
+        };
+        let big_endian = match ident[EI_DATA] {
+            ELFDATA2LSB => false,
+            ELFDATA2MSB => true,
+            encoding => return Err(ElfErr::InvalidEncoding(encoding)),
+        };
+        let encoding = Encoding { big_endian, wide };
+
+        let header = Self::parse_header(data.get(..layout.ehdr).ok_or(ElfErr::Truncated("ELF header"))?, encoding)?;
+
+        let mut program_headers = Vec::new();
+        if header.e_phnum != 0 {
+            if header.e_phentsize != layout.phdr {
+                return Err(ElfErr::BadEntrySize { table: "program header", size: header.e_phentsize as u64 });
+            }
+
+            let entries = table(data, "program header table", header.e_phoff, header.e_phentsize, header.e_phnum)?;
+            for entry in entries.chunks_exact(layout.phdr as usize) {
+                let program_header = Self::parse_program_header(entry, encoding)?;
+                Self::check_segment(data, &program_header, wide)?;
+                program_headers.push(program_header);
+            }
+        }
+
+        /*
+            e_shnum is 0 with more than 0xff00 sections, the real number is then in the first section
+            header. No binary this runs is that large, they are loaded without sections.
+        */
+        let mut section_headers = Vec::new();
+        if header.e_shnum != 0 {
+            if header.e_shentsize != layout.shdr {
+                return Err(ElfErr::BadEntrySize { table: "section header", size: header.e_shentsize as u64 });
+            }
+
+            let entries = table(data, "section header table", header.e_shoff, header.e_shentsize, header.e_shnum)?;
+            for entry in entries.chunks_exact(layout.shdr as usize) {
+                section_headers.push(Self::parse_section_header(entry, encoding)?);
+            }
+
+            if header.e_shstrndx >= header.e_shnum {
+                return Err(ElfErr::BadSectionIndex { what: "section name table", index: header.e_shstrndx as u32 });
+            }
+        }
+
+        Ok(Elf { data, encoding, header, program_headers, section_headers })
+    }
+
+    //only the fields the loader uses are kept, the rest are read past
+    fn parse_header(data: &[u8], encoding: Encoding) -> Result<ElfHeader, ElfErr> {
+        let mut fields = Fields::new(data, encoding, "ELF header");
+        fields.bytes::<EI_NIDENT>()?;
+
+        let e_type = fields.u16()?;
+        let e_machine = fields.u16()?;
+        //e_version
+        fields.u32()?;
+        let e_entry = fields.word()?;
+        let e_phoff = fields.word()?;
+        let e_shoff = fields.word()?;
+        //e_flags and e_ehsize
+        fields.u32()?;
+        fields.u16()?;
+
+        Ok(ElfHeader {
+            e_type,
+            e_machine,
+            e_entry,
+            e_phoff,
+            e_shoff,
+            e_phentsize: fields.u16()?,
+            e_phnum: fields.u16()?,
+            e_shentsize: fields.u16()?,
+            e_shnum: fields.u16()?,
+            e_shstrndx: fields.u16()?,
+        })
+    }
+
+    fn parse_program_header(data: &[u8], encoding: Encoding) -> Result<ProgramHeader, ElfErr> {
+        let mut fields = Fields::new(data, encoding, "program header");
+
+        //fields are read in the order they are written, p_flags comes later in an Elf32_Phdr, p_paddr and
+        //p_align are read past
+        let p_type = fields.u32()?;
+        let mut p_flags = if encoding.wide { fields.u32()? } else { 0 };
+        let p_offset = fields.word()?;
+        let p_vaddr = fields.word()?;
+        fields.word()?;
+        let p_filesz = fields.word()?;
+        let p_memsz = fields.word()?;
+        if !encoding.wide {
+            p_flags = fields.u32()?;
+        }
+
+        Ok(ProgramHeader { p_type, p_flags, p_offset, p_vaddr, p_filesz, p_memsz })
+    }
+
+    fn parse_section_header(data: &[u8], encoding: Encoding) -> Result<SectionHeader, ElfErr> {
+        let mut fields = Fields::new(data, encoding, "section header");
+
+        let sh_name = fields.u32()?;
+        let sh_type = fields.u32()?;
+        //sh_flags
+        fields.word()?;
+        let sh_addr = fields.word()?;
+        let sh_offset = fields.word()?;
+        let sh_size = fields.word()?;
+        let sh_link = fields.u32()?;
+        //sh_info and sh_addralign
+        fields.u32()?;
+        fields.word()?;
+
+        Ok(SectionHeader { sh_name, sh_type, sh_addr, sh_offset, sh_size, sh_link, sh_entsize: fields.word()? })
+    }
+
+    //the file bytes of every segment are in the file, a loadable one also has to fit the address space
+    fn check_segment(data: &[u8], header: &ProgramHeader, wide: bool) -> Result<(), ElfErr> {
+        range(data, "segment", header.p_offset, header.p_filesz)?;
+
+        if header.p_type != PT_LOAD {
+            return Ok(());
+        }
+        if header.p_filesz > header.p_memsz {
+            return Err(ElfErr::FileSizeExceedsMemSize { vaddr: header.p_vaddr, filesz: header.p_filesz, memsz: header.p_memsz });
+        }
+        let end = header.p_vaddr.checked_add(header.p_memsz);
+        if end.is_none_or(|end| !wide && end > 1 << 32) {
+            return Err(ElfErr::SegmentWraps { vaddr: header.p_vaddr, memsz: header.p_memsz });
+        }
+
+        Ok(())
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.encoding.big_endian
+    }
+
+    pub fn is_64(&self) -> bool {
+        self.encoding.wide
+    }
+
+    //the part of the segment that is in the file, the rest up to p_memsz is zeroed
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfErr> {
+        range(self.data, "segment", header.p_offset, header.p_filesz)
+    }
+
+    pub fn section_data(&self, header: &SectionHeader) -> Result<&'a [u8], ElfErr> {
+        //.bss and friends take no space in the file, whatever sh_offset says
+        if header.sh_type == SHT_NOBITS {
+            return Ok(&[]);
+        }
+
+        range(self.data, "section", header.sh_offset, header.sh_size)
+    }
+
+    //names are in the section e_shstrndx points to
+    pub fn section_name(&self, header: &SectionHeader) -> Result<&'a [u8], ElfErr> {
+        let names = self.section_data(&self.section_headers[self.header.e_shstrndx as usize])?;
+
+        string_at(names, header.sh_name)
+    }
+
+    pub fn section_named(&self, name: &str) -> Option<&SectionHeader> {
+        self.section_headers.iter().find(|header| {
+            self.section_name(header).is_ok_and(|section| section == name.as_bytes())
+        })
+    }
+
+    //every entry of every symbol table, the string table is the one sh_link names
+    pub fn symbols(&self) -> Result<Vec<SymbolEntry<'a>>, ElfErr> {
+        let mut symbols = Vec::new();
+
+        for header in &self.section_headers {
+            if header.sh_type != SHT_SYMTAB && header.sh_type != SHT_DYNSYM {
+                continue;
+            }
+            let layout = if self.encoding.wide { LAYOUT_64 } else { LAYOUT_32 };
+            if header.sh_entsize != layout.sym {
+                return Err(ElfErr::BadEntrySize { table: "symbol table", size: header.sh_entsize });
+            }
+
+            let strtab = self.section_headers.get(header.sh_link as usize)
+                .ok_or(ElfErr::BadSectionIndex { what: "string table", index: header.sh_link })?;
+            let strings = self.section_data(strtab)?;
+            let entries = self.section_data(header)?;
+
+            for entry in entries.chunks_exact(layout.sym as usize) {
+                let mut fields = Fields::new(entry, self.encoding, "symbol");
+                let name = string_at(strings, fields.u32()?)?;
+
+                //fields are read in the order they are written, Elf32_Sym has the value and size first, st_other
+                //(the visibility) is read past
+                let (mut st_value, mut st_size) = (0, 0);
+                if !self.encoding.wide {
+                    st_value = fields.word()?;
+                    st_size = fields.word()?;
+                }
+                let st_info = fields.u8()?;
+                fields.u8()?;
+                let st_shndx = fields.u16()?;
+                if self.encoding.wide {
+                    st_value = fields.word()?;
+                    st_size = fields.word()?;
+                }
+
+                symbols.push(SymbolEntry { name, st_info, st_shndx, st_value, st_size });
+            }
+        }
+
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: u64 = 0x10000;
+    const CODE: &[u8] = b"\x13\0\0\0\x73\0\0\0";
+    const SHSTRTAB: &[u8] = b"\0.shstrtab\0.symtab\0.strtab\0.bss\0";
+    const STRTAB: &[u8] = b"\0main\0";
+
+    //writes fields in the byte order and class of the file being built
+    struct Writer {
+        data: Vec<u8>,
+        wide: bool,
+        big_endian: bool,
+    }
+
+    impl Writer {
+        fn bytes<const N: usize>(&mut self, le: [u8; N]) {
+            let mut bytes = le;
+            if self.big_endian {
+                bytes.reverse();
+            }
+            self.data.extend_from_slice(&bytes);
+        }
+
+        fn u8(&mut self, value: u8) {
+            self.data.push(value);
+        }
+
+        fn u16(&mut self, value: u16) {
+            self.bytes(value.to_le_bytes());
+        }
+
+        fn u32(&mut self, value: u32) {
+            self.bytes(value.to_le_bytes());
+        }
+
+        fn word(&mut self, value: u64) {
+            if self.wide { self.bytes(value.to_le_bytes()) } else { self.bytes((value as u32).to_le_bytes()) }
+        }
+
+        fn section(&mut self, name: u32, sh_type: u32, offset: u64, size: u64, link: u32, entsize: u64) {
+            self.u32(name);
+            self.u32(sh_type);
+            //sh_flags
+            self.word(0x2);
+            self.word(0);
+            self.word(offset);
+            self.word(size);
+            self.u32(link);
+            //sh_info and sh_addralign
+            self.u32(1);
+            self.word(8);
+            self.word(entsize);
+        }
+
+        fn symbol(&mut self, name: u32, value: u64, size: u64, info: u8, shndx: u16) {
+            self.u32(name);
+            if !self.wide {
+                self.word(value);
+                self.word(size);
+            }
+            self.u8(info);
+            //st_other, hidden
+            self.u8(2);
+            self.u16(shndx);
+            if self.wide {
+                self.word(value);
+                self.word(size);
+            }
+        }
+    }
+
+    //offsets of the program headers, the symbol table and the section headers
+    fn offsets(wide: bool) -> (u64, u64, u64) {
+        let layout = if wide { LAYOUT_64 } else { LAYOUT_32 };
+        let phoff = layout.ehdr as u64;
+        let symtab = phoff + layout.phdr as u64 + (CODE.len() + SHSTRTAB.len() + STRTAB.len()) as u64;
+
+        (phoff, symtab, symtab + 2 * layout.sym)
+    }
+
+    /*
+        An executable with one loadable segment holding CODE and the sections null, .shstrtab, .symtab,
+        .strtab and .bss, .symtab holding the null symbol and main.
+    */
+    fn image(wide: bool, big_endian: bool) -> Vec<u8> {
+        let layout = if wide { LAYOUT_64 } else { LAYOUT_32 };
+        let (phoff, symtab, shoff) = offsets(wide);
+        let code = phoff + layout.phdr as u64;
+        let shstrtab = code + CODE.len() as u64;
+        let strtab = shstrtab + SHSTRTAB.len() as u64;
+
+        let mut w = Writer { data: MAGIC_ELF.to_vec(), wide, big_endian };
+        w.data.extend_from_slice(&[if wide { ELFCLASS64 } else { ELFCLASS32 }, if big_endian { ELFDATA2MSB } else { ELFDATA2LSB }, 1]);
+        w.data.resize(EI_NIDENT, 0);
+
+        w.u16(ET_EXEC);
+        w.u16(EM_RISCV);
+        w.u32(1);
+        w.word(ENTRY);
+        w.word(phoff);
+        w.word(shoff);
+        //e_flags, e_ehsize
+        w.u32(0x5);
+        w.u16(layout.ehdr as u16);
+        w.u16(layout.phdr);
+        w.u16(1);
+        w.u16(layout.shdr);
+        w.u16(5);
+        w.u16(1);
+
+        //p_paddr and p_align are never read, they are set to something that would show if they were
+        w.u32(PT_LOAD);
+        if wide {
+            w.u32(PF_R | PF_X);
+        }
+        w.word(code);
+        w.word(ENTRY);
+        w.word(0xdead);
+        w.word(CODE.len() as u64);
+        w.word(0x10);
+        if !wide {
+            w.u32(PF_R | PF_X);
+        }
+        w.word(0x1000);
+
+        w.data.extend_from_slice(CODE);
+        w.data.extend_from_slice(SHSTRTAB);
+        w.data.extend_from_slice(STRTAB);
+
+        w.symbol(0, 0, 0, 0, SHN_UNDEF);
+        w.symbol(1, ENTRY, CODE.len() as u64, 0x12, 1);
+
+        w.section(0, 0, 0, 0, 0, 0);
+        w.section(1, 3, shstrtab, SHSTRTAB.len() as u64, 0, 0);
+        w.section(11, SHT_SYMTAB, symtab, 2 * layout.sym, 3, layout.sym);
+        w.section(19, 3, strtab, STRTAB.len() as u64, 0, 0);
+        //NOBITS, the offset doesn't matter
+        w.section(27, SHT_NOBITS, u32::MAX as u64, 0x100, 0, 0);
+
+        assert_eq!(w.data.len() as u64, shoff + 5 * layout.shdr as u64);
+        w.data
+    }
+
+    fn patch(data: &mut [u8], at: u64, bytes: &[u8]) {
+        data[at as usize..at as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    #[test]
+    fn every_class_and_byte_order_is_parsed() {
+        for (wide, big_endian) in [(true, false), (false, false), (true, true), (false, true)] {
+            let data = image(wide, big_endian);
+            let elf = Elf::parse(&data).unwrap();
+            let (phoff, _, shoff) = offsets(wide);
+
+            assert_eq!((elf.is_64(), elf.is_big_endian()), (wide, big_endian));
+            assert_eq!((elf.header.e_type, elf.header.e_machine, elf.header.e_entry), (ET_EXEC, EM_RISCV, ENTRY));
+            assert_eq!((elf.header.e_phoff, elf.header.e_shoff, elf.header.e_shstrndx), (phoff, shoff, 1));
+
+            let [segment] = &elf.program_headers[..] else { panic!("{} segments", elf.program_headers.len()) };
+            assert_eq!((segment.p_type, segment.p_flags, segment.p_vaddr), (PT_LOAD, PF_R | PF_X, ENTRY));
+            assert_eq!((segment.p_filesz, segment.p_memsz), (CODE.len() as u64, 0x10));
+            assert_eq!(elf.segment_data(segment).unwrap(), CODE);
+
+            let bss = elf.section_named(".bss").unwrap();
+            assert_eq!((bss.sh_type, bss.sh_size, elf.section_data(bss).unwrap()), (SHT_NOBITS, 0x100, &[][..]));
+            assert_eq!(elf.section_data(elf.section_named(".strtab").unwrap()).unwrap(), STRTAB);
+            assert!(elf.section_named(".text").is_none());
+
+            let symbols = elf.symbols().unwrap();
+            assert_eq!(symbols.len(), 2);
+            let main = &symbols[1];
+            assert_eq!((main.name, main.st_info, main.st_shndx), (&b"main"[..], 0x12, 1));
+            assert_eq!((main.st_value, main.st_size), (ENTRY, CODE.len() as u64));
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let valid = image(true, false);
+        let (phoff, symtab, shoff) = offsets(true);
+        let parse = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = valid.clone();
+            edit(&mut data);
+            Elf::parse(&data).and_then(|elf| elf.symbols()).map(|_| ())
+        };
+
+        assert_eq!(parse(&|_| {}), Ok(()));
+        assert_eq!(parse(&|data| data[3] = b'G'), Err(ElfErr::NotElf));
+        assert_eq!(parse(&|data| data.truncate(10)), Err(ElfErr::Truncated("identification")));
+        assert_eq!(parse(&|data| data.truncate(40)), Err(ElfErr::Truncated("ELF header")));
+        assert_eq!(parse(&|data| data[EI_CLASS] = 3), Err(ElfErr::UnsupportedClass(3)));
+        assert_eq!(parse(&|data| data[EI_DATA] = 0), Err(ElfErr::InvalidEncoding(0)));
+
+        //e_phentsize and e_shstrndx
+        assert_eq!(parse(&|data| patch(data, 54, &[55, 0])), Err(ElfErr::BadEntrySize { table: "program header", size: 55 }));
+        assert_eq!(parse(&|data| patch(data, 62, &[5, 0])), Err(ElfErr::BadSectionIndex { what: "section name table", index: 5 }));
+
+        assert_eq!(
+            parse(&|data| data.truncate(shoff as usize + 10)),
+            Err(ElfErr::OutOfFile { what: "section header table", offset: shoff, size: 5 * 64 })
+        );
+
+        //p_filesz
+        assert_eq!(
+            parse(&|data| patch(data, phoff + 32, &0x20u64.to_le_bytes())),
+            Err(ElfErr::FileSizeExceedsMemSize { vaddr: ENTRY, filesz: 0x20, memsz: 0x10 })
+        );
+        assert_eq!(
+            parse(&|data| patch(data, phoff + 32, &u64::MAX.to_le_bytes())),
+            Err(ElfErr::OutOfFile { what: "segment", offset: phoff + 56, size: u64::MAX })
+        );
+
+        //st_name of main
+        assert_eq!(parse(&|data| patch(data, symtab + 24, &[0, 1, 0, 0])), Err(ElfErr::BadName(0x100)));
+    }
+
+    #[test]
+    fn elf32_segments_must_fit_32_bits() {
+        let mut data = image(false, false);
+        let (phoff, _, _) = offsets(false);
+
+        //p_vaddr
+        patch(&mut data, phoff + 8, &0xffff_fff8u32.to_le_bytes());
+
+        assert_eq!(Elf::parse(&data).unwrap_err(), ElfErr::SegmentWraps { vaddr: 0xffff_fff8, memsz: 0x10 });
+    }
+}
@@ -1,24 +1,121 @@
+use super::memory::{AccessFault, AccessKind};
+
 #[derive(thiserror::Error, Debug)]
 pub enum ExceptionHandlerErr {}
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Debug, Clone)]
 pub enum Exceptions {
-    #[error("Instruction address misaligned: {0}")]
+    #[error("Instruction address misaligned: {0:#x}")]
     ExceptionInstructionAddressMisaligned(usize),
     
-    #[error("[Placeholder]: {0}")]
+    #[error("Instruction access fault: {0:#x}")]
     ExceptionAccessFault(usize),
 
-    #[error("[Placeholder]: {0}")]
+    #[error("Load address misaligned: {0:#x}")]
+    ExceptionLoadAddressMisaligned(usize),
+
+    #[error("Load access fault: {0:#x}")]
+    ExceptionLoadAccessFault(usize),
+
+    #[error("Store address misaligned: {0:#x}")]
+    ExceptionStoreAddressMisaligned(usize),
+
+    #[error("Store access fault: {0:#x}")]
+    ExceptionStoreAccessFault(usize),
+
+    #[error("Instruction page fault: {0:#x}")]
     ExceptionPageFault(usize),
+
+    #[error("Load page fault: {0:#x}")]
+    ExceptionLoadPageFault(usize),
+
+    #[error("Store page fault: {0:#x}")]
+    ExceptionStorePageFault(usize),
+
+    #[error("Illegal instruction: {0:#010x}")]
+    ExceptionIllegalInstruction(u32),
+
+    #[error("Breakpoint: {0:#x}")]
+    ExceptionBreakpoint(usize),
+
+    #[error("Environment call: {0:#x}")]
+    ExceptionEnvironmentCall(usize),
+}
+
+impl Exceptions {
+    /*
+        3.1.15. Machine Cause Register (mcause)
+            Table 14. Machine cause register (mcause) values after trap. An environment call is 8, 9 or 11
+            depending on the privilege mode it was made from, which the exception itself doesn't know.
+    */
+    pub fn cause(&self, ecall_from: u64) -> u64 {
+        match self {
+            Exceptions::ExceptionInstructionAddressMisaligned(_) => 0,
+            Exceptions::ExceptionAccessFault(_) => 1,
+            Exceptions::ExceptionIllegalInstruction(_) => 2,
+            Exceptions::ExceptionBreakpoint(_) => 3,
+            Exceptions::ExceptionLoadAddressMisaligned(_) => 4,
+            Exceptions::ExceptionLoadAccessFault(_) => 5,
+            Exceptions::ExceptionStoreAddressMisaligned(_) => 6,
+            Exceptions::ExceptionStoreAccessFault(_) => 7,
+            Exceptions::ExceptionEnvironmentCall(_) => 8 + ecall_from,
+            Exceptions::ExceptionPageFault(_) => 12,
+            Exceptions::ExceptionLoadPageFault(_) => 13,
+            Exceptions::ExceptionStorePageFault(_) => 15,
+        }
+    }
+
+    //the faulting address, or the instruction bits for an illegal instruction
+    pub fn tval(&self) -> u64 {
+        match self {
+            Exceptions::ExceptionInstructionAddressMisaligned(addr) |
+            Exceptions::ExceptionAccessFault(addr) |
+            Exceptions::ExceptionLoadAddressMisaligned(addr) |
+            Exceptions::ExceptionLoadAccessFault(addr) |
+            Exceptions::ExceptionStoreAddressMisaligned(addr) |
+            Exceptions::ExceptionStoreAccessFault(addr) |
+            Exceptions::ExceptionPageFault(addr) |
+            Exceptions::ExceptionLoadPageFault(addr) |
+            Exceptions::ExceptionStorePageFault(addr) |
+            Exceptions::ExceptionBreakpoint(addr) => *addr as u64,
+            Exceptions::ExceptionIllegalInstruction(raw) => *raw as u64,
+            Exceptions::ExceptionEnvironmentCall(_) => 0,
+        }
+    }
 }
 
+//the exception the instruction raises when its access didn't happen
+impl From<AccessFault> for Exceptions {
+    fn from(fault: AccessFault) -> Self {
+        match fault {
+            AccessFault::Denied(addr, AccessKind::Read) => Exceptions::ExceptionLoadAccessFault(addr as usize),
+            AccessFault::Denied(addr, AccessKind::Write) => Exceptions::ExceptionStoreAccessFault(addr as usize),
+            AccessFault::Denied(addr, AccessKind::Execute) => Exceptions::ExceptionAccessFault(addr as usize),
+            AccessFault::Misaligned(addr, AccessKind::Read) => Exceptions::ExceptionLoadAddressMisaligned(addr as usize),
+            AccessFault::Misaligned(addr, AccessKind::Write) => Exceptions::ExceptionStoreAddressMisaligned(addr as usize),
+            AccessFault::Misaligned(addr, AccessKind::Execute) => Exceptions::ExceptionInstructionAddressMisaligned(addr as usize),
+        }
+    }
+}
 
 //do not call this directly, instead use emu.handle_exception 
 pub fn handle_expection(exception: Exceptions) -> Result<bool, ExceptionHandlerErr> {
-    let continue_execution = false;
-
-    todo!("exceptions");
+    //there is no trap handler inside the execution environment yet, so every trap is a fatal trap (Table 1)
+    //and is reported back to whoever is driving the emulator
+    let continue_execution = match exception {
+        Exceptions::ExceptionInstructionAddressMisaligned(_) |
+        Exceptions::ExceptionAccessFault(_) |
+        Exceptions::ExceptionLoadAddressMisaligned(_) |
+        Exceptions::ExceptionLoadAccessFault(_) |
+        Exceptions::ExceptionStoreAddressMisaligned(_) |
+        Exceptions::ExceptionStoreAccessFault(_) |
+        Exceptions::ExceptionPageFault(_) |
+        Exceptions::ExceptionLoadPageFault(_) |
+        Exceptions::ExceptionStorePageFault(_) |
+        Exceptions::ExceptionIllegalInstruction(_) |
+        Exceptions::ExceptionBreakpoint(_) |
+        Exceptions::ExceptionEnvironmentCall(_) => false,
+    };
 
     Ok(continue_execution)
 }
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+//devicetree specification 5.2, a version 17 blob
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+//one empty memory reservation entry terminates the block
+const RSVMAP_SIZE: usize = 16;
+
+/*
+    Writes a flattened device tree: nodes are opened and closed in order, properties belong to the node
+    that is open. Values are big endian as the format wants; cells are u32.
+*/
+#[derive(Default)]
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+    next_phandle: u32,
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        FdtBuilder { next_phandle: 1, ..Default::default() }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    //names are shared between every property that uses them
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+
+        offset
+    }
+
+    //a phandle to give the next node that others refer to
+    pub fn alloc_phandle(&mut self) -> u32 {
+        let phandle = self.next_phandle;
+        self.next_phandle += 1;
+
+        phandle
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn prop(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+
+        self.token(FDT_PROP);
+        self.structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structure.extend_from_slice(&nameoff.to_be_bytes());
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    pub fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop(name, &value.to_be_bytes());
+    }
+
+    pub fn prop_u64(&mut self, name: &str, value: u64) {
+        self.prop(name, &value.to_be_bytes());
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    pub fn prop_str(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.prop(name, &bytes);
+    }
+
+    //a string list, like compatible
+    pub fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.bytes().chain([0])).collect();
+        self.prop(name, &bytes);
+    }
+
+    //(address, size) pairs with #address-cells = #size-cells = 2
+    pub fn prop_reg(&mut self, regions: &[(u64, u64)]) {
+        let cells: Vec<u32> = regions.iter()
+            .flat_map(|(addr, size)| [(addr >> 32) as u32, *addr as u32, (size >> 32) as u32, *size as u32])
+            .collect();
+        self.prop_cells("reg", &cells);
+    }
+
+    pub fn finish(mut self, boot_hartid: u32) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced device tree nodes");
+        self.token(FDT_END);
+
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_hartid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total);
+        blob.extend(header.iter().flat_map(|field| field.to_be_bytes()));
+        blob.extend_from_slice(&[0; RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blob_layout() {
+        let mut fdt = FdtBuilder::new();
+        assert_eq!((fdt.alloc_phandle(), fdt.alloc_phandle()), (1, 2));
+
+        fdt.begin_node("");
+        fdt.prop_u32("a", 0x1234_5678);
+        fdt.begin_node("n@1");
+        fdt.prop_str("a", "xy");
+        fdt.prop_reg(&[(0x1_8000_0000, 0x1000)]);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish(3);
+
+        let off_struct = be32(&blob, 8) as usize;
+        let off_strings = be32(&blob, 12) as usize;
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!((off_struct, be32(&blob, 16) as usize), (HEADER_SIZE + RSVMAP_SIZE, HEADER_SIZE));
+        assert_eq!((be32(&blob, 20), be32(&blob, 24), be32(&blob, 28)), (17, 16, 3));
+        assert_eq!(be32(&blob, 36) as usize, off_strings - off_struct);
+
+        //"a" is stored once and shared, "reg" follows it
+        assert_eq!(&blob[off_strings..], b"a\0reg\0");
+
+        let words: Vec<u32> = (off_struct..off_strings).step_by(4).map(|at| be32(&blob, at)).collect();
+        assert_eq!(words, [
+            FDT_BEGIN_NODE, 0,
+            FDT_PROP, 4, 0, 0x1234_5678,
+            FDT_BEGIN_NODE, u32::from_be_bytes(*b"n@1\0"),
+            FDT_PROP, 3, 0, u32::from_be_bytes(*b"xy\0\0"),
+            FDT_PROP, 16, 2, 1, 0x8000_0000, 0, 0x1000,
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unbalanced")]
+    fn unbalanced_nodes_are_a_bug() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+
+        fdt.finish(0);
+    }
+}
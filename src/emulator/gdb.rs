@@ -0,0 +1,498 @@
+use std::{io::{self, Read, Write}, net::{TcpListener, TcpStream}};
+use super::{cpu::{Xlen, MAX_REGS}, decoder, exceptions::Exceptions, time_travel::{StopReason, TimeTravel, WatchKind, Watchpoint}, triage, Emulator, EmulatorErr};
+
+#[derive(thiserror::Error, Debug)]
+pub enum GdbErr {
+    #[error("Connection error: {0}")]
+    Connection(#[from] io::Error),
+
+    #[error("Debugger disconnected")]
+    Disconnected,
+}
+
+//gdb register numbers for riscv: x0-x31, then pc
+const REG_PC: usize = MAX_REGS;
+
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+const INTERRUPT: u8 = 0x03;
+
+/*
+    A GDB remote serial protocol stub, enough for `target remote` with breakpoints, watchpoints and
+    reverse execution (reverse-stepi, reverse-continue) on top of TimeTravel:
+
+        (gdb) set architecture riscv:rv64
+        (gdb) target remote :1234
+        (gdb) watch *(long *)0x12000
+        (gdb) reverse-continue
+        (gdb) monitor where
+        (gdb) monitor bt
+*/
+pub fn serve(emu: Emulator, port: u16, snapshot_interval: u64) -> Result<(), GdbErr> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+
+    let (stream, addr) = listener.accept()?;
+    eprintln!("gdb connected from {}", addr);
+
+    Stub::new(stream, TimeTravel::new(emu, snapshot_interval)).run()
+}
+
+struct Stub {
+    stream: TcpStream,
+    tt: TimeTravel,
+}
+
+impl Stub {
+    fn new(stream: TcpStream, tt: TimeTravel) -> Self {
+        Stub { stream, tt }
+    }
+
+    fn run(&mut self) -> Result<(), GdbErr> {
+        loop {
+            let packet = self.recv_packet()?;
+
+            let Some(reply) = self.handle(&packet) else {
+                return Ok(());
+            };
+
+            self.send_packet(&reply)?;
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, GdbErr> {
+        let mut byte = [0; 1];
+
+        match self.stream.read(&mut byte)? {
+            0 => Err(GdbErr::Disconnected),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    //$data#checksum, acks and stray interrupts between packets are skipped
+    fn recv_packet(&mut self) -> Result<String, GdbErr> {
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), GdbErr> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+
+        Ok(())
+    }
+
+    //true if gdb sent ^C while the guest was running
+    fn interrupted(stream: &TcpStream) -> bool {
+        let mut byte = [0; 1];
+
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let interrupted = matches!(stream.peek(&mut byte), Ok(1) if byte[0] == INTERRUPT);
+        let _ = stream.set_nonblocking(false);
+
+        if interrupted {
+            let _ = (&*stream).read(&mut byte);
+        }
+
+        interrupted
+    }
+
+    //None once gdb is done with the target
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_regs(),
+            "G" => self.write_regs(args),
+            "p" => self.read_reg(args),
+            "P" => self.write_reg(args),
+            "m" => self.read_mem(args),
+            "M" => self.write_mem(args),
+            "c" => {
+                let stream = &self.stream;
+                let stop = self.tt.cont(|| Self::interrupted(stream));
+                stop_reply(stop)
+            }
+            "s" => stop_reply(self.tt.step()),
+            "b" if args == "c" => stop_reply(self.tt.reverse_cont()),
+            "b" if args == "s" => stop_reply(self.tt.reverse_step()),
+            "Z" => self.set_point(args, true),
+            "z" => self.set_point(args, false),
+            "q" => self.query(args),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "D" => {
+                let _ = self.send_packet("OK");
+                return None;
+            }
+            "k" => return None,
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;ReverseStep+;ReverseContinue+", PACKET_SIZE);
+        }
+
+        if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',').and_then(|(o, l)| Some((parse_hex(o)? as usize, parse_hex(l)? as usize))) else {
+                return "E01".to_string();
+            };
+
+            let xml = target_xml(self.xlen());
+            let end = offset.saturating_add(len).min(xml.len());
+            let chunk = xml.get(offset.min(end)..end).unwrap_or("");
+            let more = end < xml.len();
+
+            return format!("{}{}", if more { "m" } else { "l" }, chunk);
+        }
+
+        //`monitor <cmd>`, the reply is the output gdb prints
+        if let Some(cmd) = args.strip_prefix("Rcmd,") {
+            let Some(cmd) = hex_decode(cmd).and_then(|cmd| String::from_utf8(cmd).ok()) else {
+                return "E01".to_string();
+            };
+
+            return hex_encode(self.monitor(cmd.trim()).as_bytes());
+        }
+
+        match args {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn monitor(&self, cmd: &str) -> String {
+        let emu = self.tt.emu();
+        let pc = emu.cpu.get_pc();
+
+        match cmd {
+            "where" => match triage::describe_code(emu, pc) {
+                Some(location) => format!("pc {:#x} in {}\n", pc, location),
+                None => format!("pc {:#x}, no symbol or line information\n", pc),
+            },
+            "bt" | "backtrace" => triage::format_backtrace(emu),
+            _ => format!("unknown monitor command: {}, try: where, bt\n", cmd),
+        }
+    }
+
+    fn xlen(&self) -> Xlen {
+        self.tt.emu().cpu.xlen()
+    }
+
+    //registers go over the wire as XLEN bit little endian numbers
+    fn encode_reg(&self, value: u64) -> String {
+        hex_encode(&value.to_le_bytes()[..self.xlen().bytes()])
+    }
+
+    fn get_reg(&self, reg: usize) -> Option<u64> {
+        let cpu = &self.tt.emu().cpu;
+
+        match reg {
+            REG_PC => Some(cpu.get_pc()),
+            _ => cpu.get_reg(reg).ok(),
+        }
+    }
+
+    fn set_reg(&mut self, reg: usize, value: u64) -> bool {
+        let cpu = &mut self.tt.emu_mut().cpu;
+
+        match reg {
+            REG_PC => {
+                cpu.set_pc(value);
+                true
+            }
+            _ => cpu.set_reg(reg, value).is_ok(),
+        }
+    }
+
+    fn read_regs(&self) -> String {
+        (0..=REG_PC).map(|reg| self.encode_reg(self.get_reg(reg).unwrap_or(0))).collect()
+    }
+
+    fn write_regs(&mut self, args: &str) -> String {
+        let Some(data) = hex_decode(args) else {
+            return "E01".to_string();
+        };
+
+        for (reg, value) in data.chunks_exact(self.xlen().bytes()).take(REG_PC + 1).enumerate() {
+            let value = decode_reg(value).unwrap_or(0);
+            self.set_reg(reg, value);
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_reg(&self, args: &str) -> String {
+        match parse_hex(args).and_then(|reg| self.get_reg(reg as usize)) {
+            Some(value) => self.encode_reg(value),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_reg(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(reg, value)| {
+            let bytes = hex_decode(value)?;
+            if bytes.len() != self.xlen().bytes() {
+                return None;
+            }
+            Some((parse_hex(reg)? as usize, decode_reg(&bytes)?))
+        });
+
+        match parsed {
+            Some((reg, value)) if self.set_reg(reg, value) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    /*
+        The debugger sees memory regardless of the guest's permissions. A reply holds two hex digits per
+        byte, so longer reads are cut to what fits in a packet and gdb asks again for the rest.
+    */
+    fn read_mem(&self, args: &str) -> String {
+        let Some((addr, len)) = args.split_once(',').and_then(|(a, l)| Some((parse_hex(a)?, parse_hex(l)?))) else {
+            return "E01".to_string();
+        };
+
+        let len = (len as usize).min(PACKET_SIZE / 2);
+        match self.tt.emu().mmu.dram_read(addr as usize, len) {
+            Ok(data) => hex_encode(&data),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    fn write_mem(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = range.split_once(',')?;
+            let data = hex_decode(data)?;
+
+            (parse_hex(len)? as usize == data.len()).then_some((parse_hex(addr)?, data))
+        });
+
+        let Some((addr, data)) = parsed else {
+            return "E01".to_string();
+        };
+
+        match self.tt.emu_mut().mmu.dram_write(addr as usize, &data) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E14".to_string(),
+        }
+    }
+
+    //Z<type>,<addr>,<kind>: 0/1 breakpoints, 2 write, 3 read and 4 access watchpoints
+    fn set_point(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) else {
+            return "E01".to_string();
+        };
+
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.tt.add_breakpoint(addr);
+                } else {
+                    self.tt.remove_breakpoint(addr);
+                }
+
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint { kind: watch, addr, len };
+        if insert {
+            self.tt.add_watchpoint(watchpoint);
+        } else {
+            self.tt.remove_watchpoint(watchpoint);
+        }
+
+        "OK".to_string()
+    }
+}
+
+fn stop_reply(stop: StopReason) -> String {
+    match stop {
+        StopReason::Step => format!("S{:02x}", SIGTRAP),
+        StopReason::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+        StopReason::Watchpoint { kind, addr } => {
+            let name = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+        }
+        StopReason::Interrupted => format!("S{:02x}", SIGINT),
+        StopReason::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+        StopReason::Exited(code) => format!("W{:02x}", code as u8),
+        StopReason::Trap(exception) => format!("S{:02x}", signal_for(&exception)),
+        //a hook stopping the run is the guest hitting a watch or breakpoint the debugger didn't set
+        StopReason::Error(err @ (EmulatorErr::ErrStopped(_) | EmulatorErr::ErrHookStopped(_))) => {
+            eprintln!("{}", err);
+            format!("S{:02x}", SIGTRAP)
+        }
+        StopReason::Error(err) => {
+            eprintln!("{}", err);
+            format!("S{:02x}", SIGABRT)
+        }
+    }
+}
+
+fn signal_for(exception: &Exceptions) -> u8 {
+    match exception {
+        Exceptions::ExceptionInstructionAddressMisaligned(_) | Exceptions::ExceptionLoadAddressMisaligned(_) | Exceptions::ExceptionStoreAddressMisaligned(_) => SIGBUS,
+        Exceptions::ExceptionAccessFault(_) | Exceptions::ExceptionLoadAccessFault(_) | Exceptions::ExceptionStoreAccessFault(_) |
+        Exceptions::ExceptionPageFault(_) | Exceptions::ExceptionLoadPageFault(_) | Exceptions::ExceptionStorePageFault(_) => SIGSEGV,
+        Exceptions::ExceptionIllegalInstruction(_) => SIGILL,
+        Exceptions::ExceptionBreakpoint(_) | Exceptions::ExceptionEnvironmentCall(_) => SIGTRAP,
+    }
+}
+
+fn target_xml(xlen: Xlen) -> String {
+    let mut regs = String::new();
+
+    for reg in 0..MAX_REGS {
+        let kind = match reg {
+            1 => "code_ptr",
+            2 => "data_ptr",
+            _ => "int",
+        };
+        regs += &format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>", decoder::reg_name(reg as u32), xlen.bits(), kind, reg);
+    }
+    regs += &format!("<reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\" regnum=\"{}\"/>", xlen.bits(), REG_PC);
+
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+         <architecture>riscv:rv{}</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">{}</feature></target>",
+        xlen.bits(), regs
+    )
+}
+
+fn decode_reg(bytes: &[u8]) -> Option<u64> {
+    let mut value = [0; 8];
+    value.get_mut(..bytes.len())?.copy_from_slice(bytes);
+
+    Some(u64::from_le_bytes(value))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_hex(val: &str) -> Option<u64> {
+    u64::from_str_radix(val, 16).ok()
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(val: &str) -> Option<Vec<u8>> {
+    if !val.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..val.len()).step_by(2).map(|i| u8::from_str_radix(val.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing::{self, DATA_BASE};
+
+    //a stub on a loopback connection nobody talks to, packets are handed to it directly
+    fn stub() -> Stub {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut emu = testing::emulator(&[0x0000_0073]);
+        emu.mmu.dram_write(DATA_BASE as usize, b"\x13\x37").unwrap();
+
+        Stub::new(stream, TimeTravel::new(emu, 16))
+    }
+
+    #[test]
+    fn memory_reads_fit_in_a_packet() {
+        let mut stub = stub();
+
+        assert_eq!(stub.handle(&format!("m{:x},4", DATA_BASE)).unwrap(), "13370000");
+        //the text page and the data page after it, cut to half a packet
+        assert_eq!(stub.handle(&format!("m{:x},ffffffffffffffff", DATA_BASE - 0x1000)).unwrap().len(), PACKET_SIZE);
+
+        assert_eq!(stub.handle("m0,4").unwrap(), "E01");
+        assert_eq!(stub.handle(&format!("m{:x},2000", DATA_BASE)).unwrap(), "E01");
+        assert_eq!(stub.handle("m2000").unwrap(), "E01");
+    }
+
+    #[test]
+    fn memory_writes_need_a_matching_length() {
+        let mut stub = stub();
+
+        assert_eq!(stub.handle(&format!("M{:x},2:abcd", DATA_BASE)).unwrap(), "OK");
+        assert_eq!(stub.handle(&format!("m{:x},2", DATA_BASE)).unwrap(), "abcd");
+        assert_eq!(stub.handle(&format!("M{:x},3:abcd", DATA_BASE)).unwrap(), "E01");
+        assert_eq!(stub.handle(&format!("M{:x},ffffffffffffffff:00", DATA_BASE)).unwrap(), "E01");
+    }
+
+    #[test]
+    fn target_description_is_read_in_chunks() {
+        let mut stub = stub();
+        let xml = target_xml(Xlen::Rv64);
+
+        assert_eq!(stub.handle("qXfer:features:read:target.xml:0,10").unwrap(), format!("m{}", &xml[..0x10]));
+        assert_eq!(stub.handle(&format!("qXfer:features:read:target.xml:{:x},ffff", xml.len() - 4)).unwrap(), format!("l{}", &xml[xml.len() - 4..]));
+        assert_eq!(stub.handle("qXfer:features:read:target.xml:0,ffffffffffffffff").unwrap(), format!("l{}", xml));
+        assert_eq!(stub.handle("qXfer:features:read:target.xml:ffffffffffffffff,ffffffffffffffff").unwrap(), "l");
+        assert_eq!(stub.handle("qXfer:features:read:target.xml:0").unwrap(), "E01");
+    }
+
+    #[test]
+    fn hex_helpers() {
+        assert_eq!(hex_encode(b"\x00\x7f\xff"), "007fff");
+        assert_eq!(hex_decode("007fff").unwrap(), b"\x00\x7f\xff");
+        assert_eq!(hex_decode("7ff"), None);
+        assert_eq!(hex_decode("zz"), None);
+        assert_eq!(decode_reg(&[0x34, 0x12]), Some(0x1234));
+        assert_eq!(decode_reg(&[0; 9]), None);
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{collections::HashSet, mem::{self, Discriminant}, sync::{Arc, Mutex}};
+use super::{args::ArgsErr, cpu::{self, Cpu}, decoder::{self, Inst}, exceptions::Exceptions, memory::{AccessKind, HookAction, MemAccess, Mmu}, Emulator, EmulatorErr};
+
+//which instructions an instruction hook runs before
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstHookAt {
+    Pc(u64),
+    //every instruction of the same kind, whatever its operands
+    Kind(Discriminant<Inst>),
+}
+
+impl InstHookAt {
+    pub fn kind(inst: &Inst) -> Self {
+        InstHookAt::Kind(mem::discriminant(inst))
+    }
+
+    /*
+        The kind of the instruction that disassembles to `mnemonic`. Every kind is told apart by its opcode,
+        funct3 and funct7, and the system instructions by the low bits of rs2 as well, so decoding every
+        combination of those turns up one instruction of each.
+    */
+    pub fn kind_named(mnemonic: &str) -> Option<Self> {
+        let mut seen = HashSet::new();
+
+        for opcode in (0..32).map(|op| op << 2 | 0b11) {
+            for funct3 in 0..8 {
+                for funct7 in 0..128 {
+                    for rs2 in [0, 1, 2, 5] {
+                        let inst = decoder::decode(opcode | funct3 << 12 | rs2 << 20 | funct7 << 25);
+
+                        if seen.insert(mem::discriminant(&inst)) && decoder::disassemble(inst).split(' ').next() == Some(mnemonic) {
+                            return Some(Self::kind(&inst));
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn matches(&self, pc: u64, inst: &Inst) -> bool {
+        match self {
+            InstHookAt::Pc(at) => *at == pc,
+            InstHookAt::Kind(kind) => *kind == mem::discriminant(inst),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstAction {
+    //the instruction executes, with whatever the hook changed
+    Continue,
+    //the instruction is stepped over as if it had done nothing
+    Skip,
+    //the instruction is replaced by a jump to the address
+    Jump(u64),
+    //the run stops before the instruction
+    Stop,
+}
+
+pub type InstHookFn = dyn FnMut(&mut Cpu, &mut Mmu, Inst) -> InstAction + Send;
+
+//shared with snapshots like the memory hooks
+#[derive(Clone)]
+struct InstHook {
+    at: InstHookAt,
+    hook: Arc<Mutex<InstHookFn>>,
+}
+
+#[derive(Clone, Default)]
+pub struct InstHooks {
+    hooks: Vec<InstHook>,
+}
+
+/*
+    Instruction hooks run before an instruction executes and get the hart and the memory to do what they
+    like with: patch out a check, stub a function, start or end a fuzz case. Memory hooks come after them,
+    an instruction a hook skipped or jumped over never gets fetched as far as those are concerned.
+
+    Where the hooks registered on the Mmu get to see the instructions' accesses. Loads are hooked after
+    the memory was read, so the hook sees (and may replace) the value, stores and instruction fetches
+    before anything happens, so the hook can change what is written or executed. Accesses the hooks
+    return `Skip` for are handled by the hook: a load gets its value even if the memory isn't mapped (MMIO
+    emulation), a store is dropped and an instruction is stepped over.
+*/
+impl Emulator {
+    /*
+        Registers a closure that runs before every instruction `at` matches, on whichever hart executes it.
+        Hooks run in the order they were added, until one of them doesn't just continue.
+    */
+    pub fn add_inst_hook<F>(&mut self, at: InstHookAt, hook: F)
+    where
+        F: FnMut(&mut Cpu, &mut Mmu, Inst) -> InstAction + Send + 'static,
+    {
+        self.inst_hooks.hooks.push(InstHook { at, hook: Arc::new(Mutex::new(hook)) });
+    }
+
+    pub(super) fn has_inst_hooks(&self) -> bool {
+        !self.inst_hooks.hooks.is_empty()
+    }
+
+    //false if a hook took the instruction's place, it then counts as retired
+    pub(super) fn hook_inst(&mut self, inst: Inst, size: u64) -> Result<bool, EmulatorErr> {
+        let pc = self.cpu.get_pc();
+
+        for hook in self.inst_hooks.hooks.iter().filter(|hook| hook.at.matches(pc, &inst)) {
+            let mut hook = hook.hook.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            let target = match hook(&mut self.cpu, &mut self.mmu, inst) {
+                InstAction::Continue => continue,
+                InstAction::Skip => pc + size,
+                InstAction::Jump(target) => target,
+                InstAction::Stop => return Err(EmulatorErr::ErrHookStopped(pc)),
+            };
+
+            self.cpu.set_pc(target);
+            self.icount += 1;
+
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    //--set-reg, --stub, --skip, --stop-at and --stop-on
+    pub(super) fn add_cli_hooks(&mut self, set_regs: &[(String, usize, u64)], stubs: &[(String, Option<u64>)], skips: &[String], stop_at: &[String], stop_on: &[InstHookAt]) -> Result<(), ArgsErr> {
+        let resolve = |addr: &String| self.resolve(addr).ok_or_else(|| ArgsErr::UnknownSymbol(addr.clone()));
+
+        let set_regs = set_regs.iter().map(|(addr, reg, value)| Ok((resolve(addr)?, *reg, *value))).collect::<Result<Vec<_>, ArgsErr>>()?;
+        let stubs = stubs.iter().map(|(addr, ret)| Ok((resolve(addr)?, *ret))).collect::<Result<Vec<_>, ArgsErr>>()?;
+        let skips = skips.iter().map(resolve).collect::<Result<Vec<_>, ArgsErr>>()?;
+        let stop_at = stop_at.iter().map(resolve).collect::<Result<Vec<_>, ArgsErr>>()?;
+
+        //registers are set first, so a stub or skip at the same address still sees the new value
+        for (addr, reg, value) in set_regs {
+            self.add_inst_hook(InstHookAt::Pc(addr), move |cpu, _, _| {
+                let _ = cpu.set_reg(reg, value);
+                InstAction::Continue
+            });
+        }
+
+        for (addr, ret) in stubs {
+            self.add_inst_hook(InstHookAt::Pc(addr), move |cpu, _, _| {
+                if let Some(ret) = ret {
+                    let _ = cpu.set_reg(cpu::REG_A0, ret);
+                }
+                InstAction::Jump(cpu.get_reg(cpu::REG_RA).unwrap_or(0))
+            });
+        }
+        for addr in skips {
+            self.add_inst_hook(InstHookAt::Pc(addr), |_, _, _| InstAction::Skip);
+        }
+        for addr in stop_at {
+            self.add_inst_hook(InstHookAt::Pc(addr), |_, _, _| InstAction::Stop);
+        }
+        for at in stop_on {
+            self.add_inst_hook(*at, |_, _, _| InstAction::Stop);
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn hook_load(&mut self, vaddr: u64, size: usize, result: Result<u64, EmulatorErr>) -> Result<u64, EmulatorErr> {
+        let mut access = self.access(AccessKind::Read, vaddr, size, *result.as_ref().unwrap_or(&0));
+
+        match self.mmu.run_hooks(&mut access) {
+            HookAction::Continue => result.map(|_| truncate(access.value, size)),
+            HookAction::Skip => Ok(truncate(access.value, size)),
+            HookAction::Stop => Err(EmulatorErr::ErrStopped(access)),
+        }
+    }
+
+    //the value to store, None if a hook dropped the store
+    pub(super) fn hook_store(&mut self, vaddr: u64, size: usize, value: u64) -> Result<Option<u64>, EmulatorErr> {
+        let mut access = self.access(AccessKind::Write, vaddr, size, value);
+
+        match self.mmu.run_hooks(&mut access) {
+            HookAction::Continue => Ok(Some(truncate(access.value, size))),
+            HookAction::Skip => Ok(None),
+            HookAction::Stop => Err(EmulatorErr::ErrStopped(access)),
+        }
+    }
+
+    //the instruction to execute instead of `inst`, None if a hook skipped it
+    pub(super) fn hook_exec(&mut self, inst: Inst, size: u64) -> Result<Option<Inst>, EmulatorErr> {
+        let pc = self.cpu.get_pc();
+        //it was just fetched from here, a failing fetch can't have made it this far
+        let raw = self.fetch_rinst().map_or(0, u64::from);
+        let mut access = self.access(AccessKind::Execute, pc, size as usize, raw);
+
+        match self.mmu.run_hooks(&mut access) {
+            HookAction::Continue if access.value == raw => Ok(Some(inst)),
+            HookAction::Continue => match decoder::decode(access.value as u32) {
+                Inst::Undefined => Err(Exceptions::ExceptionIllegalInstruction(access.value as u32).into()),
+                inst => Ok(Some(inst)),
+            },
+            HookAction::Skip => {
+                self.cpu.set_pc(pc + size);
+                self.icount += 1;
+
+                Ok(None)
+            }
+            HookAction::Stop => Err(EmulatorErr::ErrStopped(access)),
+        }
+    }
+
+    fn access(&self, kind: AccessKind, addr: u64, size: usize, value: u64) -> MemAccess {
+        MemAccess { addr, size, kind, value, pc: self.cpu.get_pc() }
+    }
+}
+
+fn truncate(value: u64, size: usize) -> u64 {
+    if size < 8 { value & ((1 << (size * 8)) - 1) } else { value }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+    use crate::emulator::testing::{self, CODE_BASE};
+
+    //a0 = 1, a1 = 2, a2 = a0 + a1, exits with a2
+    const ADD: [u32; 6] = [0x0010_0513, 0x0020_0593, 0x00b5_0633, 0x05d0_0893, 0x0006_0513, 0x0000_0073];
+
+    const ADD_AT: u64 = CODE_BASE + 8;
+    const ECALL_AT: u64 = CODE_BASE + 0x14;
+
+    fn exit_code(emu: &mut Emulator) -> u64 {
+        match emu.run(Some(100)) {
+            Err(EmulatorErr::ErrExited(code)) => code,
+            other => panic!("no exit: {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn kinds_are_found_by_mnemonic() {
+        for (mnemonic, raw) in [
+            ("ecall", 0x0000_0073), ("ebreak", 0x0010_0073), ("mret", 0x3020_0073), ("sret", 0x1020_0073), ("wfi", 0x1050_0073),
+            ("sfence.vma", 0x1200_0073), ("fence.i", 0x0000_100f), ("csrrw", 0x3005_9573), ("sc.w", 0x18b6_252f),
+            ("amoadd.d", 0x00b6_352f), ("srai", 0x4035_5513), ("add", 0x00b5_0633), ("addi", 0x0010_0513),
+        ] {
+            let at = InstHookAt::kind_named(mnemonic).unwrap_or_else(|| panic!("no {}", mnemonic));
+            assert!(at.matches(0, &decoder::decode(raw)), "{}", mnemonic);
+        }
+
+        assert!(!InstHookAt::kind_named("add").unwrap().matches(0, &decoder::decode(0x0010_0513)));
+        assert_eq!(InstHookAt::kind_named("addiu"), None);
+    }
+
+    #[test]
+    fn hooks_run_in_order_until_one_takes_over() {
+        let mut emu = testing::emulator(&ADD);
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        for action in [InstAction::Continue, InstAction::Skip, InstAction::Stop] {
+            let calls = calls.clone();
+            emu.add_inst_hook(InstHookAt::Pc(ADD_AT), move |_, _, _| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                action
+            });
+        }
+
+        //the skipped add leaves a2 at 0 and still counts as retired, the ecall that exits doesn't
+        assert_eq!(exit_code(&mut emu), 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(emu.icount, 5);
+    }
+
+    #[test]
+    fn hooks_get_the_hart_and_memory() {
+        let mut emu = testing::emulator(&ADD);
+
+        emu.add_inst_hook(InstHookAt::Pc(ADD_AT), |cpu, mmu, inst| {
+            assert!(matches!(inst, Inst::Add { .. }));
+            mmu.write_u64(testing::DATA_BASE, cpu.get_reg(cpu::REG_A0).unwrap()).unwrap();
+            cpu.set_reg(12, 9).unwrap();
+            InstAction::Jump(ADD_AT + 4)
+        });
+
+        assert_eq!(exit_code(&mut emu), 9);
+        assert_eq!(emu.mmu.read_u64(testing::DATA_BASE).unwrap(), 1);
+    }
+
+    #[test]
+    fn cli_hooks() {
+        let stop_on_ecall = [InstHookAt::kind_named("ecall").unwrap()];
+        let hex = |addr: u64| format!("{:#x}", addr);
+
+        let mut emu = testing::emulator(&ADD);
+        emu.add_cli_hooks(&[(hex(ADD_AT), cpu::REG_A0, 40)], &[], &[], &[], &[]).unwrap();
+        assert_eq!(exit_code(&mut emu), 42);
+
+        let mut emu = testing::emulator(&ADD);
+        emu.add_cli_hooks(&[], &[], &[hex(CODE_BASE + 4)], &[], &stop_on_ecall).unwrap();
+        assert!(matches!(emu.run(Some(100)), Err(EmulatorErr::ErrHookStopped(ECALL_AT))));
+        assert_eq!(emu.cpu.get_reg(12).unwrap(), 1);
+
+        let mut emu = testing::emulator(&ADD);
+        emu.add_cli_hooks(&[], &[(hex(CODE_BASE), Some(7))], &[], &[], &[]).unwrap();
+        emu.cpu.set_reg(cpu::REG_RA, ECALL_AT).unwrap();
+        emu.cpu.set_reg(17, 93).unwrap();
+        assert_eq!(exit_code(&mut emu), 7);
+
+        let mut emu = testing::emulator(&ADD);
+        assert!(matches!(emu.add_cli_hooks(&[], &[], &[], &["main".to_string()], &[]), Err(ArgsErr::UnknownSymbol(_))));
+
+        //a skipped RVC instruction is only 2 bytes long: c.li a0, 1; c.li a0, 2; exit
+        let mut emu = testing::emulator(&[0x4509_4505, 0x05d0_0893, 0x0000_0073]);
+        emu.add_cli_hooks(&[], &[], &[hex(CODE_BASE + 2)], &[], &[]).unwrap();
+        assert_eq!(exit_code(&mut emu), 1);
+    }
+}
@@ -0,0 +1,625 @@
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs an x86-64 linux host");
+
+mod x86;
+
+use std::{collections::HashMap, ffi::c_void, mem::offset_of, sync::Arc};
+use super::{block_cache::Block, cpu::MAX_REGS, decoder::Inst, memory, Emulator, EmulatorErr};
+use x86::{Alu, Assembler, Cond, Reg, Shift};
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum JitErr {
+    #[error("Unable to map executable memory")]
+    UnableToMapMemory,
+
+    #[error("JIT and interpreter diverged after {retired} instructions at pc {pc:#x}: {what}")]
+    Divergence { retired: u64, pc: u64, what: String },
+}
+
+//why the translated code returned
+const EXIT_BLOCK_END: u64 = 0;
+//the instruction at ctx.pc has to be executed by the interpreter: it faulted, or is not translated
+const EXIT_FALLBACK: u64 = 1;
+//same, for a memory access at ctx.access that missed the TLB or would fault
+const EXIT_ACCESS: u64 = 2;
+
+/*
+    Guest state as seen by translated code. rdi points to this struct for the whole block, guest registers
+    live in `regs` and are loaded/stored around every instruction.
+*/
+#[repr(C)]
+struct JitContext {
+    regs: [u64; MAX_REGS],
+    pc: u64,
+    tlb: *const memory::TlbEntry,
+    access: u64,
+    exit_reason: u64,
+    retired: u64,
+}
+
+const CTX: Reg = Reg::Rdi;
+
+fn reg_offset(reg: u32) -> i32 {
+    (offset_of!(JitContext, regs) + reg as usize * 8) as i32
+}
+
+//executable copy of a translated block, W^X: it is written while RW and only ever run once RX
+struct CodeBuf {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+//the mapping is immutable once created, sharing it between snapshots and threads is fine
+unsafe impl Send for CodeBuf {}
+unsafe impl Sync for CodeBuf {}
+
+impl CodeBuf {
+    fn new(code: &[u8]) -> Result<Self, JitErr> {
+        let len = code.len().max(1);
+
+        unsafe {
+            let ptr = mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if ptr == MAP_FAILED {
+                return Err(JitErr::UnableToMapMemory);
+            }
+
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                munmap(ptr, len);
+                return Err(JitErr::UnableToMapMemory);
+            }
+
+            Ok(CodeBuf { ptr, len })
+        }
+    }
+
+    fn call(&self, ctx: &mut JitContext) {
+        let func: extern "sysv64" fn(*mut JitContext) = unsafe { std::mem::transmute(self.ptr) };
+
+        func(ctx);
+    }
+}
+
+impl Drop for CodeBuf {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+struct JitBlock {
+    start: u64,
+    end: u64,
+    code: CodeBuf,
+}
+
+//translated blocks keyed by guest pc, invalidated together with the block cache they are built from
+#[derive(Clone, Default)]
+pub struct Jit {
+    blocks: HashMap<u64, Arc<JitBlock>>,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn invalidate(&mut self, ranges: &[(usize, usize)]) {
+        for (start, end) in ranges {
+            self.blocks.retain(|_, block| block.end <= *start as u64 || block.start >= *end as u64);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+    }
+
+    fn get_or_compile(&mut self, block: &Block) -> Result<Arc<JitBlock>, JitErr> {
+        if let Some(compiled) = self.blocks.get(&block.start) {
+            return Ok(compiled.clone());
+        }
+
+        let compiled = Arc::new(JitBlock {
+            start: block.start,
+            end: block.end,
+            code: CodeBuf::new(&translate(block))?,
+        });
+        self.blocks.insert(block.start, compiled.clone());
+
+        Ok(compiled)
+    }
+}
+
+impl Emulator {
+    pub fn enable_jit(&mut self) {
+        self.jit.get_or_insert_with(Jit::new);
+    }
+
+    /*
+        Runs the block at pc as native code and returns how many instructions retired. When the block bails
+        out to the interpreter at its first instruction, that instruction is single stepped so we always make
+        progress, otherwise it is left for the next call. Traps are only ever raised at the start of a block.
+    */
+    pub(super) fn exec_block_jit(&mut self, budget: u64) -> Result<u64, EmulatorErr> {
+        let ranges = self.mmu.take_exec_writes();
+        self.block_cache.invalidate(ranges.clone());
+        if let Some(jit) = &mut self.jit {
+            jit.invalidate(&ranges);
+        }
+
+        let pc = self.cpu.get_pc();
+        let block = self.block_cache.get_or_build(pc, &self.mmu);
+
+        //the translated code has no way to stop early, blocks that do not fit the budget are interpreted
+        if block.insts.is_empty() || block.insts.len() as u64 > budget {
+            return self.exec_block(budget);
+        }
+
+        let Some(jit) = &mut self.jit else {
+            return self.exec_block(budget);
+        };
+        let compiled = jit.get_or_compile(&block)?;
+
+        let mut ctx = JitContext {
+            regs: *self.cpu.get_regs(),
+            pc,
+            tlb: self.mmu.tlb(),
+            access: 0,
+            exit_reason: EXIT_BLOCK_END,
+            retired: 0,
+        };
+
+        compiled.code.call(&mut ctx);
+
+        self.cpu.set_regs(ctx.regs);
+        self.cpu.set_pc(ctx.pc);
+
+        let mut retired = ctx.retired;
+        self.icount += retired;
+
+        if ctx.exit_reason == EXIT_ACCESS {
+            self.mmu.tlb_fill(ctx.access);
+        }
+
+        if ctx.exit_reason != EXIT_BLOCK_END && retired == 0 {
+            self.exec()?;
+            retired += 1;
+        }
+
+        Ok(retired)
+    }
+
+    /*
+        Differential check of the jit against the interpreter: both run from a copy of this state, one jit
+        block at a time, and registers, pc and memory are compared after every block. Only the jit's copy
+        writes output, the two only see the same input if it doesn't come from the host.
+    */
+    pub fn jit_differential(&self, max_insts: u64) -> Result<u64, JitErr> {
+        let mut jitted = self.clone();
+        let mut interpreted = self.clone();
+
+        jitted.enable_jit();
+        interpreted.jit = None;
+        interpreted.process.set_mute_output(true);
+
+        let mut retired = 0;
+
+        while retired < max_insts {
+            //with an unlimited budget a jit block is either translated code that retired `step` instructions
+            //without trapping or a single step, which the interpreter mirrors one step at a time
+            let jit_result = jitted.exec_block_jit(u64::MAX);
+            let step = match &jit_result {
+                Ok(step) => *step,
+                Err(_) => 1,
+            };
+            let interp_result = (0..step).try_for_each(|_| interpreted.exec());
+
+            if jit_result.is_err() != interp_result.is_err() {
+                return Err(JitErr::Divergence {
+                    retired,
+                    pc: interpreted.cpu.get_pc(),
+                    what: format!("jit: {:?}, interpreter: {:?}", jit_result.err(), interp_result.err()),
+                });
+            }
+
+            retired += step;
+
+            if jitted.cpu.get_pc() != interpreted.cpu.get_pc() {
+                return Err(JitErr::Divergence {
+                    retired,
+                    pc: interpreted.cpu.get_pc(),
+                    what: format!("pc {:#x} != {:#x}", jitted.cpu.get_pc(), interpreted.cpu.get_pc()),
+                });
+            }
+
+            for (i, (a, b)) in jitted.cpu.get_regs().iter().zip(interpreted.cpu.get_regs()).enumerate() {
+                if a != b {
+                    return Err(JitErr::Divergence {
+                        retired,
+                        pc: interpreted.cpu.get_pc(),
+                        what: format!("x{} {:#x} != {:#x}", i, a, b),
+                    });
+                }
+            }
+
+            if !jitted.mmu.same_contents(&interpreted.mmu) {
+                return Err(JitErr::Divergence {
+                    retired,
+                    pc: interpreted.cpu.get_pc(),
+                    what: "memory".to_string(),
+                });
+            }
+
+            //both stopped on the same fatal trap
+            if interp_result.is_err() {
+                break;
+            }
+        }
+
+        Ok(retired)
+    }
+}
+
+/*
+    Translation, one guest instruction at a time:
+
+        rax, rcx    operands / result
+        rdx, rsi    address computation and memory checks
+
+    Everything that needs help from the interpreter (jumps that touch the shadow call stack, environment
+    calls, fences, anything not translated) ends the block with EXIT_FALLBACK at that instruction. Every
+    access that misses the TLB, would fault or writes executable memory ends it with EXIT_ACCESS, the
+    interpreter then raises the exception or invalidates the caches like it normally would.
+*/
+fn translate(block: &Block) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    let mut fallbacks = Vec::new();
+
+    let mut pc = block.start;
+
+    for (i, (inst, size)) in block.insts.iter().enumerate() {
+        let retired = i as i32;
+
+        match *inst {
+            Inst::Addi { rd, rs1, imm } => alu_imm(&mut asm, rd, rs1, Alu::Add, imm),
+            Inst::Xori { rd, rs1, imm } => alu_imm(&mut asm, rd, rs1, Alu::Xor, imm),
+            Inst::Ori { rd, rs1, imm } => alu_imm(&mut asm, rd, rs1, Alu::Or, imm),
+            Inst::Andi { rd, rs1, imm } => alu_imm(&mut asm, rd, rs1, Alu::And, imm),
+            Inst::Addiw { rd, rs1, imm } => {
+                load_reg(&mut asm, Reg::Rax, rs1);
+                asm.alu32_imm(Alu::Add, Reg::Rax, imm);
+                asm.sext32(Reg::Rax, Reg::Rax);
+                store_reg(&mut asm, rd, Reg::Rax);
+            }
+            Inst::Slti { rd, rs1, imm } => set_imm(&mut asm, rd, rs1, imm, Cond::L),
+            Inst::Sltiu { rd, rs1, imm } => set_imm(&mut asm, rd, rs1, imm, Cond::B),
+            Inst::Slli { rd, rs1, shamt } => shift_imm(&mut asm, rd, rs1, Shift::Shl, shamt, false),
+            Inst::Srli { rd, rs1, shamt } => shift_imm(&mut asm, rd, rs1, Shift::Shr, shamt, false),
+            Inst::Srai { rd, rs1, shamt } => shift_imm(&mut asm, rd, rs1, Shift::Sar, shamt, false),
+            Inst::Slliw { rd, rs1, shamt } => shift_imm(&mut asm, rd, rs1, Shift::Shl, shamt, true),
+            Inst::Srliw { rd, rs1, shamt } => shift_imm(&mut asm, rd, rs1, Shift::Shr, shamt, true),
+            Inst::Sraiw { rd, rs1, shamt } => shift_imm(&mut asm, rd, rs1, Shift::Sar, shamt, true),
+            Inst::Lui { rd, imm } => {
+                asm.mov_imm64(Reg::Rax, ((imm as i64) << 12) as u64);
+                store_reg(&mut asm, rd, Reg::Rax);
+            }
+            Inst::Auipc { rd, imm } => {
+                asm.mov_imm64(Reg::Rax, pc.wrapping_add(((imm as i64) << 12) as u64));
+                store_reg(&mut asm, rd, Reg::Rax);
+            }
+
+            Inst::Add { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::Add, false),
+            Inst::Sub { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::Sub, false),
+            Inst::Xor { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::Xor, false),
+            Inst::Or { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::Or, false),
+            Inst::And { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::And, false),
+            Inst::Addw { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::Add, true),
+            Inst::Subw { rd, rs1, rs2 } => alu(&mut asm, rd, rs1, rs2, Alu::Sub, true),
+            Inst::Slt { rd, rs1, rs2 } => set_reg(&mut asm, rd, rs1, rs2, Cond::L),
+            Inst::Sltu { rd, rs1, rs2 } => set_reg(&mut asm, rd, rs1, rs2, Cond::B),
+            Inst::Sll { rd, rs1, rs2 } => shift_reg(&mut asm, rd, rs1, rs2, Shift::Shl, false),
+            Inst::Srl { rd, rs1, rs2 } => shift_reg(&mut asm, rd, rs1, rs2, Shift::Shr, false),
+            Inst::Sra { rd, rs1, rs2 } => shift_reg(&mut asm, rd, rs1, rs2, Shift::Sar, false),
+            Inst::Sllw { rd, rs1, rs2 } => shift_reg(&mut asm, rd, rs1, rs2, Shift::Shl, true),
+            Inst::Srlw { rd, rs1, rs2 } => shift_reg(&mut asm, rd, rs1, rs2, Shift::Shr, true),
+            Inst::Sraw { rd, rs1, rs2 } => shift_reg(&mut asm, rd, rs1, rs2, Shift::Sar, true),
+
+            Inst::Lb { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 1, true),
+            Inst::Lh { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 2, true),
+            Inst::Lw { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 4, true),
+            Inst::Ld { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 8, true),
+            Inst::Lbu { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 1, false),
+            Inst::Lhu { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 2, false),
+            Inst::Lwu { rd, rs1, imm } => mem_load(&mut asm, &mut fallbacks, pc, retired, rd, rs1, imm, 4, false),
+            Inst::Sb { rs2, rs1, imm } => mem_store(&mut asm, &mut fallbacks, pc, retired, rs2, rs1, imm, 1),
+            Inst::Sh { rs2, rs1, imm } => mem_store(&mut asm, &mut fallbacks, pc, retired, rs2, rs1, imm, 2),
+            Inst::Sw { rs2, rs1, imm } => mem_store(&mut asm, &mut fallbacks, pc, retired, rs2, rs1, imm, 4),
+            Inst::Sd { rs2, rs1, imm } => mem_store(&mut asm, &mut fallbacks, pc, retired, rs2, rs1, imm, 8),
+
+            Inst::Fence { .. } | Inst::FenceTso | Inst::Pause => {}
+
+            Inst::Beq { rs1, rs2, imm } => return branch(asm, fallbacks, pc, *size, retired, rs1, rs2, imm, Cond::E),
+            Inst::Bne { rs1, rs2, imm } => return branch(asm, fallbacks, pc, *size, retired, rs1, rs2, imm, Cond::Ne),
+            Inst::Blt { rs1, rs2, imm } => return branch(asm, fallbacks, pc, *size, retired, rs1, rs2, imm, Cond::L),
+            Inst::Bge { rs1, rs2, imm } => return branch(asm, fallbacks, pc, *size, retired, rs1, rs2, imm, Cond::Ge),
+            Inst::Bltu { rs1, rs2, imm } => return branch(asm, fallbacks, pc, *size, retired, rs1, rs2, imm, Cond::B),
+            Inst::Bgeu { rs1, rs2, imm } => return branch(asm, fallbacks, pc, *size, retired, rs1, rs2, imm, Cond::Ae),
+
+            _ => {
+                exit(&mut asm, pc, retired, EXIT_FALLBACK);
+                return finish(asm, fallbacks);
+            }
+        }
+
+        pc += size;
+    }
+
+    exit(&mut asm, block.end, block.insts.len() as i32, EXIT_BLOCK_END);
+    finish(asm, fallbacks)
+}
+
+struct Fallback {
+    fixups: Vec<x86::Fixup>,
+    pc: u64,
+    retired: i32,
+}
+
+//fallback exits are emitted out of line after the block, so the fast path stays straight
+fn finish(mut asm: Assembler, fallbacks: Vec<Fallback>) -> Vec<u8> {
+    for fallback in fallbacks {
+        for fixup in fallback.fixups {
+            asm.bind(fixup);
+        }
+        //only memory accesses fall back this way, rax still holds the address
+        asm.store64(CTX, offset_of!(JitContext, access) as i32, Reg::Rax);
+        exit(&mut asm, fallback.pc, fallback.retired, EXIT_ACCESS);
+    }
+
+    asm.code
+}
+
+fn exit(asm: &mut Assembler, pc: u64, retired: i32, reason: u64) {
+    asm.mov_imm64(Reg::Rax, pc);
+    asm.store64(CTX, offset_of!(JitContext, pc) as i32, Reg::Rax);
+    asm.store64_imm(CTX, offset_of!(JitContext, retired) as i32, retired);
+    asm.store64_imm(CTX, offset_of!(JitContext, exit_reason) as i32, reason as i32);
+    asm.ret();
+}
+
+fn load_reg(asm: &mut Assembler, dst: Reg, reg: u32) {
+    if reg == 0 {
+        asm.zero(dst);
+    } else {
+        asm.load64(dst, CTX, reg_offset(reg));
+    }
+}
+
+fn store_reg(asm: &mut Assembler, reg: u32, src: Reg) {
+    //x0 is hardwired to zero
+    if reg != 0 {
+        asm.store64(CTX, reg_offset(reg), src);
+    }
+}
+
+fn alu_imm(asm: &mut Assembler, rd: u32, rs1: u32, op: Alu, imm: i32) {
+    load_reg(asm, Reg::Rax, rs1);
+    asm.alu_imm(op, Reg::Rax, imm);
+    store_reg(asm, rd, Reg::Rax);
+}
+
+fn alu(asm: &mut Assembler, rd: u32, rs1: u32, rs2: u32, op: Alu, word: bool) {
+    load_reg(asm, Reg::Rax, rs1);
+    load_reg(asm, Reg::Rcx, rs2);
+    if word {
+        asm.alu32(op, Reg::Rax, Reg::Rcx);
+        asm.sext32(Reg::Rax, Reg::Rax);
+    } else {
+        asm.alu(op, Reg::Rax, Reg::Rcx);
+    }
+    store_reg(asm, rd, Reg::Rax);
+}
+
+fn set_imm(asm: &mut Assembler, rd: u32, rs1: u32, imm: i32, cond: Cond) {
+    load_reg(asm, Reg::Rcx, rs1);
+    asm.alu_imm(Alu::Cmp, Reg::Rcx, imm);
+    asm.setcc_rax(cond);
+    store_reg(asm, rd, Reg::Rax);
+}
+
+fn set_reg(asm: &mut Assembler, rd: u32, rs1: u32, rs2: u32, cond: Cond) {
+    load_reg(asm, Reg::Rcx, rs1);
+    load_reg(asm, Reg::Rdx, rs2);
+    asm.alu(Alu::Cmp, Reg::Rcx, Reg::Rdx);
+    asm.setcc_rax(cond);
+    store_reg(asm, rd, Reg::Rax);
+}
+
+fn shift_imm(asm: &mut Assembler, rd: u32, rs1: u32, op: Shift, shamt: u32, word: bool) {
+    load_reg(asm, Reg::Rax, rs1);
+    if word {
+        asm.shift32_imm(op, Reg::Rax, shamt as u8);
+        asm.sext32(Reg::Rax, Reg::Rax);
+    } else {
+        asm.shift_imm(op, Reg::Rax, shamt as u8);
+    }
+    store_reg(asm, rd, Reg::Rax);
+}
+
+//x86 masks the count in cl exactly like RISC-V masks rs2 (6 bits, 5 bits for the W forms)
+fn shift_reg(asm: &mut Assembler, rd: u32, rs1: u32, rs2: u32, op: Shift, word: bool) {
+    load_reg(asm, Reg::Rax, rs1);
+    load_reg(asm, Reg::Rcx, rs2);
+    if word {
+        asm.shift32_cl(op, Reg::Rax);
+        asm.sext32(Reg::Rax, Reg::Rax);
+    } else {
+        asm.shift_cl(op, Reg::Rax);
+    }
+    store_reg(asm, rd, Reg::Rax);
+}
+
+/*
+    Leaves rdx pointing at the host byte of guest address rs1+imm once the access was found to be aligned,
+    so inside one page, that page is in the TLB and its permissions masked with `perm_mask` equal
+    `perm_want`. Misaligned accesses are rare enough to leave them all to the interpreter.
+*/
+#[allow(clippy::too_many_arguments)]
+fn mem_check(asm: &mut Assembler, fallbacks: &mut Vec<Fallback>, pc: u64, retired: i32, rs1: u32, imm: i32, size: usize, perm_mask: u8, perm_want: u8) {
+    let mut fixups = Vec::new();
+
+    //rax = vaddr, which must be aligned
+    load_reg(asm, Reg::Rax, rs1);
+    asm.alu_imm(Alu::Add, Reg::Rax, imm);
+    if size > 1 {
+        asm.mov(Reg::Rcx, Reg::Rax);
+        asm.alu_imm(Alu::And, Reg::Rcx, size as i32 - 1);
+        fixups.push(asm.jcc(Cond::Ne));
+    }
+
+    //rcx = page number, rsi = &tlb[page number % TLB_ENTRIES]
+    asm.mov(Reg::Rcx, Reg::Rax);
+    asm.shift_imm(Shift::Shr, Reg::Rcx, memory::PAGE_SIZE.trailing_zeros() as u8);
+    asm.mov(Reg::Rsi, Reg::Rcx);
+    asm.alu_imm(Alu::And, Reg::Rsi, memory::TLB_ENTRIES as i32 - 1);
+    asm.shift_imm(Shift::Shl, Reg::Rsi, memory::TLB_ENTRY_SHIFT);
+    asm.load64(Reg::Rdx, CTX, offset_of!(JitContext, tlb) as i32);
+    asm.alu(Alu::Add, Reg::Rsi, Reg::Rdx);
+
+    asm.load64(Reg::Rdx, Reg::Rsi, offset_of!(memory::TlbEntry, vpn) as i32);
+    asm.alu(Alu::Cmp, Reg::Rdx, Reg::Rcx);
+    fixups.push(asm.jcc(Cond::Ne));
+
+    asm.load64(Reg::Rcx, Reg::Rsi, offset_of!(memory::TlbEntry, perm) as i32);
+    asm.alu32_imm(Alu::And, Reg::Rcx, perm_mask as i32);
+    asm.alu32_imm(Alu::Cmp, Reg::Rcx, perm_want as i32);
+    fixups.push(asm.jcc(Cond::Ne));
+
+    //rdx = &page[vaddr % PAGE_SIZE]
+    asm.mov(Reg::Rcx, Reg::Rax);
+    asm.alu_imm(Alu::And, Reg::Rcx, memory::PAGE_SIZE as i32 - 1);
+    asm.load64(Reg::Rdx, Reg::Rsi, offset_of!(memory::TlbEntry, data) as i32);
+    asm.alu(Alu::Add, Reg::Rdx, Reg::Rcx);
+
+    fallbacks.push(Fallback { fixups, pc, retired });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn mem_load(asm: &mut Assembler, fallbacks: &mut Vec<Fallback>, pc: u64, retired: i32, rd: u32, rs1: u32, imm: i32, size: usize, signed: bool) {
+    mem_check(asm, fallbacks, pc, retired, rs1, imm, size, memory::PERM_R, memory::PERM_R);
+    asm.load_mem(Reg::Rax, Reg::Rdx, size, signed);
+    store_reg(asm, rd, Reg::Rax);
+}
+
+//stores to executable memory are left to the interpreter, they have to invalidate translated code
+#[allow(clippy::too_many_arguments)]
+fn mem_store(asm: &mut Assembler, fallbacks: &mut Vec<Fallback>, pc: u64, retired: i32, rs2: u32, rs1: u32, imm: i32, size: usize) {
+    mem_check(asm, fallbacks, pc, retired, rs1, imm, size, memory::PERM_W | memory::PERM_X, memory::PERM_W);
+    load_reg(asm, Reg::Rcx, rs2);
+    asm.store_mem(Reg::Rdx, Reg::Rcx, size);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn branch(mut asm: Assembler, fallbacks: Vec<Fallback>, pc: u64, size: u64, retired: i32, rs1: u32, rs2: u32, imm: i32, cond: Cond) -> Vec<u8> {
+    load_reg(&mut asm, Reg::Rax, rs1);
+    load_reg(&mut asm, Reg::Rcx, rs2);
+    asm.alu(Alu::Cmp, Reg::Rax, Reg::Rcx);
+    let taken = asm.jcc(cond);
+
+    exit(&mut asm, pc + size, retired + 1, EXIT_BLOCK_END);
+
+    asm.bind(taken);
+    exit(&mut asm, pc.wrapping_add_signed(imm as i64), retired + 1, EXIT_BLOCK_END);
+
+    finish(asm, fallbacks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{exceptions::Exceptions, hooks::{InstAction, InstHookAt}, testing};
+
+    /*
+        100 times: adds the counter into one of 32 dwords at DATA_BASE and stores it misaligned at
+        DATA_BASE + 3, then exits with the first dword
+    */
+    const LOOP: [u32; 15] = [
+        0x0000_2937, 0x0000_0413, 0x0640_0493, 0x01f4_7293, 0x0032_9293, 0x0059_0333, 0x0003_3383, 0x0083_83b3,
+        0x0073_3023, 0x0089_21a3, 0x0014_0413, 0xfe94_40e3, 0x0009_3503, 0x05d0_0893, 0x0000_0073,
+    ];
+
+    //loads from DATA_BASE twice, takes every permission away from its page with mprotect and loads again
+    const LOAD_AFTER_MPROTECT: [u32; 9] = [
+        0x0000_2937, 0x0009_3283, 0x0009_3283, 0x0009_0513, 0x0000_15b7, 0x0000_0613, 0x0e20_0893, 0x0000_0073,
+        0x0009_3283,
+    ];
+
+    #[test]
+    fn jit_and_interpreter_agree() {
+        let emu = testing::emulator(&LOOP);
+
+        let retired = emu.jit_differential(u64::MAX).unwrap();
+        assert_eq!(retired, 3 + 100 * 9 + 3);
+
+        let mut jitted = emu.clone();
+        let mut interpreted = emu.clone();
+        jitted.enable_jit();
+
+        let (Err(EmulatorErr::ErrExited(jit_code)), Err(EmulatorErr::ErrExited(code))) = (jitted.run(None), interpreted.run(None)) else {
+            panic!("both should exit");
+        };
+        assert_eq!(jit_code, code);
+        assert_eq!(jitted.icount, interpreted.icount);
+    }
+
+    #[test]
+    fn divergence_is_reported() {
+        //only the interpreter runs hooks, skipping the store on its side makes memory differ
+        let mut emu = testing::emulator(&LOOP);
+        emu.add_inst_hook(InstHookAt::Pc(testing::CODE_BASE + 0x20), |_, _, _| InstAction::Skip);
+
+        match emu.jit_differential(u64::MAX) {
+            Err(JitErr::Divergence { pc, what, .. }) => {
+                assert_eq!(pc, testing::CODE_BASE + 0x24);
+                assert_eq!(what, "memory");
+            }
+            result => panic!("expected a divergence, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn mprotect_evicts_translated_accesses() {
+        let mut emu = testing::emulator(&LOAD_AFTER_MPROTECT);
+        emu.enable_jit();
+
+        let err = emu.run(Some(100)).unwrap_err();
+        assert!(matches!(err, EmulatorErr::ErrTrap(Exceptions::ExceptionLoadAccessFault(addr)) if addr as u64 == testing::DATA_BASE));
+        assert_eq!(emu.cpu.get_pc(), testing::CODE_BASE + 0x20);
+    }
+
+    #[test]
+    fn unmap_evicts_the_tlb_entry() {
+        let mut emu = testing::emulator(&LOOP);
+        let entry = |emu: &Emulator| unsafe { *emu.mmu.tlb().add(testing::DATA_BASE as usize / memory::PAGE_SIZE % memory::TLB_ENTRIES) };
+
+        emu.mmu.tlb_fill(testing::DATA_BASE);
+        assert_eq!(entry(&emu).vpn, testing::DATA_BASE / memory::PAGE_SIZE as u64);
+
+        emu.mmu.unmap(testing::DATA_BASE as usize, testing::DATA_SIZE).unwrap();
+        assert_ne!(entry(&emu).vpn, testing::DATA_BASE / memory::PAGE_SIZE as u64);
+    }
+}
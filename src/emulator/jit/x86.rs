@@ -0,0 +1,215 @@
+/*
+    Just enough of an x86-64 assembler for the jit. Only the low eight registers are used, so apart from
+    REX.W no REX bits are ever needed.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rsi = 6,
+    Rdi = 7,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Alu {
+    Add,
+    Or,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+}
+
+impl Alu {
+    //opcode of the `op r/m, reg` form
+    fn opcode(self) -> u8 {
+        match self {
+            Alu::Add => 0x01,
+            Alu::Or => 0x09,
+            Alu::And => 0x21,
+            Alu::Sub => 0x29,
+            Alu::Xor => 0x31,
+            Alu::Cmp => 0x39,
+        }
+    }
+
+    //modrm.reg extension of the `op r/m, imm32` form
+    fn ext(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Cond {
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    L = 0xc,
+    Ge = 0xd,
+}
+
+//a rel32 that still has to be pointed at its target
+pub struct Fixup(usize);
+
+#[derive(Default)]
+pub struct Assembler {
+    pub code: Vec<u8>,
+}
+
+const REX_W: u8 = 0x48;
+
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    (mode << 6) | ((reg & 7) << 3) | (rm & 7)
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, val: u32) {
+        self.emit(&val.to_le_bytes());
+    }
+
+    //mov reg, [base + disp32]
+    pub fn load64(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.emit(&[REX_W, 0x8b, modrm(0b10, dst as u8, base as u8)]);
+        self.emit_u32(disp as u32);
+    }
+
+    //mov [base + disp32], reg
+    pub fn store64(&mut self, base: Reg, disp: i32, src: Reg) {
+        self.emit(&[REX_W, 0x89, modrm(0b10, src as u8, base as u8)]);
+        self.emit_u32(disp as u32);
+    }
+
+    //mov qword [base + disp32], sign extended imm32
+    pub fn store64_imm(&mut self, base: Reg, disp: i32, imm: i32) {
+        self.emit(&[REX_W, 0xc7, modrm(0b10, 0, base as u8)]);
+        self.emit_u32(disp as u32);
+        self.emit_u32(imm as u32);
+    }
+
+    pub fn mov_imm64(&mut self, dst: Reg, imm: u64) {
+        self.emit(&[REX_W, 0xb8 + dst as u8]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.emit(&[REX_W, 0x89, modrm(0b11, src as u8, dst as u8)]);
+    }
+
+    pub fn zero(&mut self, dst: Reg) {
+        self.emit(&[0x31, modrm(0b11, dst as u8, dst as u8)]);
+    }
+
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.emit(&[REX_W, op.opcode(), modrm(0b11, src as u8, dst as u8)]);
+    }
+
+    pub fn alu32(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.emit(&[op.opcode(), modrm(0b11, src as u8, dst as u8)]);
+    }
+
+    pub fn alu_imm(&mut self, op: Alu, dst: Reg, imm: i32) {
+        self.emit(&[REX_W, 0x81, modrm(0b11, op.ext(), dst as u8)]);
+        self.emit_u32(imm as u32);
+    }
+
+    pub fn alu32_imm(&mut self, op: Alu, dst: Reg, imm: i32) {
+        self.emit(&[0x81, modrm(0b11, op.ext(), dst as u8)]);
+        self.emit_u32(imm as u32);
+    }
+
+    //shift by cl
+    pub fn shift_cl(&mut self, op: Shift, dst: Reg) {
+        self.emit(&[REX_W, 0xd3, modrm(0b11, op as u8, dst as u8)]);
+    }
+
+    pub fn shift32_cl(&mut self, op: Shift, dst: Reg) {
+        self.emit(&[0xd3, modrm(0b11, op as u8, dst as u8)]);
+    }
+
+    pub fn shift_imm(&mut self, op: Shift, dst: Reg, imm: u8) {
+        self.emit(&[REX_W, 0xc1, modrm(0b11, op as u8, dst as u8), imm]);
+    }
+
+    pub fn shift32_imm(&mut self, op: Shift, dst: Reg, imm: u8) {
+        self.emit(&[0xc1, modrm(0b11, op as u8, dst as u8), imm]);
+    }
+
+    //movsxd dst, src32
+    pub fn sext32(&mut self, dst: Reg, src: Reg) {
+        self.emit(&[REX_W, 0x63, modrm(0b11, dst as u8, src as u8)]);
+    }
+
+    //setcc al; movzx eax, al
+    pub fn setcc_rax(&mut self, cond: Cond) {
+        self.emit(&[0x0f, 0x90 | cond as u8, 0xc0]);
+        self.emit(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    //load `size` bytes from [base], sign or zero extended to 64 bits
+    pub fn load_mem(&mut self, dst: Reg, base: Reg, size: usize, signed: bool) {
+        let m = modrm(0b00, dst as u8, base as u8);
+
+        match (size, signed) {
+            (1, false) => self.emit(&[0x0f, 0xb6, m]),
+            (1, true) => self.emit(&[REX_W, 0x0f, 0xbe, m]),
+            (2, false) => self.emit(&[0x0f, 0xb7, m]),
+            (2, true) => self.emit(&[REX_W, 0x0f, 0xbf, m]),
+            (4, false) => self.emit(&[0x8b, m]),
+            (4, true) => self.emit(&[REX_W, 0x63, m]),
+            _ => self.emit(&[REX_W, 0x8b, m]),
+        }
+    }
+
+    //store the low `size` bytes of src to [base]
+    pub fn store_mem(&mut self, base: Reg, src: Reg, size: usize) {
+        let m = modrm(0b00, src as u8, base as u8);
+
+        match size {
+            1 => self.emit(&[0x88, m]),
+            2 => self.emit(&[0x66, 0x89, m]),
+            4 => self.emit(&[0x89, m]),
+            _ => self.emit(&[REX_W, 0x89, m]),
+        }
+    }
+
+    pub fn jcc(&mut self, cond: Cond) -> Fixup {
+        self.emit(&[0x0f, 0x80 | cond as u8]);
+        self.emit_u32(0);
+        Fixup(self.code.len())
+    }
+
+    //points a jump at the current end of the code
+    pub fn bind(&mut self, fixup: Fixup) {
+        let rel = (self.code.len() - fixup.0) as u32;
+        self.code[fixup.0 - 4..fixup.0].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    pub fn ret(&mut self) {
+        self.emit(&[0xc3]);
+    }
+}
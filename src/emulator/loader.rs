@@ -1,9 +1,7 @@
 use std::{fs, io::Error, path::{self, Path}};
-use super::memory::{self, Mmu};
+use super::{cpu::Xlen, dwarf::LineTable, elf::{self, MAGIC_ELF}, memory::{self, Mmu}, symbols::Symbols, unwind::EhFrame};
 use thiserror::Error;
 
-const MAGIC_ELF: [u8; 4] = [0x7F, 0x45, 0x4C, 0x46];        //.ELF
-
 pub enum FileType {
     Elf,
 }
@@ -11,8 +9,22 @@ pub enum FileType {
 pub struct File {
     pub file_type: FileType, 
     pub entry_point: u64,
-}
 
+    //RV32 for an ELF32 file
+    pub xlen: Xlen,
+
+    //end of the highest loaded segment, the program break starts after it
+    pub image_end: u64,
+
+    //.symtab and .dynsym, empty for a stripped binary
+    pub symbols: Symbols,
+
+    //.debug_line, empty without debug info
+    pub lines: LineTable,
+
+    //.eh_frame, for unwinding the guest's stack
+    pub eh_frame: EhFrame,
+}
 
 #[derive(Error, Debug)]
 pub enum LoaderErr {
@@ -22,8 +34,26 @@ pub enum LoaderErr {
     #[error("Unsupported file type")]
     UnsupportedFileType,
     
-    #[error("Invalid file")]
-    InvalidFile,
+    #[error("Invalid ELF file: {0}")]
+    InvalidElf(#[from] elf::ElfErr),
+
+    #[error("Unsupported endianness, RISC-V binaries are little endian")]
+    UnsupportedEndianness,
+
+    #[error("Unsupported ELF type {0}, only executables can be loaded")]
+    UnsupportedType(u16),
+
+    #[error("Unsupported machine {0}, not a RISC-V binary")]
+    UnsupportedMachine(u16),
+
+    #[error("No entry point")]
+    NoEntryPoint,
+
+    #[error("No loadable segments")]
+    NoLoadableSegments,
+
+    #[error("Segment at {0:#x} is outside the host's address space")]
+    SegmentOutOfMemory(u64),
     
     #[error("DRAM I/O Fail: {0}")]
     DramIoFail(#[from] memory::MmmuErr),
@@ -43,290 +73,169 @@ pub fn load_file_to_dram<P: AsRef<Path>>(mmu: &mut Mmu, file: &P) -> Result<File
 }
 
 mod elf_loader {
+    use crate::emulator::cpu::Xlen;
     use crate::emulator::memory::{self, Mmu};
+    use crate::emulator::dwarf::{LineTable, Strings};
+    use crate::emulator::elf::{self, Elf};
+    use crate::emulator::symbols::{Symbol, SymbolKind, Symbols};
+    use crate::emulator::unwind::EhFrame;
     use super::LoaderErr;
     use super::{File, FileType};
 
-    const EI_NIDENT: usize = 16;
-    const EI_CLASS: usize = 4;
-    const ELFCLASS64: u8 = 02;
-    const EI_DATA: usize =  5;
-    const ELFDATA2LSB: u8 = 01;
-    const ET_EXEC: u16 = 02;
-    const EM_RISCV: u16 = 243;
-    const PT_LOAD: u32 = 01;
-    const PF_R:u32 = 0x4;
-    const PF_W:u32 = 0x2;
-    const PF_X:u32 = 0x1;
-
-    /*
-           typedef struct {
-               uint32_t   sh_name;
-               uint32_t   sh_type;
-               uint64_t   sh_flags;
-               Elf64_Addr sh_addr;
-               Elf64_Off  sh_offset;
-               uint64_t   sh_size;
-               uint32_t   sh_link;
-               uint32_t   sh_info;
-               uint64_t   sh_addralign;
-               uint64_t   sh_entsize;
-           } Elf64_Shdr;
-    */
-    #[derive(Debug)]
-    #[repr(C)]
-    struct SectionHeader {
-        sh_name: u32,
-        sh_type: u32,
-        sh_flags: u64,
-        sh_addr: u64,
-        sh_offset: u64,
-        sh_size: u64,
-        sh_link: u32,
-        sh_info: u32,
-        sh_addralign: u64,
-        sh_entsize: u64,
-    }
+    const STT_NOTYPE: u8 = 0;
+    const STT_OBJECT: u8 = 1;
+    const STT_FUNC: u8 = 2;
+    const STB_LOCAL: u8 = 0;
+
+    //defined functions, objects and labels of every symbol table
+    fn parse_symbols(elf: &Elf) -> Result<Symbols, LoaderErr> {
+        let mut symbols = Vec::new();
+
+        for entry in elf.symbols()? {
+            let kind = match entry.st_info & 0xf {
+                STT_FUNC => SymbolKind::Function,
+                STT_OBJECT => SymbolKind::Object,
+                STT_NOTYPE => SymbolKind::Other,
+                _ => continue,
+            };
+
+            if entry.st_shndx == elf::SHN_UNDEF {
+                continue;
+            }
 
-    /*           
-             typedef struct {
-               uint32_t   p_type;
-               uint32_t   p_flagsz;
-               Elf64_Off  p_offset;
-               Elf64_Addr p_vaddr;
-               Elf64_Addr p_paddr;
-               uint64_t   p_filesz;
-               uint64_t   p_memsz;
-               uint64_t   p_align;
-           } Elf64_Phdr; 
-    */
-    #[derive(Debug)]
-    #[repr(C)]
-    struct ProgramHeader{
-        p_type: u32,
-        p_flagsz: u32,
-        p_offset: u64,
-        p_vaddr: u64,
-        p_paddr: u64,
-        p_filesz: u64,
-        p_memsz: u64,
-        p_align: u64,
-    }
+            //$x/$d mark code and data for disassemblers, .L are assembler temporaries
+            if entry.name.is_empty() || entry.name.starts_with(b"$") || entry.name.starts_with(b".L") {
+                continue;
+            }
 
-    /*         
-                 typedef struct {
-                   unsigned char e_ident[EI_NIDENT];
-                   uint16_t      e_type;
-                   uint16_t      e_machine;
-                   uint32_t      e_version;
-                   ElfN_Addr     e_entry;
-                   ElfN_Off      e_phoff;       //offset to prgram_header
-                   ElfN_Off      e_shoff;
-                   uint32_t      e_flags;
-                   uint16_t      e_ehsize;
-                   uint16_t      e_phentsize;   //size in bytes of one entry of program_header_table
-                   uint16_t      e_phnum;       //number of entries in the program_header_table.
-                   uint16_t      e_shentsize;
-                   uint16_t      e_shnum;
-                   uint16_t      e_shstrndx;
-               } ElfN_Ehdr;
-
-                The following types are used for N-bit architectures (N=32,64,
-                ElfN stands for Elf32 or Elf64, uintN_t stands for uint32_t or
-                uint64_t):
-                    ElfN_Addr       Unsigned program address, uintN_t
-                    ElfN_Off        Unsigned file offset, uintN_t
-     */
-    #[derive(Debug)]
-    #[repr(C)]
-    struct ElfHeader {
-        e_ident: [u8; EI_NIDENT],
-        e_type: u16,
-        e_machine: u16,
-        e_version: u32,
-        e_entry: u64,
-        e_phoff: u64,
-        e_shoff: u64,
-        e_flags: u32,
-        e_ehsize: u16,
-        e_phentsize: u16,
-        e_phnum: u16,
-        e_shentsize: u16,
-        e_shnum: u16,
-        e_shstrndx: u16,
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(entry.name).into_owned(),
+                addr: entry.st_value,
+                size: entry.st_size,
+                kind,
+                global: entry.st_info >> 4 != STB_LOCAL,
+            });
+        }
 
-    }
-    #[derive(Debug)]
-    struct Elf <'a>{
-        elf_header: &'a ElfHeader,
-        program_headers: Option<Vec<&'a ProgramHeader>>,
-        section_headers: Option<Vec<&'a SectionHeader>>,
+        Ok(Symbols::new(symbols))
     }
 
+    fn section_named<'a>(elf: &Elf<'a>, name: &str) -> Option<&'a [u8]> {
+        elf.section_data(elf.section_named(name)?).ok()
+    }
 
-    fn parse_elf(data: &[u8]) -> Result<Elf, LoaderErr> {
-        let elf_header;
-        let mut program_headers = None;
-        let mut section_headers = None;
+    //debug info is a nicety, a program whose line tables we can't read still runs without them
+    fn parse_lines(elf: &Elf) -> LineTable {
+        let Some(debug_line) = section_named(elf, ".debug_line") else {
+            return LineTable::default();
+        };
 
+        let strings = Strings {
+            line_str: section_named(elf, ".debug_line_str").unwrap_or_default(),
+            str: section_named(elf, ".debug_str").unwrap_or_default(),
+        };
 
-        //parsing elf_header
-        if size_of::<ElfHeader>() > data.len() {
-            return Err(LoaderErr::InvalidFile);
-        }
+        LineTable::parse(debug_line, strings).unwrap_or_default()
+    }
 
-        elf_header = unsafe {
-            &*(data.as_ptr() as *const ElfHeader)
+    //like the line tables, a backtrace can still fall back to frame pointers without it
+    fn parse_eh_frame(elf: &Elf) -> EhFrame {
+        let Some(header) = elf.section_named(".eh_frame") else {
+            return EhFrame::default();
         };
 
-        //EI_CLASS, architecture for this binary: 
-        if elf_header.e_ident[EI_CLASS] != ELFCLASS64 {
-            return Err(LoaderErr::UnsupportedFileType);
-        } 
+        elf.section_data(header).ok()
+            .and_then(|eh_frame| EhFrame::parse(eh_frame, header.sh_addr, if elf.is_64() { 8 } else { 4 }).ok())
+            .unwrap_or_default()
+    }
 
+    pub fn load_elf_to_dram(mmu: &mut Mmu, data: &[u8]) -> Result<File, LoaderErr> {
+        let elf = Elf::parse(data)?;
 
-        //parsing program_headers
-        if size_of::<ProgramHeader>() != elf_header.e_phentsize as usize {
-            return Err(LoaderErr::InvalidFile);
-        }
-        if elf_header.e_phoff as usize + (elf_header.e_phentsize as usize * elf_header.e_phnum as usize) > data.len() {
-            return Err(LoaderErr::InvalidFile);
+        //EI_DATA, RISC-V is little endian
+        if elf.is_big_endian() {
+            return Err(LoaderErr::UnsupportedEndianness);
         }
-        if elf_header.e_phoff != 0 && elf_header.e_phnum == 0 {
-            return Err(LoaderErr::InvalidFile);
-        }
-        
-        if elf_header.e_phoff != 0 {
-            let mut headers= Vec::new();
-            
-            for i in 0..elf_header.e_phnum {
-                let ph_offset =  elf_header.e_phoff as usize + i as usize * size_of::<ProgramHeader>();
-
-                headers.push(
-                    unsafe {
-                        &*((data.as_ptr().add(ph_offset)) as * const ProgramHeader)
-                    }
-                );
-            }
-            program_headers = Some(headers);
-        }
-        
 
-        //parsing section_headers
-        if size_of::<SectionHeader>() != elf_header.e_shentsize as usize {
-            return Err(LoaderErr::InvalidFile);
+        //identifies the object file type
+        if elf.header.e_type != elf::ET_EXEC {
+            return Err(LoaderErr::UnsupportedType(elf.header.e_type));
         }
-        if elf_header.e_shoff as usize + (elf_header.e_shentsize * elf_header.e_shnum) as usize > data.len() {
-            return Err(LoaderErr::InvalidFile);
+
+        if elf.header.e_machine != elf::EM_RISCV {
+            return Err(LoaderErr::UnsupportedMachine(elf.header.e_machine));
         }
-        if elf_header.e_shoff != 0 && elf_header.e_shnum == 0 {
-            return Err(LoaderErr::InvalidFile);
+
+        if elf.header.e_entry == 0 {
+            return Err(LoaderErr::NoEntryPoint);
         }
 
-        if elf_header.e_shoff != 0 {
-            let mut headers= Vec::new();
-            
-            for i in 0..elf_header.e_shnum {
-                let sh_offset =  elf_header.e_shoff as usize + i as usize * size_of::<SectionHeader>();
-
-                headers.push(
-                    unsafe {
-                        &*((data.as_ptr().add(sh_offset)) as * const SectionHeader)
-                    }
-                );
-            }
-            section_headers = Some(headers);
+        let symbols = parse_symbols(&elf)?;
+        let lines = parse_lines(&elf);
+        let eh_frame = parse_eh_frame(&elf);
+
+        //loading program, the parser already checked every segment is in the file and fits in memory
+        let segments: Vec<_> = elf.program_headers.iter().filter(|header| header.p_type == elf::PT_LOAD).collect();
+        if segments.is_empty() {
+            return Err(LoaderErr::NoLoadableSegments);
         }
 
+        let mut image_end = 0;
 
+        for header in segments {
+            image_end = image_end.max(header.p_vaddr + header.p_memsz);
 
-        Ok(
-            Elf { 
-                elf_header: elf_header, 
-                program_headers: program_headers,
-                section_headers: section_headers,     
-            }
-        )
-    }
+            let dest = usize::try_from(header.p_vaddr).map_err(|_| LoaderErr::SegmentOutOfMemory(header.p_vaddr))?;
+            let size_in_file = header.p_filesz as usize;
+            let size_in_mem = usize::try_from(header.p_memsz).map_err(|_| LoaderErr::SegmentOutOfMemory(header.p_vaddr))?;
+            let mut perm: u8 = 0;
 
+            //mapping data to dram
+            if size_in_file != 0 {
+                mmu.dram_write(dest, elf.segment_data(header)?)?;
+            }
 
-    pub fn load_elf_to_dram(mmu: &mut Mmu, data: &[u8]) -> Result<File, LoaderErr> {
-        let elf = parse_elf(data)?;
+            if size_in_mem > size_in_file {
+                let vaddr: usize = dest + size_in_file;
+                let size: usize = size_in_mem - size_in_file;
 
-        
-        //EI_DATA, endian
-        if elf.elf_header.e_ident[EI_DATA] != ELFDATA2LSB {
-            return Err(LoaderErr::UnsupportedFileType);
-        }
+                mmu.dram_set(0, vaddr, size)?;
+            }
 
-        //identifies the object file type
-        if elf.elf_header.e_type != ET_EXEC {
-            return  Err(LoaderErr::UnsupportedFileType);
-        }
+            //setting permissions
+            if header.p_flags & elf::PF_R == elf::PF_R {
+                perm |= memory::PERM_R;
+            }
+            if header.p_flags & elf::PF_W == elf::PF_W {
+                perm |= memory::PERM_W;
+            }
+            if header.p_flags & elf::PF_X == elf::PF_X {
+                perm |= memory::PERM_X;
+            }
 
-        //e_machine TODO
-        if elf.elf_header.e_machine != EM_RISCV {
-            return  Err(LoaderErr::UnsupportedFileType);
-        }
+            //named the way the sections in the segment usually are
+            let name = if perm & memory::PERM_X != 0 {
+                "text"
+            } else if perm & memory::PERM_W != 0 {
+                "data"
+            } else {
+                "rodata"
+            };
 
-        if elf.elf_header.e_entry == 0 {
-            return  Err(LoaderErr::UnsupportedFileType);
+            mmu.map_region(dest, size_in_mem, perm, name)?;
         }
 
-
-        //loading program
-        if let Some(program_headers) = elf.program_headers {
-            for header in program_headers {
-                if header.p_type == PT_LOAD {
-                    let dest = header.p_vaddr as usize;
-                    let src = header.p_offset as usize;
-                    let size_in_file = header.p_filesz as usize;
-                    let size_in_mem = header.p_memsz as usize;
-                    let mut perm: u8 = 0;
-                    let src_end = src + size_in_file;
-
-                    if size_in_file > size_in_mem {
-                        return Err(LoaderErr::InvalidFile);
-                    }
-                    
-                    //mapping data to dram
-                    if size_in_file != 0 {
-                        mmu.dram_write(dest, &data[src..src_end])?;
-
-                    }
-
-                    if size_in_mem > size_in_file {
-                        let vaddr: usize = dest + size_in_file;
-                        let size: usize = size_in_mem - size_in_file;
-
-                        mmu.dram_set(0, vaddr, size)?;
-                    }
-                    
-                    //setting permissions
-                    
-                    if header.p_flagsz & PF_R == PF_R {
-                        perm |= memory::PERM_R;
-                    }
-                    if header.p_flagsz & PF_W == PF_W {
-                        perm |= memory::PERM_W;
-                    }
-                    if header.p_flagsz & PF_X == PF_X {
-                        perm |= memory::PERM_X;
-                    }
-
-                    mmu.perm_set(dest, size_in_mem, perm)?;
-                }
+        Ok(
+            File {
+                file_type: FileType::Elf,
+                entry_point: elf.header.e_entry,
+                xlen: if elf.is_64() { Xlen::Rv64 } else { Xlen::Rv32 },
+                image_end,
+                symbols,
+                lines,
+                eh_frame,
             }
-            Ok(
-                File {
-                    file_type: FileType::Elf,
-                    entry_point: elf.elf_header.e_entry,
-                }
-            )
-        }
-        else {
-            Err(LoaderErr::InvalidFile)
-        }
+        )
     }
 
-}
\ No newline at end of file
+}
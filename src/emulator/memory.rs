@@ -1,104 +1,908 @@
-const DRAM_SIZE_INITIAL: usize = 1024 * 1024;           //1MB
-const DRAM_SIZE_MAX: usize = DRAM_SIZE_INITIAL * 8;
+use std::{borrow::Cow, collections::{BTreeMap, HashMap}, fmt, hash::{BuildHasherDefault, Hasher}, sync::{Arc, Mutex}};
+
+pub const PAGE_SIZE: usize = 4096;
+const PAGE_SHIFT: u32 = 12;
+
+//how much guest memory may be mapped unless configured otherwise
+pub const DEFAULT_MEMORY_LIMIT: usize = 1024 * 1024 * 1024;           //1GB
 
 pub const PERM_R: u8 = 1;
 pub const PERM_W: u8 = 1 << 1;
 pub const PERM_X: u8 = 1 << 2;
 
+//what perm_get and dram_read hand out for pages that don't keep bytes of their own
+static UNIFORM_PERMS: [[u8; PAGE_SIZE]; 8] = {
+    let mut perms = [[0; PAGE_SIZE]; 8];
+    let mut perm = 0;
+    while perm < 8 {
+        perms[perm] = [perm as u8; PAGE_SIZE];
+        perm += 1;
+    }
+    perms
+};
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+#[cfg(feature = "jit")]
+static UNMAPPED: Page = Page { data: None, perms: Perms::Page(0) };
+
 
 #[derive(thiserror::Error, Debug)]
 pub enum MmmuErr {
     #[error("Index out of bounds: {0}")]
     IndexOutOfBounds(usize),
+
+    #[error("Unmapped address: {0:#x}")]
+    Unmapped(usize),
+
+    #[error("Out of guest memory mapping {0:#x}, the limit is {1} bytes")]
+    OutOfMemory(usize, usize),
+
+    #[error("Not page aligned: {0:#x}")]
+    Misaligned(usize),
 }
 
-#[derive(Clone)]
-pub struct Mmu {
-    dram: Vec<u8>,
-    perm: Vec<u8>,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
 }
 
-macro_rules! bound_check {
-    ($end: expr, $dram_size: expr) => {
-        if $end > $dram_size {
-            Err(MmmuErr::IndexOutOfBounds($end))
+impl AccessKind {
+    fn perm(self) -> u8 {
+        match self {
+            AccessKind::Read => PERM_R,
+            AccessKind::Write => PERM_W,
+            AccessKind::Execute => PERM_X,
         }
-        else {
-            Ok(())
+    }
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AccessKind::Read => "read",
+            AccessKind::Write => "write",
+            AccessKind::Execute => "execute",
+        })
+    }
+}
+
+//why a typed access didn't happen, turned into the matching exception by whoever executes the instruction
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessFault {
+    #[error("No {1} permission at {0:#x}")]
+    Denied(u64, AccessKind),
+
+    #[error("Misaligned {1} at {0:#x}")]
+    Misaligned(u64, AccessKind),
+}
+
+impl AccessFault {
+    //the same fault at the address the instruction used, for accesses that were translated first
+    pub fn at(self, addr: u64) -> Self {
+        match self {
+            AccessFault::Denied(_, kind) => AccessFault::Denied(addr, kind),
+            AccessFault::Misaligned(_, kind) => AccessFault::Misaligned(addr, kind),
         }
-    };
+    }
 }
 
-macro_rules! bound_check_and_resize {
-    ($end: expr, $mmu: expr) => {{
-        while let Err(_) = bound_check!($end, $mmu.dram.len()) {
-            if $mmu.dram.len() * 2 <= DRAM_SIZE_MAX {
-                $mmu.dram.resize($mmu.dram.len() * 2, 0);
-                $mmu.perm.resize($mmu.perm.len() * 2, 0);
-            }
-            else {
-                return Err(MmmuErr::IndexOutOfBounds($end));
+//an access as hooks see it, they may change `value`
+#[derive(Clone, Copy, Debug)]
+pub struct MemAccess {
+    pub addr: u64,
+    pub size: usize,
+    pub kind: AccessKind,
+    //what was read, what is about to be written or the instruction about to be executed
+    pub value: u64,
+    pub pc: u64,
+}
+
+impl fmt::Display for MemAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} bytes at {:#x} ({:#x}) by pc {:#x}", self.kind, self.size, self.addr, self.value, self.pc)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookAction {
+    //the access goes ahead with the hook's value
+    Continue,
+    //the hook took care of the access: a read that would fault returns the hook's value, a write isn't
+    //carried out, an instruction is skipped
+    Skip,
+    //the run stops before the instruction completes
+    Stop,
+}
+
+pub type MemHookFn = dyn FnMut(&mut MemAccess) -> HookAction + Send;
+
+//shared with snapshots like the tracer, a restored snapshot keeps calling the same closures
+#[derive(Clone)]
+struct MemHook {
+    id: usize,
+    kind: AccessKind,
+    start: u64,
+    end: u64,
+    hook: Arc<Mutex<MemHookFn>>,
+}
+
+//what a data access that isn't naturally aligned does, instruction fetches always have to be aligned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MisalignedAccess {
+    //carried out like any other access, byte by byte if need be
+    Allow,
+    //raises an address misaligned exception, like harts without misaligned access support
+    Fault,
+}
+
+//how finely permissions are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermGranularity {
+    //exactly the bytes they were set for
+    Byte,
+    //whole pages like a real MMU, a page only partly covered gains what it's granted
+    //but loses anything taken away from part of it, never allowing more than asked for
+    Page,
+}
+
+//a named stretch of the address space, like a line of /proc/<pid>/maps
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub name: String,
+}
+
+//"rwx" style
+pub fn perm_str(perm: u8) -> String {
+    [(PERM_R, 'r'), (PERM_W, 'w'), (PERM_X, 'x')].iter().map(|(bit, c)| if perm & bit != 0 { *c } else { '-' }).collect()
+}
+
+//page numbers are already well spread, a multiply is all the hashing they need
+#[derive(Default)]
+struct PageHasher(u64);
+
+impl Hasher for PageHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(self.0 ^ *byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+#[derive(Clone)]
+enum Perms {
+    //the whole page has the same permissions, by far the common case
+    Page(u8),
+    Bytes(Box<[u8; PAGE_SIZE]>),
+}
+
+/*
+    A mapped page. Its bytes are only allocated once something is written to it, until then it reads as
+    zeros, so mapping a large stack or heap costs next to nothing.
+*/
+#[derive(Clone)]
+struct Page {
+    data: Option<Box<[u8; PAGE_SIZE]>>,
+    perms: Perms,
+}
+
+impl Page {
+    fn new() -> Self {
+        Page {
+            data: None,
+            perms: Perms::Page(0),
+        }
+    }
+
+    fn data(&self) -> &[u8; PAGE_SIZE] {
+        self.data.as_deref().unwrap_or(&ZERO_PAGE)
+    }
+
+    fn data_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
+        self.data.get_or_insert_with(zeroed_page)
+    }
+
+    fn perms(&self) -> &[u8; PAGE_SIZE] {
+        match &self.perms {
+            Perms::Page(perm) => &UNIFORM_PERMS[*perm as usize & 7],
+            Perms::Bytes(perms) => perms,
+        }
+    }
+
+    fn set_perms(&mut self, offset: usize, len: usize, perm: u8) {
+        if len == PAGE_SIZE {
+            self.perms = Perms::Page(perm);
+            return;
+        }
+
+        if let Perms::Page(old) = self.perms {
+            if old == perm {
+                return;
             }
+            self.perms = Perms::Bytes(Box::new([old; PAGE_SIZE]));
         }
 
-        Ok(())
-    }};
+        if let Perms::Bytes(perms) = &mut self.perms {
+            perms[offset..offset + len].fill(perm);
+        }
+    }
+}
+
+//calloc'd, the host only backs it once the guest actually touches it
+fn zeroed_page() -> Box<[u8; PAGE_SIZE]> {
+    vec![0; PAGE_SIZE].into_boxed_slice().try_into().unwrap()
+}
+
+/*
+    The pieces [vaddr, vaddr + size) falls into: (page number, offset in the page, offset in the access,
+    length). Accesses that would wrap around the end of the address space are out of bounds.
+*/
+fn chunks(vaddr: usize, size: usize) -> Result<impl Iterator<Item = (u64, usize, usize, usize)>, MmmuErr> {
+    let end = vaddr.checked_add(size).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
+    let mut addr = vaddr;
+
+    Ok(std::iter::from_fn(move || {
+        if addr >= end {
+            return None;
+        }
+
+        let offset = addr % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(end - addr);
+        let chunk = ((addr >> PAGE_SHIFT) as u64, offset, addr - vaddr, len);
+        addr += len;
+
+        Some(chunk)
+    }))
+}
+
+fn single_page(vaddr: usize, size: usize) -> bool {
+    size != 0 && vaddr % PAGE_SIZE + size <= PAGE_SIZE
+}
+
+/*
+    Guest memory: a sparse set of 4KB pages over the whole 64 bit address space, in the same map whether
+    they hold an ELF image, the stack near the top of user space or the RAM of a bare-metal machine.
+    Pages are mapped by the first permission or data written to them, up to a configurable limit.
+*/
+#[derive(Clone)]
+pub struct Mmu {
+    pages: HashMap<u64, Page, BuildHasherDefault<PageHasher>>,
+    //most bytes that may be mapped
+    limit: usize,
+    granularity: PermGranularity,
+    misaligned: MisalignedAccess,
+
+    //keyed by start, they never overlap
+    regions: BTreeMap<usize, Region>,
+
+    //[start, end) ranges written while they were executable, consumed by the block cache
+    exec_writes: Vec<(usize, usize)>,
+
+    hooks: Vec<MemHook>,
+    next_hook_id: usize,
+
+    #[cfg(feature = "jit")]
+    tlb: Tlb,
 }
 
 impl Mmu {
     pub fn new() -> Self {
         Mmu {
-            dram: vec![0; DRAM_SIZE_INITIAL],
-            perm: vec![0; DRAM_SIZE_INITIAL],
+            pages: HashMap::default(),
+            limit: DEFAULT_MEMORY_LIMIT,
+            granularity: PermGranularity::Byte,
+            misaligned: MisalignedAccess::Allow,
+            regions: BTreeMap::new(),
+            exec_writes: Vec::new(),
+            hooks: Vec::new(),
+            next_hook_id: 0,
+            #[cfg(feature = "jit")]
+            tlb: Tlb::new(),
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn set_granularity(&mut self, granularity: PermGranularity) {
+        self.granularity = granularity;
+    }
+
+    pub fn granularity(&self) -> PermGranularity {
+        self.granularity
+    }
+
+    pub fn set_misaligned(&mut self, misaligned: MisalignedAccess) {
+        self.misaligned = misaligned;
+    }
+
+    pub fn misaligned(&self) -> MisalignedAccess {
+        self.misaligned
+    }
+
+    pub fn mapped_bytes(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    fn page_or_map(&mut self, vpn: u64) -> Result<&mut Page, MmmuErr> {
+        if !self.pages.contains_key(&vpn) && self.mapped_bytes() + PAGE_SIZE > self.limit {
+            return Err(MmmuErr::OutOfMemory((vpn as usize) << PAGE_SHIFT, self.limit));
         }
+
+        Ok(self.pages.entry(vpn).or_insert_with(Page::new))
     }
 
-    pub fn perm_get(&self, vaddr: usize, size: usize) -> Result<&[u8], MmmuErr> {
-        let end = vaddr + size;
+    fn track_exec_write(&mut self, vaddr: usize, end: usize) {
+        let exec = chunks(vaddr, end - vaddr).is_ok_and(|mut chunks| chunks.any(|(vpn, offset, _, len)| {
+            self.pages.get(&vpn).is_some_and(|page| page.perms()[offset..offset + len].iter().any(|perm| perm & PERM_X != 0))
+        }));
 
-        bound_check!(end, self.dram.len())?;
-        
-        Ok(&self.perm[vaddr..end])
-    } 
+        if exec {
+            self.exec_writes.push((vaddr, end));
+        }
+    }
+
+    pub fn has_exec_writes(&self) -> bool {
+        !self.exec_writes.is_empty()
+    }
+
+    pub fn take_exec_writes(&mut self) -> Vec<(usize, usize)> {
+        std::mem::take(&mut self.exec_writes)
+    }
+
+    //whether every byte of the access allows all of `perm`, one lookup per page; unmapped bytes allow nothing
+    pub fn perm_check(&self, vaddr: usize, size: usize, perm: u8) -> bool {
+        let Ok(mut chunks) = chunks(vaddr, size) else {
+            return false;
+        };
+
+        chunks.all(|(vpn, offset, _, len)| match self.pages.get(&vpn).map(|page| &page.perms) {
+            Some(Perms::Page(page_perm)) => page_perm & perm == perm,
+            Some(Perms::Bytes(perms)) => perms[offset..offset + len].iter().all(|byte_perm| byte_perm & perm == perm),
+            None => false,
+        })
+    }
+
+    pub fn perm_at(&self, vaddr: usize) -> u8 {
+        self.pages.get(&((vaddr >> PAGE_SHIFT) as u64)).map_or(0, |page| page.perms()[vaddr % PAGE_SIZE])
+    }
 
     pub fn perm_set(&mut self, vaddr: usize, size: usize, perm: u8) -> Result<(), MmmuErr> {
-        let end = vaddr + size;
+        let end = vaddr.checked_add(size).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
+
+        //code that stops (or starts) being executable has to be re-decoded as well
+        if perm & PERM_X != 0 {
+            self.exec_writes.push((vaddr, end));
+        } else {
+            self.track_exec_write(vaddr, end);
+        }
 
-        bound_check_and_resize!(end, self)?;
+        for (vpn, offset, _, len) in chunks(vaddr, size)? {
+            //taking every permission away doesn't need a page to remember it
+            if perm == 0 && !self.pages.contains_key(&vpn) {
+                continue;
+            }
+
+            let granularity = self.granularity;
+            let page = self.page_or_map(vpn)?;
 
-        for p in &mut self.perm[vaddr..end] {
-            *p = perm; 
+            match (granularity, &page.perms) {
+                (PermGranularity::Page, Perms::Page(old)) if len != PAGE_SIZE => {
+                    page.perms = Perms::Page(if old & !perm != 0 { old & perm } else { old | perm })
+                }
+                _ => page.set_perms(offset, len, perm),
+            }
+
+            #[cfg(feature = "jit")]
+            self.tlb.invalidate(vpn);
         }
 
         Ok(())
     }
 
+    /*
+        Gives [vaddr, vaddr + size) the permissions and a name, taking it out of whatever regions it was
+        part of before. A region right next to one of the same name grows that one instead.
+    */
+    pub fn map_region(&mut self, vaddr: usize, size: usize, perm: u8, name: &str) -> Result<(), MmmuErr> {
+        let mut start = vaddr;
+        let mut end = vaddr.checked_add(size).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
+
+        self.perm_set(vaddr, size, perm)?;
+        self.carve(start, end);
+
+        if let Some(prev) = self.regions.range(..start).next_back().map(|(_, prev)| prev.clone()) {
+            if prev.end == start && prev.name == name {
+                self.regions.remove(&prev.start);
+                start = prev.start;
+            }
+        }
+        if let Some(next) = self.regions.get(&end).cloned() {
+            if next.name == name {
+                self.regions.remove(&next.start);
+                end = next.end;
+            }
+        }
+
+        self.regions.insert(start, Region { start, end, name: name.to_string() });
+
+        Ok(())
+    }
+
+    //mprotect(2): only whole pages that are already mapped
+    pub fn mprotect(&mut self, vaddr: usize, size: usize, perm: u8) -> Result<(), MmmuErr> {
+        if !vaddr.is_multiple_of(PAGE_SIZE) {
+            return Err(MmmuErr::Misaligned(vaddr));
+        }
+
+        let size = size.checked_next_multiple_of(PAGE_SIZE).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
+
+        for (vpn, ..) in chunks(vaddr, size)? {
+            if !self.pages.contains_key(&vpn) {
+                return Err(MmmuErr::Unmapped((vpn as usize) << PAGE_SHIFT));
+            }
+        }
+
+        self.perm_set(vaddr, size, perm)
+    }
+
+    //pages entirely inside the range go away with their bytes, partly covered ones just lose their permissions
+    pub fn unmap(&mut self, vaddr: usize, size: usize) -> Result<(), MmmuErr> {
+        let end = vaddr.checked_add(size).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
+
+        self.perm_set(vaddr, size, 0)?;
+        self.carve(vaddr, end);
+
+        for (vpn, _, _, len) in chunks(vaddr, size)? {
+            if len == PAGE_SIZE && self.pages.remove(&vpn).is_some() {
+                #[cfg(feature = "jit")]
+                self.tlb.invalidate(vpn);
+            }
+        }
+
+        Ok(())
+    }
+
+    //takes [start, end) out of every region, splitting the ones it only partly covers
+    fn carve(&mut self, start: usize, end: usize) {
+        let overlapping: Vec<Region> = self.regions.range(..end).rev()
+            .take_while(|(_, region)| region.end > start)
+            .map(|(_, region)| region.clone())
+            .collect();
+
+        for region in overlapping {
+            self.regions.remove(&region.start);
+
+            if region.start < start {
+                self.regions.insert(region.start, Region { end: start, ..region.clone() });
+            }
+            if region.end > end {
+                self.regions.insert(end, Region { start: end, ..region });
+            }
+        }
+    }
+
+    pub fn region_at(&self, vaddr: usize) -> Option<&Region> {
+        self.regions.range(..=vaddr).next_back().map(|(_, region)| region).filter(|region| vaddr < region.end)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
     pub fn dram_write(&mut self, vaddr: usize, data: &[u8]) -> Result<(), MmmuErr> {
-        let end = vaddr + data.len();
+        let end = vaddr.checked_add(data.len()).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
 
-        bound_check_and_resize!(end, self)?;
+        self.track_exec_write(vaddr, end);
 
-        self.dram[vaddr..end].copy_from_slice(data);
+        for (vpn, offset, at, len) in chunks(vaddr, data.len())? {
+            self.page_or_map(vpn)?.data_mut()[offset..offset + len].copy_from_slice(&data[at..at + len]);
+        }
 
         Ok(())
     }
 
     pub fn dram_set(&mut self, val: u8, vaddr: usize, size: usize) -> Result<(), MmmuErr> {
-        let end = vaddr + size;
+        let end = vaddr.checked_add(size).ok_or(MmmuErr::IndexOutOfBounds(vaddr))?;
+
+        self.track_exec_write(vaddr, end);
 
-        bound_check_and_resize!(end, self)?;
+        for (vpn, offset, _, len) in chunks(vaddr, size)? {
+            let page = self.page_or_map(vpn)?;
 
-        self.dram[vaddr..end].fill(val);
+            //zeroing a page that was never written leaves it as it is
+            if val != 0 || page.data.is_some() {
+                page.data_mut()[offset..offset + len].fill(val);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn dram_read(&self, vaddr: usize, size: usize) -> Result<Cow<'_, [u8]>, MmmuErr> {
+        let page = |vpn: u64| self.pages.get(&vpn).ok_or(MmmuErr::Unmapped(vaddr.max((vpn as usize) << PAGE_SHIFT)));
+
+        if single_page(vaddr, size) {
+            let offset = vaddr % PAGE_SIZE;
+
+            return Ok(Cow::Borrowed(&page((vaddr >> PAGE_SHIFT) as u64)?.data()[offset..offset + size]));
+        }
+
+        let mut out = vec![0; size];
+        for (vpn, offset, at, len) in chunks(vaddr, size)? {
+            out[at..at + len].copy_from_slice(&page(vpn)?.data()[offset..offset + len]);
+        }
+
+        Ok(Cow::Owned(out))
+    }
+
+    /*
+        Checked little endian access for the instructions themselves, the raw dram_* functions above are
+        for loaders, devices and debuggers that see memory regardless of permissions. Alignment is checked
+        before permissions, every byte of the access needs the permission.
+    */
+    fn check(&self, addr: u64, size: usize, kind: AccessKind) -> Result<(), AccessFault> {
+        let strict = kind == AccessKind::Execute || self.misaligned == MisalignedAccess::Fault;
+
+        if strict && !addr.is_multiple_of(size as u64) {
+            return Err(AccessFault::Misaligned(addr, kind));
+        }
+
+        if !self.perm_check(addr as usize, size, kind.perm()) {
+            return Err(AccessFault::Denied(addr, kind));
+        }
 
         Ok(())
     }
 
-    pub fn dram_read(&self, vaddr: usize, size: usize) -> Result<&[u8], MmmuErr> {
-        let end = vaddr + size;
+    pub(super) fn read(&self, addr: u64, size: usize, kind: AccessKind) -> Result<u64, AccessFault> {
+        self.check(addr, size, kind)?;
 
-        bound_check!(end, self.dram.len())?;
+        //a page that allows the access is mapped
+        let bytes = self.dram_read(addr as usize, size).map_err(|_| AccessFault::Denied(addr, kind))?;
+        let mut value = [0; 8];
+        value[..size].copy_from_slice(&bytes);
 
-        Ok(&self.dram[vaddr..end])
+        Ok(u64::from_le_bytes(value))
     }
 
+    pub(super) fn write(&mut self, addr: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        self.check(addr, size, AccessKind::Write)?;
+
+        self.dram_write(addr as usize, &value.to_le_bytes()[..size]).map_err(|_| AccessFault::Denied(addr, AccessKind::Write))
+    }
+
+    pub fn read_u8(&self, addr: u64) -> Result<u8, AccessFault> {
+        self.read(addr, 1, AccessKind::Read).map(|value| value as u8)
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Result<u16, AccessFault> {
+        self.read(addr, 2, AccessKind::Read).map(|value| value as u16)
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32, AccessFault> {
+        self.read(addr, 4, AccessKind::Read).map(|value| value as u32)
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64, AccessFault> {
+        self.read(addr, 8, AccessKind::Read)
+    }
+
+    pub fn write_u8(&mut self, addr: u64, value: u8) -> Result<(), AccessFault> {
+        self.write(addr, 1, value as u64)
+    }
+
+    pub fn write_u16(&mut self, addr: u64, value: u16) -> Result<(), AccessFault> {
+        self.write(addr, 2, value as u64)
+    }
+
+    pub fn write_u32(&mut self, addr: u64, value: u32) -> Result<(), AccessFault> {
+        self.write(addr, 4, value as u64)
+    }
+
+    pub fn write_u64(&mut self, addr: u64, value: u64) -> Result<(), AccessFault> {
+        self.write(addr, 8, value)
+    }
+
+    //a 16 bit instruction parcel, which needs execute permission, 32 bit instructions are fetched as two
+    pub fn fetch_u16(&self, addr: u64) -> Result<u16, AccessFault> {
+        self.read(addr, 2, AccessKind::Execute).map(|value| value as u16)
+    }
+
+    /*
+        Registers a closure called for every access of `kind` by an instruction that overlaps [start, end),
+        returns the id that removes it again. Accesses by devices, loaders and the debugger aren't seen.
+    */
+    pub fn add_hook<F>(&mut self, kind: AccessKind, start: u64, end: u64, hook: F) -> usize
+    where
+        F: FnMut(&mut MemAccess) -> HookAction + Send + 'static,
+    {
+        let id = self.next_hook_id;
+        self.next_hook_id += 1;
+        self.hooks.push(MemHook { id, kind, start, end, hook: Arc::new(Mutex::new(hook)) });
+
+        id
+    }
+
+    pub fn remove_hook(&mut self, id: usize) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|hook| hook.id != id);
+
+        self.hooks.len() != len
+    }
+
+    pub fn has_hooks(&self) -> bool {
+        !self.hooks.is_empty()
+    }
+
+    //every matching hook in the order they were added, until one of them doesn't just continue
+    pub fn run_hooks(&self, access: &mut MemAccess) -> HookAction {
+        let end = access.addr.saturating_add(access.size as u64);
+
+        for hook in &self.hooks {
+            if hook.kind != access.kind || end <= hook.start || hook.end <= access.addr {
+                continue;
+            }
+
+            let mut hook = hook.hook.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let action = hook(access);
+
+            if action != HookAction::Continue {
+                return action;
+            }
+        }
+
+        HookAction::Continue
+    }
+
+    //same bytes and permissions everywhere, a page that was never written is as good as one of zeros
+    #[cfg(feature = "jit")]
+    pub fn same_contents(&self, other: &Mmu) -> bool {
+        let same_page = |page: &Page, other: Option<&Page>| {
+            let other = other.unwrap_or(&UNMAPPED);
+
+            let same_data = match (&page.data, &other.data) {
+                (None, None) => true,
+                _ => page.data() == other.data(),
+            };
+            let same_perms = match (&page.perms, &other.perms) {
+                (Perms::Page(a), Perms::Page(b)) => a == b,
+                _ => page.perms() == other.perms(),
+            };
+
+            same_data && same_perms
+        };
+
+        self.pages.iter().all(|(vpn, page)| same_page(page, other.pages.get(vpn)))
+            && other.pages.iter().all(|(vpn, page)| same_page(page, self.pages.get(vpn)))
+    }
+}
+
+#[cfg(feature = "jit")]
+pub const TLB_ENTRIES: usize = 256;
+#[cfg(feature = "jit")]
+pub const TLB_ENTRY_SHIFT: u8 = 5;
+
+/*
+    How translated code finds guest memory: a direct mapped cache from page number to the page's bytes,
+    only ever holding pages with uniform permissions, so one check covers a whole access. An access that
+    misses exits to the interpreter, which fills the entry for next time.
+*/
+#[cfg(feature = "jit")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TlbEntry {
+    pub vpn: u64,
+    pub data: *mut u8,
+    pub perm: u64,
+    _pad: u64,
+}
+
+#[cfg(feature = "jit")]
+const TLB_INVALID: TlbEntry = TlbEntry { vpn: u64::MAX, data: std::ptr::null_mut(), perm: 0, _pad: 0 };
+
+#[cfg(feature = "jit")]
+struct Tlb(Box<[TlbEntry; TLB_ENTRIES]>);
+
+//the entries point into pages owned by the same Mmu, they move between threads with it
+#[cfg(feature = "jit")]
+unsafe impl Send for Tlb {}
+
+//a copy of the Mmu has pages of its own, the entries would still point at the original's
+#[cfg(feature = "jit")]
+impl Clone for Tlb {
+    fn clone(&self) -> Self {
+        Tlb::new()
+    }
+}
+
+#[cfg(feature = "jit")]
+impl Tlb {
+    fn new() -> Self {
+        Tlb(Box::new([TLB_INVALID; TLB_ENTRIES]))
+    }
+
+    fn invalidate(&mut self, vpn: u64) {
+        let entry = &mut self.0[vpn as usize % TLB_ENTRIES];
+
+        if entry.vpn == vpn {
+            *entry = TLB_INVALID;
+        }
+    }
+}
+
+#[cfg(feature = "jit")]
+impl Mmu {
+    //stays valid until the Mmu is next borrowed mutably
+    pub fn tlb(&self) -> *const TlbEntry {
+        self.tlb.0.as_ptr()
+    }
+
+    /*
+        A page's bytes never move while it is mapped. Its entry is evicted when its permissions change
+        (perm_set, so mprotect too) and when it is unmapped, so an entry never outlives what it points at.
+    */
+    pub fn tlb_fill(&mut self, vaddr: u64) {
+        let vpn = vaddr >> PAGE_SHIFT;
+
+        let Some(page) = self.pages.get_mut(&vpn) else {
+            return;
+        };
+        let Perms::Page(perm) = page.perms else {
+            return;
+        };
+        if perm & (PERM_R | PERM_W) == 0 {
+            return;
+        }
+
+        self.tlb.0[vpn as usize % TLB_ENTRIES] = TlbEntry { vpn, data: page.data_mut().as_mut_ptr(), perm: perm as u64, _pad: 0 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HIGH: usize = 0xffff_ffc0_0000_0000;
+
+    fn names(mmu: &Mmu) -> Vec<(usize, usize, &str)> {
+        mmu.regions().map(|region| (region.start, region.end, region.name.as_str())).collect()
+    }
+
+    #[test]
+    fn pages_are_mapped_anywhere_and_read_as_zeros_until_written() {
+        let mut mmu = Mmu::new();
+
+        mmu.map_region(HIGH, 3 * PAGE_SIZE, PERM_R | PERM_W, "high").unwrap();
+        assert_eq!(mmu.mapped_bytes(), 3 * PAGE_SIZE);
+        assert_eq!(mmu.read_u64(HIGH as u64 + 0x10).unwrap(), 0);
+
+        //across a page boundary
+        mmu.write_u64(HIGH as u64 + PAGE_SIZE as u64 - 4, 0x1122_3344_5566_7788).unwrap();
+        assert_eq!(mmu.read_u32(HIGH as u64 + PAGE_SIZE as u64).unwrap(), 0x1122_3344);
+        assert_eq!(mmu.dram_read(HIGH + PAGE_SIZE - 4, 8).unwrap()[..], 0x1122_3344_5566_7788u64.to_le_bytes());
+
+        assert!(matches!(mmu.dram_read(HIGH - 1, 2), Err(MmmuErr::Unmapped(addr)) if addr == HIGH - 1));
+        assert!(matches!(mmu.dram_write(usize::MAX, &[0; 2]), Err(MmmuErr::IndexOutOfBounds(_))));
+    }
+
+    #[test]
+    fn permissions_are_checked_on_every_byte() {
+        let mut mmu = Mmu::new();
+        mmu.map_region(0x1000, 0x10, PERM_R, "ro").unwrap();
+
+        assert_eq!(mmu.read_u32(0x100c).unwrap(), 0);
+        assert_eq!(mmu.read_u32(0x100e), Err(AccessFault::Denied(0x100e, AccessKind::Read)));
+        assert_eq!(mmu.write_u8(0x1000, 1), Err(AccessFault::Denied(0x1000, AccessKind::Write)));
+        assert_eq!(mmu.fetch_u16(0x1000), Err(AccessFault::Denied(0x1000, AccessKind::Execute)));
+
+        mmu.set_misaligned(MisalignedAccess::Fault);
+        assert_eq!(mmu.read_u16(0x1001), Err(AccessFault::Misaligned(0x1001, AccessKind::Read)));
+
+        //a page granular mmu lets the rest of a partly covered page through as well
+        let mut mmu = Mmu::new();
+        mmu.set_granularity(PermGranularity::Page);
+        mmu.map_region(0x1000, 0x10, PERM_R, "ro").unwrap();
+        assert_eq!(mmu.read_u32(0x1ffc).unwrap(), 0);
+    }
+
+    #[test]
+    fn page_granularity_revokes_on_partly_covered_pages() {
+        let mut mmu = Mmu::new();
+        mmu.set_granularity(PermGranularity::Page);
+        mmu.map_region(0x1000, 0x2000, PERM_R | PERM_W | PERM_X, "rwx").unwrap();
+
+        //granting more to part of a page widens it
+        mmu.map_region(0x3000, 0x10, PERM_R, "a").unwrap();
+        mmu.map_region(0x3800, 0x10, PERM_R | PERM_W, "b").unwrap();
+        assert_eq!(mmu.perm_at(0x3000), PERM_R | PERM_W);
+
+        //but taking write or execute away from part of it takes it from the whole page
+        mmu.map_region(0x1000, 0x10, PERM_R | PERM_X, "text").unwrap();
+        assert_eq!(mmu.perm_at(0x1fff), PERM_R | PERM_X);
+        assert_eq!(mmu.write_u8(0x1800, 1), Err(AccessFault::Denied(0x1800, AccessKind::Write)));
+
+        mmu.unmap(0x2f00, 0x100).unwrap();
+        assert_eq!(mmu.perm_at(0x2000), 0);
+        assert_eq!(mmu.fetch_u16(0x2000), Err(AccessFault::Denied(0x2000, AccessKind::Execute)));
+    }
+
+    #[test]
+    fn regions_are_carved_and_merged() {
+        let mut mmu = Mmu::new();
+
+        mmu.map_region(0x1000, 0x3000, PERM_R, "a").unwrap();
+        mmu.map_region(0x2000, 0x1000, PERM_R | PERM_W, "b").unwrap();
+        assert_eq!(names(&mmu), [(0x1000, 0x2000, "a"), (0x2000, 0x3000, "b"), (0x3000, 0x4000, "a")]);
+        assert_eq!(mmu.region_at(0x2fff).unwrap().name, "b");
+
+        mmu.map_region(0x3000, 0x1000, PERM_R, "b").unwrap();
+        assert_eq!(names(&mmu), [(0x1000, 0x2000, "a"), (0x2000, 0x4000, "b")]);
+
+        mmu.unmap(0x1800, 0x1000).unwrap();
+        assert_eq!(names(&mmu), [(0x1000, 0x1800, "a"), (0x2800, 0x4000, "b")]);
+        assert!(mmu.region_at(0x2000).is_none());
+        //partly covered pages stay, only their bytes lost permissions
+        assert_eq!(mmu.mapped_bytes(), 3 * PAGE_SIZE);
+        assert_eq!((mmu.perm_at(0x17ff), mmu.perm_at(0x1800), mmu.perm_at(0x2800)), (PERM_R, 0, PERM_R | PERM_W));
+    }
+
+    #[test]
+    fn mprotect_only_takes_mapped_whole_pages() {
+        let mut mmu = Mmu::new();
+        mmu.map_region(0x1000, 0x2000, PERM_R, "a").unwrap();
+
+        assert!(matches!(mmu.mprotect(0x1004, 4, PERM_W), Err(MmmuErr::Misaligned(0x1004))));
+        assert!(matches!(mmu.mprotect(0x2000, 0x2000, PERM_W), Err(MmmuErr::Unmapped(0x3000))));
+        assert_eq!(mmu.perm_at(0x2000), PERM_R);
+
+        mmu.mprotect(0x2000, 1, PERM_R | PERM_W).unwrap();
+        assert_eq!((mmu.perm_at(0x1fff), mmu.perm_at(0x2fff)), (PERM_R, PERM_R | PERM_W));
+    }
+
+    #[test]
+    fn mapping_stops_at_the_limit() {
+        let mut mmu = Mmu::new();
+        mmu.set_limit(2 * PAGE_SIZE);
+
+        mmu.map_region(0x1000, PAGE_SIZE, PERM_R, "a").unwrap();
+        assert!(matches!(mmu.map_region(0x10_0000, 2 * PAGE_SIZE, PERM_R, "b"), Err(MmmuErr::OutOfMemory(0x10_1000, _))));
+        assert!(matches!(mmu.dram_write(0x20_0000, &[1]), Err(MmmuErr::OutOfMemory(..))));
+
+        mmu.unmap(0x10_0000, PAGE_SIZE).unwrap();
+        mmu.dram_write(0x20_0000, &[1]).unwrap();
+    }
+
+    #[test]
+    fn hooks_see_accesses_in_their_range() {
+        let mut mmu = Mmu::new();
+        mmu.map_region(0x1000, PAGE_SIZE, PERM_R | PERM_W, "a").unwrap();
+
+        let id = mmu.add_hook(AccessKind::Write, 0x1000, 0x1008, |access| {
+            access.value += 1;
+            HookAction::Skip
+        });
+        let mut inside = MemAccess { addr: 0x1004, size: 4, kind: AccessKind::Write, value: 1, pc: 0 };
+        let mut outside = MemAccess { addr: 0x1008, ..inside };
+        let mut read = MemAccess { kind: AccessKind::Read, ..inside };
+
+        assert_eq!(mmu.run_hooks(&mut inside), HookAction::Skip);
+        assert_eq!(inside.value, 2);
+        assert_eq!(mmu.run_hooks(&mut outside), HookAction::Continue);
+        assert_eq!(mmu.run_hooks(&mut read), HookAction::Continue);
+
+        assert!(mmu.remove_hook(id));
+        assert!(!mmu.has_hooks());
+    }
 }
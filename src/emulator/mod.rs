@@ -3,11 +3,44 @@ mod memory;
 mod cpu;
 mod loader;
 mod exceptions;
+mod triage;
+mod cmplog;
+mod block_cache;
+#[cfg(feature = "jit")]
+mod jit;
+mod trace;
+mod args;
+mod replay;
+mod syscall;
+mod time_travel;
+mod gdb;
+mod bus;
+mod devices;
+mod csr;
+mod trap;
+mod fdt;
+mod sbi;
+mod boot;
+mod smp;
+mod paging;
+mod hooks;
+mod symbols;
+mod dwarf;
+mod elf;
+mod unwind;
 
-use std::path::Path;
+use std::{io::{self, Read}, path::Path, sync::{Arc, Mutex}};
 use memory::Mmu;
 use cpu::Cpu;
 use exceptions::Exceptions;
+use cmplog::CmpLog;
+use block_cache::BlockCache;
+use symbols::Symbols;
+use dwarf::LineTable;
+use trace::Tracer;
+use replay::Nondet;
+use bus::Bus;
+use devices::{clint::Clock, virtio::VirtioMmio};
 
 #[derive(thiserror::Error, Debug)]
 pub enum EmulatorErr {
@@ -22,12 +55,84 @@ pub enum EmulatorErr {
 
     #[error("Exception Handler error: {0}")]
     ErrExceptionHandler(#[from] exceptions::ExceptionHandlerErr),
+
+    //raised by an instruction, it only leaves the emulator if handle_exception decides the trap is fatal
+    #[error("Trap: {0}")]
+    ErrTrap(#[from] Exceptions),
+
+    #[cfg(feature = "jit")]
+    #[error("JIT error: {0}")]
+    ErrJit(#[from] jit::JitErr),
+
+    #[error("Trace error: {0}")]
+    ErrTrace(#[from] trace::TraceErr),
+
+    #[error("Replay error: {0}")]
+    ErrReplay(#[from] replay::ReplayErr),
+
+    #[error("Bus error: {0}")]
+    ErrBus(#[from] bus::BusErr),
+
+    #[error("Guest exited with code {0}")]
+    ErrExited(u64),
+
+    #[error("Stopped by a memory hook: {0}")]
+    ErrStopped(memory::MemAccess),
+
+    #[error("Stopped by an instruction hook at {0:#x}")]
+    ErrHookStopped(u64),
 }
 
 #[derive(Clone)]
 struct Emulator {
     cpu: Cpu,
     mmu: Mmu,
+    bus: Bus,
+
+    //compare-operand logging, only paid for when enabled
+    cmplog: Option<CmpLog>,
+
+    block_cache: BlockCache,
+
+    //native translation of hot blocks, the interpreter is used while this is None
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+
+    //instructions retired so far
+    icount: u64,
+
+    //shared with snapshots, so a restored snapshot keeps writing to the same sink
+    tracer: Option<Arc<Mutex<Tracer>>>,
+
+    //every nondeterministic input goes through here, so it can be recorded or replayed
+    nondet: Nondet,
+
+    //user mode state the system calls work on
+    process: syscall::Process,
+
+    //closures run before instructions, see hooks.rs
+    inst_hooks: hooks::InstHooks,
+
+    //of the loaded ELF, shared with snapshots
+    symbols: Arc<Symbols>,
+    lines: Arc<LineTable>,
+    eh_frame: Arc<unwind::EhFrame>,
+
+    //traps go to the guest's own handlers instead of the emulator's execution environment
+    bare_metal: bool,
+    //where the time CSR is read from
+    clint_base: Option<u64>,
+    //ecalls from S mode go to the emulator's SBI instead of an M mode handler
+    sbi: bool,
+
+    //every hart, the running one is `cpu` and its slot here is stale until it is switched out
+    harts: Vec<Cpu>,
+    //the hart in `cpu`
+    hart: usize,
+    //instructions a hart runs before the next one gets its turn
+    quantum: u64,
+    //what is left of the running hart's turn
+    slice: u64,
 }
 
 impl Emulator {
@@ -35,9 +140,41 @@ impl Emulator {
         Emulator {
             cpu: Cpu::new(),
             mmu: Mmu::new(),
+            bus: Bus::new(),
+            cmplog: None,
+            block_cache: BlockCache::new(),
+            #[cfg(feature = "jit")]
+            jit: None,
+            icount: 0,
+            tracer: None,
+            nondet: Nondet::default(),
+            process: syscall::Process::default(),
+            inst_hooks: hooks::InstHooks::default(),
+            symbols: Arc::default(),
+            lines: Arc::default(),
+            eh_frame: Arc::default(),
+            bare_metal: false,
+            clint_base: None,
+            sbi: false,
+            harts: vec![Cpu::new()],
+            hart: 0,
+            quantum: smp::DEFAULT_QUANTUM,
+            slice: u64::MAX,
         }
     }
 
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer.map(|tracer| Arc::new(Mutex::new(tracer)));
+    }
+
+    pub fn enable_cmplog(&mut self) -> &mut CmpLog {
+        self.cmplog.get_or_insert_with(CmpLog::new)
+    }
+
+    pub fn disable_cmplog(&mut self) -> Option<CmpLog> {
+        self.cmplog.take()
+    }
+
     pub fn take_snapshot(&self) -> Emulator{
         self.clone()
 
@@ -48,56 +185,290 @@ impl Emulator {
 
         let pc_val = file.entry_point;
 
+        self.cpu.set_xlen(file.xlen);
         self.cpu.set_pc(pc_val);
+        self.process = syscall::Process::new(file.image_end);
+        self.symbols = Arc::new(file.symbols.clone());
+        self.lines = Arc::new(file.lines.clone());
+        self.eh_frame = Arc::new(file.eh_frame.clone());
 
         Ok(file)
     }
 
-    fn fetch_rinst(&self) -> Result<u32, EmulatorErr>{
-        //do not throw any exception for fetch coz of perms according to: 1.4. Memory
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
 
-        let mut inst = 0;
+    pub fn lines(&self) -> &LineTable {
+        &self.lines
+    }
+
+    //a number, or the name of a symbol of the loaded program
+    fn resolve(&self, addr: &str) -> Option<u64> {
+        args::parse_num(addr).or_else(|| self.symbols.addr_of(addr))
+    }
+
+    /*
+        The instruction at pc, which has to be aligned and executable. It is fetched 16 bits at a time, the
+        upper half of a 32 bit instruction is only read once the lower one says there is one, and it may
+        sit on another page, which faults with its own address.
+    */
+    fn fetch_rinst(&self) -> Result<u32, EmulatorErr> {
+        let pc = self.fetchable_pc()?;
+        let low = self.fetch_half(pc, self.translate_peek(pc, memory::AccessKind::Execute)?)?;
+
+        if decoder::inst_size(low) == 2 {
+            return Ok(low);
+        }
+
+        let high = pc.wrapping_add(2);
+        Ok(low | self.fetch_half(high, self.translate_peek(high, memory::AccessKind::Execute)?)? << 16)
+    }
+
+    //fetch_rinst for the instruction about to execute, its translation is cached and its page marked accessed
+    fn fetch(&mut self) -> Result<u32, EmulatorErr> {
+        let pc = self.fetchable_pc()?;
+        let paddr = self.translate(pc, memory::AccessKind::Execute)?;
+        let low = self.fetch_half(pc, paddr)?;
+
+        if decoder::inst_size(low) == 2 {
+            return Ok(low);
+        }
+
+        let high = pc.wrapping_add(2);
+        let paddr = self.translate(high, memory::AccessKind::Execute)?;
+        Ok(low | self.fetch_half(high, paddr)? << 16)
+    }
+
+    fn fetch_half(&self, vaddr: u64, paddr: u64) -> Result<u32, EmulatorErr> {
+        self.mmu.fetch_u16(paddr).map(u32::from).map_err(|fault| Exceptions::from(fault.at(vaddr)).into())
+    }
+
+    //RV32 harts don't have the C extension, their instructions stay 4 byte aligned
+    fn fetchable_pc(&self) -> Result<u64, Exceptions> {
         let pc = self.cpu.get_pc();
-        
-        let inst_l = self.mmu.dram_read(pc as usize, cpu::RAW_INST_SIZE as usize)?;
-    
-        //coz little endian
-        for (i, val) in inst_l.iter().enumerate() {
-            inst = inst | (*val as u32) << 8 * i; 
+
+        if self.cpu.xlen() == cpu::Xlen::Rv32 && !pc.is_multiple_of(cpu::RAW_INST_SIZE) {
+            return Err(Exceptions::ExceptionInstructionAddressMisaligned(pc as usize));
         }
 
-        Ok(inst)
+        Ok(pc)
     }
 
+    //single step, fetches and decodes straight from memory without going through the block cache
     fn exec(&mut self) -> Result<(), EmulatorErr> {
+        match self.fetch_decode_exec() {
+            Err(EmulatorErr::ErrTrap(exception)) => self.handle_exception(exception),
+            result => result,
+        }
+    }
+
+    //single step that also lets devices see the instruction go by and takes pending interrupts after it
+    fn step(&mut self) -> Result<(), EmulatorErr> {
+        if !self.take_turn() {
+            self.idle();
+            return Ok(());
+        }
+
+        let icount = self.icount;
+        let result = self.exec();
+        self.tick_devices(self.icount - icount);
+        self.check_interrupts();
+        self.end_turn(icount);
+
+        result
+    }
+
+    //false when no hart can run right now
+    fn take_turn(&mut self) -> bool {
+        if self.slice == 0 || self.cpu.waiting || self.cpu.stopped {
+            return self.next_hart();
+        }
+
+        true
+    }
+
+    //a trap that retired nothing still uses up some of the turn, a hart stuck trapping can't starve the others
+    fn end_turn(&mut self, icount: u64) {
+        self.slice = self.slice.saturating_sub((self.icount - icount).max(1));
+    }
+
+    fn tick_devices(&mut self, elapsed: u64) {
+        if elapsed != 0 {
+            self.bus.tick(elapsed, self.icount, &mut self.mmu, &mut self.nondet);
+        }
+    }
+
+    fn fetch_decode_exec(&mut self) -> Result<(), EmulatorErr> {
         
         let pc = self.cpu.get_pc();
-        let perms = self.mmu.perm_get(pc as usize, cpu::RAW_INST_SIZE as usize)?;
 
-        //checking if PC points to executable memory
-        if perms.iter().all(|perm| perm & memory::PERM_X == 0) {
-            todo!("exception perm");
-        }    
+        //FDE
+        let rinst = self.fetch()?;
 
-        //checking alignment
-        if pc % 4 != 0 {
-            self.handle_exception(Exceptions::ExceptionInstructionAddressMisaligned(pc as usize))?;
+        if let Some(cmplog) = &mut self.cmplog {
+            cmplog.on_pc(pc, &self.cpu, &self.mmu);
         }
-  
-        //FDE
-        let rinst = self.fetch_rinst()?;
+
         let inst = decoder::decode(rinst);
-        cpu::exec(self, inst)?;
+
+        if let decoder::Inst::Undefined = inst {
+            return Err(Exceptions::ExceptionIllegalInstruction(rinst).into());
+        }
+
+        self.exec_inst(inst, decoder::inst_size(rinst))?;
 
         
         Ok(())
     }
 
-    fn handle_exception(&self, exception: Exceptions) -> Result<(), EmulatorErr> {
-        let continue_execution = exceptions::handle_expection(exception)?;
+    /*
+        Executes at most `budget` instructions of the basic block at pc out of the block cache and returns
+        how many retired. Anything the block builder refused to decode (not executable, misaligned,
+        illegal) goes through the single step path, which raises the right exception for it.
+    */
+    fn exec_block(&mut self, budget: u64) -> Result<u64, EmulatorErr> {
+        self.block_cache.invalidate(self.mmu.take_exec_writes());
+
+        //blocks are kept by physical address and never cross a page, one translation covers the whole block
+        let block = self.fetchable_pc()
+            .and_then(|pc| self.translate(pc, memory::AccessKind::Execute))
+            .map(|paddr| self.block_cache.get_or_build(paddr, &self.mmu));
+
+        let Some(block) = block.ok().filter(|block| !block.insts.is_empty()) else {
+            self.exec()?;
+            return Ok(1);
+        };
+
+        let mut retired = 0;
+        let mut inst_pc = self.cpu.get_pc();
+
+        for (inst, size) in block.insts.iter().take(budget as usize) {
+            //a trap handler or a taken branch left the block
+            if self.cpu.get_pc() != inst_pc {
+                break;
+            }
+
+            if let Some(cmplog) = &mut self.cmplog {
+                cmplog.on_pc(inst_pc, &self.cpu, &self.mmu);
+            }
+
+            match self.exec_inst(*inst, *size) {
+                Err(EmulatorErr::ErrTrap(exception)) => {
+                    self.handle_exception(exception)?;
+                    return Ok(retired);
+                }
+                result => result?,
+            }
+            retired += 1;
+            inst_pc += size;
+
+            //the block may have just overwritten itself, the rest of it could be stale
+            if self.mmu.has_exec_writes() {
+                break;
+            }
+        }
+
+        Ok(retired)
+    }
+
+    //every instruction the interpreter executes goes through here, whichever way it was fetched
+    fn exec_inst(&mut self, inst: decoder::Inst, size: u64) -> Result<(), EmulatorErr> {
+        if self.has_inst_hooks() && !self.hook_inst(inst, size)? {
+            return Ok(());
+        }
+
+        let inst = if self.mmu.has_hooks() {
+            match self.hook_exec(inst, size)? {
+                Some(inst) => inst,
+                None => return Ok(()),
+            }
+        } else {
+            inst
+        };
+
+        let Some(tracer) = self.tracer.clone() else {
+            cpu::exec(self, inst, size)?;
+            self.icount += 1;
+
+            return Ok(());
+        };
+
+        let pc = self.cpu.get_pc();
+        let regs_before = *self.cpu.get_regs();
+        let raw = self.fetch_rinst().unwrap_or(0);
+
+        lock_tracer(&tracer).begin(self.icount, pc);
+        let result = cpu::exec(self, inst, size);
+        lock_tracer(&tracer).end(self.icount, pc, raw, inst, &regs_before, self.cpu.get_regs(), result.is_err())?;
+
+        if result.is_ok() {
+            self.icount += 1;
+        }
+
+        result
+    }
+
+    //executes until a fatal trap/error or until `max_insts` instructions have retired
+    pub fn run(&mut self, max_insts: Option<u64>) -> Result<(), EmulatorErr> {
+        let mut retired = 0;
+
+        while max_insts.is_none_or(|max| retired < max) {
+            if !self.take_turn() {
+                self.idle();
+                continue;
+            }
+
+            let budget = max_insts.map_or(u64::MAX, |max| max - retired).min(self.slice);
+            let icount = self.icount;
+
+            let result = self.run_block(budget);
+            self.end_turn(icount);
+            retired += result?;
+        }
+
+        Ok(())
+    }
+
+    //one block of the running hart, then the devices catch up and pending interrupts are taken
+    fn run_block(&mut self, budget: u64) -> Result<u64, EmulatorErr> {
+        let icount = self.icount;
+
+        //compare operands, traces and hooks are only seen by the interpreter, reservations only kept by it,
+        //and the translator only knows RV64 with virtual and physical addresses the same
+        #[cfg(feature = "jit")]
+        let result = if self.jit.is_some() && self.cmplog.is_none() && self.tracer.is_none() && !self.mmu.has_hooks()
+            && !self.has_inst_hooks() && !self.reservations_held() && self.cpu.xlen() == cpu::Xlen::Rv64 && !self.paging() {
+            self.exec_block_jit(budget)
+        } else {
+            self.exec_block(budget)
+        };
+        #[cfg(not(feature = "jit"))]
+        let result = self.exec_block(budget);
+
+        self.tick_devices(self.icount - icount);
+        self.check_interrupts();
+
+        result
+    }
+
+    fn handle_exception(&mut self, exception: Exceptions) -> Result<(), EmulatorErr> {
+        if self.bare_metal {
+            return self.take_exception(exception);
+        }
+
+        //system calls are serviced here, the guest carries on after the ecall
+        if let Exceptions::ExceptionEnvironmentCall(_) = exception {
+            syscall::handle(self)?;
+            self.icount += 1;
+
+            return Ok(());
+        }
+
+        let continue_execution = exceptions::handle_expection(exception.clone())?;
 
         if !continue_execution {
-            println!();
+            return Err(EmulatorErr::ErrTrap(exception));
         }
 
         Ok(())
@@ -105,28 +476,280 @@ impl Emulator {
 
 }
 
+//a panic while tracing must not take the rest of the trace with it
+fn lock_tracer(tracer: &Mutex<Tracer>) -> std::sync::MutexGuard<'_, Tracer> {
+    tracer.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 pub fn emulate() {
+    let options = match args::Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("{}", args::USAGE);
+            return;
+        }
+    };
+
     let mut emu = Emulator::new();
-    //println!("{:?}", decoder::decode(0x7369));
-    if let Ok(file) = emu.load(&"/home/Deep/Desktop/emu/temp") {
-        if let Ok(rinst) = emu.fetch_rinst() {
-            println!("{0:x?}", rinst);
-            println!("{:?}", decoder::decode(rinst));
-            let inst = decoder::decode(rinst);
-            
-            println!("hii");
-            if let Err(err) = emu.exec() {
-                println!("nohi{:?}", err);
+    emu.mmu.set_limit(options.memory_limit);
+    emu.mmu.set_granularity(options.perm_granularity);
+    emu.mmu.set_misaligned(options.misaligned);
+
+    for &(kind, start, end) in &options.watches {
+        let stop = options.watch_stop;
+
+        emu.mmu.add_hook(kind, start, end, move |access| {
+            eprintln!("watch: {}", access);
+            if stop { memory::HookAction::Stop } else { memory::HookAction::Continue }
+        });
+    }
+
+    //a kernel is loaded once the devices it gets a device tree for are attached
+    if !options.kernel {
+        let file = match emu.load(&options.file) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+
+        //bare-metal code sets up a stack of its own
+        if !options.bare_metal {
+            let argv = [options.file.display().to_string()];
+
+            if let Err(err) = syscall::setup_stack(&mut emu, &argv, file.entry_point) {
+                eprintln!("{}", err);
+                return;
             }
-  
-            
         }
-        else if let Err(err) = emu.fetch_rinst() {
-            println!("{:?}", err);
+    }
+
+    if options.bare_metal || options.kernel {
+        let clock = if options.wall_clock { Clock::WallClock(std::time::Instant::now()) } else { Clock::Instructions };
+
+        if let Err(err) = emu.enable_bare_metal(clock, options.harts) {
+            eprintln!("{}", err);
+            return;
         }
+        emu.set_quantum(options.quantum);
     }
-    else {
-        println!("cookedok");
+
+    if options.uart_output.is_some() || options.uart_input.is_some() {
+        let uart = devices::uart::Uart::new(
+            options.uart_output.unwrap_or(devices::uart::UartOutput::Stdout),
+            options.uart_input.unwrap_or(devices::uart::UartInput::None),
+        );
+
+        if let Err(err) = emu.bus.attach_with_irq(devices::uart::UART_BASE, devices::uart::UART_IRQ, Box::new(uart)) {
+            eprintln!("{}", err);
+            return;
+        }
+    }
+
+    //virtio devices take fixed slots, slot n at VIRTIO_BASE + n * VIRTIO_STRIDE wired to VIRTIO_IRQ + n
+    let mut virtio: Vec<(u64, Box<dyn bus::Device>)> = Vec::new();
+
+    if let Some(disk) = options.disk {
+        virtio.push((0, Box::new(VirtioMmio::new(devices::virtio_blk::VirtioBlk::new(disk)))));
+    }
+    if !options.console_ports.is_empty() {
+        virtio.push((1, Box::new(VirtioMmio::new(devices::virtio_console::VirtioConsole::new(options.console_ports)))));
     }
-    println!("{:?}",decoder::decode(0x00000073));
-}
\ No newline at end of file
+    if options.virtio_rng {
+        virtio.push((2, Box::new(VirtioMmio::new(devices::virtio_rng::VirtioRng::new(options.rng_seed)))));
+    }
+
+    for (slot, device) in virtio {
+        let base = devices::virtio::VIRTIO_BASE + slot * devices::virtio::VIRTIO_STRIDE;
+
+        if let Err(err) = emu.bus.attach_with_irq(base, devices::virtio::VIRTIO_IRQ + slot as u32, device) {
+            eprintln!("{}", err);
+            return;
+        }
+    }
+
+    if options.kernel {
+        if let Err(err) = emu.boot_kernel(&options.file, options.initrd.as_deref(), &options.cmdline) {
+            eprintln!("{}", err);
+            return;
+        }
+    }
+
+    //addresses can be symbols, they are known once the program is loaded
+    if let Err(err) = emu.add_cli_hooks(&options.set_regs, &options.stubs, &options.skips, &options.stop_at, &options.stop_on) {
+        eprintln!("{}", err);
+        return;
+    }
+
+    if options.cmplog.is_some() {
+        if let Err(err) = emu.add_cli_cmplog(&options.cmp_hooks) {
+            eprintln!("{}", err);
+            return;
+        }
+    }
+
+    if let Some(sink) = options.trace_sink {
+        let mut tracer = Tracer::new(sink, options.trace_filter);
+        tracer.set_lines(emu.lines.clone());
+        emu.set_tracer(Some(tracer));
+    }
+
+    if let Some(path) = &options.replay {
+        match Nondet::load(path) {
+            Ok(log) => emu.nondet = Nondet::replay(log),
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        }
+    } else if options.record.is_some() {
+        emu.nondet = Nondet::record();
+    }
+
+    //a crash can only be saved, and minimized, together with the input that caused it
+    let input: Option<Arc<[u8]>> = match options.input {
+        Some(input) => Some(input.into()),
+        None if options.crash_dir.is_some() => {
+            let mut input = Vec::new();
+
+            if let Err(err) = io::stdin().read_to_end(&mut input) {
+                eprintln!("Unable to read stdin: {}", err);
+                return;
+            }
+            Some(input.into())
+        }
+        None => None,
+    };
+
+    if let Some(input) = &input {
+        emu.process.set_stdin(input.clone());
+    }
+
+    let mut crash_db = match &options.crash_dir {
+        Some(dir) => match triage::CrashDb::new(dir) {
+            Ok(db) => Some(db),
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if let Some(port) = options.gdb_port {
+        if let Err(err) = gdb::serve(emu, port, options.snapshot_interval) {
+            eprintln!("{}", err);
+        }
+        return;
+    }
+
+    #[cfg(feature = "jit")]
+    if options.jit_check {
+        match emu.jit_differential(options.max_insts.unwrap_or(u64::MAX)) {
+            Ok(retired) => eprintln!("jit check: {} instructions, no divergence", retired),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    #[cfg(feature = "jit")]
+    if options.jit {
+        emu.enable_jit();
+    }
+
+    //minimization candidates start over from here
+    let start = options.minimize.then(|| emu.clone());
+
+    let result = emu.run(options.max_insts);
+
+    if let Some(tracer) = &emu.tracer {
+        if let Err(err) = lock_tracer(tracer).flush() {
+            eprintln!("{}", err);
+        }
+    }
+
+    if let (Some(path), Some(cmplog)) = (&options.cmplog, emu.disable_cmplog()) {
+        if let Err(err) = cmplog.save(path) {
+            eprintln!("Unable to write {}: {}", path.display(), err);
+        }
+
+        if let (Some(dir), Some(input)) = (&options.cmplog_mutations, &input) {
+            if let Err(err) = cmplog.save_mutations(input, dir) {
+                eprintln!("Unable to write mutations to {}: {}", dir.display(), err);
+            }
+        }
+    }
+
+    //the log is kept even if the guest crashed, that is usually the run worth replaying
+    if let Some(path) = &options.record {
+        if let Err(err) = emu.nondet.save(path) {
+            eprintln!("{}", err);
+        }
+    }
+
+    //a replay that ends without having consumed the whole log went somewhere else
+    let result = match result {
+        Ok(()) | Err(EmulatorErr::ErrExited(_)) => emu.nondet.finish().map_err(EmulatorErr::from).and(result),
+        result => result,
+    };
+
+    match result {
+        Ok(()) => {}
+        Err(EmulatorErr::ErrExited(code)) => std::process::exit(code as i32),
+        Err(err @ EmulatorErr::ErrReplay(_)) => {
+            eprintln!("*** REPLAY FAILED, the execution no longer matches the recording ***");
+            eprintln!("{}", triage::report(&emu, &err));
+            std::process::exit(1);
+        }
+        //a hook or watchpoint stopping the run is what was asked for, the run ends normally
+        Err(err @ (EmulatorErr::ErrStopped(_) | EmulatorErr::ErrHookStopped(_))) => eprintln!("{}", triage::stop_report(&emu, &err)),
+        Err(err) if triage::is_crash(&err) => {
+            eprintln!("{}", triage::report(&emu, &err));
+
+            if let Some(db) = &mut crash_db {
+                let input = input.as_deref().unwrap_or(&[]);
+
+                match triage::store_crash(db, &emu, &err, input, start.as_ref()) {
+                    Ok(Some(bucket)) => eprintln!("new crash bucket, saved to {}", db.input_path(&bucket).display()),
+                    Ok(None) => eprintln!("known crash bucket, not saved again"),
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+
+            std::process::exit(triage::CRASH_EXIT_CODE);
+        }
+        //the emulator itself failed, the guest did nothing wrong
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+//a small user mode machine for unit tests to run hand assembled code on
+#[cfg(test)]
+mod testing {
+    use super::{memory, Emulator};
+
+    pub const CODE_BASE: u64 = 0x1000;
+    pub const DATA_BASE: u64 = 0x2000;
+    pub const DATA_SIZE: usize = 0x1000;
+
+    //`code` at CODE_BASE with pc on its first instruction, a page of zeroed read/write data at DATA_BASE
+    pub fn emulator(code: &[u32]) -> Emulator {
+        let mut emu = Emulator::new();
+        let bytes: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+
+        emu.mmu.map_region(CODE_BASE as usize, bytes.len(), memory::PERM_R | memory::PERM_X, "text").unwrap();
+        emu.mmu.dram_write(CODE_BASE as usize, &bytes).unwrap();
+        emu.mmu.map_region(DATA_BASE as usize, DATA_SIZE, memory::PERM_R | memory::PERM_W, "data").unwrap();
+        emu.cpu.set_pc(CODE_BASE);
+
+        emu
+    }
+}
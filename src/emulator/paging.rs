@@ -0,0 +1,508 @@
+use super::{csr::{self, Privilege}, exceptions::Exceptions, memory::{AccessFault, AccessKind, MisalignedAccess, PAGE_SIZE, PERM_W}, Emulator};
+
+/*
+    4.4. Sv39: Page-Based 39-bit Virtual-Memory System
+
+    A virtual address is three 9 bit page numbers and a 12 bit offset. The walk starts at the table
+    satp.PPN points to, any of the three levels can hold a leaf, mapping a 1GB or 2MB superpage above
+    the last one. Accessed and dirty bits are set by the walk itself (Svadu), so software never sees a page
+    fault just for them.
+
+    Fetches are translated below M mode, loads and stores too but from M mode with MPRV set as well.
+    Page tables are read and written in physical memory regardless of the permissions Mmu keeps, those
+    only apply to the translated access.
+*/
+
+const PAGE_SHIFT: u64 = 12;
+const LEVELS: u64 = 3;
+const VPN_BITS: u64 = 9;
+const VA_BITS: u64 = 39;
+const PTE_SIZE: u64 = 8;
+
+const PTE_V: u64 = 1;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN: u64 = ((1 << 44) - 1) << PTE_PPN_SHIFT;
+//N and PBMT are reserved too without Svnapot and Svpbmt
+const PTE_RESERVED: u64 = !((1 << 54) - 1);
+
+const TLB_ENTRIES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    vpn: u64,
+    //physical address of the 4KB page, superpages are cached a page at a time
+    page: u64,
+    pte: u64,
+}
+
+const TLB_INVALID: TlbEntry = TlbEntry { vpn: u64::MAX, page: 0, pte: 0 };
+
+/*
+    A hart's recent translations, direct mapped by virtual page number. Like a hardware TLB it keeps what
+    the page tables said until SFENCE.VMA (or a satp write, an SBI remote fence) flushes it; permissions
+    are checked against the cached PTE on every hit since privilege, SUM and MXR change without a fence.
+*/
+#[derive(Clone)]
+pub struct Tlb(Box<[TlbEntry; TLB_ENTRIES]>);
+
+impl Tlb {
+    pub fn new() -> Self {
+        Tlb(Box::new([TLB_INVALID; TLB_ENTRIES]))
+    }
+
+    pub fn flush(&mut self) {
+        self.0.fill(TLB_INVALID);
+    }
+
+    fn lookup(&self, vaddr: u64) -> Option<TlbEntry> {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let entry = self.0[vpn as usize % TLB_ENTRIES];
+
+        (entry.vpn == vpn).then_some(entry)
+    }
+
+    fn insert(&mut self, vaddr: u64, paddr: u64, pte: u64) {
+        let vpn = vaddr >> PAGE_SHIFT;
+
+        self.0[vpn as usize % TLB_ENTRIES] = TlbEntry { vpn, page: paddr & !(PAGE_SIZE as u64 - 1), pte };
+    }
+}
+
+//where the bytes of a load or store are
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phys {
+    At(u64),
+    //crosses into a virtual page mapped somewhere else: the first `len` bytes at the first address, the rest at the second
+    Split(u64, usize, u64),
+}
+
+//the leaf a walk ended at
+struct Leaf {
+    paddr: u64,
+    pte: u64,
+    pte_addr: u64,
+}
+
+fn page_fault(vaddr: u64, kind: AccessKind) -> Exceptions {
+    match kind {
+        AccessKind::Read => Exceptions::ExceptionLoadPageFault(vaddr as usize),
+        AccessKind::Write => Exceptions::ExceptionStorePageFault(vaddr as usize),
+        AccessKind::Execute => Exceptions::ExceptionPageFault(vaddr as usize),
+    }
+}
+
+impl Emulator {
+    //the privilege an access is checked against, None when it isn't translated
+    fn translated_as(&self, kind: AccessKind) -> Option<Privilege> {
+        let csr = &self.cpu.csr;
+        let privilege = if kind == AccessKind::Execute { csr.privilege } else { csr.data_privilege() };
+
+        (csr.satp() >> csr::SATP_MODE_SHIFT == csr::SATP_MODE_SV39 && privilege != Privilege::Machine).then_some(privilege)
+    }
+
+    //whether the running hart's fetches or data accesses go through the page tables right now
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    pub(super) fn paging(&self) -> bool {
+        self.translated_as(AccessKind::Execute).is_some() || self.translated_as(AccessKind::Read).is_some()
+    }
+
+    /*
+        4.3.1: whether a leaf PTE allows the access. Reads need R, or X with mstatus.MXR. U mode only gets
+        at U pages, S mode only at those with mstatus.SUM and it never executes them.
+    */
+    fn check_pte(&self, pte: u64, vaddr: u64, kind: AccessKind, privilege: Privilege) -> Result<(), Exceptions> {
+        let mstatus = self.cpu.csr.mstatus();
+
+        let user_page = pte & PTE_U != 0;
+        let privilege_ok = match privilege {
+            Privilege::User => user_page,
+            _ => !user_page || (kind != AccessKind::Execute && mstatus & csr::MSTATUS_SUM != 0),
+        };
+        let perm_ok = match kind {
+            AccessKind::Read => pte & PTE_R != 0 || (mstatus & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0),
+            AccessKind::Write => pte & PTE_W != 0,
+            AccessKind::Execute => pte & PTE_X != 0,
+        };
+
+        if !privilege_ok || !perm_ok {
+            return Err(page_fault(vaddr, kind));
+        }
+
+        Ok(())
+    }
+
+    /*
+        4.3.2. Virtual Address Translation Process, for Sv39. A PTE that can't be read is an access fault
+        of the original access, a PTE that is invalid or reserved or a misaligned superpage a page fault.
+    */
+    fn walk(&self, vaddr: u64, kind: AccessKind, privilege: Privilege) -> Result<Leaf, Exceptions> {
+        //bits 63:39 have to be copies of bit 38
+        if ((vaddr << (64 - VA_BITS)) as i64 >> (64 - VA_BITS)) as u64 != vaddr {
+            return Err(page_fault(vaddr, kind));
+        }
+
+        let mut table = (self.cpu.csr.satp() & csr::SATP_PPN) << PAGE_SHIFT;
+
+        for level in (0..LEVELS).rev() {
+            let offset_bits = PAGE_SHIFT + level * VPN_BITS;
+            let pte_addr = table + ((vaddr >> offset_bits) & ((1 << VPN_BITS) - 1)) * PTE_SIZE;
+
+            let pte = self.mmu.dram_read(pte_addr as usize, PTE_SIZE as usize)
+                .map(|bytes| u64::from_le_bytes(bytes[..].try_into().unwrap()))
+                .map_err(|_| Exceptions::from(AccessFault::Denied(vaddr, kind)))?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0 {
+                return Err(page_fault(vaddr, kind));
+            }
+
+            let base = ((pte & PTE_PPN) >> PTE_PPN_SHIFT) << PAGE_SHIFT;
+
+            //neither readable nor executable, a pointer to the next level
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = base;
+                continue;
+            }
+
+            let offset_mask = (1 << offset_bits) - 1;
+            if base & offset_mask != 0 {
+                return Err(page_fault(vaddr, kind));
+            }
+
+            self.check_pte(pte, vaddr, kind, privilege)?;
+
+            return Ok(Leaf { paddr: base | (vaddr & offset_mask), pte, pte_addr });
+        }
+
+        //the last level has to be a leaf
+        Err(page_fault(vaddr, kind))
+    }
+
+    //the physical address of vaddr, through the TLB, setting the leaf's A and D bits the first time they're needed
+    pub(super) fn translate(&mut self, vaddr: u64, kind: AccessKind) -> Result<u64, Exceptions> {
+        let Some(privilege) = self.translated_as(kind) else {
+            return Ok(vaddr);
+        };
+
+        if let Some(entry) = self.cpu.tlb.lookup(vaddr) {
+            //the first write to a page walks again to set its dirty bit
+            if kind != AccessKind::Write || entry.pte & PTE_D != 0 {
+                self.check_pte(entry.pte, vaddr, kind, privilege)?;
+                return Ok(entry.page | (vaddr & (PAGE_SIZE as u64 - 1)));
+            }
+        }
+
+        let leaf = self.walk(vaddr, kind, privilege)?;
+        let pte = leaf.pte | PTE_A | if kind == AccessKind::Write { PTE_D } else { 0 };
+
+        if pte != leaf.pte {
+            //the walk just read the PTE from there
+            let _ = self.mmu.dram_write(leaf.pte_addr as usize, &pte.to_le_bytes());
+        }
+
+        self.cpu.tlb.insert(vaddr, leaf.paddr, pte);
+
+        Ok(leaf.paddr)
+    }
+
+    //translate without touching the TLB or the page tables, for looking at memory the way the hart sees it
+    pub(super) fn translate_peek(&self, vaddr: u64, kind: AccessKind) -> Result<u64, Exceptions> {
+        let Some(privilege) = self.translated_as(kind) else {
+            return Ok(vaddr);
+        };
+
+        if let Some(entry) = self.cpu.tlb.lookup(vaddr) {
+            self.check_pte(entry.pte, vaddr, kind, privilege)?;
+            return Ok(entry.page | (vaddr & (PAGE_SIZE as u64 - 1)));
+        }
+
+        self.walk(vaddr, kind, privilege).map(|leaf| leaf.paddr)
+    }
+
+    /*
+        Translates a load or store of `size` bytes. A misaligned access that faults does so before it is
+        translated, one that is carried out may cross into the next page and needs that translated too.
+    */
+    pub(super) fn translate_access(&mut self, vaddr: u64, size: usize, kind: AccessKind) -> Result<Phys, Exceptions> {
+        if self.translated_as(kind).is_none() {
+            return Ok(Phys::At(vaddr));
+        }
+
+        if self.mmu.misaligned() == MisalignedAccess::Fault && !vaddr.is_multiple_of(size as u64) {
+            return Err(AccessFault::Misaligned(vaddr, kind).into());
+        }
+
+        let low = self.translate(vaddr, kind)?;
+        let len = PAGE_SIZE - vaddr as usize % PAGE_SIZE;
+        if size <= len {
+            return Ok(Phys::At(low));
+        }
+
+        let high = self.translate(vaddr.wrapping_add(len as u64), kind)?;
+
+        Ok(if high == low + len as u64 { Phys::At(low) } else { Phys::Split(low, len, high) })
+    }
+
+    //the parts of a Phys::Split load, access faults are reported at the virtual address of the part
+    pub(super) fn read_split(&self, vaddr: u64, size: usize, (low, len, high): (u64, usize, u64)) -> Result<u64, Exceptions> {
+        let low = self.mmu.read(low, len, AccessKind::Read).map_err(|fault| fault.at(vaddr))?;
+        let high = self.mmu.read(high, size - len, AccessKind::Read).map_err(|fault| fault.at(vaddr + len as u64))?;
+
+        Ok(low | high << (len * 8))
+    }
+
+    //the same for stores, nothing is written unless both parts can be
+    pub(super) fn write_split(&mut self, vaddr: u64, size: usize, (low, len, high): (u64, usize, u64), value: u64) -> Result<(), Exceptions> {
+        if !self.mmu.perm_check(high as usize, size - len, PERM_W) {
+            return Err(AccessFault::Denied(vaddr + len as u64, AccessKind::Write).into());
+        }
+
+        self.mmu.write(low, len, value).map_err(|fault| fault.at(vaddr))?;
+        self.mmu.write(high, size - len, value >> (len * 8)).map_err(|fault| fault.at(vaddr + len as u64))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{cpu::REG_A0, devices::clint::Clock, memory::{PERM_R, PERM_X}, testing::{self, CODE_BASE, DATA_BASE}};
+
+    const NOP: u32 = 0x0000_0013;
+    //ld a0, 0(a1)
+    const LD: u32 = 0x0005_b503;
+    //sd a0, 0(a1)
+    const SD: u32 = 0x00a5_b023;
+    const SFENCE_VMA: u32 = 0x1200_0073;
+    //csrw satp, a2
+    const CSRW_SATP: u32 = 0x1806_1073;
+
+    const ROOT: u64 = 0x10000;
+    const L1: u64 = 0x11000;
+    const L0: u64 = 0x12000;
+    const FRAME: u64 = 0x13000;
+    const SATP: u64 = (csr::SATP_MODE_SV39 << csr::SATP_MODE_SHIFT) | (ROOT >> PAGE_SHIFT);
+
+    //both in the first 2MB of the second gigabyte, which L0 maps
+    const CODE_VA: u64 = 0x4000_1000;
+    const DATA_VA: u64 = 0x4000_2000;
+
+    const COUNTERS: csr::Counters = csr::Counters { cycle: 0, instret: 0, time: None };
+
+    fn pte(paddr: u64, flags: u64) -> u64 {
+        ((paddr >> PAGE_SHIFT) << PTE_PPN_SHIFT) | flags
+    }
+
+    fn write_pte(emu: &mut Emulator, addr: u64, pte: u64) {
+        emu.mmu.dram_write(addr as usize, &pte.to_le_bytes()).unwrap();
+    }
+
+    fn read_pte(emu: &Emulator, addr: u64) -> u64 {
+        u64::from_le_bytes(emu.mmu.dram_read(addr as usize, 8).unwrap()[..].try_into().unwrap())
+    }
+
+    fn leaf_addr(vaddr: u64) -> u64 {
+        L0 + ((vaddr >> PAGE_SHIFT) & 0x1ff) * PTE_SIZE
+    }
+
+    fn map(emu: &mut Emulator, vaddr: u64, paddr: u64, flags: u64) {
+        write_pte(emu, leaf_addr(vaddr), pte(paddr, flags | PTE_V));
+    }
+
+    //`code` running from CODE_VA in S mode, DATA_VA mapped to the data page
+    fn paged(code: &[u32]) -> Emulator {
+        let mut emu = testing::emulator(code);
+        emu.enable_bare_metal(Clock::Instructions, 1).unwrap();
+
+        emu.mmu.map_region(ROOT as usize, 3 * PAGE_SIZE, PERM_R | PERM_W, "tables").unwrap();
+        emu.mmu.map_region(FRAME as usize, PAGE_SIZE, PERM_R | PERM_W, "frame").unwrap();
+        write_pte(&mut emu, ROOT + PTE_SIZE, pte(L1, PTE_V));
+        write_pte(&mut emu, L1, pte(L0, PTE_V));
+        map(&mut emu, CODE_VA, CODE_BASE, PTE_R | PTE_X);
+        map(&mut emu, DATA_VA, DATA_BASE, PTE_R | PTE_W);
+
+        emu.cpu.csr.write(csr::CSR_SATP, SATP);
+        emu.cpu.csr.write(csr::CSR_MTVEC, 0x8000);
+        emu.cpu.csr.privilege = Privilege::Supervisor;
+        emu.cpu.set_pc(CODE_VA);
+        emu
+    }
+
+    //mcause and mtval of the trap the last instruction took
+    fn trapped(emu: &Emulator) -> (u64, u64) {
+        let csrs = &emu.cpu.csr;
+        (csrs.read(csr::CSR_MCAUSE, &COUNTERS).unwrap(), csrs.read(csr::CSR_MTVAL, &COUNTERS).unwrap())
+    }
+
+    #[test]
+    fn walks_to_pages_and_superpages() {
+        let mut emu = paged(&[NOP]);
+        assert_eq!(emu.translate(DATA_VA + 0x18, AccessKind::Read).unwrap(), DATA_BASE + 0x18);
+
+        //a 2MB page out of L1 and a 1GB one out of the root, each has to be aligned to its size
+        write_pte(&mut emu, L1 + PTE_SIZE, pte(0x8020_0000, PTE_V | PTE_R));
+        assert_eq!(emu.translate(0x4030_1234, AccessKind::Read).unwrap(), 0x8030_1234);
+        write_pte(&mut emu, ROOT + 2 * PTE_SIZE, pte(0x8000_0000, PTE_V | PTE_R));
+        assert_eq!(emu.translate(0x8765_4321, AccessKind::Read).unwrap(), 0x8765_4321);
+        write_pte(&mut emu, L1 + 2 * PTE_SIZE, pte(0x8020_1000, PTE_V | PTE_R));
+        assert!(matches!(emu.translate(0x4040_0000, AccessKind::Read), Err(Exceptions::ExceptionLoadPageFault(0x4040_0000))));
+
+        //unmapped, writable without being readable, and not sign extended from bit 38
+        assert!(matches!(emu.translate(0x4000_3000, AccessKind::Read), Err(Exceptions::ExceptionLoadPageFault(0x4000_3000))));
+        map(&mut emu, 0x4000_3000, FRAME, PTE_W);
+        assert!(matches!(emu.translate(0x4000_3000, AccessKind::Write), Err(Exceptions::ExceptionStorePageFault(0x4000_3000))));
+        assert!(matches!(emu.translate(1 << 39, AccessKind::Execute), Err(Exceptions::ExceptionPageFault(_))));
+
+        //a table that isn't in memory is an access fault of the access that needed it
+        write_pte(&mut emu, ROOT + 3 * PTE_SIZE, pte(0x7000_0000, PTE_V));
+        assert!(matches!(emu.translate(0xc000_0000, AccessKind::Read), Err(Exceptions::ExceptionLoadAccessFault(0xc000_0000))));
+    }
+
+    #[test]
+    fn faults_have_the_cause_of_the_access() {
+        let mut emu = paged(&[LD, SD]);
+
+        emu.cpu.set_reg(11, 0x4000_5008).unwrap();
+        emu.exec().unwrap();
+        assert_eq!(trapped(&emu), (13, 0x4000_5008));
+
+        //code is mapped read-only
+        emu.cpu.csr.privilege = Privilege::Supervisor;
+        emu.cpu.set_pc(CODE_VA + 4);
+        emu.cpu.set_reg(11, CODE_VA).unwrap();
+        emu.exec().unwrap();
+        assert_eq!(trapped(&emu), (15, CODE_VA));
+
+        emu.cpu.csr.privilege = Privilege::Supervisor;
+        emu.cpu.set_pc(0x4000_5000);
+        emu.exec().unwrap();
+        assert_eq!(trapped(&emu), (12, 0x4000_5000));
+    }
+
+    #[test]
+    fn walks_set_accessed_and_dirty() {
+        let mut emu = paged(&[NOP]);
+
+        emu.translate(DATA_VA, AccessKind::Read).unwrap();
+        assert_eq!(read_pte(&emu, leaf_addr(DATA_VA)) & (PTE_A | PTE_D), PTE_A);
+
+        //the page is in the TLB, but not as dirty yet
+        emu.translate(DATA_VA, AccessKind::Write).unwrap();
+        assert_eq!(read_pte(&emu, leaf_addr(DATA_VA)) & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn translations_are_cached_until_fenced() {
+        let mut emu = paged(&[LD, LD, SFENCE_VMA, LD, CSRW_SATP, LD]);
+        emu.mmu.dram_write(DATA_BASE as usize, &1u64.to_le_bytes()).unwrap();
+        emu.mmu.dram_write(FRAME as usize, &2u64.to_le_bytes()).unwrap();
+        emu.cpu.set_reg(11, DATA_VA).unwrap();
+        emu.cpu.set_reg(12, SATP).unwrap();
+
+        let step = |emu: &mut Emulator| {
+            emu.exec().unwrap();
+            emu.cpu.get_reg(REG_A0).unwrap()
+        };
+
+        assert_eq!(step(&mut emu), 1);
+        map(&mut emu, DATA_VA, FRAME, PTE_R | PTE_W);
+        assert_eq!(step(&mut emu), 1);
+        step(&mut emu);
+        assert_eq!(step(&mut emu), 2);
+
+        //writing satp drops what was cached for the old tables as well
+        map(&mut emu, DATA_VA, DATA_BASE, PTE_R | PTE_W);
+        step(&mut emu);
+        assert_eq!(step(&mut emu), 1);
+    }
+
+    #[test]
+    fn user_pages_and_execute_only_pages() {
+        let mut emu = paged(&[NOP]);
+
+        //S mode only reads user pages with SUM, and never runs them
+        map(&mut emu, DATA_VA, DATA_BASE, PTE_R | PTE_W | PTE_U);
+        assert!(matches!(emu.translate(DATA_VA, AccessKind::Read), Err(Exceptions::ExceptionLoadPageFault(_))));
+        emu.cpu.csr.write(csr::CSR_SSTATUS, csr::MSTATUS_SUM);
+        assert_eq!(emu.translate(DATA_VA, AccessKind::Read).unwrap(), DATA_BASE);
+
+        map(&mut emu, CODE_VA, CODE_BASE, PTE_X | PTE_U);
+        emu.cpu.tlb.flush();
+        assert!(matches!(emu.translate(CODE_VA, AccessKind::Execute), Err(Exceptions::ExceptionPageFault(_))));
+
+        //U mode only gets at user pages
+        emu.cpu.csr.privilege = Privilege::User;
+        assert_eq!(emu.translate(CODE_VA, AccessKind::Execute).unwrap(), CODE_BASE);
+        map(&mut emu, DATA_VA, DATA_BASE, PTE_R | PTE_W);
+        emu.cpu.tlb.flush();
+        assert!(matches!(emu.translate(DATA_VA, AccessKind::Read), Err(Exceptions::ExceptionLoadPageFault(_))));
+
+        //execute-only pages are readable with MXR
+        emu.cpu.csr.privilege = Privilege::Supervisor;
+        map(&mut emu, CODE_VA, CODE_BASE, PTE_X);
+        emu.cpu.tlb.flush();
+        assert!(matches!(emu.translate(CODE_VA, AccessKind::Read), Err(Exceptions::ExceptionLoadPageFault(_))));
+        emu.cpu.csr.write(csr::CSR_SSTATUS, csr::MSTATUS_MXR);
+        assert_eq!(emu.translate(CODE_VA, AccessKind::Read).unwrap(), CODE_BASE);
+    }
+
+    #[test]
+    fn mprv_translates_m_mode_loads_and_stores() {
+        let mut emu = paged(&[NOP]);
+        emu.cpu.csr.privilege = Privilege::Machine;
+        assert_eq!(emu.translate(DATA_VA, AccessKind::Read).unwrap(), DATA_VA);
+
+        emu.cpu.csr.write(csr::CSR_MSTATUS, csr::MSTATUS_MPRV | (1 << csr::MSTATUS_MPP_SHIFT));
+        assert_eq!(emu.translate(DATA_VA, AccessKind::Write).unwrap(), DATA_BASE);
+        assert_eq!(emu.translate(CODE_VA, AccessKind::Execute).unwrap(), CODE_VA);
+    }
+
+    #[test]
+    fn accesses_crossing_pages_go_to_both_frames() {
+        let mut emu = paged(&[SD, LD]);
+        map(&mut emu, DATA_VA + PAGE_SIZE as u64, FRAME, PTE_R | PTE_W);
+
+        emu.cpu.set_reg(11, DATA_VA + 0xffc).unwrap();
+        emu.cpu.set_reg(REG_A0, 0x1122_3344_5566_7788).unwrap();
+        emu.exec().unwrap();
+        assert_eq!(emu.mmu.dram_read(DATA_BASE as usize + 0xffc, 4).unwrap()[..], 0x5566_7788u32.to_le_bytes());
+        assert_eq!(emu.mmu.dram_read(FRAME as usize, 4).unwrap()[..], 0x1122_3344u32.to_le_bytes());
+
+        emu.cpu.set_reg(REG_A0, 0).unwrap();
+        emu.exec().unwrap();
+        assert_eq!(emu.cpu.get_reg(REG_A0).unwrap(), 0x1122_3344_5566_7788);
+    }
+
+    #[test]
+    fn instructions_crossing_pages_are_fetched_from_both_frames() {
+        const LOW_VA: u64 = 0x4000_3000;
+        const HIGH_VA: u64 = 0x4000_4000;
+        const HIGH_FRAME: u64 = FRAME + PAGE_SIZE as u64;
+
+        let mut emu = paged(&[NOP]);
+        emu.mmu.mprotect(FRAME as usize, PAGE_SIZE, PERM_R | PERM_X).unwrap();
+        emu.mmu.map_region(HIGH_FRAME as usize, PAGE_SIZE, PERM_R | PERM_X, "high").unwrap();
+        map(&mut emu, LOW_VA, FRAME, PTE_R | PTE_X);
+
+        //addi a0, a0, 1 with its upper half on the next page
+        emu.mmu.dram_write(FRAME as usize + 0xffe, &[0x13, 0x05]).unwrap();
+        emu.mmu.dram_write(HIGH_FRAME as usize, &[0x15, 0x00]).unwrap();
+
+        //the fault is for the half that isn't mapped
+        emu.cpu.set_pc(HIGH_VA - 2);
+        emu.exec().unwrap();
+        assert_eq!(trapped(&emu), (12, HIGH_VA));
+
+        map(&mut emu, HIGH_VA, HIGH_FRAME, PTE_R | PTE_X);
+        emu.cpu.csr.privilege = Privilege::Supervisor;
+        emu.cpu.set_pc(HIGH_VA - 2);
+        emu.exec().unwrap();
+        assert_eq!((emu.cpu.get_reg(REG_A0).unwrap(), emu.cpu.get_pc()), (1, HIGH_VA + 2));
+    }
+}
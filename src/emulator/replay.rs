@@ -0,0 +1,405 @@
+use std::{fs, io, path::Path};
+
+const LOG_MAGIC: [u8; 4] = *b"CRRR";
+const LOG_VERSION: u8 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayErr {
+    #[error("Unable to access replay log: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid replay log")]
+    InvalidLog,
+
+    #[error("Replay log exhausted at icount {icount}, guest asked for {requested:?}")]
+    Exhausted { icount: u64, requested: NondetSource },
+
+    #[error("Replay diverged: log has {expected:?} at icount {expected_icount}, guest asked for {got:?} at icount {got_icount}")]
+    Divergence { expected: NondetSource, expected_icount: u64, got: NondetSource, got_icount: u64 },
+
+    #[error("Replay diverged at icount {icount}: log has {logged} bytes for {requested:?}, guest asked for at most {max}")]
+    SizeMismatch { icount: u64, requested: NondetSource, logged: usize, max: usize },
+
+    #[error("Replay diverged: the run ended with {left} logged values never asked for, the next one {next:?} at icount {next_icount}")]
+    Unconsumed { left: usize, next: NondetSource, next_icount: u64 },
+}
+
+//where a nondeterministic value entered the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NondetSource {
+    Syscall(u64),
+    Device(u64),
+}
+
+impl NondetSource {
+    fn encode(&self) -> (u8, u64) {
+        match self {
+            NondetSource::Syscall(nr) => (0, *nr),
+            NondetSource::Device(id) => (1, *id),
+        }
+    }
+
+    fn decode(kind: u8, id: u64) -> Option<Self> {
+        match kind {
+            0 => Some(NondetSource::Syscall(id)),
+            1 => Some(NondetSource::Device(id)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NondetEvent {
+    pub icount: u64,
+    pub source: NondetSource,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NondetMode {
+    //straight from the host
+    Live,
+    //from the host, and every value is appended to the log; a rewound log is replayed up to its end first
+    Record,
+    //only from the log, the host is never asked
+    Replay,
+}
+
+/*
+    Every value the guest can observe that does not follow from its own state (input bytes, time,
+    randomness, device input) has to be fetched through `take`. In record mode the values are logged with
+    the instruction count they were consumed at, in replay mode they are fed back from the log, and any
+    mismatch between what the guest asks for and what was logged stops the run instead of silently
+    producing a different execution.
+*/
+#[derive(Debug, Clone)]
+pub struct Nondet {
+    mode: NondetMode,
+    log: Vec<NondetEvent>,
+    cursor: usize,
+}
+
+impl Default for Nondet {
+    fn default() -> Self {
+        Nondet {
+            mode: NondetMode::Live,
+            log: Vec::new(),
+            cursor: 0,
+        }
+    }
+}
+
+impl Nondet {
+    pub fn record() -> Self {
+        Nondet {
+            mode: NondetMode::Record,
+            ..Default::default()
+        }
+    }
+
+    pub fn replay(log: Vec<NondetEvent>) -> Self {
+        Nondet {
+            mode: NondetMode::Replay,
+            log,
+            cursor: 0,
+        }
+    }
+
+    pub fn mode(&self) -> NondetMode {
+        self.mode
+    }
+
+    pub fn log(&self) -> &[NondetEvent] {
+        &self.log
+    }
+
+    //position of the next value in the log
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /*
+        The same log, positioned at `cursor`, for a snapshot taken back when that many values had been
+        consumed. A recording that is rewound replays what it already logged and only asks the host again
+        once it runs past the end of the log.
+    */
+    pub fn rewound(&self, cursor: usize) -> Self {
+        Nondet {
+            mode: self.mode,
+            log: self.log.clone(),
+            cursor: cursor.min(self.log.len()),
+        }
+    }
+
+    //forgets everything after the cursor, once the guest state was changed behind the log's back
+    pub fn truncate(&mut self) {
+        if self.mode == NondetMode::Record {
+            self.log.truncate(self.cursor);
+        }
+    }
+
+    //a replay has to consume the whole log, a run that ends before it did went somewhere else
+    pub fn finish(&self) -> Result<(), ReplayErr> {
+        match self.log.get(self.cursor) {
+            Some(event) if self.mode == NondetMode::Replay => Err(ReplayErr::Unconsumed {
+                left: self.log.len() - self.cursor,
+                next: event.source,
+                next_icount: event.icount,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    //`live` produces at most `max` bytes, only called when the value does not come from the log
+    pub fn take<F>(&mut self, icount: u64, source: NondetSource, max: usize, live: F) -> Result<Vec<u8>, ReplayErr>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        match self.mode {
+            NondetMode::Live => Ok(live()),
+            NondetMode::Record if self.cursor == self.log.len() => {
+                let data = live();
+                self.log.push(NondetEvent { icount, source, data: data.clone() });
+                self.cursor += 1;
+
+                Ok(data)
+            }
+            NondetMode::Record | NondetMode::Replay => self.next_logged(icount, source, max),
+        }
+    }
+
+    /*
+        For input that arrives on its own (a device polling the host) rather than being asked for: nothing
+        is logged while `live` has nothing, and a replay delivers each logged value once the guest has
+        reached the icount it was recorded at.
+    */
+    pub fn poll<F>(&mut self, icount: u64, source: NondetSource, live: F) -> Option<Vec<u8>>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        if self.mode == NondetMode::Live {
+            return Some(live()).filter(|data| !data.is_empty());
+        }
+
+        if let Some(event) = self.log.get(self.cursor) {
+            if event.source == source && event.icount <= icount {
+                self.cursor += 1;
+                return Some(event.data.clone());
+            }
+        }
+
+        //values the guest has not reached yet can't be overtaken by new ones
+        if self.mode == NondetMode::Replay || self.cursor < self.log.len() {
+            return None;
+        }
+
+        let data = live();
+        if data.is_empty() {
+            return None;
+        }
+
+        self.log.push(NondetEvent { icount, source, data: data.clone() });
+        self.cursor += 1;
+
+        Some(data)
+    }
+
+    fn next_logged(&mut self, icount: u64, source: NondetSource, max: usize) -> Result<Vec<u8>, ReplayErr> {
+        let Some(event) = self.log.get(self.cursor) else {
+            return Err(ReplayErr::Exhausted { icount, requested: source });
+        };
+
+        if event.source != source || event.icount != icount {
+            return Err(ReplayErr::Divergence {
+                expected: event.source,
+                expected_icount: event.icount,
+                got: source,
+                got_icount: icount,
+            });
+        }
+        if event.data.len() > max {
+            return Err(ReplayErr::SizeMismatch { icount, requested: source, logged: event.data.len(), max });
+        }
+
+        self.cursor += 1;
+
+        Ok(event.data.clone())
+    }
+
+    /*
+        Log file, all integers little endian:
+            header:     "CRRR" u8 version
+            event:      u64 icount, u8 source kind (0 syscall, 1 device), u64 source id, u32 len, len bytes
+    */
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ReplayErr> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(&LOG_MAGIC);
+        out.push(LOG_VERSION);
+
+        for event in &self.log {
+            let (kind, id) = event.source.encode();
+
+            out.extend_from_slice(&event.icount.to_le_bytes());
+            out.push(kind);
+            out.extend_from_slice(&id.to_le_bytes());
+            out.extend_from_slice(&(event.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&event.data);
+        }
+
+        fs::write(path, out)?;
+
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<NondetEvent>, ReplayErr> {
+        let data = fs::read(path)?;
+
+        if data.len() < LOG_MAGIC.len() + 1 || data[..LOG_MAGIC.len()] != LOG_MAGIC || data[LOG_MAGIC.len()] != LOG_VERSION {
+            return Err(ReplayErr::InvalidLog);
+        }
+
+        let mut log = Vec::new();
+        let mut rest = &data[LOG_MAGIC.len() + 1..];
+
+        while !rest.is_empty() {
+            let icount = u64::from_le_bytes(take_bytes(&mut rest)?);
+            let [kind] = take_bytes(&mut rest)?;
+            let id = u64::from_le_bytes(take_bytes(&mut rest)?);
+            let len = u32::from_le_bytes(take_bytes(&mut rest)?) as usize;
+
+            if rest.len() < len {
+                return Err(ReplayErr::InvalidLog);
+            }
+            let (event_data, tail) = rest.split_at(len);
+            rest = tail;
+
+            log.push(NondetEvent {
+                icount,
+                source: NondetSource::decode(kind, id).ok_or(ReplayErr::InvalidLog)?,
+                data: event_data.to_vec(),
+            });
+        }
+
+        Ok(log)
+    }
+}
+
+fn take_bytes<const N: usize>(rest: &mut &[u8]) -> Result<[u8; N], ReplayErr> {
+    if rest.len() < N {
+        return Err(ReplayErr::InvalidLog);
+    }
+
+    let (head, tail) = rest.split_at(N);
+    *rest = tail;
+
+    Ok(head.try_into().unwrap_or([0; N]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ: NondetSource = NondetSource::Syscall(63);
+    const TIME: NondetSource = NondetSource::Syscall(113);
+    const UART: NondetSource = NondetSource::Device(0x1000_0000);
+
+    fn recorded() -> Nondet {
+        let mut nondet = Nondet::record();
+
+        assert_eq!(nondet.take(5, READ, 16, || b"hello".to_vec()).unwrap(), b"hello");
+        assert_eq!(nondet.take(9, TIME, 16, || vec![1; 16]).unwrap(), vec![1; 16]);
+        assert_eq!(nondet.poll(12, UART, Vec::new), None);
+        assert_eq!(nondet.poll(20, UART, || b"x".to_vec()).unwrap(), b"x");
+
+        nondet
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("crimson-replay-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn log_survives_a_round_trip_through_a_file() {
+        let path = temp_path("round-trip");
+        recorded().save(&path).unwrap();
+        let log = Nondet::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let events: Vec<_> = log.iter().map(|event| (event.icount, event.source, event.data.clone())).collect();
+        assert_eq!(events, [(5, READ, b"hello".to_vec()), (9, TIME, vec![1; 16]), (20, UART, b"x".to_vec())]);
+    }
+
+    #[test]
+    fn damaged_logs_are_rejected() {
+        let path = temp_path("damaged");
+        recorded().save(&path).unwrap();
+        let data = fs::read(&path).unwrap();
+
+        for damaged in [&data[..3], &data[..data.len() - 1], &[&b"CRRR\x02"[..], &data[5..]].concat()[..]] {
+            fs::write(&path, damaged).unwrap();
+            assert!(matches!(Nondet::load(&path), Err(ReplayErr::InvalidLog)));
+        }
+
+        //a bad source kind
+        let mut bad_kind = data.clone();
+        bad_kind[5 + 8] = 7;
+        fs::write(&path, bad_kind).unwrap();
+        assert!(matches!(Nondet::load(&path), Err(ReplayErr::InvalidLog)));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn replay_feeds_the_log_back_without_asking_the_host() {
+        let mut replay = Nondet::replay(recorded().log().to_vec());
+        let host = || -> Vec<u8> { panic!("a replay asked the host") };
+
+        assert_eq!(replay.take(5, READ, 16, host).unwrap(), b"hello");
+        assert_eq!(replay.take(9, TIME, 16, host).unwrap(), vec![1; 16]);
+        //device input only arrives once the guest got as far as it did when it was recorded
+        assert_eq!(replay.poll(19, UART, host), None);
+        assert_eq!(replay.poll(25, UART, host).unwrap(), b"x");
+
+        assert!(replay.finish().is_ok());
+        assert!(matches!(replay.take(30, READ, 16, host), Err(ReplayErr::Exhausted { icount: 30, .. })));
+    }
+
+    #[test]
+    fn replay_stops_where_the_guest_no_longer_matches_the_log() {
+        let log = recorded().log().to_vec();
+        let host = Vec::new;
+
+        let mut replay = Nondet::replay(log.clone());
+        assert!(matches!(replay.take(6, READ, 16, host), Err(ReplayErr::Divergence { expected_icount: 5, got_icount: 6, .. })));
+
+        let mut replay = Nondet::replay(log.clone());
+        assert!(matches!(replay.take(5, TIME, 16, host), Err(ReplayErr::Divergence { expected: READ, got: TIME, .. })));
+
+        let mut replay = Nondet::replay(log.clone());
+        assert!(matches!(replay.take(5, READ, 4, host), Err(ReplayErr::SizeMismatch { logged: 5, max: 4, .. })));
+
+        //ending early is a divergence too
+        let mut replay = Nondet::replay(log);
+        replay.take(5, READ, 16, host).unwrap();
+        assert!(matches!(replay.finish(), Err(ReplayErr::Unconsumed { left: 2, next: TIME, next_icount: 9 })));
+    }
+
+    #[test]
+    fn rewound_recording_replays_before_asking_the_host_again() {
+        let nondet = recorded();
+
+        let mut rewound = nondet.rewound(1);
+        assert_eq!(rewound.cursor(), 1);
+        assert_eq!(rewound.take(9, TIME, 16, || vec![2; 16]).unwrap(), vec![1; 16]);
+
+        //changing the guest behind the log's back forgets what came after
+        let mut rewound = nondet.rewound(1);
+        rewound.truncate();
+        assert_eq!(rewound.take(9, TIME, 16, || vec![2; 16]).unwrap(), vec![2; 16]);
+        assert_eq!(rewound.log().len(), 2);
+
+        assert!(rewound.finish().is_ok());
+        assert_eq!(Nondet::default().take(0, READ, 16, || b"live".to_vec()).unwrap(), b"live");
+    }
+}
@@ -0,0 +1,222 @@
+use super::{cpu, csr::{Csrs, MIP_SSIP}, devices::clint, Emulator, EmulatorErr};
+
+/*
+    Supervisor Binary Interface, implemented by the emulator in place of M mode firmware (OpenSBI). The
+    supervisor calls in with ecall: a7 is the extension, a6 the function, a0..a5 the arguments; a0 comes
+    back as the error and a1 as the value.
+*/
+
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+const REG_A6: usize = 16;
+const REG_A7: usize = 17;
+
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x0048_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+
+const SUPPORTED: [u64; 6] = [EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST];
+
+//SBI 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+//not a registered implementation id, nothing should depend on it
+const IMPL_ID: u64 = 0x6372;
+const IMPL_VERSION: u64 = 1;
+
+const SUCCESS: i64 = 0;
+const ERR_FAILED: i64 = -1;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const HSM_STARTED: u64 = 0;
+const HSM_STOPPED: u64 = 1;
+const HSM_SUSPEND_RETENTIVE: u64 = 0;
+
+const SRST_WARM_REBOOT: u64 = 2;
+const SRST_REASON_FAILURE: u64 = 1;
+
+//hart_mask_base meaning every hart
+const ALL_HARTS: u64 = u64::MAX;
+
+type SbiResult = Result<u64, i64>;
+
+impl Emulator {
+    //an ecall from S mode, the return values are in a0/a1 and execution goes on after the ecall
+    pub(super) fn sbi_call(&mut self) -> Result<(), EmulatorErr> {
+        let ext = self.cpu.get_reg(REG_A7)?;
+        let func = self.cpu.get_reg(REG_A6)?;
+        let mut args = [0; 6];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = self.cpu.get_reg(REG_A0 + i)?;
+        }
+
+        let result = match ext {
+            EXT_BASE => self.sbi_base(func, args),
+            EXT_TIME => self.sbi_time(func, args),
+            EXT_IPI => self.sbi_ipi(func, args),
+            EXT_RFENCE => self.sbi_rfence(func, args),
+            EXT_HSM => self.sbi_hsm(func, args),
+            EXT_SRST => self.sbi_srst(func, args)?,
+            _ => Err(ERR_NOT_SUPPORTED),
+        };
+
+        let (error, value) = match result {
+            Ok(value) => (SUCCESS, value),
+            Err(error) => (error, 0),
+        };
+
+        self.cpu.set_reg(REG_A0, error as u64)?;
+        self.cpu.set_reg(REG_A1, value)?;
+        self.cpu.set_pc(self.cpu.get_pc() + cpu::RAW_INST_SIZE);
+
+        Ok(())
+    }
+
+    fn sbi_base(&self, func: u64, args: [u64; 6]) -> SbiResult {
+        match func {
+            0 => Ok(SPEC_VERSION),
+            1 => Ok(IMPL_ID),
+            2 => Ok(IMPL_VERSION),
+            3 => Ok(SUPPORTED.contains(&args[0]) as u64),
+            //mvendorid, marchid, mimpid
+            4..=6 => Ok(0),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    //the next timer interrupt, it stays pending (as STIP) until a later time is set
+    fn sbi_time(&mut self, func: u64, args: [u64; 6]) -> SbiResult {
+        if func != 0 {
+            return Err(ERR_NOT_SUPPORTED);
+        }
+
+        let hart = self.cpu.csr.hartid();
+        let mtimecmp = clint::CLINT_BASE + clint::REG_MTIMECMP + 8 * hart;
+
+        self.bus.write(mtimecmp, 8, args[0]).map_err(|_| ERR_FAILED)?;
+
+        Ok(0)
+    }
+
+    //the harts a (hart_mask, hart_mask_base) pair selects, all of them have to exist
+    fn sbi_harts(&self, mask: u64, base: u64) -> Result<Vec<usize>, i64> {
+        let harts = self.hart_count() as u64;
+
+        if base == ALL_HARTS {
+            return Ok((0..harts as usize).collect());
+        }
+
+        let selected: Vec<u64> = (0..64).filter(|bit| mask >> bit & 1 != 0).map(|bit| base.wrapping_add(bit)).collect();
+        if selected.iter().any(|hart| *hart >= harts) {
+            return Err(ERR_INVALID_PARAM);
+        }
+
+        Ok(selected.into_iter().map(|hart| hart as usize).collect())
+    }
+
+    //an IPI shows up as a supervisor software interrupt on the target harts, it also ends their WFI
+    fn sbi_ipi(&mut self, func: u64, args: [u64; 6]) -> SbiResult {
+        if func != 0 {
+            return Err(ERR_NOT_SUPPORTED);
+        }
+
+        for hart in self.sbi_harts(args[0], args[1])? {
+            self.hart_mut(hart).csr.set_mip(MIP_SSIP);
+        }
+
+        Ok(0)
+    }
+
+    /*
+        Writes to code are tracked by the block cache all harts share, so remote FENCE.I only has to check
+        its hart mask; the SFENCE.VMA ones flush the TLBs of the harts named. The hypervisor fences need
+        the H extension.
+    */
+    fn sbi_rfence(&mut self, func: u64, args: [u64; 6]) -> SbiResult {
+        match func {
+            0 => self.sbi_harts(args[0], args[1]).map(|_| 0),
+            1 | 2 => {
+                for hart in self.sbi_harts(args[0], args[1])? {
+                    self.hart_mut(hart).tlb.flush();
+                }
+
+                Ok(0)
+            }
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    /*
+        Hart state management, how a kernel brings up its secondary harts: they sit stopped until it starts
+        them at an address of its choosing, in S mode with a0 = hartid and a1 = opaque.
+    */
+    fn sbi_hsm(&mut self, func: u64, args: [u64; 6]) -> SbiResult {
+        let target = self.sbi_harts(1, args[0]).ok().map(|harts| harts[0]);
+
+        match func {
+            //hart_start
+            0 => {
+                let hart = target.ok_or(ERR_INVALID_PARAM)?;
+                if !self.hart(hart).stopped {
+                    return Err(ERR_ALREADY_AVAILABLE);
+                }
+
+                let cpu = self.hart_mut(hart);
+                let mut regs = [0; cpu::MAX_REGS];
+                regs[REG_A0] = args[0];
+                regs[REG_A1] = args[2];
+
+                cpu.set_regs(regs);
+                cpu.set_pc(args[1]);
+                cpu.csr = Csrs::new(args[0]);
+                cpu.csr.enter_supervisor();
+                cpu.tlb.flush();
+                cpu.reservation = None;
+                cpu.waiting = false;
+                cpu.stopped = false;
+
+                Ok(0)
+            }
+            //hart_stop, doesn't return; the last running hart can't stop, nothing could start it again
+            1 => {
+                let running = (0..self.hart_count()).filter(|hart| !self.hart(*hart).stopped).count();
+                if running == 1 {
+                    return Err(ERR_FAILED);
+                }
+
+                self.cpu.stopped = true;
+                Ok(0)
+            }
+            //hart_get_status
+            2 => {
+                let hart = target.ok_or(ERR_INVALID_PARAM)?;
+                Ok(if self.hart(hart).stopped { HSM_STOPPED } else { HSM_STARTED })
+            }
+            //hart_suspend, a retentive suspend is a WFI that returns success
+            3 if args[0] as u32 as u64 == HSM_SUSPEND_RETENTIVE => {
+                self.wait_for_interrupt();
+                Ok(0)
+            }
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    //there is nothing to reboot into, a reset of any kind ends the run
+    fn sbi_srst(&mut self, func: u64, args: [u64; 6]) -> Result<SbiResult, EmulatorErr> {
+        if func != 0 {
+            return Ok(Err(ERR_NOT_SUPPORTED));
+        }
+
+        if args[0] as u32 as u64 > SRST_WARM_REBOOT {
+            return Ok(Err(ERR_INVALID_PARAM));
+        }
+
+        let code = if args[1] as u32 as u64 == SRST_REASON_FAILURE { 1 } else { 0 };
+
+        Err(EmulatorErr::ErrExited(code))
+    }
+}
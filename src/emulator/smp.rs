@@ -0,0 +1,142 @@
+use super::{cpu::Cpu, csr::Csrs, Emulator};
+
+//the CLINT and PLIC register maps have room for more, SBI hart masks are 64 bits
+pub const MAX_HARTS: usize = 64;
+
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+/*
+    Several harts sharing the Mmu, the bus and the block cache. The running hart is `cpu`, the others are
+    parked in `harts` and swapped in when their turn comes. Round-robin scheduling gives every hart a
+    quantum of instructions in hart order, so runs stay deterministic and can be recorded, replayed and
+    debugged like single hart ones. Time (the CLINT) counts the instructions of all harts together.
+
+    Harts only ever run one at a time on the emulator's thread, nothing here runs them in parallel on
+    host threads.
+*/
+impl Emulator {
+    //every hart starts like the running one, at the same pc in M mode, with its own mhartid
+    pub(super) fn add_harts(&mut self, harts: usize) {
+        self.harts = (0..harts).map(|hartid| {
+            let mut cpu = self.cpu.clone();
+            cpu.csr = Csrs::new(hartid as u64);
+            cpu
+        }).collect();
+
+        self.cpu = self.harts[self.hart].clone();
+        self.slice = self.full_slice();
+    }
+
+    pub fn set_quantum(&mut self, quantum: u64) {
+        self.quantum = quantum;
+        self.slice = self.full_slice();
+    }
+
+    //a single hart never has to give up its turn, its blocks aren't cut short by the quantum
+    fn full_slice(&self) -> u64 {
+        if self.harts.len() > 1 { self.quantum } else { u64::MAX }
+    }
+
+    pub fn hart_count(&self) -> usize {
+        self.harts.len()
+    }
+
+    pub fn hart(&self, hartid: usize) -> &Cpu {
+        if hartid == self.hart { &self.cpu } else { &self.harts[hartid] }
+    }
+
+    pub fn hart_mut(&mut self, hartid: usize) -> &mut Cpu {
+        if hartid == self.hart { &mut self.cpu } else { &mut self.harts[hartid] }
+    }
+
+    //the running hart's slot in `harts` only holds whatever was swapped out of it last
+    fn switch_hart(&mut self, hartid: usize) {
+        if hartid == self.hart {
+            return;
+        }
+
+        std::mem::swap(&mut self.cpu, &mut self.harts[self.hart]);
+        std::mem::swap(&mut self.cpu, &mut self.harts[hartid]);
+        self.hart = hartid;
+    }
+
+    /*
+        Passes the turn on to the next hart that can run, the current one included if it is the only one.
+        A parked hart only sees its interrupts when it is switched in, that is also what ends its WFI.
+        Returns false if every hart waits or is stopped.
+    */
+    pub(super) fn next_hart(&mut self) -> bool {
+        let harts = self.harts.len();
+        let current = self.hart;
+
+        for step in 1..=harts {
+            self.switch_hart((current + step) % harts);
+            self.check_interrupts();
+
+            if !self.cpu.waiting && !self.cpu.stopped {
+                self.slice = self.full_slice();
+                return true;
+            }
+        }
+
+        false
+    }
+
+    //8.2: a store to a reserved address from any other hart makes that hart's SC fail
+    pub(super) fn break_reservations(&mut self, addr: u64, size: usize) {
+        if self.harts.len() == 1 {
+            return;
+        }
+
+        let hart = self.hart;
+        for (hartid, cpu) in self.harts.iter_mut().enumerate() {
+            if hartid == hart {
+                continue;
+            }
+
+            if let Some((reserved, reserved_size)) = cpu.reservation {
+                if addr < reserved + reserved_size as u64 && reserved < addr + size as u64 {
+                    cpu.reservation = None;
+                }
+            }
+        }
+    }
+
+    //translated code stores straight to memory, past break_reservations
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    pub(super) fn reservations_held(&self) -> bool {
+        self.cpu.reservation.is_some() || (self.harts.len() > 1 && self.harts.iter().any(|cpu| cpu.reservation.is_some()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{devices::clint::Clock, testing::{self, DATA_BASE}};
+
+    //every hart counts up its own dword at DATA_BASE + 8 * hartid, 4 instructions of setup and 4 per count
+    const COUNT: [u32; 8] = [0xf140_2573, 0x0035_1513, 0x0000_2937, 0x00a9_0933, 0x0009_3283, 0x0012_8293, 0x0059_3023, 0xff5f_f06f];
+
+    fn machine(harts: usize) -> Emulator {
+        let mut emu = testing::emulator(&COUNT);
+        emu.enable_bare_metal(Clock::Instructions, harts).unwrap();
+        emu.set_quantum(10);
+
+        emu
+    }
+
+    fn counts(emu: &Emulator, harts: usize) -> Vec<u64> {
+        (0..harts as u64).map(|hart| u64::from_le_bytes(emu.mmu.dram_read((DATA_BASE + 8 * hart) as usize, 8).unwrap()[..].try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn round_robin_gives_every_hart_a_quantum() {
+        let mut emu = machine(3);
+
+        //four turns each, the setup and 9 counts
+        emu.run(Some(3 * 40)).unwrap();
+
+        assert_eq!(counts(&emu, 3), [9, 9, 9]);
+        assert_eq!(emu.hart(1).csr.hartid(), 1);
+    }
+}
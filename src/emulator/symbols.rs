@@ -0,0 +1,156 @@
+use std::{collections::HashMap, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    //labels without a type, hand written assembly is full of them
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    //0 when the symbol doesn't say, it then covers everything up to the next one
+    pub size: u64,
+    pub kind: SymbolKind,
+    pub global: bool,
+}
+
+//an address as a symbol and how far into it the address is
+#[derive(Clone, Copy, Debug)]
+pub struct Location<'a> {
+    pub symbol: &'a Symbol,
+    pub offset: u64,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            0 => write!(f, "{}", self.symbol.name),
+            offset => write!(f, "{}+{:#x}", self.symbol.name, offset),
+        }
+    }
+}
+
+/*
+    The symbols of the loaded program, looked up by name or by an address inside them. Several symbols
+    often share an address (a local alias, a weak and a strong definition), the address lookup picks the
+    one most likely to be the function's own name: sized over unsized, global over local, functions over
+    anything else.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    //sorted by address, one per address
+    by_addr: Vec<Symbol>,
+    by_name: HashMap<String, u64>,
+}
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        let mut by_name = HashMap::new();
+
+        //a global definition wins over a local one of the same name, the first one seen otherwise
+        for symbol in &symbols {
+            if symbol.global || !by_name.contains_key(&symbol.name) {
+                by_name.insert(symbol.name.clone(), symbol.addr);
+            }
+        }
+
+        symbols.sort_by_key(|symbol| (symbol.addr, std::cmp::Reverse(rank(symbol))));
+        symbols.dedup_by_key(|symbol| symbol.addr);
+
+        Symbols { by_addr: symbols, by_name }
+    }
+
+    pub fn addr_of(&self, name: &str) -> Option<u64> {
+        self.by_name.get(name).copied()
+    }
+
+    //the symbol the address is in, an unsized one reaches up to the next symbol
+    pub fn locate(&self, addr: u64) -> Option<Location<'_>> {
+        let idx = self.by_addr.partition_point(|symbol| symbol.addr <= addr).checked_sub(1)?;
+        let symbol = &self.by_addr[idx];
+        let offset = addr - symbol.addr;
+
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        Some(Location { symbol, offset })
+    }
+
+    //the symbol starting exactly at addr
+    pub fn at(&self, addr: u64) -> Option<&Symbol> {
+        self.locate(addr).filter(|location| location.offset == 0).map(|location| location.symbol)
+    }
+}
+
+fn rank(symbol: &Symbol) -> (bool, bool, bool) {
+    (symbol.size != 0, symbol.global, symbol.kind == SymbolKind::Function)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, addr: u64, size: u64, kind: SymbolKind, global: bool) -> Symbol {
+        Symbol { name: name.to_string(), addr, size, kind, global }
+    }
+
+    #[test]
+    fn aliases_resolve_to_the_best_name() {
+        let symbols = Symbols::new(vec![
+            symbol(".L0", 0x100, 0, SymbolKind::Other, false),
+            symbol("helper", 0x100, 0x10, SymbolKind::Function, false),
+            symbol("main", 0x100, 0x10, SymbolKind::Function, true),
+            symbol("data", 0x100, 0x10, SymbolKind::Object, true),
+        ]);
+
+        assert_eq!(symbols.at(0x100).unwrap().name, "main");
+
+        //every alias is still found by name
+        for name in [".L0", "helper", "main", "data"] {
+            assert_eq!(symbols.addr_of(name), Some(0x100));
+        }
+        assert_eq!(symbols.addr_of("missing"), None);
+    }
+
+    #[test]
+    fn globals_win_name_lookups() {
+        let symbols = Symbols::new(vec![
+            symbol("f", 0x100, 4, SymbolKind::Function, false),
+            symbol("f", 0x200, 4, SymbolKind::Function, true),
+            symbol("f", 0x300, 4, SymbolKind::Function, false),
+            symbol("g", 0x400, 4, SymbolKind::Function, false),
+            symbol("g", 0x500, 4, SymbolKind::Function, false),
+        ]);
+
+        assert_eq!(symbols.addr_of("f"), Some(0x200));
+        assert_eq!(symbols.addr_of("g"), Some(0x400));
+    }
+
+    #[test]
+    fn addresses_are_located_inside_symbols() {
+        let symbols = Symbols::new(vec![
+            symbol("sized", 0x100, 0x10, SymbolKind::Function, true),
+            symbol("unsized", 0x200, 0, SymbolKind::Other, false),
+            symbol("last", 0x300, 0x8, SymbolKind::Object, true),
+        ]);
+
+        assert!(symbols.locate(0xff).is_none());
+        assert_eq!(symbols.locate(0x100).unwrap().to_string(), "sized");
+        assert_eq!(symbols.locate(0x10f).unwrap().to_string(), "sized+0xf");
+
+        //past the end of a sized symbol there's nothing, an unsized one reaches the next symbol
+        assert!(symbols.locate(0x110).is_none());
+        assert_eq!(symbols.locate(0x2ff).unwrap().to_string(), "unsized+0xff");
+        assert_eq!(symbols.locate(0x307).unwrap().to_string(), "last+0x7");
+        assert!(symbols.locate(0x308).is_none());
+
+        assert!(symbols.at(0x104).is_none());
+        assert_eq!(symbols.at(0x200).unwrap().name, "unsized");
+        assert!(Symbols::default().locate(0).is_none());
+    }
+}
@@ -0,0 +1,412 @@
+use std::{fs, io::{self, Read, Write}, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use super::{cpu::{self, Xlen}, memory::{self, MmmuErr}, replay::NondetSource, Emulator, EmulatorErr};
+
+/*
+    Linux user mode system calls, the part of the execution environment a statically linked RV64 or RV32
+    Linux binary expects when it executes ECALL: the number is in a7, arguments in a0-a5, the result (or
+    -errno) goes back to a0. RV32 only has the 64 bit time variants of the time calls, their structures
+    are laid out like the RV64 ones.
+
+    Every value coming from the host that the guest can observe (input, time, randomness) is fetched through
+    emu.nondet so a run can be recorded and replayed.
+*/
+
+const REG_SP: usize = 2;
+const REG_A0: usize = 10;
+const REG_A7: usize = 17;
+
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_BRK: u64 = 214;
+const SYS_MPROTECT: u64 = 226;
+const SYS_GETRANDOM: u64 = 278;
+const SYS_CLOCK_GETTIME64: u64 = 403;
+
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+
+const STDIN: u64 = 0;
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+//largest single read/write we service, the guest just gets a short count beyond this
+const MAX_IO_SIZE: usize = 1024 * 1024;
+
+//there is no process or thread besides the guest itself
+const GUEST_TID: i64 = 1;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+//the stack grows down from right below the top of a 47 bit user address space, like on Linux
+pub const STACK_TOP: u64 = 0x7fff_ffff_f000;
+//and for RV32 from below 2 GiB, where addresses are still positive when sign extended
+pub const STACK_TOP_32: u64 = 0x7fff_f000;
+pub const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+//auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Clone, Default)]
+pub struct Process {
+    brk_start: u64,
+    brk: u64,
+
+    //set while history is re-executed, the host already saw this output once
+    mute_output: bool,
+
+    //what the guest reads from stdin instead of the host's, and how much of it was read so far
+    stdin: Option<Arc<[u8]>>,
+    stdin_pos: usize,
+}
+
+impl Process {
+    //the heap starts at the first page after the loaded image
+    pub fn new(image_end: u64) -> Self {
+        let brk_start = image_end.next_multiple_of(PAGE_SIZE);
+
+        Process {
+            brk_start,
+            brk: brk_start,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_mute_output(&mut self, mute: bool) {
+        self.mute_output = mute;
+    }
+
+    //the guest reads `input` from the start instead of the host's stdin
+    pub fn set_stdin(&mut self, input: Arc<[u8]>) {
+        self.stdin = Some(input);
+        self.stdin_pos = 0;
+    }
+}
+
+/*
+    Maps the stack and lays out what the ELF psABI says a process starts with: sp points at argc, then
+    come argv, an empty envp and the auxiliary vector, each terminated by a zero, with the strings above.
+    Every entry is XLEN bits wide.
+*/
+pub fn setup_stack(emu: &mut Emulator, argv: &[String], entry: u64) -> Result<(), EmulatorErr> {
+    let xlen = emu.cpu.xlen();
+    let stack_top = if xlen == Xlen::Rv32 { STACK_TOP_32 } else { STACK_TOP };
+
+    let stack_bottom = stack_top - STACK_SIZE;
+    emu.mmu.map_region(stack_bottom as usize, STACK_SIZE as usize, memory::PERM_R | memory::PERM_W, "stack")?;
+
+    let mut top = stack_top;
+    let mut argv_addrs = Vec::new();
+    for arg in argv {
+        top -= arg.len() as u64 + 1;
+        emu.mmu.dram_write(top as usize, arg.as_bytes())?;
+        argv_addrs.push(top);
+    }
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(argv_addrs);
+    words.push(0);
+    words.push(0);
+    words.extend([AT_PAGESZ, PAGE_SIZE, AT_ENTRY, entry, AT_NULL, 0]);
+
+    let word_size = xlen.bytes();
+    let sp = (top - (word_size * words.len()) as u64) & !0xf;
+    let data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes().into_iter().take(word_size)).collect();
+    emu.mmu.dram_write(sp as usize, &data)?;
+
+    emu.cpu.set_reg(REG_SP, sp)?;
+
+    Ok(())
+}
+
+pub fn handle(emu: &mut Emulator) -> Result<(), EmulatorErr> {
+    let nr = emu.cpu.get_reg(REG_A7)?;
+    let mut args = [0; 6];
+    //RV32 registers are sign extended, the arguments are XLEN bit pointers and sizes
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = emu.cpu.zero_extend(emu.cpu.get_reg(REG_A0 + i)?);
+    }
+
+    let ret = match nr {
+        SYS_READ => sys_read(emu, args[0], args[1], args[2] as usize)?,
+        SYS_WRITE => sys_write(emu, args[0], args[1], args[2] as usize),
+        SYS_EXIT | SYS_EXIT_GROUP => return Err(EmulatorErr::ErrExited(args[0])),
+        SYS_SET_TID_ADDRESS => GUEST_TID,
+        SYS_CLOCK_GETTIME | SYS_CLOCK_GETTIME64 => sys_clock_gettime(emu, args[1])?,
+        SYS_GETTIMEOFDAY => sys_gettimeofday(emu, args[0])?,
+        SYS_BRK => sys_brk(emu, args[0]),
+        SYS_MPROTECT => sys_mprotect(emu, args[0], args[1], args[2]),
+        SYS_GETRANDOM => sys_getrandom(emu, args[0], args[1] as usize)?,
+        _ => -ENOSYS,
+    };
+
+    emu.cpu.set_reg(REG_A0, ret as u64)?;
+    emu.cpu.set_pc(emu.cpu.get_pc() + cpu::RAW_INST_SIZE);
+
+    Ok(())
+}
+
+fn check_perm(emu: &Emulator, vaddr: u64, len: usize, perm: u8) -> bool {
+    emu.mmu.perm_check(vaddr as usize, len, perm)
+}
+
+fn guest_read(emu: &Emulator, vaddr: u64, len: usize) -> Result<Vec<u8>, i64> {
+    if !check_perm(emu, vaddr, len, memory::PERM_R) {
+        return Err(-EFAULT);
+    }
+
+    emu.mmu.dram_read(vaddr as usize, len).map(|data| data.to_vec()).map_err(|_| -EFAULT)
+}
+
+fn guest_write(emu: &mut Emulator, vaddr: u64, data: &[u8]) -> Result<(), i64> {
+    if !check_perm(emu, vaddr, data.len(), memory::PERM_W) {
+        return Err(-EFAULT);
+    }
+
+    emu.mmu.dram_write(vaddr as usize, data).map_err(|_| -EFAULT)
+}
+
+//fetches a nondeterministic value and copies it to the guest, returns what the syscall returns
+fn nondet_to_guest<F>(emu: &mut Emulator, nr: u64, vaddr: u64, max: usize, live: F) -> Result<i64, EmulatorErr>
+where
+    F: FnOnce() -> Vec<u8>,
+{
+    //a bad buffer must not consume a value, or record and replay would disagree on what was consumed
+    if !check_perm(emu, vaddr, max, memory::PERM_W) {
+        return Ok(-EFAULT);
+    }
+
+    let data = emu.nondet.take(emu.icount, NondetSource::Syscall(nr), max, live)?;
+
+    Ok(match guest_write(emu, vaddr, &data) {
+        Ok(()) => data.len() as i64,
+        Err(errno) => errno,
+    })
+}
+
+fn sys_read(emu: &mut Emulator, fd: u64, buf: u64, count: usize) -> Result<i64, EmulatorErr> {
+    if fd != STDIN {
+        return Ok(-EBADF);
+    }
+
+    let count = count.min(MAX_IO_SIZE);
+    let input = emu.process.stdin.clone();
+    let pos = emu.process.stdin_pos;
+
+    let ret = nondet_to_guest(emu, SYS_READ, buf, count, || match &input {
+        Some(input) => input[pos.min(input.len())..].iter().take(count).copied().collect(),
+        None => {
+            let mut data = vec![0; count];
+            let len = io::stdin().read(&mut data).unwrap_or(0);
+            data.truncate(len);
+
+            data
+        }
+    })?;
+
+    if ret > 0 {
+        emu.process.stdin_pos += ret as usize;
+    }
+
+    Ok(ret)
+}
+
+fn sys_write(emu: &mut Emulator, fd: u64, buf: u64, count: usize) -> i64 {
+    let data = match guest_read(emu, buf, count.min(MAX_IO_SIZE)) {
+        Ok(data) => data,
+        Err(errno) => return errno,
+    };
+
+    if emu.process.mute_output && (fd == STDOUT || fd == STDERR) {
+        return data.len() as i64;
+    }
+
+    let written = match fd {
+        STDOUT => io::stdout().write_all(&data),
+        STDERR => io::stderr().write_all(&data),
+        _ => return -EBADF,
+    };
+
+    match written {
+        Ok(()) => data.len() as i64,
+        Err(_) => -EFAULT,
+    }
+}
+
+fn host_time() -> (u64, u32) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    (now.as_secs(), now.subsec_nanos())
+}
+
+//all clocks read the host's realtime clock, the guest can't tell the difference between them here
+fn sys_clock_gettime(emu: &mut Emulator, tp: u64) -> Result<i64, EmulatorErr> {
+    let ret = nondet_to_guest(emu, SYS_CLOCK_GETTIME, tp, 16, || {
+        let (sec, nsec) = host_time();
+
+        [sec.to_le_bytes(), (nsec as u64).to_le_bytes()].concat()
+    })?;
+
+    Ok(ret.min(0))
+}
+
+fn sys_gettimeofday(emu: &mut Emulator, tv: u64) -> Result<i64, EmulatorErr> {
+    if tv == 0 {
+        return Ok(0);
+    }
+
+    let ret = nondet_to_guest(emu, SYS_GETTIMEOFDAY, tv, 16, || {
+        let (sec, nsec) = host_time();
+
+        [sec.to_le_bytes(), (nsec as u64 / 1000).to_le_bytes()].concat()
+    })?;
+
+    Ok(ret.min(0))
+}
+
+fn sys_getrandom(emu: &mut Emulator, buf: u64, len: usize) -> Result<i64, EmulatorErr> {
+    let len = len.min(MAX_IO_SIZE);
+
+    nondet_to_guest(emu, SYS_GETRANDOM, buf, len, || {
+        let mut data = vec![0; len];
+        let len = fs::File::open("/dev/urandom").and_then(|mut file| file.read(&mut data)).unwrap_or(0);
+        data.truncate(len);
+
+        data
+    })
+}
+
+/*
+    brk(0) (or anything below the start of the heap) returns the current break, otherwise the break is
+    moved and the pages in between become readable and writable. When memory runs out the old break is
+    returned, which is how the guest learns that the call failed.
+*/
+fn sys_brk(emu: &mut Emulator, addr: u64) -> i64 {
+    let process = &mut emu.process;
+
+    if addr < process.brk_start {
+        return process.brk as i64;
+    }
+
+    let old = process.brk;
+
+    if addr > old {
+        let size = (addr - old) as usize;
+
+        //running out of memory partway leaves the pages mapped so far behind, they go again
+        if emu.mmu.dram_set(0, old as usize, size).is_err() || emu.mmu.map_region(old as usize, size, memory::PERM_R | memory::PERM_W, "heap").is_err() {
+            let _ = emu.mmu.unmap(old as usize, size);
+            return old as i64;
+        }
+    } else {
+        //a page granular mmu would take the page the new break is on with it, the kernel keeps that page
+        let from = match emu.mmu.granularity() {
+            memory::PermGranularity::Page => (addr as usize).next_multiple_of(memory::PAGE_SIZE).min(old as usize),
+            memory::PermGranularity::Byte => addr as usize,
+        };
+
+        if emu.mmu.unmap(from, old as usize - from).is_err() {
+            return old as i64;
+        }
+    }
+
+    emu.process.brk = addr;
+
+    addr as i64
+}
+
+//the region keeps its name, only its pages' permissions change
+fn sys_mprotect(emu: &mut Emulator, addr: u64, len: u64, prot: u64) -> i64 {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return -EINVAL;
+    }
+
+    let mut perm = 0;
+    for (bit, perm_bit) in [(PROT_READ, memory::PERM_R), (PROT_WRITE, memory::PERM_W), (PROT_EXEC, memory::PERM_X)] {
+        if prot & bit != 0 {
+            perm |= perm_bit;
+        }
+    }
+
+    match emu.mmu.mprotect(addr as usize, len as usize, perm) {
+        Ok(()) => 0,
+        Err(MmmuErr::Unmapped(_) | MmmuErr::OutOfMemory(..)) => -ENOMEM,
+        Err(_) => -EINVAL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing::{self, DATA_BASE};
+
+    const HEAP: u64 = 0x10_0000;
+
+    fn process() -> Emulator {
+        let mut emu = testing::emulator(&[]);
+        emu.process = Process::new(HEAP - 0x10);
+
+        emu
+    }
+
+    #[test]
+    fn brk_grows_and_shrinks_the_heap() {
+        let mut emu = process();
+        let rw = memory::PERM_R | memory::PERM_W;
+
+        assert_eq!(sys_brk(&mut emu, 0), HEAP as i64);
+        assert_eq!(sys_brk(&mut emu, HEAP + 0x1800), (HEAP + 0x1800) as i64);
+        assert_eq!((emu.mmu.perm_at(HEAP as usize), emu.mmu.perm_at(HEAP as usize + 0x17ff)), (rw, rw));
+        assert_eq!(emu.mmu.perm_at(HEAP as usize + 0x1800), 0);
+        assert_eq!(emu.mmu.region_at(HEAP as usize + 0x1000).unwrap().name, "heap");
+
+        //what was freed comes back zeroed
+        emu.mmu.dram_write(HEAP as usize + 0x100, &[0xff]).unwrap();
+        assert_eq!(sys_brk(&mut emu, HEAP + 0x80), (HEAP + 0x80) as i64);
+        assert_eq!(emu.mmu.perm_at(HEAP as usize + 0x100), 0);
+        assert_eq!(sys_brk(&mut emu, HEAP + 0x200), (HEAP + 0x200) as i64);
+        assert_eq!(emu.mmu.dram_read(HEAP as usize + 0x100, 1).unwrap()[0], 0);
+    }
+
+    #[test]
+    fn brk_that_runs_out_of_memory_maps_nothing() {
+        let mut emu = process();
+        sys_brk(&mut emu, HEAP + 0x100);
+
+        let mapped = emu.mmu.mapped_bytes();
+        emu.mmu.set_limit(mapped + 4 * PAGE_SIZE as usize);
+
+        assert_eq!(sys_brk(&mut emu, HEAP + 16 * PAGE_SIZE), (HEAP + 0x100) as i64);
+        assert_eq!(emu.mmu.mapped_bytes(), mapped);
+        assert_eq!(emu.mmu.perm_at(HEAP as usize + 0x100), 0);
+        assert_eq!(emu.mmu.perm_at(HEAP as usize + 0xff), memory::PERM_R | memory::PERM_W);
+
+        assert_eq!(sys_brk(&mut emu, HEAP + 4 * PAGE_SIZE), (HEAP + 4 * PAGE_SIZE) as i64);
+    }
+
+    #[test]
+    fn reads_take_the_process_input_in_order() {
+        let mut emu = process();
+        emu.process.set_stdin(Arc::from(&b"hello"[..]));
+
+        assert_eq!(sys_read(&mut emu, STDIN, DATA_BASE, 3).unwrap(), 3);
+        assert_eq!(sys_read(&mut emu, STDIN, DATA_BASE + 3, 100).unwrap(), 2);
+        assert_eq!(sys_read(&mut emu, STDIN, DATA_BASE, 100).unwrap(), 0);
+        assert_eq!(emu.mmu.dram_read(DATA_BASE as usize, 6).unwrap()[..], *b"hello\0");
+
+        assert_eq!(sys_read(&mut emu, 5, DATA_BASE, 1).unwrap(), -EBADF);
+    }
+}
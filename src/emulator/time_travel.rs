@@ -0,0 +1,427 @@
+use std::collections::BTreeSet;
+use super::{cpu, decoder::{self, Inst}, exceptions::Exceptions, replay::NondetMode, trace::MemAccessKind, Emulator, EmulatorErr};
+use super::replay::Nondet;
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 100_000;
+
+//how often a long forward run asks whether it should stop
+const INTERRUPT_CHECK_INTERVAL: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(&self, kind: MemAccessKind) -> bool {
+        match self {
+            WatchKind::Write => kind == MemAccessKind::Write,
+            WatchKind::Read => kind == MemAccessKind::Read,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub addr: u64,
+    pub len: u64,
+}
+
+#[derive(Debug)]
+pub enum StopReason {
+    Step,
+    //stopped before the instruction at pc
+    Breakpoint,
+    Watchpoint { kind: WatchKind, addr: u64 },
+    //the caller asked to stop
+    Interrupted,
+    //reverse execution ran into the oldest snapshot
+    HistoryStart,
+    Exited(u64),
+    Trap(Exceptions),
+    Error(EmulatorErr),
+}
+
+/*
+    Time travel by snapshots and re-execution: a full copy of the emulator is kept every `interval`
+    instructions while going forward, going back to instruction n restores the last snapshot before n and
+    executes forward to it again. Everything the guest observes from the host goes through the nondet log,
+    which is switched to recording for this, so re-execution follows the original run exactly.
+
+    Breakpoints stop before the instruction at their address executes. Watchpoints stop after the accessing
+    instruction when going forward and before it when going back, so a reverse continue lands on the
+    instruction that did the access.
+*/
+pub struct TimeTravel {
+    emu: Emulator,
+    interval: u64,
+    //ordered by icount, the first one is the start of history
+    snapshots: Vec<Emulator>,
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    //furthest point execution has reached, guest output before it was already shown once
+    frontier: u64,
+}
+
+impl TimeTravel {
+    pub fn new(mut emu: Emulator, interval: u64) -> Self {
+        //a live run can't be re-executed, whatever the host returned has to be kept
+        if emu.nondet.mode() == NondetMode::Live {
+            emu.nondet = Nondet::record();
+        }
+
+        TimeTravel {
+            snapshots: vec![emu.take_snapshot()],
+            interval: interval.max(1),
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            frontier: emu.icount,
+            emu,
+        }
+    }
+
+    pub fn emu(&self) -> &Emulator {
+        &self.emu
+    }
+
+    /*
+        For changing guest state from outside (registers, memory). The recorded future no longer follows
+        from the new state, so snapshots after the current point and not yet consumed inputs are dropped.
+    */
+    pub fn emu_mut(&mut self) -> &mut Emulator {
+        let icount = self.emu.icount;
+
+        self.snapshots.retain(|snapshot| snapshot.icount <= icount);
+        self.emu.nondet.truncate();
+        self.frontier = icount;
+
+        &mut self.emu
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.retain(|w| *w != watchpoint);
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        let watch = self.watch_hit(&self.emu);
+
+        if let Some(stop) = self.exec_forward() {
+            return stop;
+        }
+
+        watch.unwrap_or(StopReason::Step)
+    }
+
+    //runs until a breakpoint, watchpoint, fatal trap or until `interrupted` returns true
+    pub fn cont<F: FnMut() -> bool>(&mut self, mut interrupted: F) -> StopReason {
+        let mut executed = 0u64;
+
+        loop {
+            let watch = self.watch_hit(&self.emu);
+
+            if let Some(stop) = self.exec_forward() {
+                return stop;
+            }
+            executed += 1;
+
+            if let Some(stop) = watch {
+                return stop;
+            }
+
+            let pc = self.emu.cpu.get_pc();
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint;
+            }
+
+            if executed.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+    }
+
+    pub fn reverse_step(&mut self) -> StopReason {
+        let icount = self.emu.icount;
+
+        if icount <= self.snapshots[0].icount {
+            return StopReason::HistoryStart;
+        }
+
+        match self.rewind_to(icount - 1) {
+            Ok(()) => StopReason::Step,
+            Err(err) => StopReason::Error(err),
+        }
+    }
+
+    /*
+        Goes back to the most recent breakpoint or watchpoint hit before the current instruction: the
+        interval between each snapshot and the next is re-executed, newest first, until one contains a hit,
+        then the last hit in it is executed up to.
+    */
+    pub fn reverse_cont(&mut self) -> StopReason {
+        let now = self.emu.icount;
+        let newest = self.snapshots.partition_point(|snapshot| snapshot.icount < now);
+
+        for i in (0..newest).rev() {
+            let end = self.snapshots.get(i + 1).map_or(now, |next| next.icount.min(now));
+
+            match self.last_hit(i, end) {
+                Ok(Some((icount, stop))) => {
+                    return match self.rewind_to(icount) {
+                        Ok(()) => stop,
+                        Err(err) => StopReason::Error(err),
+                    };
+                }
+                Ok(None) => {}
+                Err(err) => return StopReason::Error(err),
+            }
+        }
+
+        self.emu = self.restore(0);
+
+        StopReason::HistoryStart
+    }
+
+    //one instruction forward, returns why execution has to stop there if it does
+    fn exec_forward(&mut self) -> Option<StopReason> {
+        self.emu.process.set_mute_output(self.emu.icount < self.frontier);
+        let result = self.emu.step();
+        self.emu.process.set_mute_output(false);
+        self.frontier = self.frontier.max(self.emu.icount);
+
+        if let Some(newest) = self.snapshots.last() {
+            if self.emu.icount >= newest.icount + self.interval {
+                self.snapshots.push(self.emu.take_snapshot());
+            }
+        }
+
+        match result {
+            Ok(()) => None,
+            Err(err) => Some(stop_for(err)),
+        }
+    }
+
+    //a copy of snapshot `i` that takes its inputs from the current log
+    fn restore(&self, i: usize) -> Emulator {
+        let snapshot = &self.snapshots[i];
+        let mut emu = snapshot.take_snapshot();
+
+        emu.nondet = self.emu.nondet.rewound(snapshot.nondet.cursor());
+        emu.tracer = self.emu.tracer.clone();
+
+        emu
+    }
+
+    //re-executes from the last snapshot at or before `icount` up to it
+    fn rewind_to(&mut self, icount: u64) -> Result<(), EmulatorErr> {
+        let i = self.snapshots.partition_point(|snapshot| snapshot.icount <= icount).saturating_sub(1);
+        let mut emu = self.restore(i);
+
+        //history was already traced once
+        let tracer = emu.tracer.take();
+        emu.process.set_mute_output(true);
+
+        while emu.icount < icount {
+            emu.step()?;
+        }
+
+        emu.tracer = tracer;
+        emu.process.set_mute_output(false);
+        self.emu = emu;
+
+        Ok(())
+    }
+
+    //the last hit at an icount in [snapshot i, end)
+    fn last_hit(&self, i: usize, end: u64) -> Result<Option<(u64, StopReason)>, EmulatorErr> {
+        let mut emu = self.restore(i);
+        let mut last = None;
+
+        emu.tracer = None;
+        emu.process.set_mute_output(true);
+
+        while emu.icount < end {
+            let pc = emu.cpu.get_pc();
+
+            if self.breakpoints.contains(&pc) {
+                last = Some((emu.icount, StopReason::Breakpoint));
+            }
+            if let Some(stop) = self.watch_hit(&emu) {
+                last = Some((emu.icount, stop));
+            }
+
+            emu.step()?;
+        }
+
+        Ok(last)
+    }
+
+    //whether the instruction at pc is about to touch a watched range
+    fn watch_hit(&self, emu: &Emulator) -> Option<StopReason> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let raw = emu.fetch_rinst().ok()?;
+        let (kind, vaddr, size) = mem_access(decoder::decode(raw), &emu.cpu)?;
+
+        self.watchpoints.iter()
+            .find(|w| w.kind.matches(kind) && overlaps(vaddr, size, w.addr, w.len))
+            .map(|w| StopReason::Watchpoint { kind: w.kind, addr: w.addr })
+    }
+}
+
+//compares the last bytes of both ranges so a range ending at the top of the address space doesn't wrap
+fn overlaps(a: u64, a_len: u64, b: u64, b_len: u64) -> bool {
+    let last = |addr: u64, len: u64| addr.saturating_add(len.max(1) - 1);
+
+    a <= last(b, b_len) && b <= last(a, a_len)
+}
+
+fn stop_for(err: EmulatorErr) -> StopReason {
+    match err {
+        EmulatorErr::ErrExited(code) => StopReason::Exited(code),
+        EmulatorErr::ErrTrap(exception) => StopReason::Trap(exception),
+        err => StopReason::Error(err),
+    }
+}
+
+//the memory an instruction is going to access, computed the same way cpu::exec does
+fn mem_access(inst: Inst, cpu: &cpu::Cpu) -> Option<(MemAccessKind, u64, u64)> {
+    let (kind, rs1, imm, size) = match inst {
+        Inst::Lb { rs1, imm, .. } | Inst::Lbu { rs1, imm, .. } => (MemAccessKind::Read, rs1, imm, 1),
+        Inst::Lh { rs1, imm, .. } | Inst::Lhu { rs1, imm, .. } => (MemAccessKind::Read, rs1, imm, 2),
+        Inst::Lw { rs1, imm, .. } | Inst::Lwu { rs1, imm, .. } => (MemAccessKind::Read, rs1, imm, 4),
+        Inst::Ld { rs1, imm, .. } => (MemAccessKind::Read, rs1, imm, 8),
+        Inst::Sb { rs1, imm, .. } => (MemAccessKind::Write, rs1, imm, 1),
+        Inst::Sh { rs1, imm, .. } => (MemAccessKind::Write, rs1, imm, 2),
+        Inst::Sw { rs1, imm, .. } => (MemAccessKind::Write, rs1, imm, 4),
+        Inst::Sd { rs1, imm, .. } => (MemAccessKind::Write, rs1, imm, 8),
+        Inst::LrW { rs1, .. } => (MemAccessKind::Read, rs1, 0, 4),
+        Inst::LrD { rs1, .. } => (MemAccessKind::Read, rs1, 0, 8),
+        //an AMO also reads, as a write it is caught by write and access watchpoints
+        Inst::ScW { rs1, .. } | Inst::AmoswapW { rs1, .. } | Inst::AmoaddW { rs1, .. } | Inst::AmoxorW { rs1, .. } |
+        Inst::AmoandW { rs1, .. } | Inst::AmoorW { rs1, .. } | Inst::AmominW { rs1, .. } | Inst::AmomaxW { rs1, .. } |
+        Inst::AmominuW { rs1, .. } | Inst::AmomaxuW { rs1, .. } => (MemAccessKind::Write, rs1, 0, 4),
+        Inst::ScD { rs1, .. } | Inst::AmoswapD { rs1, .. } | Inst::AmoaddD { rs1, .. } | Inst::AmoxorD { rs1, .. } |
+        Inst::AmoandD { rs1, .. } | Inst::AmoorD { rs1, .. } | Inst::AmominD { rs1, .. } | Inst::AmomaxD { rs1, .. } |
+        Inst::AmominuD { rs1, .. } | Inst::AmomaxuD { rs1, .. } => (MemAccessKind::Write, rs1, 0, 8),
+        _ => return None,
+    };
+
+    let base = cpu.get_reg(rs1 as usize).ok()?;
+
+    //the address cpu::exec accesses, on RV32 the register is sign extended but the address isn't
+    Some((kind, cpu.zero_extend(base.wrapping_add(imm as i64 as u64)), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{memory, testing::{self, CODE_BASE, DATA_BASE}};
+
+    const S0: usize = 8;
+
+    //counts s0 to 10 storing every value to DATA_BASE + 8, then stores it to DATA_BASE + 16 and exits with it
+    const COUNT: [u32; 10] = [
+        0x0000_2937, 0x0000_0413, 0x00a0_0493, 0x0014_0413, 0x0089_3423, 0xfe94_4ce3, 0x0089_2823, 0x0004_0513,
+        0x05d0_0893, 0x0000_0073,
+    ];
+
+    fn time_travel() -> TimeTravel {
+        TimeTravel::new(testing::emulator(&COUNT), 4)
+    }
+
+    fn s0(tt: &TimeTravel) -> u64 {
+        tt.emu().cpu.get_reg(S0).unwrap()
+    }
+
+    #[test]
+    fn ranges_overlap_up_to_their_last_byte() {
+        assert!(overlaps(0x100, 4, 0x103, 1));
+        assert!(!overlaps(0x100, 4, 0x104, 4));
+        assert!(!overlaps(0x104, 4, 0x100, 4));
+        assert!(overlaps(0x100, 8, 0x102, 2));
+        //a zero length watch still covers its address
+        assert!(overlaps(0x100, 1, 0x100, 0));
+
+        assert!(overlaps(u64::MAX - 3, 8, u64::MAX, 1));
+        assert!(overlaps(u64::MAX, u64::MAX, 0xffff_ffff_0000_0000, u64::MAX));
+        assert!(!overlaps(u64::MAX - 3, 4, 0, 8));
+    }
+
+    #[test]
+    fn breakpoints_stop_before_and_watchpoints_after_the_instruction() {
+        let mut tt = time_travel();
+
+        tt.add_breakpoint(CODE_BASE + 0x14);
+        assert!(matches!(tt.cont(|| false), StopReason::Breakpoint));
+        assert_eq!((tt.emu().cpu.get_pc(), s0(&tt)), (CODE_BASE + 0x14, 1));
+        assert!(matches!(tt.cont(|| false), StopReason::Breakpoint));
+        assert_eq!(s0(&tt), 2);
+        tt.remove_breakpoint(CODE_BASE + 0x14);
+
+        tt.add_watchpoint(Watchpoint { kind: WatchKind::Write, addr: DATA_BASE + 0x12, len: 1 });
+        tt.add_watchpoint(Watchpoint { kind: WatchKind::Read, addr: DATA_BASE + 8, len: 8 });
+        assert!(matches!(tt.cont(|| false), StopReason::Watchpoint { kind: WatchKind::Write, addr } if addr == DATA_BASE + 0x12));
+        assert_eq!((tt.emu().cpu.get_pc(), s0(&tt)), (CODE_BASE + 0x1c, 10));
+
+        //an access at the very top of the address space doesn't overflow the range check
+        tt.add_watchpoint(Watchpoint { kind: WatchKind::Access, addr: u64::MAX - 3, len: 8 });
+        assert!(matches!(tt.cont(|| false), StopReason::Exited(10)));
+    }
+
+    #[test]
+    fn reverse_execution_lands_on_the_access() {
+        let mut tt = time_travel();
+
+        tt.add_breakpoint(CODE_BASE + 0x18);
+        tt.cont(|| false);
+        let icount = tt.emu().icount;
+
+        assert!(matches!(tt.reverse_step(), StopReason::Step));
+        assert_eq!(tt.emu().icount, icount - 1);
+
+        tt.add_watchpoint(Watchpoint { kind: WatchKind::Write, addr: DATA_BASE + 8, len: 8 });
+        assert!(matches!(tt.reverse_cont(), StopReason::Watchpoint { .. }));
+        assert_eq!((tt.emu().cpu.get_pc(), s0(&tt)), (CODE_BASE + 0x10, 10));
+        assert!(matches!(tt.reverse_cont(), StopReason::Watchpoint { .. }));
+        assert_eq!(s0(&tt), 9);
+
+        tt.remove_watchpoint(Watchpoint { kind: WatchKind::Write, addr: DATA_BASE + 8, len: 8 });
+        assert!(matches!(tt.reverse_cont(), StopReason::HistoryStart));
+        assert_eq!((tt.emu().icount, tt.emu().cpu.get_pc()), (0, CODE_BASE));
+        assert!(matches!(tt.reverse_step(), StopReason::HistoryStart));
+    }
+
+    #[test]
+    fn rv32_watchpoints_see_the_zero_extended_address() {
+        //a5 = 0x80000000 (sign extended in the register), [a5 + 4] = s1, exit
+        let mut emu = testing::emulator(&[0x8000_07b7, 0x0097_a223, 0x0000_0513, 0x05d0_0893, 0x0000_0073]);
+        emu.cpu.set_xlen(cpu::Xlen::Rv32);
+        emu.mmu.map_region(0x8000_0000, 0x1000, memory::PERM_R | memory::PERM_W, "high").unwrap();
+        let mut tt = TimeTravel::new(emu, 4);
+
+        tt.add_watchpoint(Watchpoint { kind: WatchKind::Write, addr: 0x8000_0004, len: 4 });
+
+        assert!(matches!(tt.cont(|| false), StopReason::Watchpoint { kind: WatchKind::Write, addr: 0x8000_0004 }));
+        assert_eq!(tt.emu().cpu.get_pc(), CODE_BASE + 8);
+    }
+}
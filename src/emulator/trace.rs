@@ -0,0 +1,295 @@
+use std::{fs, io::{self, BufWriter, Write}, path::Path, sync::Arc};
+use super::{cpu::MAX_REGS, decoder::{self, Inst}, dwarf::LineTable};
+
+const BINARY_MAGIC: [u8; 4] = *b"CRTR";
+const BINARY_VERSION: u8 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TraceErr {
+    #[error("Unable to write trace: {0}")]
+    UnableToWrite(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemAccess {
+    pub kind: MemAccessKind,
+    pub vaddr: u64,
+    pub size: u8,
+    pub value: u64,
+}
+
+pub enum TraceSink {
+    Stderr,
+    Text(BufWriter<fs::File>),
+    /*
+        Compact format for offline tools, all integers little endian:
+
+            header:     "CRTR" u8 version
+            record:     u64 icount, u64 pc, u32 raw, u8 flags (bit 0: trapped), u8 nregs, u8 nmem
+                        nregs * (u8 reg, u64 value)
+                        nmem * (u8 kind (0 read, 1 write), u8 size, u64 vaddr, u64 value)
+    */
+    Binary(BufWriter<fs::File>),
+}
+
+impl TraceSink {
+    pub fn text<P: AsRef<Path>>(path: P) -> Result<Self, TraceErr> {
+        Ok(TraceSink::Text(BufWriter::new(fs::File::create(path)?)))
+    }
+
+    pub fn binary<P: AsRef<Path>>(path: P) -> Result<Self, TraceErr> {
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        out.write_all(&BINARY_MAGIC)?;
+        out.write_all(&[BINARY_VERSION])?;
+
+        Ok(TraceSink::Binary(out))
+    }
+}
+
+//[start, end) bounds, an instruction is traced only if it is inside every bound that is set
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<(u64, u64)>,
+    pub icount_range: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    fn matches(&self, icount: u64, pc: u64) -> bool {
+        let in_range = |range: Option<(u64, u64)>, val: u64| range.is_none_or(|(start, end)| start <= val && val < end);
+
+        in_range(self.pc_range, pc) && in_range(self.icount_range, icount)
+    }
+}
+
+pub struct TraceRecord {
+    pub icount: u64,
+    pub pc: u64,
+    pub raw: u32,
+    pub inst: Inst,
+    pub trapped: bool,
+    pub reg_writes: Vec<(u8, u64)>,
+    pub mem: Vec<MemAccess>,
+}
+
+/*
+    Collects what one instruction did between begin() and end(): memory accesses are reported by the load
+    and store helpers while it executes, register writes are found by diffing the register file.
+*/
+pub struct Tracer {
+    sink: TraceSink,
+    filter: TraceFilter,
+    active: bool,
+    mem: Vec<MemAccess>,
+
+    //text traces name the source line whenever it changes
+    lines: Arc<LineTable>,
+    last_line: Option<(String, u32)>,
+}
+
+impl Tracer {
+    pub fn new(sink: TraceSink, filter: TraceFilter) -> Self {
+        Tracer {
+            sink,
+            filter,
+            active: false,
+            mem: Vec::new(),
+            lines: Arc::default(),
+            last_line: None,
+        }
+    }
+
+    pub fn set_lines(&mut self, lines: Arc<LineTable>) {
+        self.lines = lines;
+    }
+
+    //the source line if it isn't the one the previous traced instruction was on
+    fn new_line(&mut self, pc: u64) -> Option<String> {
+        let source = self.lines.locate(pc)?;
+
+        if self.last_line.as_ref().is_some_and(|(file, line)| file == source.file && *line == source.line) {
+            return None;
+        }
+        self.last_line = Some((source.file.to_string(), source.line));
+
+        Some(source.to_string())
+    }
+
+    pub fn begin(&mut self, icount: u64, pc: u64) {
+        self.active = self.filter.matches(icount, pc);
+        self.mem.clear();
+    }
+
+    pub fn log_mem(&mut self, kind: MemAccessKind, vaddr: u64, size: usize, value: u64) {
+        if self.active {
+            self.mem.push(MemAccess { kind, vaddr, size: size as u8, value });
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn end(&mut self, icount: u64, pc: u64, raw: u32, inst: Inst, regs_before: &[u64; MAX_REGS], regs_after: &[u64; MAX_REGS], trapped: bool) -> Result<(), TraceErr> {
+        if !self.active {
+            return Ok(());
+        }
+        self.active = false;
+
+        let reg_writes = regs_before.iter().zip(regs_after).enumerate()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(i, (_, after))| (i as u8, *after))
+            .collect();
+
+        let record = TraceRecord {
+            icount,
+            pc,
+            raw,
+            inst,
+            trapped,
+            reg_writes,
+            mem: std::mem::take(&mut self.mem),
+        };
+
+        let source = match self.sink {
+            TraceSink::Binary(_) => None,
+            _ => self.new_line(pc),
+        };
+
+        match &mut self.sink {
+            TraceSink::Stderr => write_text(&mut io::stderr().lock(), &record, source)?,
+            TraceSink::Text(out) => write_text(out, &record, source)?,
+            TraceSink::Binary(out) => write_binary(out, &record)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), TraceErr> {
+        match &mut self.sink {
+            TraceSink::Stderr => io::stderr().flush()?,
+            TraceSink::Text(out) | TraceSink::Binary(out) => out.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+fn write_text<W: Write>(out: &mut W, record: &TraceRecord, source: Option<String>) -> io::Result<()> {
+    if let Some(source) = source {
+        writeln!(out, "{:>10} {}", "", source)?;
+    }
+
+    write!(out, "{:>10} {:#018x}: {:08x}  {:<32}", record.icount, record.pc, record.raw, decoder::disassemble(record.inst))?;
+
+    for (reg, value) in &record.reg_writes {
+        write!(out, " {}={:#x}", decoder::reg_name(*reg as u32), value)?;
+    }
+
+    for access in &record.mem {
+        let kind = match access.kind {
+            MemAccessKind::Read => "r",
+            MemAccessKind::Write => "w",
+        };
+        write!(out, " [{}{} {:#x}]={:#x}", kind, access.size, access.vaddr, access.value)?;
+    }
+
+    if record.trapped {
+        write!(out, " <trap>")?;
+    }
+
+    writeln!(out)
+}
+
+fn write_binary<W: Write>(out: &mut W, record: &TraceRecord) -> io::Result<()> {
+    out.write_all(&record.icount.to_le_bytes())?;
+    out.write_all(&record.pc.to_le_bytes())?;
+    out.write_all(&record.raw.to_le_bytes())?;
+    out.write_all(&[record.trapped as u8, record.reg_writes.len() as u8, record.mem.len() as u8])?;
+
+    for (reg, value) in &record.reg_writes {
+        out.write_all(&[*reg])?;
+        out.write_all(&value.to_le_bytes())?;
+    }
+
+    for access in &record.mem {
+        out.write_all(&[(access.kind == MemAccessKind::Write) as u8, access.size])?;
+        out.write_all(&access.vaddr.to_le_bytes())?;
+        out.write_all(&access.value.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing;
+
+    //lui s2, DATA_BASE; addi a0, zero, 5; sd a0, 8(s2); ld a1, 8(s2)
+    const STORE_LOAD: [u32; 4] = [0x0000_2937, 0x0050_0513, 0x00a9_3423, 0x0089_3583];
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("crimson-trace-{}-{}", name, std::process::id()))
+    }
+
+    //traces STORE_LOAD into a file and returns what ended up in it
+    fn trace(sink: fn(&Path) -> Result<TraceSink, TraceErr>, name: &str, filter: TraceFilter) -> Vec<u8> {
+        let path = temp_path(name);
+        let mut emu = testing::emulator(&STORE_LOAD);
+
+        emu.set_tracer(Some(Tracer::new(sink(&path).unwrap(), filter)));
+        emu.run(Some(STORE_LOAD.len() as u64)).unwrap();
+        emu.tracer.as_ref().unwrap().lock().unwrap().flush().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        data
+    }
+
+    #[test]
+    fn filter_bounds_are_half_open_and_all_have_to_match() {
+        let filter = TraceFilter { pc_range: Some((0x1000, 0x1008)), icount_range: None };
+        assert!(filter.matches(0, 0x1000) && filter.matches(7, 0x1004));
+        assert!(!filter.matches(0, 0x1008) && !filter.matches(0, 0xffc));
+
+        let filter = TraceFilter { pc_range: Some((0x1000, 0x1008)), icount_range: Some((1, 2)) };
+        assert!(filter.matches(1, 0x1004));
+        assert!(!filter.matches(2, 0x1004) && !filter.matches(1, 0x1008));
+
+        assert!(TraceFilter::default().matches(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn text_trace_has_register_writes_and_accesses() {
+        let text = String::from_utf8(trace(|path| TraceSink::text(path), "text", TraceFilter::default())).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].trim_start().starts_with("0 0x0000000000001000: 00002937"));
+        assert!(lines[1].ends_with(" a0=0x5"));
+        assert!(lines[2].ends_with(" [w8 0x2008]=0x5"));
+        assert!(lines[3].ends_with(" a1=0x5 [r8 0x2008]=0x5"));
+    }
+
+    #[test]
+    fn filtered_binary_trace_only_has_matching_records() {
+        let filter = TraceFilter { pc_range: None, icount_range: Some((2, 3)) };
+        let data = trace(|path| TraceSink::binary(path), "bin", filter);
+
+        assert_eq!(&data[..5], b"CRTR\x01");
+        let record = &data[5..];
+
+        assert_eq!(u64::from_le_bytes(record[0..8].try_into().unwrap()), 2);
+        assert_eq!(u64::from_le_bytes(record[8..16].try_into().unwrap()), testing::CODE_BASE + 8);
+        assert_eq!(u32::from_le_bytes(record[16..20].try_into().unwrap()), STORE_LOAD[2]);
+        //not trapped, no register writes, one access: a write of 8 bytes of 5 to DATA_BASE + 8
+        assert_eq!(&record[20..25], [0, 0, 1, 1, 8]);
+        assert_eq!(u64::from_le_bytes(record[25..33].try_into().unwrap()), testing::DATA_BASE + 8);
+        assert_eq!(u64::from_le_bytes(record[33..41].try_into().unwrap()), 5);
+        assert_eq!(record.len(), 41);
+    }
+}
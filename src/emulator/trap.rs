@@ -0,0 +1,233 @@
+use super::{csr::{MIP_MTIP, MIP_STIP}, devices::{clint::{self, Clint, Clock}, plic::{self, Plic}}, exceptions::Exceptions, Emulator, EmulatorErr};
+
+//cycles that pass per check while the hart waits in WFI
+const WFI_IDLE_CYCLES: u64 = 256;
+
+const CAUSE_SUPERVISOR_ECALL: u64 = 9;
+
+/*
+    Traps for bare-metal guests: exceptions and interrupts are delivered to the handlers the guest
+    installed in mtvec/stvec, instead of the emulator acting as the execution environment.
+*/
+impl Emulator {
+    //the harts start in M mode at the entry point, with a CLINT for timer and software interrupts and a PLIC
+    //routing the device interrupt lines
+    pub fn enable_bare_metal(&mut self, clock: Clock, harts: usize) -> Result<(), EmulatorErr> {
+        self.bus.attach(clint::CLINT_BASE, Box::new(Clint::new(harts, clock)))?;
+        self.bus.attach(plic::PLIC_BASE, Box::new(Plic::new(harts)))?;
+
+        self.add_harts(harts);
+        self.clint_base = Some(clint::CLINT_BASE);
+        self.bare_metal = true;
+
+        Ok(())
+    }
+
+    //what the time CSR reads, None without a CLINT to read it from
+    pub fn mtime(&mut self) -> Option<u64> {
+        let base = self.clint_base?;
+
+        self.bus.read(base + clint::REG_MTIME, 8).ok()
+    }
+
+    /*
+        An exception enters the guest's handler. With no handler installed there (tvec still 0) the trap
+        can only loop on itself, so it is fatal and reported like in user mode.
+    */
+    pub(super) fn take_exception(&mut self, exception: Exceptions) -> Result<(), EmulatorErr> {
+        let cause = exception.cause(self.cpu.csr.privilege as u64);
+
+        //the SBI is where M mode firmware would be
+        if self.sbi && cause == CAUSE_SUPERVISOR_ECALL {
+            return self.sbi_call();
+        }
+
+        if self.cpu.csr.trap_vector(cause) == 0 {
+            return Err(EmulatorErr::ErrTrap(exception));
+        }
+
+        let pc = self.cpu.csr.trap(self.cpu.get_pc(), cause, exception.tval());
+        self.cpu.set_pc(pc);
+
+        Ok(())
+    }
+
+    //WFI only stalls when some interrupt is enabled that could end the wait
+    pub(super) fn wait_for_interrupt(&mut self) {
+        if self.bare_metal && self.cpu.csr.mie() != 0 {
+            self.cpu.waiting = true;
+        }
+    }
+
+    //time passes without instructions retiring, until an interrupt becomes pending
+    pub(super) fn idle(&mut self) {
+        self.tick_devices(WFI_IDLE_CYCLES);
+        self.check_interrupts();
+    }
+
+    //interrupts are only taken between instructions, this is called after every block or step
+    pub(super) fn check_interrupts(&mut self) {
+        if !self.bare_metal {
+            return;
+        }
+
+        let mut mip = self.bus.mip(self.cpu.csr.hartid() as usize);
+
+        //the firmware would take the machine timer interrupt and pass it on as a supervisor one, pending for
+        //as long as mtime is past what the supervisor set through the SBI
+        if self.sbi && mip & MIP_MTIP != 0 {
+            mip = (mip & !MIP_MTIP) | MIP_STIP;
+        }
+
+        self.cpu.csr.set_mip_hw(mip);
+
+        //an enabled pending interrupt ends WFI even if it is globally disabled and won't be taken
+        if self.cpu.csr.mip() & self.cpu.csr.mie() != 0 {
+            self.cpu.waiting = false;
+        }
+
+        if let Some(cause) = self.cpu.csr.pending_interrupt() {
+            let pc = self.cpu.csr.trap(self.cpu.get_pc(), cause, 0);
+            self.cpu.set_pc(pc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{bus::{Device, DeviceErr}, csr::{self, Privilege, CAUSE_INTERRUPT, MIP_MEIP, MIP_MSIP, MIP_SEIP, MIP_SSIP}, testing};
+
+    const LINES_BASE: u64 = 0x3000_0000;
+    const MTVEC: u64 = 0x8000;
+    const STVEC: u64 = 0x9000;
+    const COUNTERS: csr::Counters = csr::Counters { cycle: 0, instret: 0, time: None };
+
+    //drives whatever mip bits were written to it, standing in for the PLIC and for supervisor sources
+    #[derive(Clone)]
+    struct Lines(u64);
+
+    impl Device for Lines {
+        fn name(&self) -> &str {
+            "lines"
+        }
+
+        fn size(&self) -> u64 {
+            8
+        }
+
+        fn read(&mut self, _offset: u64, _size: usize) -> Result<u64, DeviceErr> {
+            Ok(self.0)
+        }
+
+        fn write(&mut self, _offset: u64, _size: usize, value: u64) -> Result<(), DeviceErr> {
+            self.0 = value;
+            Ok(())
+        }
+
+        fn mip(&self, _hart: usize) -> u64 {
+            self.0
+        }
+
+        fn box_clone(&self) -> Box<dyn Device> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn machine() -> Emulator {
+        let mut emu = testing::emulator(&[0x0000_0013]);
+        emu.enable_bare_metal(Clock::Instructions, 1).unwrap();
+        emu.bus.attach(LINES_BASE, Box::new(Lines(0))).unwrap();
+
+        let csrs = &mut emu.cpu.csr;
+        csrs.write(csr::CSR_MTVEC, MTVEC);
+        csrs.write(csr::CSR_STVEC, STVEC);
+        csrs.write(csr::CSR_MIE, !0);
+        emu
+    }
+
+    //takes whatever is pending running at `privilege`, the cause it trapped with and the privilege it trapped to
+    fn interrupt(emu: &mut Emulator, privilege: Privilege, mstatus: u64) -> Option<(u64, Privilege)> {
+        let csrs = &mut emu.cpu.csr;
+        csrs.privilege = Privilege::Machine;
+        csrs.write(csr::CSR_MSTATUS, mstatus);
+        csrs.privilege = privilege;
+        emu.cpu.set_pc(testing::CODE_BASE);
+
+        emu.check_interrupts();
+
+        let csrs = &emu.cpu.csr;
+        let cause = match emu.cpu.get_pc() {
+            MTVEC => csrs.read(csr::CSR_MCAUSE, &COUNTERS)?,
+            STVEC => csrs.read(csr::CSR_SCAUSE, &COUNTERS)?,
+            _ => return None,
+        };
+
+        Some((cause & !CAUSE_INTERRUPT, csrs.privilege))
+    }
+
+    #[test]
+    fn clint_interrupts_are_raised_and_cleared() {
+        let mut emu = machine();
+        let mtimecmp = clint::CLINT_BASE + clint::REG_MTIMECMP;
+
+        emu.bus.write(mtimecmp, 8, 2).unwrap();
+        emu.tick_devices(10);
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE), None);
+
+        emu.tick_devices(10);
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE), Some((7, Privilege::Machine)));
+        assert_eq!(emu.cpu.csr.mip() & MIP_MTIP, MIP_MTIP);
+
+        emu.bus.write(mtimecmp, 8, u64::MAX).unwrap();
+        emu.bus.write(clint::CLINT_BASE, 4, 1).unwrap();
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE), Some((3, Privilege::Machine)));
+        assert_eq!(emu.cpu.csr.mip() & (MIP_MTIP | MIP_MSIP), MIP_MSIP);
+
+        emu.bus.write(clint::CLINT_BASE, 4, 0).unwrap();
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE), None);
+        assert_eq!(emu.cpu.csr.mip(), 0);
+    }
+
+    #[test]
+    fn interrupts_are_taken_in_priority_order() {
+        let mut emu = machine();
+        let mut pending = MIP_MEIP | MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_SSIP | MIP_STIP;
+
+        for cause in [11, 3, 7, 9, 1, 5] {
+            emu.bus.write(LINES_BASE, 8, pending).unwrap();
+            assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE), Some((cause, Privilege::Machine)));
+            pending &= !(1 << cause);
+        }
+
+        //masked in mstatus in M mode, and in mie everywhere
+        emu.bus.write(LINES_BASE, 8, MIP_MEIP).unwrap();
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, 0), None);
+        emu.cpu.csr.write(csr::CSR_MIE, !MIP_MEIP);
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE), None);
+    }
+
+    #[test]
+    fn delegated_interrupts_go_to_supervisor_mode() {
+        let mut emu = machine();
+        emu.cpu.csr.write(csr::CSR_MIDELEG, MIP_SSIP | MIP_STIP | MIP_SEIP);
+        emu.bus.write(LINES_BASE, 8, MIP_STIP).unwrap();
+
+        //M mode never takes interrupts it delegated
+        assert_eq!(interrupt(&mut emu, Privilege::Machine, csr::MSTATUS_MIE | csr::MSTATUS_SIE), None);
+
+        assert_eq!(interrupt(&mut emu, Privilege::Supervisor, 0), None);
+        assert_eq!(interrupt(&mut emu, Privilege::Supervisor, csr::MSTATUS_SIE), Some((5, Privilege::Supervisor)));
+
+        //below S mode they're always enabled
+        assert_eq!(interrupt(&mut emu, Privilege::User, 0), Some((5, Privilege::Supervisor)));
+
+        //one that isn't delegated goes to M mode first, from S mode whatever mstatus.MIE says
+        emu.bus.write(LINES_BASE, 8, MIP_STIP | MIP_SEIP | MIP_MTIP).unwrap();
+        assert_eq!(interrupt(&mut emu, Privilege::Supervisor, csr::MSTATUS_SIE), Some((7, Privilege::Machine)));
+
+        //SEI outranks STI within S mode too
+        emu.bus.write(LINES_BASE, 8, MIP_STIP | MIP_SEIP).unwrap();
+        assert_eq!(interrupt(&mut emu, Privilege::Supervisor, csr::MSTATUS_SIE), Some((9, Privilege::Supervisor)));
+    }
+}
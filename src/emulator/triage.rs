@@ -0,0 +1,506 @@
+use std::{collections::HashMap, fmt::Write as _, fs, path::{Path, PathBuf}, sync::Arc};
+use super::{cpu, decoder, exceptions::Exceptions, memory::{self, AccessKind, MmmuErr}, replay::Nondet, Emulator, EmulatorErr};
+
+//how many instructions before and after the faulting pc end up in a crash report
+const REPORT_DISASM_WINDOW: u64 = 8;
+
+//what the emulator exits with after a guest crash, what a shell reports for a process killed by SIGSEGV
+pub const CRASH_EXIT_CODE: i32 = 128 + 11;
+
+//how many times the instructions of the original crash a minimization candidate may run for
+const MINIMIZE_BUDGET_FACTOR: u64 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TriageErr {
+    #[error("Unable to write crash: {0}")]
+    UnableToWriteCrash(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultKind {
+    InstructionAddressMisaligned,
+    AddressMisaligned,
+    AccessFault,
+    PageFault,
+    IllegalInstruction,
+    Breakpoint,
+    EnvironmentCall,
+    Mmu,
+    Cpu,
+    Other,
+}
+
+impl FaultKind {
+    pub fn from_err(err: &EmulatorErr) -> Self {
+        match err {
+            EmulatorErr::ErrTrap(exception) => match exception {
+                Exceptions::ExceptionInstructionAddressMisaligned(_) => FaultKind::InstructionAddressMisaligned,
+                Exceptions::ExceptionLoadAddressMisaligned(_) |
+                Exceptions::ExceptionStoreAddressMisaligned(_) => FaultKind::AddressMisaligned,
+                Exceptions::ExceptionAccessFault(_) |
+                Exceptions::ExceptionLoadAccessFault(_) |
+                Exceptions::ExceptionStoreAccessFault(_) => FaultKind::AccessFault,
+                Exceptions::ExceptionPageFault(_) |
+                Exceptions::ExceptionLoadPageFault(_) |
+                Exceptions::ExceptionStorePageFault(_) => FaultKind::PageFault,
+                Exceptions::ExceptionIllegalInstruction(_) => FaultKind::IllegalInstruction,
+                Exceptions::ExceptionBreakpoint(_) => FaultKind::Breakpoint,
+                Exceptions::ExceptionEnvironmentCall(_) => FaultKind::EnvironmentCall,
+            },
+            EmulatorErr::ErrMmu(_) => FaultKind::Mmu,
+            EmulatorErr::ErrCpu(_) => FaultKind::Cpu,
+            _ => FaultKind::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultKind::InstructionAddressMisaligned => "misaligned",
+            FaultKind::AddressMisaligned => "misaligned_data",
+            FaultKind::AccessFault => "access",
+            FaultKind::PageFault => "page",
+            FaultKind::IllegalInstruction => "illegal",
+            FaultKind::Breakpoint => "breakpoint",
+            FaultKind::EnvironmentCall => "ecall",
+            FaultKind::Mmu => "mmu",
+            FaultKind::Cpu => "cpu",
+            FaultKind::Other => "other",
+        }
+    }
+}
+
+/*
+    Two crashes land in the same bucket when they fault the same way, at the same pc, through the same
+    chain of calls. The call chain comes from the shadow call stack the cpu keeps from JAL/JALR link hints.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CrashBucket {
+    pub kind: FaultKind,
+    pub pc: u64,
+    pub stack_hash: u64,
+}
+
+impl CrashBucket {
+    pub fn new(emu: &Emulator, err: &EmulatorErr) -> Self {
+        CrashBucket {
+            kind: FaultKind::from_err(err),
+            pc: emu.cpu.get_pc(),
+            stack_hash: hash_call_stack(emu.cpu.call_stack()),
+        }
+    }
+
+    //used as the file name stem of everything saved for this bucket
+    pub fn id(&self) -> String {
+        format!("{}_{:x}_{:016x}", self.kind.name(), self.pc, self.stack_hash)
+    }
+}
+
+//the guest went wrong, as opposed to exiting, being stopped on purpose or the emulator itself failing
+pub fn is_crash(err: &EmulatorErr) -> bool {
+    !matches!(
+        err,
+        EmulatorErr::ErrExited(_) | EmulatorErr::ErrStopped(_) | EmulatorErr::ErrHookStopped(_) |
+        EmulatorErr::ErrReplay(_) | EmulatorErr::ErrTrace(_)
+    )
+}
+
+//FNV-1a, we need the hash to be stable across runs and hosts so buckets can be compared later
+fn hash_call_stack(call_stack: &[u64]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+
+    for byte in call_stack.iter().flat_map(|addr| addr.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+//the data or instruction address an access went wrong at
+fn fault_address(err: &EmulatorErr) -> Option<usize> {
+    match err {
+        EmulatorErr::ErrTrap(
+            Exceptions::ExceptionAccessFault(addr) |
+            Exceptions::ExceptionLoadAddressMisaligned(addr) |
+            Exceptions::ExceptionLoadAccessFault(addr) |
+            Exceptions::ExceptionStoreAddressMisaligned(addr) |
+            Exceptions::ExceptionStoreAccessFault(addr) |
+            Exceptions::ExceptionPageFault(addr) |
+            Exceptions::ExceptionLoadPageFault(addr) |
+            Exceptions::ExceptionStorePageFault(addr)
+        ) => Some(*addr),
+        EmulatorErr::ErrMmu(MmmuErr::Unmapped(addr) | MmmuErr::OutOfMemory(addr, _)) => Some(*addr),
+        EmulatorErr::ErrStopped(access) => Some(access.addr as usize),
+        _ => None,
+    }
+}
+
+//symbol+offset and file:line of a code address, as much of it as the program's symbols and debug info know
+pub fn describe_code(emu: &Emulator, addr: u64) -> Option<String> {
+    match (emu.symbols().locate(addr), emu.lines().locate(addr)) {
+        (Some(symbol), Some(source)) => Some(format!("{} at {}", symbol, source)),
+        (Some(symbol), None) => Some(symbol.to_string()),
+        (None, Some(source)) => Some(source.to_string()),
+        (None, None) => None,
+    }
+}
+
+//one line per frame, innermost first
+pub fn format_backtrace(emu: &Emulator) -> String {
+    let mut out = String::new();
+
+    for (depth, frame) in emu.backtrace().iter().enumerate() {
+        let location = describe_code(emu, frame.pc).map(|location| format!(" in {}", location)).unwrap_or_default();
+        let _ = writeln!(out, "  #{:<3} {:#x}{} [{}]", depth, frame.pc, location, frame.unwinder);
+    }
+
+    out
+}
+
+//which region the address is in and what its page allows
+fn describe_address(emu: &Emulator, addr: usize) -> String {
+    match emu.mmu.region_at(addr) {
+        Some(region) => format!(
+            "{:#x} in {} [{:#x}, {:#x}) {}",
+            addr, region.name, region.start, region.end, memory::perm_str(emu.mmu.perm_at(addr))
+        ),
+        None => format!("{:#x}, not in any region", addr),
+    }
+}
+
+//where a run a hook or watchpoint stopped on purpose is, no fault and no bucket since nothing went wrong
+pub fn stop_report(emu: &Emulator, err: &EmulatorErr) -> String {
+    let mut out = err.to_string();
+
+    if let Some(location) = describe_code(emu, emu.cpu.get_pc()) {
+        let _ = write!(out, "\nin: {}", location);
+    }
+
+    out
+}
+
+//register state, call stack and disassembly around the faulting pc
+pub fn report(emu: &Emulator, err: &EmulatorErr) -> String {
+    let bucket = CrashBucket::new(emu, err);
+    let pc = emu.cpu.get_pc();
+    let mut out = String::new();
+
+    let _ = writeln!(out, "fault: {}", err);
+    let _ = writeln!(out, "bucket: {}", bucket.id());
+    //the registers below are the faulting hart's
+    if emu.hart_count() > 1 {
+        let _ = writeln!(out, "hart: {}", emu.cpu.csr.hartid());
+    }
+    if let Some(location) = describe_code(emu, pc) {
+        let _ = writeln!(out, "in: {}", location);
+    }
+    if let Some(addr) = fault_address(err) {
+        let _ = writeln!(out, "address: {}", describe_address(emu, addr));
+    }
+    let _ = writeln!(out);
+
+    //as wide as the registers are, an RV32 register's sign extension isn't part of its value
+    let width = emu.cpu.xlen().bytes() * 2 + 2;
+
+    let _ = writeln!(out, "registers:");
+    let _ = writeln!(out, "  {:>4}: {:#0width$x}", "pc", pc);
+    for (i, val) in emu.cpu.get_regs().iter().enumerate() {
+        let _ = writeln!(out, "  {:>4}: {:#0width$x}", decoder::reg_name(i as u32), emu.cpu.zero_extend(*val));
+    }
+    let _ = writeln!(out);
+
+    let _ = writeln!(out, "backtrace:");
+    let _ = write!(out, "{}", format_backtrace(emu));
+    let _ = writeln!(out);
+
+    let _ = writeln!(out, "disassembly:");
+    let start = pc.saturating_sub(REPORT_DISASM_WINDOW * cpu::RAW_INST_SIZE);
+    let end = pc.saturating_add(REPORT_DISASM_WINDOW * cpu::RAW_INST_SIZE);
+
+    let half = |addr: u64| {
+        let paddr = emu.translate_peek(addr, AccessKind::Execute).unwrap_or(addr);
+        emu.mmu.dram_read(paddr as usize, 2).ok().map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+    };
+
+    let mut addr = start;
+    while addr <= end {
+        let marker = if addr == pc { "=>" } else { "  " };
+
+        if let Some(symbol) = emu.symbols().at(addr) {
+            let _ = writeln!(out, "   <{}>:", symbol.name);
+        }
+
+        let low = half(addr);
+        let size = low.map_or(cpu::RAW_INST_SIZE, decoder::inst_size);
+
+        match low.and_then(|low| if size == 2 { Some(low) } else { half(addr.wrapping_add(2)).map(|high| low | high << 16) }) {
+            Some(rinst) if size == 2 => {
+                let _ = writeln!(out, "{} {:#x}: {:04x}      {}", marker, addr, rinst, decoder::disassemble(decoder::decode(rinst)));
+            }
+            Some(rinst) => {
+                let _ = writeln!(out, "{} {:#x}: {:08x}  {}", marker, addr, rinst, decoder::disassemble(decoder::decode(rinst)));
+            }
+            None => {
+                let _ = writeln!(out, "{} {:#x}: ????????", marker, addr);
+            }
+        }
+
+        //decoding from before pc is a guess, one that runs into pc gets back in step there
+        let Some(next) = addr.checked_add(size) else {
+            break;
+        };
+        addr = if addr < pc && next > pc { pc } else { next };
+    }
+
+    out
+}
+
+/*
+    Keeps one reproducer per bucket on disk:
+        <dir>/<bucket id>.input     the input that reproduces the crash
+        <dir>/<bucket id>.txt       the report for that input
+*/
+pub struct CrashDb {
+    dir: PathBuf,
+    hits: HashMap<CrashBucket, u64>,
+}
+
+impl CrashDb {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, TriageErr> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(CrashDb {
+            dir: dir.as_ref().to_path_buf(),
+            hits: HashMap::new(),
+        })
+    }
+
+    //returns the bucket if this is the first time we see it, duplicates (also of earlier runs) are only counted
+    pub fn record(&mut self, emu: &Emulator, input: &[u8], err: &EmulatorErr) -> Result<Option<CrashBucket>, TriageErr> {
+        let bucket = CrashBucket::new(emu, err);
+        let hits = self.hits.entry(bucket).or_insert(0);
+
+        *hits += 1;
+        if *hits > 1 || self.input_path(&bucket).exists() {
+            return Ok(None);
+        }
+
+        fs::write(self.input_path(&bucket), input)?;
+        fs::write(self.report_path(&bucket), report(emu, err))?;
+
+        Ok(Some(bucket))
+    }
+
+    //replaces the saved reproducer, e.g. with a minimized one
+    pub fn update_input(&self, bucket: &CrashBucket, input: &[u8]) -> Result<(), TriageErr> {
+        fs::write(self.input_path(bucket), input)?;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn hits(&self) -> &HashMap<CrashBucket, u64> {
+        &self.hits
+    }
+
+    pub fn input_path(&self, bucket: &CrashBucket) -> PathBuf {
+        self.dir.join(format!("{}.input", bucket.id()))
+    }
+
+    pub fn report_path(&self, bucket: &CrashBucket) -> PathBuf {
+        self.dir.join(format!("{}.txt", bucket.id()))
+    }
+}
+
+/*
+    Shrinks `input` while `run` keeps reporting the same bucket. `run` executes one case from a fresh
+    snapshot and returns the bucket it crashed in, if it crashed at all.
+
+        1. remove chunks, halving the chunk size every time no chunk can be removed
+        2. replace the remaining bytes with zero, so only the bytes that matter stay interesting
+*/
+pub fn minimize<F>(input: &[u8], bucket: &CrashBucket, mut run: F) -> Vec<u8>
+where
+    F: FnMut(&[u8]) -> Option<CrashBucket>,
+{
+    let mut current = input.to_vec();
+    let mut chunk_size = current.len() / 2;
+
+    while chunk_size > 0 {
+        let mut removed = false;
+        let mut offset = 0;
+
+        while offset < current.len() {
+            let end = (offset + chunk_size).min(current.len());
+            let mut candidate = current.clone();
+            candidate.drain(offset..end);
+
+            if run(&candidate).as_ref() == Some(bucket) {
+                current = candidate;
+                removed = true;
+            } else {
+                offset += chunk_size;
+            }
+        }
+
+        if !removed {
+            chunk_size /= 2;
+        }
+    }
+
+    for i in 0..current.len() {
+        if current[i] == 0 {
+            continue;
+        }
+
+        let mut candidate = current.clone();
+        candidate[i] = 0;
+
+        if run(&candidate).as_ref() == Some(bucket) {
+            current = candidate;
+        }
+    }
+
+    current
+}
+
+/*
+    Runs `input` as the guest's stdin on a copy of `start`, a snapshot taken right before the crashing
+    run, for at most `max_insts` instructions. The copy's output goes nowhere and nothing it does is traced,
+    recorded or compare-logged.
+*/
+pub fn run_case(start: &Emulator, input: &[u8], max_insts: u64) -> Option<CrashBucket> {
+    let mut case = start.clone();
+    case.set_tracer(None);
+    case.disable_cmplog();
+    case.nondet = Nondet::default();
+    case.process.set_mute_output(true);
+    case.process.set_stdin(Arc::from(input));
+
+    match case.run(Some(max_insts)) {
+        Err(err) if is_crash(&err) => Some(CrashBucket::new(&case, &err)),
+        _ => None,
+    }
+}
+
+/*
+    Saves the crash `crashed` ended with to the crash database and, when `start` is given, shrinks the
+    input of a bucket seen for the first time and saves the smaller one instead. Returns the bucket if it
+    is new.
+*/
+pub fn store_crash(db: &mut CrashDb, crashed: &Emulator, err: &EmulatorErr, input: &[u8], start: Option<&Emulator>) -> Result<Option<CrashBucket>, TriageErr> {
+    let Some(bucket) = db.record(crashed, input, err)? else {
+        return Ok(None);
+    };
+
+    if let Some(start) = start {
+        //a candidate that runs much longer than the original did has most likely stopped crashing
+        let max_insts = (crashed.icount - start.icount).saturating_mul(MINIMIZE_BUDGET_FACTOR).max(1);
+        let minimized = minimize(input, &bucket, |candidate| run_case(start, candidate, max_insts));
+
+        db.update_input(&bucket, &minimized)?;
+    }
+
+    Ok(Some(bucket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing;
+
+    //reads up to 256 bytes of stdin to DATA_BASE and loads from address 0 if any of them is 'X'
+    const CRASH_ON_X: [u32; 16] = [
+        0x0000_0513, 0x0000_25b7, 0x1000_0613, 0x03f0_0893, 0x0000_0073, 0x0000_2337, 0x0580_0e13, 0x0005_0e63,
+        0x0003_4383, 0x01c3_8863, 0x0013_0313, 0xfff5_0513, 0xfedf_f06f, 0x0000_3e83, 0x05d0_0893, 0x0000_0073,
+    ];
+
+    fn bucket(pc: u64) -> CrashBucket {
+        CrashBucket { kind: FaultKind::AccessFault, pc, stack_hash: hash_call_stack(&[]) }
+    }
+
+    #[test]
+    fn call_stack_hash_is_stable() {
+        assert_eq!(hash_call_stack(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash_call_stack(&[0x1000, 0x2000]), hash_call_stack(&[0x1000, 0x2000]));
+        assert_ne!(hash_call_stack(&[0x1000, 0x2000]), hash_call_stack(&[0x2000, 0x1000]));
+        assert_eq!(bucket(0x1234).id(), "access_1234_cbf29ce484222325");
+    }
+
+    #[test]
+    fn minimize_keeps_only_what_crashes() {
+        let target = bucket(0x1000);
+        let input = b"aaaa BUG bbbb cccc dddd";
+
+        let crashes = |input: &[u8]| input.windows(3).any(|w| w == b"BUG").then_some(target);
+        assert_eq!(minimize(input, &target, crashes), b"BUG");
+
+        //bytes that can't be removed but don't matter are zeroed
+        let crashes = |input: &[u8]| (input.len() >= 4 && input[3] == b'!').then_some(target);
+        assert_eq!(minimize(b"abc!def", &target, crashes), b"\0\0\0!");
+
+        //a different bucket is not the same crash
+        let other = |_: &[u8]| Some(bucket(0x2000));
+        assert_eq!(minimize(input, &target, other), input);
+    }
+
+    #[test]
+    fn run_case_finds_the_crash_and_its_bucket() {
+        let start = testing::emulator(&CRASH_ON_X);
+
+        let crash = run_case(&start, b"abcXdef", 1000).expect("input with an X crashes");
+        assert_eq!(crash.kind, FaultKind::AccessFault);
+        assert_eq!(crash.pc, testing::CODE_BASE + 0x34);
+
+        assert_eq!(run_case(&start, b"abcdef", 1000), None);
+        //out of instructions before reaching the X
+        assert_eq!(run_case(&start, b"abcdefX", 10), None);
+    }
+
+    #[test]
+    fn crash_db_saves_each_bucket_once_and_minimizes_new_ones() {
+        let dir = std::env::temp_dir().join(format!("crimson-crash-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let start = testing::emulator(&CRASH_ON_X);
+        let input = b"hello X world";
+
+        let mut crashed = start.clone();
+        crashed.process.set_stdin(Arc::from(&input[..]));
+        let err = crashed.run(Some(1000)).unwrap_err();
+        assert!(is_crash(&err));
+
+        let mut db = CrashDb::new(&dir).unwrap();
+        let bucket = store_crash(&mut db, &crashed, &err, input, Some(&start)).unwrap().expect("a new bucket");
+        assert_eq!(fs::read(db.input_path(&bucket)).unwrap(), b"X");
+        assert!(fs::read_to_string(db.report_path(&bucket)).unwrap().contains(&bucket.id()));
+
+        assert_eq!(store_crash(&mut db, &crashed, &err, input, None).unwrap(), None);
+        assert_eq!(db.hits()[&bucket], 2);
+
+        //a later run finds it on disk
+        let mut db = CrashDb::new(&dir).unwrap();
+        assert_eq!(store_crash(&mut db, &crashed, &err, input, None).unwrap(), None);
+        assert_eq!(fs::read(db.input_path(&bucket)).unwrap(), b"X");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn exits_and_requested_stops_are_not_crashes() {
+        assert!(!is_crash(&EmulatorErr::ErrExited(1)));
+        assert!(!is_crash(&EmulatorErr::ErrHookStopped(0x1000)));
+        assert!(is_crash(&EmulatorErr::ErrTrap(Exceptions::ExceptionIllegalInstruction(0))));
+
+        let mut emu = testing::emulator(&CRASH_ON_X);
+        emu.add_cli_hooks(&[], &[], &[], &[format!("{:#x}", testing::CODE_BASE + 8)], &[]).unwrap();
+        let err = emu.run(Some(100)).unwrap_err();
+
+        let report = stop_report(&emu, &err);
+        assert!(report.starts_with("Stopped by an instruction hook at 0x1008"), "{}", report);
+        assert!(!report.contains("fault:") && !report.contains("bucket:"), "{}", report);
+    }
+}
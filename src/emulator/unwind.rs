@@ -0,0 +1,729 @@
+use std::{collections::HashMap, fmt, ops::Range};
+use super::{cpu::{Xlen, MAX_REGS}, dwarf::{DwarfErr, Reader}, memory::{self, Mmu}, Emulator};
+
+//deeper than any sane guest stack, a corrupt one can't keep us walking forever
+const MAX_FRAMES: usize = 64;
+
+const REG_SP: usize = 2;
+const REG_FP: usize = 8;
+
+//how .eh_frame pointers are stored, the low nibble is the format and the high one what it is relative to
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+//call frame instructions with an operand in the low 6 bits
+const DW_CFA_ADVANCE_LOC: u8 = 0x1;
+const DW_CFA_OFFSET: u8 = 0x2;
+const DW_CFA_RESTORE: u8 = 0x3;
+
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_SET_LOC: u8 = 0x01;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_RESTORE_EXTENDED: u8 = 0x06;
+const DW_CFA_UNDEFINED: u8 = 0x07;
+const DW_CFA_SAME_VALUE: u8 = 0x08;
+const DW_CFA_REGISTER: u8 = 0x09;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0a;
+const DW_CFA_RESTORE_STATE: u8 = 0x0b;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_DEF_CFA_EXPRESSION: u8 = 0x0f;
+const DW_CFA_EXPRESSION: u8 = 0x10;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+const DW_CFA_DEF_CFA_SF: u8 = 0x12;
+const DW_CFA_DEF_CFA_OFFSET_SF: u8 = 0x13;
+const DW_CFA_VAL_OFFSET: u8 = 0x14;
+const DW_CFA_VAL_OFFSET_SF: u8 = 0x15;
+const DW_CFA_VAL_EXPRESSION: u8 = 0x16;
+const DW_CFA_GNU_ARGS_SIZE: u8 = 0x2e;
+
+#[derive(Clone)]
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra_reg: usize,
+    fde_encoding: u8,
+    //the FDEs carry augmentation data of their own
+    augmented: bool,
+    instructions: Range<usize>,
+}
+
+#[derive(Clone)]
+struct Fde {
+    start: u64,
+    end: u64,
+    cie: usize,
+    instructions: Range<usize>,
+}
+
+//where the caller's value of a register is, relative to the canonical frame address
+#[derive(Clone, Copy, Debug)]
+enum Rule {
+    SameValue,
+    Undefined,
+    Offset(i64),
+    ValOffset(i64),
+    Register(usize),
+    //a DWARF expression, we don't evaluate those
+    Expression,
+}
+
+#[derive(Clone)]
+struct Rules {
+    //register + offset, None when it is an expression
+    cfa: Option<(usize, i64)>,
+    regs: [Rule; MAX_REGS],
+}
+
+impl Rules {
+    fn set(&mut self, reg: u64, rule: Rule) {
+        //floating point and vector registers don't matter for finding the caller
+        if let Some(slot) = self.regs.get_mut(reg as usize) {
+            *slot = rule;
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules { cfa: None, regs: [Rule::SameValue; MAX_REGS] }
+    }
+}
+
+/*
+    The call frame information in .eh_frame, which compilers emit even for C so exceptions and
+    backtraces can cross any function. For every pc it says how to compute the canonical frame address
+    (the sp at the call) and where the caller's registers were saved relative to it.
+*/
+#[derive(Clone, Default)]
+pub struct EhFrame {
+    data: Vec<u8>,
+    //where the section is loaded, pc relative pointers are relative to it
+    addr: u64,
+    //of absolute pointers, 4 in an ELF32 file
+    address_size: usize,
+    cies: Vec<Cie>,
+    //sorted by start
+    fdes: Vec<Fde>,
+}
+
+impl EhFrame {
+    pub fn parse(data: &[u8], addr: u64, address_size: usize) -> Result<Self, DwarfErr> {
+        let mut frame = EhFrame { data: data.to_vec(), addr, address_size, ..EhFrame::default() };
+        let mut cie_at = HashMap::new();
+        let mut reader = Reader::new(data);
+
+        while !reader.is_empty() {
+            let start = reader.pos();
+
+            let (length, id_size) = match reader.uint(4)? {
+                //terminator
+                0 => break,
+                0xffff_ffff => (reader.uint(8)?, 8),
+                len => (len, 4),
+            };
+            let pos = reader.pos();
+            let mut entry = Reader::at(reader.bytes(length as usize)?, pos);
+            let end = entry.pos() + length as usize;
+
+            //0 for a CIE, for an FDE how far back its CIE is from this field
+            let id_pos = entry.pos();
+            let id = entry.uint(id_size)? as usize;
+
+            if id == 0 {
+                cie_at.insert(start, frame.cies.len());
+                let cie = frame.parse_cie(&mut entry, end)?;
+                frame.cies.push(cie);
+                continue;
+            }
+
+            let cie = id_pos.checked_sub(id).and_then(|cie| cie_at.get(&cie).copied()).ok_or(DwarfErr::InvalidCfi)?;
+            let fde = frame.parse_fde(&mut entry, end, cie)?;
+
+            //left behind by functions the linker dropped
+            if fde.start != 0 && fde.start < fde.end {
+                frame.fdes.push(fde);
+            }
+        }
+
+        frame.fdes.sort_by_key(|fde| fde.start);
+
+        Ok(frame)
+    }
+
+    fn parse_cie(&self, entry: &mut Reader, end: usize) -> Result<Cie, DwarfErr> {
+        let version = entry.u8()?;
+        let augmentation = entry.cstr()?;
+
+        if augmentation.windows(2).any(|chars| chars == b"eh") {
+            entry.uint(self.address_size)?;
+        }
+        if version >= 4 {
+            //address and segment selector size
+            entry.bytes(2)?;
+        }
+
+        let code_align = entry.uleb()?;
+        let data_align = entry.sleb()?;
+        let ra_reg = if version == 1 { entry.u8()? as u64 } else { entry.uleb()? } as usize;
+
+        let mut fde_encoding = DW_EH_PE_ABSPTR;
+        let augmented = augmentation.first() == Some(&b'z');
+
+        if augmented {
+            let len = entry.uleb()? as usize;
+            let pos = entry.pos();
+            let mut data = Reader::at(entry.bytes(len)?, pos);
+
+            for augmentation in &augmentation[1..] {
+                match augmentation {
+                    b'R' => fde_encoding = data.u8()?,
+                    b'L' => { data.u8()?; }
+                    b'P' => {
+                        let encoding = data.u8()?;
+                        self.read_pointer(&mut data, encoding)?;
+                    }
+                    //signal frames and the like, nothing to read
+                    _ => {}
+                }
+            }
+        } else if !augmentation.is_empty() && augmentation != b"eh" {
+            return Err(DwarfErr::InvalidCfi);
+        }
+
+        Ok(Cie { code_align, data_align, ra_reg, fde_encoding, augmented, instructions: entry.pos()..end })
+    }
+
+    fn parse_fde(&self, entry: &mut Reader, end: usize, cie: usize) -> Result<Fde, DwarfErr> {
+        let encoding = self.cies[cie].fde_encoding;
+
+        let start = self.read_pointer(entry, encoding)?;
+        //a length, only the format of the encoding applies
+        let len = self.read_pointer(entry, encoding & 0x0f)?;
+
+        if self.cies[cie].augmented {
+            let len = entry.uleb()? as usize;
+            entry.bytes(len)?;
+        }
+
+        Ok(Fde { start, end: start.wrapping_add(len), cie, instructions: entry.pos()..end })
+    }
+
+    fn read_pointer(&self, reader: &mut Reader, encoding: u8) -> Result<u64, DwarfErr> {
+        if encoding == DW_EH_PE_OMIT {
+            return Ok(0);
+        }
+
+        let field = self.addr.wrapping_add(reader.pos() as u64);
+
+        let value = match encoding & 0x0f {
+            DW_EH_PE_ABSPTR => reader.uint(self.address_size)?,
+            DW_EH_PE_UDATA8 => reader.uint(8)?,
+            DW_EH_PE_ULEB128 => reader.uleb()?,
+            DW_EH_PE_UDATA2 => reader.uint(2)?,
+            DW_EH_PE_UDATA4 => reader.uint(4)?,
+            DW_EH_PE_SLEB128 => reader.sleb()? as u64,
+            DW_EH_PE_SDATA2 => reader.sint(2)? as u64,
+            DW_EH_PE_SDATA4 => reader.sint(4)? as u64,
+            DW_EH_PE_SDATA8 => reader.sint(8)? as u64,
+            _ => return Err(DwarfErr::UnsupportedEncoding(encoding)),
+        };
+
+        //0x80 marks an indirect pointer, only personality routines use those and we never follow them
+        match encoding & 0x70 {
+            0 => Ok(value),
+            DW_EH_PE_PCREL => Ok(field.wrapping_add(value)),
+            _ => Err(DwarfErr::UnsupportedEncoding(encoding)),
+        }
+    }
+
+    fn fde_for(&self, pc: u64) -> Option<&Fde> {
+        let idx = self.fdes.partition_point(|fde| fde.start <= pc).checked_sub(1)?;
+        let fde = &self.fdes[idx];
+
+        (pc < fde.end).then_some(fde)
+    }
+
+    //the rules in effect at pc: the CIE's initial instructions, then the FDE's up to pc
+    fn rules_at(&self, fde: &Fde, pc: u64) -> Result<Rules, DwarfErr> {
+        let cie = &self.cies[fde.cie];
+        let mut rules = Rules::default();
+
+        self.execute(cie, cie.instructions.clone(), fde.start, pc, &mut rules, None)?;
+        let initial = rules.clone();
+        self.execute(cie, fde.instructions.clone(), fde.start, pc, &mut rules, Some(&initial))?;
+
+        Ok(rules)
+    }
+
+    fn execute(&self, cie: &Cie, code: Range<usize>, mut loc: u64, pc: u64, rules: &mut Rules, initial: Option<&Rules>) -> Result<(), DwarfErr> {
+        let mut reader = Reader::at(self.data.get(code.clone()).ok_or(DwarfErr::InvalidCfi)?, code.start);
+        let mut stack = Vec::new();
+
+        let restore = |rules: &mut Rules, reg: u64| {
+            let rule = initial.and_then(|initial| initial.regs.get(reg as usize).copied()).unwrap_or(Rule::SameValue);
+            rules.set(reg, rule);
+        };
+
+        while !reader.is_empty() {
+            let op = reader.u8()?;
+            let operand = (op & 0x3f) as u64;
+
+            let advance = match op >> 6 {
+                DW_CFA_ADVANCE_LOC => Some(operand),
+                DW_CFA_OFFSET => {
+                    let offset = reader.uleb()? as i64 * cie.data_align;
+                    rules.set(operand, Rule::Offset(offset));
+                    None
+                }
+                DW_CFA_RESTORE => {
+                    restore(rules, operand);
+                    None
+                }
+                _ => match op {
+                    DW_CFA_NOP => None,
+                    DW_CFA_SET_LOC => {
+                        loc = self.read_pointer(&mut reader, cie.fde_encoding)?;
+                        if loc > pc {
+                            return Ok(());
+                        }
+                        None
+                    }
+                    DW_CFA_ADVANCE_LOC1 => Some(reader.uint(1)?),
+                    DW_CFA_ADVANCE_LOC2 => Some(reader.uint(2)?),
+                    DW_CFA_ADVANCE_LOC4 => Some(reader.uint(4)?),
+                    DW_CFA_OFFSET_EXTENDED => {
+                        let reg = reader.uleb()?;
+                        rules.set(reg, Rule::Offset(reader.uleb()? as i64 * cie.data_align));
+                        None
+                    }
+                    DW_CFA_OFFSET_EXTENDED_SF => {
+                        let reg = reader.uleb()?;
+                        rules.set(reg, Rule::Offset(reader.sleb()? * cie.data_align));
+                        None
+                    }
+                    DW_CFA_VAL_OFFSET => {
+                        let reg = reader.uleb()?;
+                        rules.set(reg, Rule::ValOffset(reader.uleb()? as i64 * cie.data_align));
+                        None
+                    }
+                    DW_CFA_VAL_OFFSET_SF => {
+                        let reg = reader.uleb()?;
+                        rules.set(reg, Rule::ValOffset(reader.sleb()? * cie.data_align));
+                        None
+                    }
+                    DW_CFA_RESTORE_EXTENDED => {
+                        let reg = reader.uleb()?;
+                        restore(rules, reg);
+                        None
+                    }
+                    DW_CFA_UNDEFINED => {
+                        rules.set(reader.uleb()?, Rule::Undefined);
+                        None
+                    }
+                    DW_CFA_SAME_VALUE => {
+                        rules.set(reader.uleb()?, Rule::SameValue);
+                        None
+                    }
+                    DW_CFA_REGISTER => {
+                        let reg = reader.uleb()?;
+                        rules.set(reg, Rule::Register(reader.uleb()? as usize));
+                        None
+                    }
+                    DW_CFA_REMEMBER_STATE => {
+                        stack.push(rules.clone());
+                        None
+                    }
+                    DW_CFA_RESTORE_STATE => {
+                        *rules = stack.pop().ok_or(DwarfErr::InvalidCfi)?;
+                        None
+                    }
+                    DW_CFA_DEF_CFA => {
+                        rules.cfa = Some((reader.uleb()? as usize, reader.uleb()? as i64));
+                        None
+                    }
+                    DW_CFA_DEF_CFA_SF => {
+                        rules.cfa = Some((reader.uleb()? as usize, reader.sleb()? * cie.data_align));
+                        None
+                    }
+                    DW_CFA_DEF_CFA_REGISTER => {
+                        let reg = reader.uleb()? as usize;
+                        rules.cfa = Some((reg, rules.cfa.map_or(0, |(_, offset)| offset)));
+                        None
+                    }
+                    DW_CFA_DEF_CFA_OFFSET => {
+                        let offset = reader.uleb()? as i64;
+                        rules.cfa = rules.cfa.map(|(reg, _)| (reg, offset));
+                        None
+                    }
+                    DW_CFA_DEF_CFA_OFFSET_SF => {
+                        let offset = reader.sleb()? * cie.data_align;
+                        rules.cfa = rules.cfa.map(|(reg, _)| (reg, offset));
+                        None
+                    }
+                    DW_CFA_DEF_CFA_EXPRESSION => {
+                        let len = reader.uleb()? as usize;
+                        reader.bytes(len)?;
+                        rules.cfa = None;
+                        None
+                    }
+                    DW_CFA_EXPRESSION | DW_CFA_VAL_EXPRESSION => {
+                        let reg = reader.uleb()?;
+                        let len = reader.uleb()? as usize;
+                        reader.bytes(len)?;
+                        rules.set(reg, Rule::Expression);
+                        None
+                    }
+                    DW_CFA_GNU_ARGS_SIZE => {
+                        reader.uleb()?;
+                        None
+                    }
+                    op => return Err(DwarfErr::UnsupportedCfi(op)),
+                },
+            };
+
+            //the rows so far describe every address before the one this moves to
+            if let Some(delta) = advance {
+                loc = loc.wrapping_add(delta * cie.code_align);
+                if loc > pc {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        The caller's registers and the address it continues at, None without CFI for pc or once the
+        return address is undefined (the outermost frame). A return address points after the call, which
+        can be past the end of the caller's FDE for a call that never returns, so callers are looked up
+        one byte earlier.
+    */
+    fn step(&self, mmu: &Mmu, regs: &[u64; MAX_REGS], pc: u64, caller: bool, xlen: Xlen) -> Option<([u64; MAX_REGS], u64)> {
+        let pc = if caller { pc.wrapping_sub(1) } else { pc };
+        let fde = self.fde_for(pc)?;
+        let cie = &self.cies[fde.cie];
+        let rules = self.rules_at(fde, pc).ok()?;
+
+        let (cfa_reg, cfa_offset) = rules.cfa?;
+        let cfa = regs.get(cfa_reg)?.wrapping_add_signed(cfa_offset);
+        let mut caller_regs = *regs;
+
+        for (reg, rule) in rules.regs.iter().enumerate() {
+            caller_regs[reg] = match *rule {
+                Rule::SameValue | Rule::Undefined => regs[reg],
+                Rule::Offset(offset) => read_word(mmu, cfa.wrapping_add_signed(offset), xlen)?,
+                Rule::ValOffset(offset) => cfa.wrapping_add_signed(offset),
+                Rule::Register(from) => *regs.get(from)?,
+                Rule::Expression => return None,
+            };
+        }
+        caller_regs[REG_SP] = cfa;
+
+        match rules.regs.get(cie.ra_reg)? {
+            Rule::Undefined => None,
+            _ => Some((caller_regs, caller_regs[cie.ra_reg])),
+        }
+    }
+}
+
+//a saved register, XLEN bits wide
+fn read_word(mmu: &Mmu, addr: u64, xlen: Xlen) -> Option<u64> {
+    let bytes = mmu.dram_read(addr as usize, xlen.bytes()).ok()?;
+    let mut word = [0; 8];
+    word.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+
+    Some(u64::from_le_bytes(word))
+}
+
+/*
+    The standard RISC-V frame record: with frame pointers the prologue saves ra at fp - 8 and the caller's
+    fp at fp - 16 (fp - 4 and fp - 8 on RV32), fp being the sp at the call. The frame pointer has to point into the stack above sp,
+    code built without frame pointers leaves anything in s0.
+*/
+fn step_frame_pointer(mmu: &Mmu, regs: &[u64; MAX_REGS], xlen: Xlen) -> Option<([u64; MAX_REGS], u64)> {
+    let fp = regs[REG_FP];
+    let sp = regs[REG_SP];
+    let word = xlen.bytes() as u64;
+
+    if fp < sp || !fp.is_multiple_of(word) || mmu.region_at(sp as usize)?.end < fp as usize {
+        return None;
+    }
+
+    let mut caller_regs = *regs;
+    caller_regs[REG_FP] = read_word(mmu, fp.checked_sub(2 * word)?, xlen)?;
+    caller_regs[REG_SP] = fp;
+
+    Some((caller_regs, read_word(mmu, fp - word, xlen)?))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unwinder {
+    //the faulting frame itself
+    Pc,
+    Cfi,
+    FramePointer,
+    ShadowStack,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub pc: u64,
+    //how this frame was found
+    pub unwinder: Unwinder,
+}
+
+impl fmt::Display for Unwinder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Unwinder::Pc => "pc",
+            Unwinder::Cfi => "cfi",
+            Unwinder::FramePointer => "fp",
+            Unwinder::ShadowStack => "shadow stack",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl Emulator {
+    /*
+        The running hart's frames, innermost first. Each frame is unwound with the CFI if its function has
+        any and by the frame pointer otherwise. When neither gets past the first frame the return addresses
+        the cpu tracked from calls (JAL/JALR with rd = ra) are all we have.
+    */
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let mut regs = *self.cpu.get_regs();
+        let mut pc = self.cpu.get_pc();
+        let mut frames = vec![Frame { pc, unwinder: Unwinder::Pc }];
+        let xlen = self.cpu.xlen();
+
+        while frames.len() < MAX_FRAMES {
+            let caller = frames.len() > 1;
+
+            let step = match self.eh_frame.step(&self.mmu, &regs, pc, caller, xlen) {
+                Some(step) => Some((step, Unwinder::Cfi)),
+                None => step_frame_pointer(&self.mmu, &regs, xlen).map(|step| (step, Unwinder::FramePointer)),
+            };
+            let Some(((caller_regs, ret), unwinder)) = step else {
+                break;
+            };
+
+            //the stack only grows down, a caller below its callee means the walk went wrong
+            let stuck = caller_regs[REG_SP] < regs[REG_SP] || (caller_regs[REG_SP] == regs[REG_SP] && ret == pc);
+            if ret == 0 || stuck || self.mmu.perm_at(ret as usize) & memory::PERM_X == 0 {
+                break;
+            }
+
+            frames.push(Frame { pc: ret, unwinder });
+            regs = caller_regs;
+            pc = ret;
+        }
+
+        if frames.len() == 1 {
+            frames.extend(self.cpu.call_stack().iter().rev().map(|&pc| Frame { pc, unwinder: Unwinder::ShadowStack }));
+        }
+
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{cpu::REG_RA, testing::{self, CODE_BASE, DATA_BASE, DATA_SIZE}};
+
+    //where the .eh_frame below would be loaded, pc relative pointers are relative to it
+    const SECTION_ADDR: u64 = 0x8000;
+
+    const MAIN: u64 = CODE_BASE;
+    const F: u64 = CODE_BASE + 0x18;
+    const F_END: u64 = CODE_BASE + 0x30;
+
+    const NOP: u32 = 0x0000_0013;
+
+    //a "zR" CIE with pc relative, signed 4 byte FDE pointers and cfa = sp on entry, then FDEs for it
+    struct Section {
+        data: Vec<u8>,
+    }
+
+    impl Section {
+        fn new() -> Self {
+            let mut body = 0u32.to_le_bytes().to_vec();
+            //version 1, code_align 1, data_align -8, ra_reg 1
+            body.extend_from_slice(b"\x01zR\0\x01\x78\x01");
+            body.extend_from_slice(&[1, DW_EH_PE_PCREL | DW_EH_PE_SDATA4]);
+            body.extend_from_slice(&[DW_CFA_DEF_CFA, REG_SP as u8, 0]);
+
+            let mut section = Section { data: Vec::new() };
+            section.entry(body);
+            section
+        }
+
+        fn entry(&mut self, body: Vec<u8>) {
+            self.data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            self.data.extend(body);
+        }
+
+        fn fde(&mut self, start: u64, end: u64, instructions: &[u8]) {
+            //how far back the CIE (at 0) is from the id field, then where pc_begin is
+            let id_pos = self.data.len() + 4;
+            let field = SECTION_ADDR + id_pos as u64 + 4;
+
+            let mut body = (id_pos as u32).to_le_bytes().to_vec();
+            body.extend_from_slice(&(start.wrapping_sub(field) as u32).to_le_bytes());
+            body.extend_from_slice(&((end - start) as u32).to_le_bytes());
+            body.push(0);
+            body.extend_from_slice(instructions);
+            self.entry(body);
+        }
+
+        fn parse(self) -> Result<EhFrame, DwarfErr> {
+            EhFrame::parse(&self.data, SECTION_ADDR, 8)
+        }
+    }
+
+    /*
+        main is the outermost frame, f saves ra and s0 in a 16 byte frame after its first instruction and
+        pops it again before its last two.
+    */
+    fn eh_frame() -> EhFrame {
+        let mut section = Section::new();
+        section.fde(MAIN, F, &[DW_CFA_UNDEFINED, REG_RA as u8]);
+        section.fde(F, F_END, &[
+            DW_CFA_ADVANCE_LOC << 6 | 4,
+            DW_CFA_DEF_CFA_OFFSET, 16,
+            DW_CFA_OFFSET << 6 | REG_RA as u8, 1,
+            DW_CFA_OFFSET << 6 | REG_FP as u8, 2,
+            DW_CFA_ADVANCE_LOC << 6 | 12,
+            DW_CFA_DEF_CFA_OFFSET, 0,
+            DW_CFA_RESTORE << 6 | REG_RA as u8,
+            DW_CFA_RESTORE << 6 | REG_FP as u8,
+        ]);
+
+        section.parse().unwrap()
+    }
+
+    fn emulator(eh_frame: EhFrame, pc: u64, sp: u64, fp: u64, ra: u64) -> Emulator {
+        let mut emu = testing::emulator(&[NOP; 12]);
+        emu.eh_frame = std::sync::Arc::new(eh_frame);
+        emu.cpu.set_pc(pc);
+        emu.cpu.set_reg(REG_SP, sp).unwrap();
+        emu.cpu.set_reg(REG_FP, fp).unwrap();
+        emu.cpu.set_reg(REG_RA, ra).unwrap();
+
+        emu
+    }
+
+    fn frames(emu: &Emulator) -> Vec<(u64, Unwinder)> {
+        emu.backtrace().iter().map(|frame| (frame.pc, frame.unwinder)).collect()
+    }
+
+    #[test]
+    fn rules_follow_the_pc_through_the_function() {
+        let eh_frame = eh_frame();
+        let rules = |pc| eh_frame.rules_at(eh_frame.fde_for(pc).unwrap(), pc).unwrap();
+
+        assert!(eh_frame.fde_for(MAIN - 1).is_none());
+        assert!(eh_frame.fde_for(F_END).is_none());
+        assert!(matches!(rules(MAIN).regs[REG_RA], Rule::Undefined));
+
+        let entry = rules(F + 3);
+        assert_eq!(entry.cfa, Some((REG_SP, 0)));
+        assert!(matches!(entry.regs[REG_RA], Rule::SameValue));
+
+        let body = rules(F + 4);
+        assert_eq!(body.cfa, Some((REG_SP, 16)));
+        assert!(matches!(body.regs[REG_RA], Rule::Offset(-8)));
+        assert!(matches!(body.regs[REG_FP], Rule::Offset(-16)));
+
+        let epilogue = rules(F + 16);
+        assert_eq!(epilogue.cfa, Some((REG_SP, 0)));
+        assert!(matches!(epilogue.regs[REG_RA], Rule::SameValue));
+        assert!(matches!(epilogue.regs[REG_FP], Rule::SameValue));
+    }
+
+    #[test]
+    fn broken_call_frame_information_is_an_error() {
+        //an FDE whose CIE pointer goes nowhere
+        let mut section = Section::new();
+        section.fde(MAIN, F, &[]);
+        let id = section.data.len() - 13;
+        section.data[id] ^= 0x40;
+        assert!(matches!(section.parse(), Err(DwarfErr::InvalidCfi)));
+
+        let mut section = Section::new();
+        section.fde(MAIN, F, &[]);
+        section.data.pop();
+        assert!(matches!(section.parse(), Err(DwarfErr::Truncated)));
+
+        //pointers relative to the data segment (DW_EH_PE_datarel) need a base we don't have
+        let mut section = Section::new();
+        let encoding = section.data.iter().position(|byte| *byte == DW_EH_PE_PCREL | DW_EH_PE_SDATA4).unwrap();
+        section.data[encoding] = 0x30 | DW_EH_PE_SDATA4;
+        section.fde(MAIN, F, &[]);
+        assert!(matches!(section.parse(), Err(DwarfErr::UnsupportedEncoding(0x3b))));
+
+        let mut section = Section::new();
+        section.fde(MAIN, F, &[0x3f]);
+        let eh_frame = section.parse().unwrap();
+        assert!(matches!(eh_frame.rules_at(&eh_frame.fdes[0], MAIN), Err(DwarfErr::UnsupportedCfi(0x3f))));
+    }
+
+    #[test]
+    fn frames_are_unwound_with_the_cfi() {
+        let sp = DATA_BASE + 0xf00;
+
+        //in the body of f: ra and the caller's s0 are on the stack
+        let mut emu = emulator(eh_frame(), F + 8, sp, 0, 0);
+        emu.mmu.write_u64(sp + 8, MAIN + 0x10).unwrap();
+        assert_eq!(frames(&emu), [(F + 8, Unwinder::Pc), (MAIN + 0x10, Unwinder::Cfi)]);
+
+        //in the epilogue the stack is popped and ra holds the return address again
+        let emu = emulator(eh_frame(), F + 0x14, sp, 0, MAIN + 0x14);
+        assert_eq!(frames(&emu), [(F + 0x14, Unwinder::Pc), (MAIN + 0x14, Unwinder::Cfi)]);
+    }
+
+    #[test]
+    fn frames_without_cfi_are_unwound_by_the_frame_pointer() {
+        let sp = DATA_BASE + 0xf00;
+        let fp = sp + 0x10;
+        let caller_fp = sp + 0x40;
+
+        let mut emu = emulator(EhFrame::default(), F + 8, sp, fp, 0);
+        emu.mmu.write_u64(fp - 8, F + 4).unwrap();
+        emu.mmu.write_u64(fp - 16, caller_fp).unwrap();
+        emu.mmu.write_u64(caller_fp - 8, MAIN + 4).unwrap();
+        //the outermost frame's record points at the top of the stack, where the return address is null
+        emu.mmu.write_u64(caller_fp - 16, DATA_BASE + DATA_SIZE as u64).unwrap();
+
+        assert_eq!(frames(&emu), [(F + 8, Unwinder::Pc), (F + 4, Unwinder::FramePointer), (MAIN + 4, Unwinder::FramePointer)]);
+
+        //a frame pointer below sp is just whatever s0 held
+        let emu = emulator(EhFrame::default(), F + 8, sp, sp - 0x10, 0);
+        assert_eq!(frames(&emu), [(F + 8, Unwinder::Pc)]);
+    }
+
+    #[test]
+    fn tracked_calls_are_the_last_resort() {
+        //jal ra, 8
+        let mut emu = testing::emulator(&[0x0080_00ef, NOP, NOP]);
+        emu.run(Some(1)).unwrap();
+
+        assert_eq!(frames(&emu), [(CODE_BASE + 8, Unwinder::Pc), (CODE_BASE + 4, Unwinder::ShadowStack)]);
+    }
+}